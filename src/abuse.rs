@@ -0,0 +1,375 @@
+//SPDX-License-Identifier: MIT
+
+//! Abuse scoring for /send/. Each request gets a score, the weighted sum of a few signals that,
+//! on their own, don't mean much, but together tell scripts from people:
+//!
+//!  - ip: how many payouts the client's IP got in the last day
+//!  - address: how many payouts the address got in the last week
+//!  - frequency: how many other requests the client made in the last ten minutes
+//!  - user_agent: 1 if the client didn't send a user agent, or sent a scripting tool's
+//!  - amount: 1 if the client asked for as much as anonymous users may get, or more
+//!
+//! Requests scoring high enough have to solve a captcha or proof of work, even if those are
+//! optional, and higher still only get the anonymous tier. Every request that scores something
+//! is logged to the database, for operators to review at /admin/abuse and tune the weights.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use actix_web::http::header;
+use actix_web::web;
+use actix_web::HttpRequest;
+use bitcoin::Address;
+use bitcoin::Amount;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+use utoipa::IntoParams;
+use utoipa::ToSchema;
+
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+use crate::db::Database;
+use crate::ratelimit::client_ip;
+
+/// How far back the ip signal looks
+const IP_WINDOW: Duration = Duration::from_secs(24 * 3_600);
+
+/// How far back the address signal looks
+const ADDRESS_WINDOW: Duration = Duration::from_secs(7 * 24 * 3_600);
+
+/// How far back the frequency signal looks
+const FREQUENCY_WINDOW: Duration = Duration::from_secs(600);
+
+/// User agents of tools people script faucets with, lowercase
+const SCRIPTED_AGENTS: &[&str] = &[
+    "curl",
+    "wget",
+    "python",
+    "go-http-client",
+    "okhttp",
+    "axios/",
+    "node-fetch",
+    "libwww-perl",
+    "java/",
+];
+
+/// How much each signal weighs in the score
+#[derive(Debug, Clone, Copy)]
+pub struct Weights {
+    pub ip: f64,
+    pub address: f64,
+    pub frequency: f64,
+    pub user_agent: f64,
+    pub amount: f64,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Self {
+            ip: 1.0,
+            address: 1.0,
+            frequency: 0.5,
+            user_agent: 2.0,
+            amount: 0.5,
+        }
+    }
+}
+
+impl Weights {
+    /// Parses ABUSE_WEIGHTS, like `ip=1,user_agent=2`. Signals it doesn't name keep their
+    /// default weight
+    pub fn parse(weights: &str) -> Result<Self, String> {
+        let mut parsed = Self::default();
+        for weight in weights
+            .split(',')
+            .filter(|weight| !weight.trim().is_empty())
+        {
+            let (name, value) = weight
+                .split_once('=')
+                .ok_or(format!("{weight} isn't name=weight"))?;
+            let value = value
+                .trim()
+                .parse::<f64>()
+                .map_err(|e| format!("{weight}: {e}"))?;
+            match name.trim() {
+                "ip" => parsed.ip = value,
+                "address" => parsed.address = value,
+                "frequency" => parsed.frequency = value,
+                "user_agent" => parsed.user_agent = value,
+                "amount" => parsed.amount = value,
+                name => return Err(format!("there's no {name} signal")),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// What we saw of a request
+#[derive(Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct Signals {
+    pub ip: u64,
+    pub address: u64,
+    pub frequency: u64,
+    pub user_agent: bool,
+    pub amount: bool,
+}
+
+/// What we decided to do with a request
+#[derive(Debug, Default, Clone, Copy, Serialize, ToSchema)]
+pub struct Verdict {
+    pub score: f64,
+    /// It has to solve a captcha or proof of work
+    pub challenge: bool,
+    /// It only gets the anonymous tier
+    pub downgrade: bool,
+}
+
+/// A scored request, as /admin/abuse shows it
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Decision {
+    pub id: i64,
+    /// In unix time
+    pub created_at: u64,
+    /// The salted hash of the client's IP
+    pub client: Option<String>,
+    pub address: String,
+    /// In sats
+    pub amount: u64,
+    pub signals: Signals,
+    #[serde(flatten)]
+    pub verdict: Verdict,
+}
+
+pub struct AbuseScorer {
+    weights: Weights,
+    /// The score from which requests have to solve a challenge
+    challenge_at: Option<f64>,
+    /// The score from which requests only get the anonymous tier
+    downgrade_at: Option<f64>,
+    /// When each client made its last requests
+    recent: Mutex<HashMap<IpAddr, VecDeque<Instant>>>,
+}
+
+/// Whether `agent` is missing, or belongs to a scripting tool
+fn is_scripted(agent: Option<&str>) -> bool {
+    let Some(agent) = agent.map(str::to_lowercase) else {
+        return true;
+    };
+
+    agent.trim().is_empty() || SCRIPTED_AGENTS.iter().any(|tool| agent.starts_with(tool))
+}
+
+impl AbuseScorer {
+    pub fn new(weights: Weights, challenge_at: Option<f64>, downgrade_at: Option<f64>) -> Self {
+        Self {
+            weights,
+            challenge_at,
+            downgrade_at,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a request from `client`, returning how many others it made lately
+    fn frequency(&self, client: IpAddr) -> u64 {
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, requests| {
+            requests
+                .back()
+                .is_some_and(|last| last.elapsed() < FREQUENCY_WINDOW)
+        });
+
+        let requests = recent.entry(client).or_default();
+        while requests
+            .front()
+            .is_some_and(|first| first.elapsed() >= FREQUENCY_WINDOW)
+        {
+            requests.pop_front();
+        }
+        requests.push_back(Instant::now());
+
+        requests.len() as u64 - 1
+    }
+
+    /// Scores `req`, asking for `amount` at `address`, when no one may get more than
+    /// `max_sendable`, and logs what we decided if it scored anything
+    pub fn score(
+        &self,
+        db: &Database,
+        req: &HttpRequest,
+        address: &Address,
+        amount: Amount,
+        max_sendable: Amount,
+    ) -> Result<Verdict, Error> {
+        let client = client_ip(req);
+        let agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|agent| agent.to_str().ok());
+
+        let signals = Signals {
+            ip: match client {
+                Some(ip) => db.client_payouts(ip, IP_WINDOW)?,
+                None => 0,
+            },
+            address: db.address_payouts(address, ADDRESS_WINDOW)?,
+            frequency: client.map_or(0, |ip| self.frequency(ip)),
+            user_agent: is_scripted(agent),
+            amount: amount >= max_sendable,
+        };
+
+        let weights = &self.weights;
+        let score = signals.ip as f64 * weights.ip
+            + signals.address as f64 * weights.address
+            + signals.frequency as f64 * weights.frequency
+            + signals.user_agent as u8 as f64 * weights.user_agent
+            + signals.amount as u8 as f64 * weights.amount;
+        let verdict = Verdict {
+            score,
+            challenge: self.challenge_at.is_some_and(|at| score >= at),
+            downgrade: self.downgrade_at.is_some_and(|at| score >= at),
+        };
+
+        if score > 0.0 {
+            if let Err(e) = db.record_decision(client, address, amount, &signals, &verdict) {
+                warn!("couldn't log abuse decision: {e}");
+            }
+        }
+
+        Ok(verdict)
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct DecisionsQuery {
+    limit: Option<u32>,
+}
+
+/// Lists the latest scored requests, 100 unless asked for a `limit`
+#[utoipa::path(
+    get,
+    path = "/v1/admin/abuse",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    params(DecisionsQuery),
+    responses(
+        (status = 200, body = Vec<Decision>),
+        (status = 401, body = ErrorBody),
+    )
+)]
+pub async fn list_decisions<B: ChainBackend>(
+    query: web::Query<DecisionsQuery>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Vec<Decision>>, Error> {
+    let limit = query.limit.unwrap_or(100);
+    Ok(web::Json(data.db.decisions(limit)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::testing::address;
+    use crate::testing::CHANGE_ADDRESS;
+
+    const MAX: Amount = Amount::from_sat(100_000);
+
+    fn request(agent: Option<&str>) -> HttpRequest {
+        let req = TestRequest::default().peer_addr("192.0.2.1:1234".parse().unwrap());
+        match agent {
+            Some(agent) => req.insert_header((header::USER_AGENT, agent)),
+            None => req,
+        }
+        .to_http_request()
+    }
+
+    #[test]
+    fn parses_weights() {
+        let weights = Weights::parse("ip=3, user_agent = 0.5").unwrap();
+        assert_eq!(weights.ip, 3.0);
+        assert_eq!(weights.user_agent, 0.5);
+        assert_eq!(weights.address, Weights::default().address);
+
+        assert_eq!(Weights::parse("").unwrap().ip, Weights::default().ip);
+        assert!(Weights::parse("ip").is_err());
+        assert!(Weights::parse("ip=lots").is_err());
+        assert!(Weights::parse("country=1").is_err());
+    }
+
+    #[test]
+    fn tells_scripts_by_their_user_agent() {
+        assert!(is_scripted(None));
+        assert!(is_scripted(Some(" ")));
+        assert!(is_scripted(Some("curl/8.5.0")));
+        assert!(is_scripted(Some("Python-urllib/3.12")));
+        assert!(!is_scripted(Some(
+            "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0"
+        )));
+    }
+
+    #[test]
+    fn scores_people_nothing() {
+        let db = Database::open(":memory:".as_ref()).unwrap();
+        let scorer = AbuseScorer::new(Weights::default(), Some(1.0), Some(2.0));
+
+        let verdict = scorer
+            .score(
+                &db,
+                &request(Some("Mozilla/5.0")),
+                &address(CHANGE_ADDRESS),
+                Amount::from_sat(10_000),
+                MAX,
+            )
+            .unwrap();
+        assert_eq!(verdict.score, 0.0);
+        assert!(!verdict.challenge && !verdict.downgrade);
+        // and doesn't log them
+        assert!(db.decisions(10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn scores_scripts_asking_for_the_most() {
+        let db = Database::open(":memory:".as_ref()).unwrap();
+        let scorer = AbuseScorer::new(Weights::default(), Some(1.0), Some(3.0));
+        let score = || {
+            scorer
+                .score(&db, &request(None), &address(CHANGE_ADDRESS), MAX, MAX)
+                .unwrap()
+        };
+
+        // user_agent and amount
+        let verdict = score();
+        assert_eq!(verdict.score, 2.5);
+        assert!(verdict.challenge && !verdict.downgrade);
+
+        // and one more request than before, each time
+        assert_eq!(score().score, 3.0);
+        let verdict = score();
+        assert_eq!(verdict.score, 3.5);
+        assert!(verdict.downgrade);
+
+        let decisions = db.decisions(10).unwrap();
+        assert_eq!(decisions.len(), 3);
+        assert_eq!(decisions[0].signals.frequency, 2);
+        assert!(decisions[0].signals.user_agent && decisions[0].signals.amount);
+    }
+
+    #[test]
+    fn counts_requests_per_client() {
+        let scorer = AbuseScorer::new(Weights::default(), None, None);
+        let alice = "192.0.2.1".parse().unwrap();
+        let bob = "192.0.2.2".parse().unwrap();
+
+        assert_eq!(scorer.frequency(alice), 0);
+        assert_eq!(scorer.frequency(alice), 1);
+        assert_eq!(scorer.frequency(bob), 0);
+        assert_eq!(scorer.frequency(alice), 2);
+    }
+}
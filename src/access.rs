@@ -0,0 +1,471 @@
+//SPDX-License-Identifier: MIT
+
+//! Block and allow lists, for cutting off known abusers without a restart. Rules match a
+//! destination address, a raw scriptPubKey or a client IP range, and are kept in the database,
+//! with a copy in memory so checking them doesn't hit the disk. Admins manage them through
+//! /admin/access.
+//!
+//! Allow rules win over block rules, so one client can be let through a blocked range. Allowed
+//! clients aren't rate limited either.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use actix_web::web;
+use bitcoin::Address;
+use bitcoin::Script;
+use bitcoin::ScriptBuf;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+use crate::db::Database;
+use crate::response::Removed;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum List {
+    Block,
+    Allow,
+}
+
+/// What a rule's value is
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Address,
+    /// A hex encoded scriptPubKey
+    Script,
+    /// An IP, or a range of them in CIDR notation
+    Ip,
+}
+
+impl List {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            List::Block => "block",
+            List::Allow => "allow",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "block" => Some(List::Block),
+            "allow" => Some(List::Allow),
+            _ => None,
+        }
+    }
+}
+
+impl Kind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Kind::Address => "address",
+            Kind::Script => "script",
+            Kind::Ip => "ip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "address" => Some(Kind::Address),
+            "script" => Some(Kind::Script),
+            "ip" => Some(Kind::Ip),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct Rule {
+    pub id: i64,
+    pub list: List,
+    pub kind: Kind,
+    pub value: String,
+    /// Why the rule is there, for the admins
+    pub note: Option<String>,
+    /// When the rule was added, in unix time
+    pub created_at: u64,
+}
+
+/// A range of IPs, like 192.0.2.0/24
+#[derive(Debug, Clone, Copy)]
+struct IpRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix) = match s.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (s, None),
+        };
+        let network = IpAddr::from_str(network).map_err(|e| e.to_string())?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u32>()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or(format!("{prefix} isn't a valid prefix length"))?,
+            None => bits,
+        };
+
+        Ok(IpRange { network, prefix })
+    }
+}
+
+impl IpRange {
+    fn contains(&self, ip: IpAddr) -> bool {
+        // so ::ffff:192.0.2.1 matches 192.0.2.0/24
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What a rule matches
+enum Matcher {
+    Script(ScriptBuf),
+    Ip(IpRange),
+}
+
+impl Matcher {
+    fn parse(kind: Kind, value: &str) -> Result<Self, String> {
+        match kind {
+            Kind::Address => Address::from_str(value)
+                .map_err(|e| e.to_string())?
+                .require_network(bitcoin::Network::Signet)
+                .map(|address| Matcher::Script(address.script_pubkey()))
+                .map_err(|e| e.to_string()),
+            Kind::Script => ScriptBuf::from_hex(value)
+                .map(Matcher::Script)
+                .map_err(|e| e.to_string()),
+            Kind::Ip => value.parse().map(Matcher::Ip),
+        }
+    }
+
+    fn matches(&self, client: Option<IpAddr>, script: Option<&Script>) -> bool {
+        match (self, client, script) {
+            (Matcher::Ip(range), Some(ip), _) => range.contains(ip),
+            (Matcher::Script(ours), _, Some(script)) => ours.as_script() == script,
+            _ => false,
+        }
+    }
+}
+
+pub struct AccessLists {
+    rules: RwLock<Vec<(Rule, Matcher)>>,
+}
+
+impl AccessLists {
+    /// Loads the rules saved in `db`. Those we can't parse anymore are skipped
+    pub fn load(db: &Database) -> anyhow::Result<Self> {
+        let rules = db
+            .access_rules()?
+            .into_iter()
+            .filter_map(|rule| match Matcher::parse(rule.kind, &rule.value) {
+                Ok(matcher) => Some((rule, matcher)),
+                Err(e) => {
+                    warn!("skipping access rule {}: {e}", rule.id);
+                    None
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            rules: RwLock::new(rules),
+        })
+    }
+
+    /// Whether a rule in `list` matches `client` or `script`
+    fn matches(&self, list: List, client: Option<IpAddr>, script: Option<&Script>) -> bool {
+        self.rules
+            .read()
+            .unwrap()
+            .iter()
+            .any(|(rule, matcher)| rule.list == list && matcher.matches(client, script))
+    }
+
+    /// Refuses `client`, or paying `script`, if they're blocked and not allowed
+    pub fn check(&self, client: Option<IpAddr>, script: Option<&Script>) -> Result<(), Error> {
+        if self.matches(List::Allow, client, script) {
+            return Ok(());
+        }
+        if self.matches(List::Block, client, script) {
+            return Err(Error::Blocked);
+        }
+
+        Ok(())
+    }
+
+    /// Whether `client` is on the allow list
+    pub fn is_allowed(&self, client: IpAddr) -> bool {
+        self.matches(List::Allow, Some(client), None)
+    }
+}
+
+/// The data passed to POST /admin/access
+#[derive(Deserialize, ToSchema)]
+pub struct NewRule {
+    list: List,
+    kind: Kind,
+    value: String,
+    note: Option<String>,
+}
+
+/// Lists every rule
+#[utoipa::path(
+    get,
+    path = "/v1/admin/access",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = Vec<Rule>),
+        (status = 401, body = ErrorBody),
+    )
+)]
+pub async fn list_rules<B: ChainBackend>(
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Vec<Rule>>, Error> {
+    let rules = data.access.rules.read().unwrap();
+    Ok(web::Json(
+        rules.iter().map(|(rule, _)| rule.clone()).collect(),
+    ))
+}
+
+/// Adds a rule, returning it with its id
+#[utoipa::path(
+    post,
+    path = "/v1/admin/access",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    request_body = NewRule,
+    responses(
+        (status = 200, body = Rule),
+        (status = 400, body = ErrorBody),
+        (status = 401, body = ErrorBody),
+    )
+)]
+pub async fn add_rule<B: ChainBackend>(
+    params: web::Json<NewRule>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Rule>, Error> {
+    let NewRule {
+        list,
+        kind,
+        value,
+        note,
+    } = params.into_inner();
+    let value = value.trim().to_string();
+    let matcher = Matcher::parse(kind, &value).map_err(Error::InvalidAccessRule)?;
+
+    let rule = data
+        .db
+        .add_access_rule(list, kind, &value, note.as_deref())?;
+    data.access
+        .rules
+        .write()
+        .unwrap()
+        .push((rule.clone(), matcher));
+
+    Ok(web::Json(rule))
+}
+
+/// Removes the rule with `id`
+#[utoipa::path(
+    delete,
+    path = "/v1/admin/access/{id}",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    params(("id" = i64, Path, description = "The rule's id")),
+    responses(
+        (status = 200, body = Removed),
+        (status = 401, body = ErrorBody),
+        (status = 404, body = ErrorBody),
+    )
+)]
+pub async fn remove_rule<B: ChainBackend>(
+    id: web::Path<i64>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Removed>, Error> {
+    let id = id.into_inner();
+    if !data.db.remove_access_rule(id)? {
+        return Err(Error::UnknownAccessRule);
+    }
+    data.access
+        .rules
+        .write()
+        .unwrap()
+        .retain(|(rule, _)| rule.id != id);
+
+    Ok(web::Json(Removed { removed: id }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::address;
+    use crate::testing::app_state;
+    use crate::testing::MockNode;
+    use crate::testing::CHANGE_ADDRESS;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn range(range: &str) -> IpRange {
+        range.parse().unwrap()
+    }
+
+    fn rule(list: List, kind: Kind, value: &str) -> web::Json<NewRule> {
+        web::Json(NewRule {
+            list,
+            kind,
+            value: value.to_string(),
+            note: None,
+        })
+    }
+
+    #[test]
+    fn parses_ip_ranges() {
+        assert_eq!(range("192.0.2.1").prefix, 32);
+        assert_eq!(range("2001:db8::/32").prefix, 32);
+        assert_eq!(range("2001:db8::1").prefix, 128);
+
+        for invalid in ["192.0.2.0/33", "2001:db8::/129", "192.0.2.0/x", "nope", ""] {
+            assert!(invalid.parse::<IpRange>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn matches_ips_in_range() {
+        let v4 = range("192.0.2.0/24");
+        assert!(v4.contains(ip("192.0.2.1")));
+        assert!(v4.contains(ip("192.0.2.255")));
+        assert!(!v4.contains(ip("192.0.3.1")));
+        assert!(v4.contains(ip("::ffff:192.0.2.1")));
+        assert!(!v4.contains(ip("2001:db8::1")));
+
+        let v6 = range("2001:db8::/32");
+        assert!(v6.contains(ip("2001:db8:ffff::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+        assert!(!v6.contains(ip("192.0.2.1")));
+
+        assert!(range("0.0.0.0/0").contains(ip("203.0.113.1")));
+        assert!(range("192.0.2.1").contains(ip("192.0.2.1")));
+        assert!(!range("192.0.2.1").contains(ip("192.0.2.2")));
+    }
+
+    #[actix_web::test]
+    async fn blocks_clients_and_addresses() {
+        let data = web::Data::new(app_state(MockNode::default()));
+        let script = address(CHANGE_ADDRESS).script_pubkey();
+
+        add_rule(rule(List::Block, Kind::Ip, "192.0.2.0/24"), data.clone())
+            .await
+            .unwrap();
+        add_rule(
+            rule(List::Block, Kind::Address, CHANGE_ADDRESS),
+            data.clone(),
+        )
+        .await
+        .unwrap();
+
+        let access = &data.access;
+        assert!(matches!(
+            access.check(Some(ip("192.0.2.1")), None),
+            Err(Error::Blocked)
+        ));
+        assert!(matches!(
+            access.check(None, Some(&script)),
+            Err(Error::Blocked)
+        ));
+        assert!(access.check(Some(ip("198.51.100.1")), None).is_ok());
+        assert!(!access.is_allowed(ip("192.0.2.1")));
+    }
+
+    #[actix_web::test]
+    async fn allow_rules_win() {
+        let data = web::Data::new(app_state(MockNode::default()));
+        add_rule(rule(List::Block, Kind::Ip, "192.0.2.0/24"), data.clone())
+            .await
+            .unwrap();
+        add_rule(rule(List::Allow, Kind::Ip, "192.0.2.7"), data.clone())
+            .await
+            .unwrap();
+
+        assert!(data.access.check(Some(ip("192.0.2.7")), None).is_ok());
+        assert!(data.access.is_allowed(ip("192.0.2.7")));
+        assert!(data.access.check(Some(ip("192.0.2.8")), None).is_err());
+    }
+
+    #[actix_web::test]
+    async fn removes_rules() {
+        let data = web::Data::new(app_state(MockNode::default()));
+        let rule = add_rule(rule(List::Block, Kind::Ip, "192.0.2.1"), data.clone())
+            .await
+            .unwrap();
+
+        remove_rule(web::Path::from(rule.id), data.clone())
+            .await
+            .unwrap();
+        assert!(data.access.check(Some(ip("192.0.2.1")), None).is_ok());
+        assert!(matches!(
+            remove_rule(web::Path::from(rule.id), data.clone()).await,
+            Err(Error::UnknownAccessRule)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn refuses_rules_it_cant_parse() {
+        let data = web::Data::new(app_state(MockNode::default()));
+
+        for (kind, value) in [
+            (Kind::Ip, "192.0.2.0/40"),
+            (Kind::Script, "not hex"),
+            (Kind::Address, "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+        ] {
+            assert!(matches!(
+                add_rule(rule(List::Block, kind, value), data.clone()).await,
+                Err(Error::InvalidAccessRule(_))
+            ));
+        }
+        assert!(data.db.access_rules().unwrap().is_empty());
+    }
+
+    #[test]
+    fn loads_saved_rules() {
+        let db = Database::open(":memory:".as_ref()).unwrap();
+        db.add_access_rule(List::Block, Kind::Ip, "192.0.2.1", Some("spam"))
+            .unwrap();
+        // saved by a version that took these, say
+        db.add_access_rule(List::Block, Kind::Ip, "nope", None)
+            .unwrap();
+
+        let access = AccessLists::load(&db).unwrap();
+        assert_eq!(access.rules.read().unwrap().len(), 1);
+        assert!(access.check(Some(ip("192.0.2.1")), None).is_err());
+    }
+}
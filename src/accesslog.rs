@@ -0,0 +1,182 @@
+//SPDX-License-Identifier: MIT
+
+//! Ties what we answer to what we log, so a user's complaint can be matched to what happened on
+//! our side. Every request gets an id, the one in its tracing span, which we send back as
+//! `X-Request-Id` and in error bodies. Once answered, each request is also written to the access
+//! log, at the `access` target, with its method, path, status, latency and client IP.
+//!
+//! The client IP in both is the one [client_ip] finds, not whatever `X-Forwarded-For` says.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::Service;
+use actix_web::dev::ServiceRequest;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::HeaderMap;
+use actix_web::http::header::HeaderName;
+use actix_web::http::header::HeaderValue;
+use actix_web::http::StatusCode;
+use actix_web::HttpMessage;
+use actix_web::HttpResponse;
+use actix_web::ResponseError;
+use tracing::field::Empty;
+use tracing::info;
+use tracing::info_span;
+use tracing::Span;
+use tracing_actix_web::DefaultRootSpanBuilder;
+use tracing_actix_web::RequestId;
+use tracing_actix_web::RootSpanBuilder;
+
+use crate::ratelimit::client_ip;
+#[cfg(feature = "sentry")]
+use crate::reporting;
+
+/// The header we send the request id in
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// The id of the request we're answering, if any
+pub fn request_id() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// A failure from further in, answered in the scope of the request it's for, since it's only
+/// turned into a response once we're done with it
+#[derive(Debug)]
+struct Failed {
+    error: actix_web::Error,
+    id: String,
+}
+
+impl Display for Failed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl ResponseError for Failed {
+    fn status_code(&self) -> StatusCode {
+        self.error.as_response_error().status_code()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = REQUEST_ID.sync_scope(self.id.clone(), || self.error.error_response());
+        tag(response.headers_mut(), &self.id);
+        response
+    }
+}
+
+/// Tells the client a response's request id
+fn tag(headers: &mut HeaderMap, id: &str) {
+    if let Ok(value) = HeaderValue::from_str(id) {
+        headers.insert(REQUEST_ID_HEADER, value);
+    }
+}
+
+/// Makes each request's span like [DefaultRootSpanBuilder] does, but with the client IP we
+/// trust, since the default one believes any forwarding header
+pub struct RequestSpan;
+
+impl RootSpanBuilder for RequestSpan {
+    fn on_request_start(req: &ServiceRequest) -> Span {
+        let route = req.match_pattern().unwrap_or_else(|| "default".into());
+        let client = client_ip(req.request()).map_or_else(String::new, |ip| ip.to_string());
+        let user_agent = req
+            .headers()
+            .get("User-Agent")
+            .and_then(|agent| agent.to_str().ok())
+            .unwrap_or("");
+        let id = req
+            .extensions()
+            .get::<RequestId>()
+            .map_or_else(String::new, ToString::to_string);
+
+        info_span!(
+            "HTTP request",
+            http.method = %req.method(),
+            http.route = %route,
+            http.client_ip = %client,
+            http.user_agent = %user_agent,
+            http.target = %req.uri(),
+            http.status_code = Empty,
+            otel.status_code = Empty,
+            request_id = %id,
+            exception.message = Empty,
+            exception.details = Empty,
+        )
+    }
+
+    fn on_request_end<B: MessageBody>(
+        span: Span,
+        outcome: &Result<ServiceResponse<B>, actix_web::Error>,
+    ) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+/// A middleware giving out request ids and writing the access log, for `wrap_fn`. It has to be
+/// wrapped by [tracing_actix_web::TracingLogger], which makes the ids
+pub fn log_requests<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let id = req
+        .extensions()
+        .get::<RequestId>()
+        .map_or_else(String::new, ToString::to_string);
+    let method = req.method().to_string();
+    let path = req.path().to_string();
+    let client = client_ip(req.request()).map_or("-".into(), |ip| ip.to_string());
+    let started = Instant::now();
+    #[cfg(feature = "sentry")]
+    let info = reporting::RequestInfo::new(&req, id.clone());
+
+    // middlewares may fail before their future is polled, so they need the id right away too
+    let response = REQUEST_ID.sync_scope(id.clone(), || srv.call(req));
+
+    REQUEST_ID.scope(id.clone(), async move {
+        let response = response.await.map_err(|error| Failed {
+            error,
+            id: id.clone(),
+        });
+        let status = match &response {
+            Ok(response) => response.status(),
+            Err(failed) => failed.status_code(),
+        };
+
+        #[cfg(feature = "sentry")]
+        match &response {
+            Ok(response) => {
+                if let Some(error) = response.response().error() {
+                    reporting::report_failure(info, error);
+                }
+            }
+            Err(failed) => reporting::report_failure(info, &failed.error),
+        }
+
+        info!(
+            target: "access",
+            request_id = %id,
+            method,
+            path,
+            status = status.as_u16(),
+            latency_ms = started.elapsed().as_millis() as u64,
+            client_ip = client,
+            "{method} {path} {}",
+            status.as_u16(),
+        );
+
+        let mut response = response?;
+        tag(response.headers_mut(), &id);
+        Ok(response)
+    })
+}
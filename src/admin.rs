@@ -0,0 +1,500 @@
+//SPDX-License-Identifier: MIT
+
+//! The admin API, for operators to react to abuse without getting on the box. Every route
+//! under /admin takes the admin token, as `Authorization: Bearer <token>`, or an admin API key.
+//!
+//! Admins may pause the faucet, refusing requests and holding the queue and hold-invoice
+//! payouts, or drain it, refusing requests but paying out what's waiting. They may also change
+//! how much we give out while we run, and sweep the wallet to a cold address. Operators keeping
+//! the signing key offline may have us build payouts as PSBTs, for them to sign and broadcast.
+//! Changes only live in memory, a restart brings back what the environment says.
+
+use std::future::Future;
+use std::sync::RwLock;
+
+use actix_web::dev::Service;
+use actix_web::dev::ServiceRequest;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header;
+use actix_web::web;
+use actix_web::HttpRequest;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::Txid;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::api::blocking;
+use crate::api::AppState;
+use crate::api::BatchOutput;
+use crate::api::Error;
+use crate::apikeys::Scope;
+use crate::backend::ChainBackend;
+use crate::fees;
+
+/// Whether we take requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    Running,
+    /// We refuse new requests, but pay those that are waiting
+    Draining,
+    /// We refuse new requests, and hold those that are waiting until we resume
+    Paused,
+}
+
+/// How much we give out
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// The most anonymous users may get, in the lowest tier
+    pub max_sendable: Amount,
+    pub min_sendable: Amount,
+    /// How much we may give out in a rolling day, if there's a limit
+    pub daily_budget: Option<Amount>,
+}
+
+/// What admins may change while we run
+pub struct Controls {
+    mode: RwLock<Mode>,
+    limits: RwLock<Limits>,
+}
+
+impl Controls {
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            mode: RwLock::new(Mode::Running),
+            limits: RwLock::new(limits),
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        *self.mode.read().unwrap()
+    }
+
+    pub fn limits(&self) -> Limits {
+        *self.limits.read().unwrap()
+    }
+
+    /// Refuses new requests, unless we're running
+    pub fn check_running(&self) -> Result<(), Error> {
+        match self.mode() {
+            Mode::Running => Ok(()),
+            Mode::Draining | Mode::Paused => Err(Error::Paused),
+        }
+    }
+
+    /// Whether the workers may pay the requests that are waiting
+    pub fn may_pay(&self) -> bool {
+        self.mode() != Mode::Paused
+    }
+
+    fn set_mode(&self, mode: Mode) {
+        *self.mode.write().unwrap() = mode;
+        info!("an admin set our mode to {mode:?}");
+    }
+}
+
+/// How the faucet is set up right now
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Status {
+    pub mode: Mode,
+    /// In sats
+    pub max_sendable: u64,
+    pub min_sendable: u64,
+    pub daily_budget: Option<u64>,
+    /// What our wallet has
+    pub balance: u64,
+}
+
+/// The data passed to /admin/limits, in sats. Limits that aren't set stay as they are, and a
+/// `daily_budget` of 0 removes the budget
+#[derive(Deserialize, ToSchema)]
+pub struct NewLimits {
+    max_sendable: Option<u64>,
+    min_sendable: Option<u64>,
+    daily_budget: Option<u64>,
+}
+
+/// The data passed to /admin/sweep
+#[derive(Deserialize, ToSchema)]
+pub struct Sweep {
+    /// Where all our coins go
+    address: String,
+    /// What the sweep pays per vbyte, if not what a payout would. Sweeps when retiring a faucet
+    /// may wait for fees to go down
+    fee_rate: Option<u64>,
+}
+
+/// What a sweep did
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Swept {
+    #[schema(value_type = String)]
+    pub txid: Txid,
+    /// What `address` got, in sats
+    pub amount: u64,
+    pub fee: u64,
+}
+
+/// The data passed to /admin/psbt
+#[derive(Deserialize, ToSchema)]
+pub struct Export {
+    /// What the PSBT pays
+    outputs: Vec<BatchOutput>,
+}
+
+/// A payout for the operator to sign and broadcast
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Exported {
+    /// The unsigned PSBT, in base64
+    pub psbt: String,
+    /// What it pays in fees, in sats
+    pub fee: u64,
+    /// What goes back to us, in sats
+    pub change: u64,
+}
+
+/// Checks that `req` carries our admin token, as `Authorization: Bearer <token>`, or an admin
+/// API key
+fn check_admin<B: ChainBackend>(req: &HttpRequest, data: &AppState<B>) -> Result<(), Error> {
+    if let Some(key) = data.api_keys.authenticate(req)? {
+        if key.scope == Scope::Admin {
+            info!("{} used the admin route {}", key.name, req.path());
+            return Ok(());
+        }
+    }
+
+    let Some(token) = &data.admin_token else {
+        return Err(Error::Unauthorized);
+    };
+
+    let given = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    // compare every byte, so the time it takes doesn't tell how much of the token is right
+    let matches = given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0;
+
+    if !matches {
+        return Err(Error::Unauthorized);
+    }
+
+    Ok(())
+}
+
+/// A middleware for the /admin scope, for `wrap_fn`. It turns away requests without our admin
+/// token before they get to a handler
+pub fn admin_only<B: ChainBackend, S>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let allowed = match req.app_data::<web::Data<AppState<B>>>() {
+        Some(data) => check_admin(req.request(), data),
+        None => Err(Error::Unauthorized),
+    };
+
+    let response = allowed.map(|_| srv.call(req));
+    async move { response?.await }
+}
+
+fn status<B: ChainBackend>(data: &AppState<B>) -> Result<Status, Error> {
+    let limits = data.controls.limits();
+    Ok(Status {
+        mode: data.controls.mode(),
+        max_sendable: limits.max_sendable.to_sat(),
+        min_sendable: limits.min_sendable.to_sat(),
+        daily_budget: limits.daily_budget.map(Amount::to_sat),
+        balance: data.backend.get_balance()?.to_sat(),
+    })
+}
+
+/// Tells how the faucet is set up
+#[utoipa::path(
+    get,
+    path = "/v1/admin/status",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = Status),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn get_status<B: ChainBackend>(
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Status>, Error> {
+    Ok(web::Json(blocking(&data, status).await?))
+}
+
+/// Refuses new requests, and holds those that are waiting
+#[utoipa::path(
+    post,
+    path = "/v1/admin/pause",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = Status),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn pause<B: ChainBackend>(
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Status>, Error> {
+    data.controls.set_mode(Mode::Paused);
+    Ok(web::Json(status(&data)?))
+}
+
+/// Refuses new requests, but pays those that are waiting
+#[utoipa::path(
+    post,
+    path = "/v1/admin/drain",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = Status),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn drain<B: ChainBackend>(
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Status>, Error> {
+    data.controls.set_mode(Mode::Draining);
+    Ok(web::Json(status(&data)?))
+}
+
+/// Takes requests again, after a pause or drain
+#[utoipa::path(
+    post,
+    path = "/v1/admin/resume",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = Status),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn resume<B: ChainBackend>(
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Status>, Error> {
+    data.controls.set_mode(Mode::Running);
+    Ok(web::Json(status(&data)?))
+}
+
+/// Changes how much we give out, until we restart
+#[utoipa::path(
+    post,
+    path = "/v1/admin/limits",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    request_body = NewLimits,
+    responses(
+        (status = 200, body = Status),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn set_limits<B: ChainBackend>(
+    params: web::Json<NewLimits>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Status>, Error> {
+    let NewLimits {
+        max_sendable,
+        min_sendable,
+        daily_budget,
+    } = params.into_inner();
+
+    {
+        let mut limits = data.controls.limits.write().unwrap();
+        let mut new = *limits;
+        if let Some(max) = max_sendable {
+            new.max_sendable = Amount::from_sat(max);
+        }
+        if let Some(min) = min_sendable {
+            new.min_sendable = Amount::from_sat(min);
+        }
+        if let Some(budget) = daily_budget {
+            new.daily_budget =
+                Some(Amount::from_sat(budget)).filter(|budget| *budget > Amount::ZERO);
+        }
+
+        if new.min_sendable > new.max_sendable {
+            return Err(Error::InvalidRequest(
+                "min_sendable can't be more than max_sendable".into(),
+            ));
+        }
+
+        *limits = new;
+        info!("an admin changed our limits to {new:?}");
+    }
+
+    Ok(web::Json(status(&data)?))
+}
+
+/// Sends every coin in our wallet to `address`, the fee taken out of it like Bitcoin Core's
+/// sendall does, and pauses the faucet, since there's nothing left to give out. For when keys
+/// leak, or the faucet is retired or moves to another wallet
+#[utoipa::path(
+    post,
+    path = "/v1/admin/sweep",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    request_body = Sweep,
+    responses(
+        (status = 200, body = Swept),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn sweep<B: ChainBackend>(
+    params: web::Json<Sweep>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Swept>, Error> {
+    let address = params
+        .address
+        .parse::<Address<_>>()
+        .map_err(|_| Error::InvalidAddress)?
+        .require_network(bitcoin::Network::Signet)
+        .map_err(|_| Error::InvalidAddress)?;
+
+    let fee_rate = params.fee_rate;
+
+    // nothing should spend our coins while we sweep them, and if we couldn't sweep them we go
+    // back to what we were doing
+    let mode = data.controls.mode();
+    data.controls.set_mode(Mode::Paused);
+
+    match blocking(&data, move |data| sweep_to(data, address, fee_rate)).await {
+        Ok(swept) => Ok(web::Json(swept)),
+        Err(e) => {
+            data.controls.set_mode(mode);
+            Err(e)
+        }
+    }
+}
+
+/// Spends all our coins that can go somewhere to `address`, paying `fee_rate` sat/vB, or what
+/// we'd pay for a payout now
+fn sweep_to<B: ChainBackend>(
+    data: &AppState<B>,
+    address: Address,
+    fee_rate: Option<u64>,
+) -> Result<Swept, Error> {
+    let backend = &data.backend;
+    // coinbases that haven't matured can't go anywhere yet
+    let inputs = backend
+        .list_unspent()?
+        .into_iter()
+        .filter(|utxo| utxo.is_mature())
+        .collect::<Vec<_>>();
+    let total: Amount = inputs.iter().map(|utxo| utxo.amount).sum();
+
+    let feerate = match fee_rate {
+        Some(rate) => FeeRate::from_sat_per_vb(rate)
+            .filter(|rate| *rate > FeeRate::ZERO)
+            .ok_or_else(|| Error::InvalidRequest("fee_rate must be positive".into()))?,
+        None => data.fees.feerate(backend)?,
+    };
+    let fee = fees::transaction_fee(feerate, inputs.len(), 1);
+
+    let amount = total
+        .checked_sub(fee)
+        .filter(|amount| *amount >= address.script_pubkey().dust_value())
+        .ok_or(Error::OutOfMoney)?;
+
+    let tx = backend.create_transaction(&inputs, &[(address.clone(), amount)])?;
+    let tx = backend.sign_transaction(&tx)?;
+    let txid = backend.broadcast_transaction(&tx)?;
+    info!("an admin swept {amount} to {address} in {txid}");
+
+    Ok(Swept {
+        txid,
+        amount: amount.to_sat(),
+        fee: fee.to_sat(),
+    })
+}
+
+/// Builds a transaction paying `outputs` with our coins, like we would a batch, and returns it as
+/// an unsigned PSBT instead of signing and broadcasting it. Nothing keeps us from spending its
+/// coins until it's broadcast, so pause the faucet if that matters
+#[utoipa::path(
+    post,
+    path = "/v1/admin/psbt",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    request_body = Export,
+    responses(
+        (status = 200, body = Exported),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn export_psbt<B: ChainBackend>(
+    params: web::Json<Export>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Exported>, Error> {
+    if params.outputs.is_empty() {
+        return Err(Error::InvalidRequest("the psbt has no outputs".into()));
+    }
+    let mut outputs = vec![];
+    for output in &params.outputs {
+        let address = output
+            .address
+            .parse::<Address<_>>()
+            .map_err(|_| Error::InvalidAddress)?
+            .require_network(bitcoin::Network::Signet)
+            .map_err(|_| Error::InvalidAddress)?;
+        let amount = Amount::from_sat(output.amount);
+        if amount < address.script_pubkey().dust_value() {
+            return Err(Error::Dust);
+        }
+        outputs.push((address, amount));
+    }
+
+    Ok(web::Json(
+        blocking(&data, move |data| build_psbt(data, outputs)).await?,
+    ))
+}
+
+/// Selects coins paying `outputs`, and returns the unsigned transaction spending them
+fn build_psbt<B: ChainBackend>(
+    data: &AppState<B>,
+    mut outputs: Vec<(Address, Amount)>,
+) -> Result<Exported, Error> {
+    let backend = &data.backend;
+    let total: Amount = outputs.iter().map(|(_, amount)| *amount).sum();
+    let selection = data.coin_selection.select(
+        backend.list_unspent()?,
+        total,
+        outputs.len(),
+        data.fees.feerate(backend)?,
+    )?;
+    if let Some(change) = selection.change {
+        outputs.push((data.change_address.clone(), change));
+    }
+
+    let tx = backend.create_transaction(&selection.inputs, &outputs)?;
+    let psbt = backend.create_psbt(&tx)?;
+    info!("an admin exported a psbt paying {total}");
+
+    Ok(Exported {
+        psbt: psbt.to_string(),
+        fee: selection.fee.to_sat(),
+        change: selection.change.unwrap_or(Amount::ZERO).to_sat(),
+    })
+}
@@ -1,53 +1,483 @@
 //SPDX-License-Identifier: MIT
 
-//! This is a simple REST API that can be used to query Utreexo data. You can get the roots
-//! of the accumulator, get a proof for a leaf, and get a block and the associated UData.
+//! This is a simple REST API for the faucet. You can ask for sats to be sent to an address,
+//! and, depending on the features enabled, for a channel, the status of a payout or the
+//! roots of our utreexo accumulator.
 
-use std::collections::HashMap;
+use std::collections::HashSet;
 use std::fmt::Display;
+use std::future::Future;
+use std::net::IpAddr;
 use std::str::FromStr;
+#[cfg(any(feature = "redis", feature = "tls", feature = "zmq"))]
+use std::sync::Arc;
 
-use actix_cors::Cors;
+use actix_web::dev::Service;
+use actix_web::dev::ServiceRequest;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header;
+use actix_web::http::header::ContentType;
+use actix_web::http::header::HeaderValue;
 use actix_web::http::StatusCode;
 use actix_web::web;
 use actix_web::App;
+use actix_web::HttpRequest;
 use actix_web::HttpResponse;
 use actix_web::HttpServer;
 use actix_web::ResponseError;
+#[cfg(feature = "lightning")]
+use bitcoin::hashes::sha256;
+use bitcoin::hex::FromHex;
+use bitcoin::script::PushBytesBuf;
+#[cfg(feature = "lightning")]
+use bitcoin::secp256k1::PublicKey;
 use bitcoin::Address;
+use bitcoin::AddressType;
 use bitcoin::Amount;
-
-use bitcoincore_rpc::{bitcoincore_rpc_json::CreateRawTransactionInput, Client, RpcApi};
-#[cfg(feature = "ln")]
-use cln_rpc::primitives::PublicKey;
+use bitcoin::Denomination;
+use bitcoin::OutPoint;
+use bitcoin::ScriptBuf;
+use bitcoin::Transaction;
+use bitcoin::TxOut;
+use bitcoin::Txid;
+use bitcoincore_rpc::jsonrpc::serde_json;
 use serde::Deserialize;
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+use tracing_actix_web::TracingLogger;
+use utoipa::IntoParams;
+use utoipa::ToSchema;
 
-#[cfg(feature = "ln")]
-use crate::open_channel::CLNDaemon;
+use crate::abuse;
+use crate::abuse::AbuseScorer;
+use crate::abuse::Verdict;
+use crate::access;
+use crate::access::AccessLists;
+use crate::accesslog;
+use crate::accesslog::RequestSpan;
+use crate::admin;
+use crate::admin::Controls;
+#[cfg(feature = "alerts")]
+use crate::alerts;
+#[cfg(feature = "alerts")]
+use crate::alerts::Alerts;
+use crate::apikeys::ApiKeys;
+use crate::assets;
+use crate::assets::Assets;
+#[cfg(feature = "utreexod")]
+use crate::backend::utreexod::UtreexoInfo;
+use crate::backend::ChainBackend;
+use crate::backend::TxState;
+use crate::backend::Utxo;
+use crate::bip322;
+use crate::bump;
+use crate::bump::Bumper;
+use crate::bump::Pending;
+#[cfg(feature = "captcha")]
+use crate::captcha::Captcha;
+use crate::coinselect::CoinSelection;
+use crate::consolidate;
+use crate::consolidate::Consolidation;
+use crate::cooldown::Cooldowns;
+use crate::cors::CorsPolicy;
+use crate::cpfp;
+use crate::cpfp::Accelerator;
+use crate::db;
+use crate::db::Database;
+use crate::events;
+use crate::events::Activity;
+use crate::events::ActivityFeed;
+use crate::export;
+use crate::fees;
+use crate::fees::FeePolicy;
+#[cfg(feature = "github")]
+use crate::github;
+#[cfg(feature = "github")]
+use crate::github::GithubTier;
+#[cfg(feature = "lightning")]
+use crate::hold;
+#[cfg(feature = "lightning")]
+use crate::hold::HoldPayouts;
+#[cfg(feature = "lightning")]
+use crate::hold::HoldStatus;
+use crate::idempotency;
+use crate::idempotency::IdempotencyKeys;
+use crate::idempotency::Started;
+#[cfg(feature = "lightning")]
+use crate::ln::check_invoice;
+#[cfg(feature = "lightning")]
+use crate::ln::check_offer;
+#[cfg(feature = "lightning")]
+use crate::ln::ChannelInfo;
+#[cfg(feature = "lightning")]
+use crate::ln::ChannelLimits;
+#[cfg(feature = "lightning")]
+use crate::ln::ChannelRequest;
+#[cfg(feature = "lightning")]
+use crate::ln::DualFundedChannel;
+#[cfg(feature = "lightning")]
+use crate::ln::LightningBackend;
+#[cfg(feature = "lightning")]
+use crate::ln::DUAL_FUND;
+#[cfg(feature = "lightning")]
+use crate::ln::STATIC_REMOTE_KEY;
+#[cfg(feature = "lightning")]
+use crate::ln::ZERO_CONF;
+#[cfg(feature = "lightning")]
+use crate::lnurl;
+#[cfg(feature = "nostr")]
+use crate::nostr::NostrTier;
+use crate::openapi;
+use crate::payjoin;
+use crate::payjoin::Payjoins;
+use crate::pow::Challenge;
+use crate::pow::Challenges;
+use crate::proxy::TrustedProxies;
+use crate::qr;
+use crate::queue;
+use crate::queue::PayoutQueue;
+use crate::queue::QueueStatus;
+use crate::ratelimit;
+use crate::ratelimit::client_ip;
+use crate::ratelimit::RateLimiter;
+#[cfg(feature = "lightning")]
+use crate::reclaim;
+use crate::response::Balance;
+#[cfg(feature = "lightning")]
+use crate::response::Channel;
+#[cfg(feature = "lightning")]
+use crate::response::ChannelPolicy;
+#[cfg(feature = "lightning")]
+use crate::response::ClosedChannel;
+use crate::response::CooldownInfo;
+use crate::response::Dependency;
+use crate::response::Donation;
+use crate::response::ErrorBody;
+use crate::response::Fees;
+use crate::response::Health;
+use crate::response::History;
+use crate::response::Info;
+#[cfg(feature = "lightning")]
+use crate::response::LightningBalance;
+#[cfg(feature = "lightning")]
+use crate::response::Offer;
+#[cfg(feature = "lightning")]
+use crate::response::Payment;
+use crate::response::PayoutProgress;
+use crate::response::QueueInfo;
+use crate::response::RateLimits;
+use crate::response::Readiness;
+use crate::response::ReadyStatus;
+use crate::response::SendResponse;
+use crate::response::TxStatus;
+use crate::scheduler;
+use crate::scheduler::Scheduler;
+#[cfg(feature = "redis")]
+use crate::shared::SharedStore;
+use crate::silentpayments;
+use crate::silentpayments::SilentPaymentAddress;
+use crate::stats;
+#[cfg(feature = "systemd")]
+use crate::systemd;
+use crate::tiers::Tier;
+use crate::tiers::TierInfo;
+use crate::tiers::TierLimits;
+#[cfg(feature = "tls")]
+use crate::tls;
+#[cfg(feature = "tor")]
+use crate::tor;
+#[cfg(feature = "tor")]
+use crate::tor::TorExits;
+#[cfg(feature = "tor")]
+use crate::tor::TorPolicy;
+#[cfg(feature = "zmq")]
+use crate::tracker::PayoutStatus;
+#[cfg(feature = "zmq")]
+use crate::tracker::PayoutTracker;
+#[cfg(feature = "webhooks")]
+use crate::webhook;
+#[cfg(feature = "webhooks")]
+use crate::webhook::Webhooks;
+use crate::ws;
 
-struct AppState {
-    rpc: Client,
-    change_address: Address,
-    max_sendable_amount: Amount,
-    min_sendable_amount: Amount,
-    #[cfg(feature = "ln")]
-    cln: CLNDaemon,
+/// How far back the daily budget looks
+const BUDGET_WINDOW: std::time::Duration = std::time::Duration::from_secs(24 * 3_600);
+
+/// How many times we select coins for a payout, when the ones we selected get spent before we
+/// broadcast it
+const MAX_BROADCAST_ATTEMPTS: u32 = 3;
+
+/// The longest memo we put in a payout, which is as much as nodes relay in an OP_RETURN output
+const MAX_MEMO_LENGTH: usize = 80;
+
+/// How many blocks /fee estimates for, unless asked for a `target`
+const DEFAULT_FEE_TARGET: u16 = 6;
+
+/// How many payouts a /history page has, unless asked for a `limit`
+const HISTORY_PAGE_SIZE: u32 = 20;
+
+/// The most payouts a /history page may have
+const MAX_HISTORY_PAGE_SIZE: u32 = 100;
+
+/// Where we serve the API, over HTTPS if we have a certificate
+const ADDRESS: &str = "0.0.0.0:8080";
+
+pub struct AppState<B: ChainBackend> {
+    pub backend: B,
+    pub change_address: Address,
+    /// Whether we take requests, and how much we give out, as admins may change them
+    pub controls: Controls,
+    /// How much users who passed our checks may get, if more than others
+    pub tiers: TierLimits,
+    /// Set if established Nostr users may get more than others
+    #[cfg(feature = "nostr")]
+    pub nostr: Option<NostrTier>,
+    /// Set if old enough GitHub accounts may get more than others
+    #[cfg(feature = "github")]
+    pub github: Option<GithubTier>,
+    /// Limits how much each client can ask for, if set
+    pub rate_limiter: Option<RateLimiter>,
+    /// The scripts we paid recently, if they have to wait before getting paid again
+    pub address_cooldowns: Option<Cooldowns>,
+    /// Where we record what we gave out
+    pub db: Database,
+    /// Who and what we refuse, or let through no matter what
+    pub access: AccessLists,
+    /// What we answered to the idempotency keys /send/ got lately
+    pub idempotency: IdempotencyKeys,
+    /// Where we tell /events listeners what we did
+    pub activity: ActivityFeed,
+    /// Set if we score /send/ requests, making those that look like abuse prove more
+    pub abuse: Option<AbuseScorer>,
+    /// Set if requests coming from Tor exits have to prove more or get less
+    #[cfg(feature = "tor")]
+    pub tor: Option<TorExits>,
+    /// The reverse proxies whose forwarding headers we believe
+    pub trusted_proxies: web::Data<TrustedProxies>,
+    /// Which websites may call us from a browser
+    pub cors: CorsPolicy,
+    /// How we pick the coins paying for payouts
+    pub coin_selection: CoinSelection,
+    /// What our payouts pay in fees
+    pub fees: FeePolicy,
+    /// Speeds up donations stuck in the mempool
+    pub accelerator: Accelerator,
+    /// Set if donors may refill us with payjoins
+    pub payjoins: Option<Payjoins>,
+    /// Set if we merge our small coins when it's quiet
+    pub consolidation: Option<Consolidation>,
+    /// The payouts we may have to bump
+    pub bumper: Bumper,
+    /// Runs our background jobs
+    pub scheduler: Scheduler,
+    /// The pages, styles, scripts and images we serve
+    pub assets: Assets,
+    /// Set if we serve HTTPS ourselves
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<tls::Certificates>>,
+    /// Set if we tell operators when we're running low
+    #[cfg(feature = "alerts")]
+    pub alerts: Option<Alerts>,
+    /// How many outputs /send/batch pays at once, it's disabled if this isn't set
+    pub max_batch_outputs: Option<usize>,
+    /// How many addresses a single /send/ request may pay
+    pub max_recipients: usize,
+    /// The kinds of addresses we pay, any of them if this isn't set
+    pub address_types: Option<Vec<AddressType>>,
+    /// Set if /send/ queues requests for a worker to pay in batches
+    pub payout_queue: Option<PayoutQueue>,
+    /// Set if /send/ users may ask us to call them back once their payout confirms
+    #[cfg(feature = "webhooks")]
+    pub webhooks: Option<Webhooks>,
+    /// Set if /send/ users have to solve a proof of work, unless they solve a CAPTCHA
+    pub challenges: Option<Challenges>,
+    /// Set if /send/ users have to solve a CAPTCHA
+    #[cfg(feature = "captcha")]
+    pub captcha: Option<Captcha>,
+    #[cfg(feature = "lightning")]
+    pub lightning: Box<dyn LightningBackend>,
+    #[cfg(feature = "lightning")]
+    pub lnurl_withdrawals: lnurl::Withdrawals,
+    /// Our offer for refills, created the first time someone asks for it
+    #[cfg(feature = "lightning")]
+    pub bolt12_offer: std::sync::OnceLock<String>,
+    /// Whether users may ask for zero-conf channels. Our node is the one taking the risk of
+    /// the funding transaction being double-spent, which is fine on signet
+    #[cfg(feature = "lightning")]
+    pub allow_zero_conf: bool,
+    #[cfg(feature = "lightning")]
+    pub channel_limits: ChannelLimits,
+    /// The bearer token admin routes expect, they're disabled if this isn't set and there are
+    /// no admin API keys
+    pub admin_token: Option<String>,
+    /// The API keys clients may send, for their own limits or the admin routes
+    pub api_keys: ApiKeys,
+    /// For how long a channel may be inactive before we close it, if at all
+    #[cfg(feature = "lightning")]
+    pub reclaim_after: Option<std::time::Duration>,
+    /// Set if on-chain payouts wait for users to pay a hold invoice
+    #[cfg(feature = "lightning")]
+    pub hold_payouts: Option<HoldPayouts>,
+    /// The nodes that got a channel recently, if they have to wait before getting another one
+    #[cfg(feature = "lightning")]
+    pub channel_cooldowns: Option<Cooldowns>,
+    /// The Redis server we share with other faucets, if any, which keeps our daily budget
+    #[cfg(feature = "redis")]
+    pub shared: Option<Arc<SharedStore>>,
+    #[cfg(feature = "zmq")]
+    pub tracker: Arc<PayoutTracker>,
+    /// Only set if our backend is utreexod
+    #[cfg(feature = "utreexod")]
+    pub utreexo: Option<UtreexoInfo>,
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     /// This is a generic error with our bitcoin core
     JsonRpcNotWorking,
     /// We ran out of money and can't fulfill this request
     OutOfMoney,
+    /// This client made too many requests, or asked for too many sats, lately
+    RateLimited { retry_after: std::time::Duration },
     /// The provided address is invalid
     InvalidAddress,
+    /// We don't pay this kind of address, only the `allowed` ones
+    AddressTypeNotAllowed { allowed: String },
     /// The user is asking for too much money
     AmountTooLarge,
     /// The user is ask for a amount too little
     Dust,
+    /// Coins we tried to spend were spent by something else by the time we broadcast
+    InputsSpent,
+    /// We couldn't sign the transaction with our own keys
+    SigningFailed,
+    /// The esplora server returned an error or something we don't understand
+    #[cfg(feature = "esplora")]
+    EsploraError(String),
+    /// The electrum server returned an error
+    #[cfg(feature = "electrum")]
+    ElectrumError(String),
+    /// Our BDK wallet or its chain source failed
+    #[cfg(feature = "bdk")]
+    BdkError(String),
+    /// Our external signer is unreachable or returned something we can't use
+    #[cfg(feature = "external-signer")]
+    SignerError(String),
+    /// The request's body, path or query string is malformed
+    InvalidRequest(String),
+    /// The Idempotency-Key header is empty, too long or not text
+    InvalidIdempotencyKey,
+    /// The request first sent with this idempotency key is still being handled
+    IdempotencyKeyInUse,
+    /// This idempotency key was first sent with another request
+    IdempotencyKeyReused,
+    /// Our payout queue is full
+    QueueFull,
+    /// We don't know about the queued request we were asked about
+    UnknownRequest,
+    /// We gave out our daily budget already, enough of it is back after `retry_after`
+    BudgetExhausted { retry_after: std::time::Duration },
+    /// Our database failed
+    DatabaseError(String),
+    /// The signature proving the user owns the address is wrong
+    InvalidSignature,
+    /// The proof of work is missing or wrong, or its challenge expired or was used already
+    InvalidProofOfWork,
+    /// We were asked for a proof of work challenge, but we don't use those
+    ProofOfWorkDisabled,
+    /// We were asked to pay a batch, but we don't do that
+    BatchUnavailable,
+    /// The batch has more outputs than we pay at once
+    BatchTooLarge { max: usize },
+    /// The /send/ request pays more addresses than we pay for one request
+    TooManyRecipients { max: usize },
+    /// We were asked to call back about a payout, but we don't do that
+    CallbacksUnavailable,
+    /// Too many clients are listening to /events already
+    TooManyListeners,
+    /// An admin paused or is draining the faucet, so we don't take requests
+    Paused,
+    /// The request's NIP-98 auth header is no good
+    #[cfg(feature = "nostr")]
+    InvalidNostrAuth(String),
+    /// The CAPTCHA token is missing, or the user didn't solve it
+    #[cfg(feature = "captcha")]
+    CaptchaFailed,
+    /// We couldn't ask our CAPTCHA provider about the token, or it doesn't like our keys
+    #[cfg(feature = "captcha")]
+    CaptchaError(String),
+    /// The GitHub login didn't go through, or the account can't use it
+    #[cfg(feature = "github")]
+    GithubLoginFailed(String),
+    /// We couldn't ask GitHub about the user
+    #[cfg(feature = "github")]
+    GithubError(String),
+    /// We were asked to log someone in with GitHub, but we don't do that
+    #[cfg(feature = "github")]
+    GithubDisabled,
+    /// The Redis server we share with other faucets failed or is unreachable
+    #[cfg(feature = "redis")]
+    SharedStoreError(String),
+    /// We didn't send this transaction, or don't remember doing so
+    UnknownTransaction,
+    /// We can't bump this payout's fee, for the reason given
+    CantBump(String),
+    /// We can't speed up a transaction paying us with a child, for this reason
+    CantAccelerate(String),
+    /// Our backend can't tell the keys of our coins, which silent payments need
+    SilentPaymentsUnsupported,
+    /// We aren't running on top of utreexod
+    #[cfg(feature = "utreexod")]
+    NotUtreexo,
+    /// The invoice we were asked to pay is no good
+    #[cfg(feature = "lightning")]
+    InvalidInvoice(String),
+    /// Our Lightning node can't do what we were asked to
+    #[cfg(feature = "lightning")]
+    NotSupported,
+    /// We were asked for a zero-conf channel, but those are turned off
+    #[cfg(feature = "lightning")]
+    ZeroConfDisabled,
+    /// This requester got something from us recently, and has to wait before asking again
+    Cooldown {
+        what: String,
+        retry_after: std::time::Duration,
+    },
+    /// We don't know about the hold-invoice gated payout we were asked about
+    #[cfg(feature = "lightning")]
+    UnknownPayout,
+    /// An admin route was called without the right token
+    Unauthorized,
+    /// The request carries an API key we don't know
+    InvalidApiKey,
+    /// The client or the address is on our block list
+    Blocked,
+    /// The block or allow rule we were asked to add is no good
+    InvalidAccessRule(String),
+    /// We were asked to remove a block or allow rule we don't have
+    UnknownAccessRule,
+    /// We couldn't connect to the node we were asked to open a channel with
+    #[cfg(feature = "lightning")]
+    PeerUnreachable(String),
+    /// The node we were asked to open a channel with lacks a feature that channel needs
+    #[cfg(feature = "ln")]
+    PeerMissingFeature(usize),
+    /// The channel we were asked for is too big, too small or pushes too much
+    #[cfg(feature = "lightning")]
+    InvalidChannel(String),
     #[cfg(feature = "ln")]
     CLNError(String),
+    /// LND refused our request or we couldn't reach it
+    #[cfg(feature = "lnd")]
+    LNDError(String),
+    /// Eclair refused our request or we couldn't reach it
+    #[cfg(feature = "eclair")]
+    EclairError(String),
+    /// Our embedded ldk node couldn't do what we asked
+    #[cfg(feature = "ldk")]
+    LDKError(String),
 }
 
 impl From<bitcoincore_rpc::Error> for Error {
@@ -56,22 +486,96 @@ impl From<bitcoincore_rpc::Error> for Error {
     }
 }
 
+/// How a request proves it's no bot, if it does. If we ask for a CAPTCHA, `captcha` is the
+/// token the widget gave the user. If we ask for a proof of work, `nonce` solves `challenge`,
+/// which came from /challenge
+#[derive(Deserialize, ToSchema)]
+pub struct Proof {
+    challenge: Option<String>,
+    nonce: Option<String>,
+    #[cfg(feature = "captcha")]
+    captcha: Option<String>,
+}
+
+/// Who a /send/ request pays: `amount` to `address`, or each of `outputs`, which share what a
+/// single address may get
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum Recipients {
+    One { address: String, amount: u64 },
+    Many { outputs: Vec<BatchOutput> },
+}
+
+impl Recipients {
+    /// The `(address, amount)` pairs to pay
+    fn outputs(&self) -> Vec<(String, u64)> {
+        match self {
+            Recipients::One { address, amount } => vec![(address.clone(), *amount)],
+            Recipients::Many { outputs } => outputs
+                .iter()
+                .map(|output| (output.address.clone(), output.amount))
+                .collect(),
+        }
+    }
+}
+
 /// The data passed to /send/
 ///
-/// This is a POST route that will send `amount` to `address`
-#[derive(Deserialize)]
+/// This is a POST route that will pay its [Recipients], for those who pass our checks with
+/// their [Proof]. Users may get more by signing "faucet payout to <address>" with its key,
+/// as `signature`, in the BIP322 simple format. If the faucet does callbacks, we post to
+/// `callback_url` once the payout confirms or gets replaced. A `memo`, or `memo_hex` for raw
+/// bytes, goes in an OP_RETURN output of the payout, for those who want to find it later. Those
+/// needing a confirmed coin soon may pay more for it with a `fee_target`, the blocks the payout
+/// should confirm within, down to what we allow
+#[derive(Deserialize, ToSchema)]
 pub struct SendMoney {
-    address: String,
-    amount: u64,
+    #[serde(flatten)]
+    recipients: Recipients,
+    signature: Option<String>,
+    callback_url: Option<String>,
+    memo: Option<String>,
+    memo_hex: Option<String>,
+    fee_target: Option<u16>,
+    #[serde(flatten)]
+    proof: Proof,
+}
+
+/// One of the payouts a /send/batch request asks for
+#[derive(Deserialize, ToSchema)]
+pub struct BatchOutput {
+    pub(crate) address: String,
+    pub(crate) amount: u64,
+}
+
+/// The data passed to /send/batch
+///
+/// Pays every one of `outputs` in a single transaction, for those funding many wallets at once.
+/// Each output may get as much as a /send/ request would, and the whole batch passes our checks
+/// with a single [Proof]
+#[derive(Deserialize, ToSchema)]
+pub struct SendBatch {
+    outputs: Vec<BatchOutput>,
+    #[serde(flatten)]
+    proof: Proof,
 }
 
 /// The data passed to the openchannel route
 ///
-/// This will open a fixed-size channel to a node with `node_id`
-#[cfg(feature = "ln")]
-#[derive(Deserialize)]
-struct GetChannel {
+/// This will open a channel to a node with `node_id`, that can be used right away if
+/// `zero_conf` is set. `capacity` and `push_amount` are in sats, and we use our defaults for the
+/// ones that aren't set. If the node isn't connected to ours, we'll reach it at `address`, as
+/// `host:port`
+#[cfg(feature = "lightning")]
+#[derive(Deserialize, ToSchema)]
+pub struct GetChannel {
+    #[schema(value_type = String)]
     node_id: PublicKey,
+    address: Option<String>,
+    #[serde(default)]
+    zero_conf: bool,
+    capacity: Option<u64>,
+    push_amount: Option<u64>,
 }
 
 impl Display for Error {
@@ -79,181 +583,2613 @@ impl Display for Error {
         match self {
             Error::JsonRpcNotWorking => write!(f, "our bitcoin core isn't working"),
             Error::OutOfMoney => write!(f, "we ran out of money, sorry :/"),
+            Error::RateLimited { retry_after } => write!(
+                f,
+                "too many requests, try again in {} seconds",
+                retry_after.as_secs()
+            ),
             Error::InvalidAddress => write!(f, "the provided address is invalid"),
+            Error::AddressTypeNotAllowed { allowed } => {
+                write!(f, "we only pay {allowed} addresses")
+            }
             Error::AmountTooLarge => write!(f, "the request amount is too large"),
             Error::Dust => write!(f, "the requested amount is too little"),
+            Error::InputsSpent => write!(f, "the coins we tried to spend were spent already"),
+            Error::SigningFailed => write!(f, "we couldn't sign the transaction"),
+            #[cfg(feature = "esplora")]
+            Error::EsploraError(s) => write!(f, "some esplora error: {s}"),
+            #[cfg(feature = "electrum")]
+            Error::ElectrumError(s) => write!(f, "some electrum error: {s}"),
+            #[cfg(feature = "bdk")]
+            Error::BdkError(s) => write!(f, "some bdk error: {s}"),
+            #[cfg(feature = "external-signer")]
+            Error::SignerError(s) => write!(f, "some signer error: {s}"),
+            #[cfg(feature = "redis")]
+            Error::SharedStoreError(s) => write!(f, "some redis error: {s}"),
+            Error::BudgetExhausted { retry_after } => write!(
+                f,
+                "we gave out our daily budget, try again in {} seconds",
+                retry_after.as_secs()
+            ),
+            Error::DatabaseError(s) => write!(f, "some database error: {s}"),
+            Error::InvalidRequest(s) => write!(f, "invalid request: {s}"),
+            Error::InvalidIdempotencyKey => write!(f, "invalid idempotency key"),
+            Error::IdempotencyKeyInUse => write!(f, "idempotency key in use"),
+            Error::IdempotencyKeyReused => write!(f, "idempotency key reused"),
+            Error::QueueFull => write!(f, "the payout queue is full"),
+            Error::UnknownRequest => write!(f, "we don't know this request"),
+            Error::InvalidSignature => write!(f, "invalid signature"),
+            Error::InvalidProofOfWork => write!(f, "invalid proof of work"),
+            Error::ProofOfWorkDisabled => write!(f, "proof of work is disabled"),
+            Error::BatchUnavailable => write!(f, "batch sends are disabled"),
+            Error::BatchTooLarge { max } => write!(f, "batches may have up to {max} outputs"),
+            Error::TooManyRecipients { max } => {
+                write!(f, "requests may pay up to {max} addresses")
+            }
+            Error::CallbacksUnavailable => write!(f, "callbacks are disabled"),
+            Error::TooManyListeners => write!(f, "too many event listeners"),
+            Error::Paused => write!(f, "the faucet is paused"),
+            #[cfg(feature = "nostr")]
+            Error::InvalidNostrAuth(s) => write!(f, "invalid nostr auth: {s}"),
+            #[cfg(feature = "captcha")]
+            Error::CaptchaFailed => write!(f, "the captcha wasn't solved"),
+            #[cfg(feature = "captcha")]
+            Error::CaptchaError(s) => write!(f, "some captcha error: {s}"),
+            #[cfg(feature = "github")]
+            Error::GithubLoginFailed(s) => write!(f, "github login failed: {s}"),
+            #[cfg(feature = "github")]
+            Error::GithubError(s) => write!(f, "some github error: {s}"),
+            #[cfg(feature = "github")]
+            Error::GithubDisabled => write!(f, "github logins are disabled"),
+            Error::UnknownTransaction => write!(f, "we don't know this transaction"),
+            Error::CantBump(e) => write!(f, "can't bump this payout, {e}"),
+            Error::CantAccelerate(e) => write!(f, "can't speed up this transaction, {e}"),
+            Error::SilentPaymentsUnsupported => {
+                write!(f, "our backend can't pay silent payment addresses")
+            }
+            #[cfg(feature = "utreexod")]
+            Error::NotUtreexo => write!(f, "we aren't using utreexod"),
+            #[cfg(feature = "lightning")]
+            Error::InvalidInvoice(s) => write!(f, "invalid invoice, {s}"),
+            #[cfg(feature = "lightning")]
+            Error::NotSupported => write!(f, "our lightning node doesn't support this"),
+            #[cfg(feature = "lightning")]
+            Error::ZeroConfDisabled => write!(f, "zero-conf channels are disabled"),
+            #[cfg(feature = "lightning")]
+            Error::InvalidChannel(s) => write!(f, "invalid channel: {s}"),
+            Error::Cooldown { what, retry_after } => write!(
+                f,
+                "already got {what}, try again in {} seconds",
+                retry_after.as_secs()
+            ),
+            #[cfg(feature = "lightning")]
+            Error::UnknownPayout => write!(f, "we don't know this payout"),
+            Error::Unauthorized => write!(f, "missing or wrong admin token"),
+            Error::InvalidApiKey => write!(f, "unknown api key"),
+            Error::Blocked => write!(f, "blocked"),
+            Error::InvalidAccessRule(s) => write!(f, "invalid access rule: {s}"),
+            Error::UnknownAccessRule => write!(f, "we don't have this access rule"),
+            #[cfg(feature = "lightning")]
+            Error::PeerUnreachable(s) => write!(f, "couldn't connect to the peer: {s}"),
+            #[cfg(feature = "ln")]
+            Error::PeerMissingFeature(bit) => write!(f, "the peer doesn't support feature {bit}"),
             #[cfg(feature = "ln")]
             Error::CLNError(s) => write!(f, "some cln error: {s}"),
+            #[cfg(feature = "lnd")]
+            Error::LNDError(s) => write!(f, "some lnd error: {s}"),
+            #[cfg(feature = "eclair")]
+            Error::EclairError(s) => write!(f, "some eclair error: {s}"),
+            #[cfg(feature = "ldk")]
+            Error::LDKError(s) => write!(f, "some ldk error: {s}"),
         }
     }
 }
 
+impl std::error::Error for Error {}
+
 impl ResponseError for Error {
     fn status_code(&self) -> actix_web::http::StatusCode {
         match self {
             Error::JsonRpcNotWorking => StatusCode::from_u16(500).unwrap(),
             Error::OutOfMoney => StatusCode::from_u16(500).unwrap(),
+            Error::RateLimited { .. } => StatusCode::from_u16(429).unwrap(),
             Error::InvalidAddress => StatusCode::from_u16(400).unwrap(),
+            Error::AddressTypeNotAllowed { .. } => StatusCode::from_u16(400).unwrap(),
             Error::AmountTooLarge => StatusCode::from_u16(400).unwrap(),
             Error::Dust => StatusCode::from_u16(400).unwrap(),
+            Error::InputsSpent => StatusCode::from_u16(503).unwrap(),
+            Error::SigningFailed => StatusCode::from_u16(500).unwrap(),
+            #[cfg(feature = "esplora")]
+            Error::EsploraError(_) => StatusCode::from_u16(500).unwrap(),
+            #[cfg(feature = "electrum")]
+            Error::ElectrumError(_) => StatusCode::from_u16(500).unwrap(),
+            #[cfg(feature = "bdk")]
+            Error::BdkError(_) => StatusCode::from_u16(500).unwrap(),
+            #[cfg(feature = "external-signer")]
+            Error::SignerError(_) => StatusCode::from_u16(500).unwrap(),
+            #[cfg(feature = "redis")]
+            Error::SharedStoreError(_) => StatusCode::from_u16(500).unwrap(),
+            Error::BudgetExhausted { .. } => StatusCode::from_u16(503).unwrap(),
+            Error::DatabaseError(_) => StatusCode::from_u16(500).unwrap(),
+            Error::InvalidRequest(_) => StatusCode::from_u16(400).unwrap(),
+            Error::InvalidIdempotencyKey => StatusCode::from_u16(400).unwrap(),
+            Error::IdempotencyKeyInUse => StatusCode::from_u16(409).unwrap(),
+            Error::IdempotencyKeyReused => StatusCode::from_u16(422).unwrap(),
+            Error::QueueFull => StatusCode::from_u16(503).unwrap(),
+            Error::UnknownRequest => StatusCode::from_u16(404).unwrap(),
+            Error::InvalidSignature => StatusCode::from_u16(400).unwrap(),
+            Error::InvalidProofOfWork => StatusCode::from_u16(403).unwrap(),
+            Error::ProofOfWorkDisabled => StatusCode::from_u16(404).unwrap(),
+            Error::BatchUnavailable => StatusCode::from_u16(404).unwrap(),
+            Error::BatchTooLarge { .. } => StatusCode::from_u16(400).unwrap(),
+            Error::TooManyRecipients { .. } => StatusCode::from_u16(400).unwrap(),
+            Error::CallbacksUnavailable => StatusCode::from_u16(400).unwrap(),
+            Error::TooManyListeners => StatusCode::from_u16(503).unwrap(),
+            Error::Paused => StatusCode::from_u16(503).unwrap(),
+            #[cfg(feature = "nostr")]
+            Error::InvalidNostrAuth(_) => StatusCode::from_u16(401).unwrap(),
+            #[cfg(feature = "captcha")]
+            Error::CaptchaFailed => StatusCode::from_u16(403).unwrap(),
+            #[cfg(feature = "captcha")]
+            Error::CaptchaError(_) => StatusCode::from_u16(500).unwrap(),
+            #[cfg(feature = "github")]
+            Error::GithubLoginFailed(_) => StatusCode::from_u16(403).unwrap(),
+            #[cfg(feature = "github")]
+            Error::GithubError(_) => StatusCode::from_u16(500).unwrap(),
+            #[cfg(feature = "github")]
+            Error::GithubDisabled => StatusCode::from_u16(404).unwrap(),
+            Error::UnknownTransaction => StatusCode::from_u16(404).unwrap(),
+            Error::CantBump(_) => StatusCode::from_u16(409).unwrap(),
+            Error::CantAccelerate(_) => StatusCode::from_u16(409).unwrap(),
+            Error::SilentPaymentsUnsupported => StatusCode::from_u16(501).unwrap(),
+            #[cfg(feature = "utreexod")]
+            Error::NotUtreexo => StatusCode::from_u16(404).unwrap(),
+            #[cfg(feature = "lightning")]
+            Error::InvalidInvoice(_) => StatusCode::from_u16(400).unwrap(),
+            #[cfg(feature = "lightning")]
+            Error::NotSupported => StatusCode::from_u16(501).unwrap(),
+            #[cfg(feature = "lightning")]
+            Error::ZeroConfDisabled => StatusCode::from_u16(403).unwrap(),
+            #[cfg(feature = "lightning")]
+            Error::InvalidChannel(_) => StatusCode::from_u16(400).unwrap(),
+            Error::Cooldown { .. } => StatusCode::from_u16(429).unwrap(),
+            #[cfg(feature = "lightning")]
+            Error::UnknownPayout => StatusCode::from_u16(404).unwrap(),
+            Error::Unauthorized => StatusCode::from_u16(401).unwrap(),
+            Error::InvalidApiKey => StatusCode::from_u16(401).unwrap(),
+            Error::Blocked => StatusCode::from_u16(403).unwrap(),
+            Error::InvalidAccessRule(_) => StatusCode::from_u16(400).unwrap(),
+            Error::UnknownAccessRule => StatusCode::from_u16(404).unwrap(),
+            #[cfg(feature = "lightning")]
+            Error::PeerUnreachable(_) => StatusCode::from_u16(400).unwrap(),
+            #[cfg(feature = "ln")]
+            Error::PeerMissingFeature(_) => StatusCode::from_u16(400).unwrap(),
             #[cfg(feature = "ln")]
             Error::CLNError(_) => StatusCode::from_u16(400).unwrap(),
+            #[cfg(feature = "lnd")]
+            Error::LNDError(_) => StatusCode::from_u16(400).unwrap(),
+            #[cfg(feature = "eclair")]
+            Error::EclairError(_) => StatusCode::from_u16(400).unwrap(),
+            #[cfg(feature = "ldk")]
+            Error::LDKError(_) => StatusCode::from_u16(400).unwrap(),
         }
     }
 
     fn error_response(&self) -> actix_web::HttpResponse<actix_web::body::BoxBody> {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(retry_after) = self.retry_after() {
+            response.insert_header((header::RETRY_AFTER, retry_after.as_secs().max(1)));
+        }
+
+        response.json(ErrorBody::new(self.code(), self.message()))
+    }
+}
+
+impl Error {
+    /// What went wrong, for scripts to match on
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::JsonRpcNotWorking => "node_unavailable",
+            Error::OutOfMoney => "out_of_money",
+            Error::RateLimited { .. } => "rate_limited",
+            Error::InvalidAddress => "invalid_address",
+            Error::AddressTypeNotAllowed { .. } => "address_type_not_allowed",
+            Error::AmountTooLarge => "amount_too_large",
+            Error::Dust => "amount_too_small",
+            Error::InputsSpent => "inputs_spent",
+            Error::SigningFailed => "signing_failed",
+            #[cfg(feature = "esplora")]
+            Error::EsploraError(_) => "esplora_error",
+            #[cfg(feature = "electrum")]
+            Error::ElectrumError(_) => "electrum_error",
+            #[cfg(feature = "bdk")]
+            Error::BdkError(_) => "bdk_error",
+            #[cfg(feature = "external-signer")]
+            Error::SignerError(_) => "signer_error",
+            #[cfg(feature = "redis")]
+            Error::SharedStoreError(_) => "shared_store_error",
+            Error::BudgetExhausted { .. } => "budget_exhausted",
+            Error::DatabaseError(_) => "database_error",
+            Error::InvalidRequest(_) => "invalid_request",
+            Error::InvalidIdempotencyKey => "invalid_idempotency_key",
+            Error::IdempotencyKeyInUse => "idempotency_key_in_use",
+            Error::IdempotencyKeyReused => "idempotency_key_reused",
+            Error::QueueFull => "queue_full",
+            Error::UnknownRequest => "unknown_request",
+            Error::InvalidSignature => "invalid_signature",
+            Error::InvalidProofOfWork => "invalid_proof_of_work",
+            Error::ProofOfWorkDisabled => "proof_of_work_disabled",
+            Error::BatchUnavailable => "batch_unavailable",
+            Error::BatchTooLarge { .. } => "batch_too_large",
+            Error::TooManyRecipients { .. } => "too_many_recipients",
+            Error::CallbacksUnavailable => "callbacks_unavailable",
+            Error::TooManyListeners => "too_many_listeners",
+            Error::Paused => "paused",
+            #[cfg(feature = "nostr")]
+            Error::InvalidNostrAuth(_) => "invalid_nostr_auth",
+            #[cfg(feature = "captcha")]
+            Error::CaptchaFailed => "captcha_failed",
+            #[cfg(feature = "captcha")]
+            Error::CaptchaError(_) => "captcha_error",
+            #[cfg(feature = "github")]
+            Error::GithubLoginFailed(_) => "github_login_failed",
+            #[cfg(feature = "github")]
+            Error::GithubError(_) => "github_error",
+            #[cfg(feature = "github")]
+            Error::GithubDisabled => "github_disabled",
+            Error::UnknownTransaction => "unknown_transaction",
+            Error::CantBump(_) => "cant_bump",
+            Error::CantAccelerate(_) => "cant_accelerate",
+            Error::SilentPaymentsUnsupported => "silent_payments_unsupported",
+            #[cfg(feature = "utreexod")]
+            Error::NotUtreexo => "not_utreexo",
+            #[cfg(feature = "lightning")]
+            Error::InvalidInvoice(_) => "invalid_invoice",
+            #[cfg(feature = "lightning")]
+            Error::NotSupported => "not_supported",
+            #[cfg(feature = "lightning")]
+            Error::ZeroConfDisabled => "zero_conf_disabled",
+            #[cfg(feature = "lightning")]
+            Error::InvalidChannel(_) => "invalid_channel",
+            Error::Cooldown { .. } => "cooldown",
+            #[cfg(feature = "lightning")]
+            Error::UnknownPayout => "unknown_payout",
+            Error::Unauthorized => "unauthorized",
+            Error::InvalidApiKey => "invalid_api_key",
+            Error::Blocked => "blocked",
+            Error::InvalidAccessRule(_) => "invalid_access_rule",
+            Error::UnknownAccessRule => "unknown_access_rule",
+            #[cfg(feature = "lightning")]
+            Error::PeerUnreachable(_) => "peer_unreachable",
+            #[cfg(feature = "ln")]
+            Error::PeerMissingFeature(_) => "peer_missing_feature",
+            #[cfg(feature = "ln")]
+            Error::CLNError(_) => "cln_error",
+            #[cfg(feature = "lnd")]
+            Error::LNDError(_) => "lnd_error",
+            #[cfg(feature = "eclair")]
+            Error::EclairError(_) => "eclair_error",
+            #[cfg(feature = "ldk")]
+            Error::LDKError(_) => "ldk_error",
+        }
+    }
+
+    /// What went wrong, for people. Our own failures aren't detailed, they're in our logs
+    pub fn message(&self) -> String {
         match self {
-            Error::JsonRpcNotWorking => HttpResponse::InternalServerError().into(),
-            Error::OutOfMoney => HttpResponse::InternalServerError()
-                .body("We don't have enough money to handle this request right now\n")
-                .into(),
-            Error::InvalidAddress => HttpResponse::BadRequest()
-                .body("The informed address is not a valid bitcoin address\n")
-                .into(),
-            Error::AmountTooLarge => {
-                HttpResponse::BadRequest().body("The requested amount is too big\n")
+            Error::OutOfMoney => {
+                "We don't have enough money to handle this request right now".into()
+            }
+            Error::RateLimited { retry_after } => format!(
+                "You're asking too much, try again in {} minutes",
+                retry_after.as_secs().div_ceil(60)
+            ),
+            Error::InvalidAddress => "The informed address is not a valid bitcoin address".into(),
+            Error::AddressTypeNotAllowed { allowed } => {
+                format!("This faucet only pays {allowed} addresses")
+            }
+            Error::AmountTooLarge => "The requested amount is too big".into(),
+            Error::InputsSpent => "We're busy paying others right now, try again soon".into(),
+            Error::Dust => "The requested amount is too little".into(),
+            Error::BudgetExhausted { retry_after } => format!(
+                "We gave out all we could today, come back in {} minutes",
+                retry_after.as_secs().div_ceil(60)
+            ),
+            Error::InvalidRequest(e) => format!("Invalid request: {e}"),
+            Error::InvalidIdempotencyKey => format!(
+                "The {} header has to be text, at most 255 characters long",
+                idempotency::HEADER
+            ),
+            Error::IdempotencyKeyInUse => {
+                "We're still handling the first request with this key, try again later".into()
+            }
+            Error::IdempotencyKeyReused => {
+                "This key was used for another request, use a new one".into()
+            }
+            Error::QueueFull => "Too many people are waiting for coins, try again later".into(),
+            Error::UnknownRequest => "We don't know about this request".into(),
+            Error::InvalidSignature => "The signature doesn't prove you own this address".into(),
+            Error::InvalidProofOfWork => {
+                "Missing or wrong proof of work, GET /challenge and solve it first".into()
+            }
+            Error::ProofOfWorkDisabled => "This faucet doesn't use proofs of work".into(),
+            Error::BatchUnavailable => "This faucet doesn't pay batches".into(),
+            Error::BatchTooLarge { max } => {
+                format!("A batch may pay up to {max} addresses")
             }
-            Error::Dust => HttpResponse::BadRequest().body("The requested amount is too little\n"),
+            Error::TooManyRecipients { max } => {
+                format!("A request may pay up to {max} addresses")
+            }
+            Error::CallbacksUnavailable => "This faucet doesn't call back about payouts".into(),
+            Error::TooManyListeners => "Too many people are watching, try again later".into(),
+            Error::Paused => "The faucet is paused for now, try again later".into(),
+            #[cfg(feature = "nostr")]
+            Error::InvalidNostrAuth(e) => format!("Invalid Nostr auth: {e}"),
+            #[cfg(feature = "captcha")]
+            Error::CaptchaFailed => "Please solve the captcha first".into(),
+            #[cfg(feature = "github")]
+            Error::GithubLoginFailed(e) => format!("GitHub login failed: {e}"),
+            #[cfg(feature = "github")]
+            Error::GithubDisabled => "This faucet doesn't do GitHub logins".into(),
+            Error::UnknownTransaction => "We didn't send this transaction".into(),
+            Error::CantBump(e) => format!("We can't bump this payout, {e}"),
+            Error::CantAccelerate(e) => format!("We can't speed up this transaction, {e}"),
+            Error::SilentPaymentsUnsupported => {
+                "This faucet can't pay silent payment addresses".into()
+            }
+            #[cfg(feature = "utreexod")]
+            Error::NotUtreexo => "This faucet isn't running on utreexod".into(),
+            #[cfg(feature = "lightning")]
+            Error::InvalidInvoice(e) => format!("Invalid invoice, {e}"),
+            #[cfg(feature = "lightning")]
+            Error::NotSupported => "Our lightning node doesn't support this".into(),
+            #[cfg(feature = "lightning")]
+            Error::ZeroConfDisabled => "This faucet doesn't open zero-conf channels".into(),
+            #[cfg(feature = "lightning")]
+            Error::InvalidChannel(e) => e.clone(),
+            Error::Cooldown { what, retry_after } => format!(
+                "You already got {what}, try again in {} minutes",
+                retry_after.as_secs().div_ceil(60)
+            ),
+            #[cfg(feature = "lightning")]
+            Error::UnknownPayout => "We don't know about this payout".into(),
+            Error::Unauthorized => "Missing or wrong admin token".into(),
+            Error::InvalidApiKey => "We don't know this API key".into(),
+            Error::Blocked => "You can't use this faucet".into(),
+            Error::InvalidAccessRule(e) => format!("Invalid rule: {e}"),
+            Error::UnknownAccessRule => "We don't have this rule".into(),
+            #[cfg(feature = "lightning")]
+            Error::PeerUnreachable(e) => format!("We couldn't connect to your node: {e}"),
             #[cfg(feature = "ln")]
-            Error::CLNError(e) => {
-                HttpResponse::BadRequest().body(format!("Some problem with cln {e}"))
+            Error::PeerMissingFeature(bit) => {
+                format!("Your node doesn't support feature {bit}, which this channel needs")
             }
+            #[cfg(feature = "ln")]
+            Error::CLNError(e) => format!("Some problem with cln {e}"),
+            #[cfg(feature = "lnd")]
+            Error::LNDError(e) => format!("Some problem with lnd {e}"),
+            #[cfg(feature = "eclair")]
+            Error::EclairError(e) => format!("Some problem with eclair {e}"),
+            #[cfg(feature = "ldk")]
+            Error::LDKError(e) => format!("Some problem with ldk {e}"),
+            _ => "Something went wrong on our side, try again later".into(),
+        }
+    }
+
+    /// When trying again may work, for errors that tell
+    fn retry_after(&self) -> Option<std::time::Duration> {
+        match self {
+            Error::RateLimited { retry_after }
+            | Error::BudgetExhausted { retry_after }
+            | Error::Cooldown { retry_after, .. } => Some(*retry_after),
+            _ => None,
         }
     }
 }
 
-#[cfg(feature = "ln")]
-async fn open_channel(
+/// Opens a channel to whoever asks for one
+#[cfg(feature = "lightning")]
+#[utoipa::path(
+    post,
+    path = "/v1/channel/",
+    tag = "lightning",
+    request_body = GetChannel,
+    responses(
+        (status = 200, body = Channel),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn open_channel<B: ChainBackend>(
+    req: HttpRequest,
     params: web::Json<GetChannel>,
-    data: web::Data<AppState>,
-) -> Result<String, Error> {
-    let GetChannel { node_id } = params.into_inner();
-    let cln = &data.cln;
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Channel>, Error> {
+    let GetChannel {
+        node_id,
+        address,
+        zero_conf,
+        capacity,
+        push_amount,
+    } = params.into_inner();
+
+    if zero_conf && !data.allow_zero_conf {
+        return Err(Error::ZeroConfDisabled);
+    }
+
+    let limits = &data.channel_limits;
+    let capacity = capacity.map_or(limits.default_capacity, Amount::from_sat);
+    let push = push_amount.map_or(limits.default_push, Amount::from_sat);
+    let required_features: &[usize] = if zero_conf {
+        &[STATIC_REMOTE_KEY, ZERO_CONF]
+    } else {
+        &[STATIC_REMOTE_KEY]
+    };
+    let existing = check_channel(
+        &req,
+        &data,
+        node_id,
+        address.as_deref(),
+        capacity,
+        push,
+        required_features,
+    )
+    .await?;
+
+    // rather than opening another channel, we grow the one the node has, if our node can
+    if let Some(channel) = existing.iter().find(|channel| channel.active) {
+        match data
+            .lightning
+            .splice_in(&channel.channel_id, capacity)
+            .await
+        {
+            Err(Error::NotSupported) => {}
+            Ok(txid) => {
+                record_channel(&req, &data, node_id, &channel.channel_id, capacity);
+                return Ok(web::Json(Channel { channel: txid }));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    let channel = ChannelRequest {
+        capacity,
+        push,
+        zero_conf,
+    };
+    let channel = data.lightning.open_channel(node_id, channel).await?;
+    record_channel(&req, &data, node_id, &channel, capacity);
 
-    cln.open_channel(node_id).await
+    Ok(web::Json(Channel { channel }))
 }
 
-async fn send_to_address(
-    params: web::Json<SendMoney>,
-    data: web::Data<AppState>,
-) -> Result<String, Error> {
-    let rpc = &data.rpc;
-    let SendMoney { address, amount } = params.into_inner();
+/// Lists our channels, so people can see whether theirs was opened
+#[cfg(feature = "lightning")]
+#[utoipa::path(
+    get,
+    path = "/v1/channels",
+    tag = "lightning",
+    responses(
+        (status = 200, body = Vec<ChannelInfo>),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn list_channels<B: ChainBackend>(
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Vec<ChannelInfo>>, Error> {
+    let channels = data.lightning.list_channels().await?;
+    Ok(web::Json(channels))
+}
 
-    let amount = Amount::from_sat(amount);
+/// The data passed to /admin/channel/close
+///
+/// This will close the channel with `channel_id`, as /channels shows it. If `force` is set and
+/// the peer won't close it cooperatively, we close it unilaterally
+#[cfg(feature = "lightning")]
+#[derive(Deserialize, ToSchema)]
+pub struct CloseChannel {
+    channel_id: String,
+    #[serde(default)]
+    force: bool,
+}
+
+/// Closes one of our channels
+#[cfg(feature = "lightning")]
+#[utoipa::path(
+    post,
+    path = "/v1/admin/channel/close",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    request_body = CloseChannel,
+    responses(
+        (status = 200, body = ClosedChannel),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn close_channel<B: ChainBackend>(
+    params: web::Json<CloseChannel>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<ClosedChannel>, Error> {
+    let CloseChannel { channel_id, force } = params.into_inner();
+    let txid = data.lightning.close_channel(&channel_id, force).await?;
+
+    Ok(web::Json(ClosedChannel { txid }))
+}
+
+/// Checks whoever sent `req` may get a channel to `node_id` with `capacity` and `push`, as every
+/// route opening one does: they aren't blocked, the node isn't cooling down, the channel is
+/// within our limits and their allowance, and the node, once we're connected to it, supports
+/// `required_features` and wouldn't have too much with us. Returns the channels it has already
+#[cfg(feature = "lightning")]
+async fn check_channel<B: ChainBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    node_id: PublicKey,
+    address: Option<&str>,
+    capacity: Amount,
+    push: Amount,
+    required_features: &[usize],
+) -> Result<Vec<ChannelInfo>, Error> {
+    data.controls.check_running()?;
+    data.access.check(client_ip(req), None)?;
+    check_channel_cooldown(data, node_id).await?;
+
+    let limits = &data.channel_limits;
+    limits.check(capacity, push)?;
+    ratelimit::take_sats(req, data, capacity.to_sat())?;
+
+    data.lightning
+        .connect(node_id, address, required_features)
+        .await?;
+
+    let existing = data
+        .lightning
+        .list_channels()
+        .await?
+        .into_iter()
+        .filter(|channel| channel.peer == node_id)
+        .collect::<Vec<_>>();
+    let existing_capacity = existing.iter().map(|channel| channel.capacity).sum();
+    limits.check_peer(existing_capacity, capacity)?;
+
+    Ok(existing)
+}
+
+/// Refuses to give `node_id` another channel while it's cooling down from the last one
+#[cfg(feature = "lightning")]
+async fn check_channel_cooldown<B: ChainBackend>(
+    data: &AppState<B>,
+    node_id: PublicKey,
+) -> Result<(), Error> {
+    let Some(cooldowns) = &data.channel_cooldowns else {
+        return Ok(());
+    };
+    let Some((grant, retry_after)) = cooldowns.check(&node_id.to_string())? else {
+        return Ok(());
+    };
+
+    // point them to the channel they have, rather than to whatever we called it back then
+    let outpoint = data
+        .lightning
+        .list_channels()
+        .await
+        .ok()
+        .and_then(|channels| {
+            channels
+                .into_iter()
+                .find(|channel| channel.peer == node_id)
+                .and_then(|channel| channel.funding_outpoint)
+        });
+
+    Err(Error::Cooldown {
+        what: format!("channel {}", outpoint.unwrap_or(grant.what)),
+        retry_after,
+    })
+}
+
+/// Starts `node_id`'s cooldown and writes `channel` down, now that `req`'s client got it
+#[cfg(feature = "lightning")]
+fn record_channel<B: ChainBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    node_id: PublicKey,
+    channel: &str,
+    capacity: Amount,
+) {
+    if let Some(cooldowns) = &data.channel_cooldowns {
+        cooldowns.record(&node_id.to_string(), channel.to_string());
+    }
+    if let Err(e) = data
+        .db
+        .record_channel(&node_id.to_string(), channel, capacity, client_ip(req))
+    {
+        error!("couldn't record channel {channel}: {e}");
+    }
+    data.activity.publish(Activity::ChannelOpened {
+        node_id,
+        channel: channel.to_string(),
+        capacity: capacity.to_sat(),
+    });
+}
+
+/// The data passed to /channel/inbound
+///
+/// This gives `node_id` `amount` sats of inbound liquidity, by opening a channel of that size
+/// that's all on our side, so the node can receive up to that much right away
+#[cfg(feature = "lightning")]
+#[derive(Deserialize, ToSchema)]
+pub struct GetInboundChannel {
+    #[schema(value_type = String)]
+    node_id: PublicKey,
+    address: Option<String>,
+    amount: u64,
+}
+
+/// Gives a node inbound liquidity, with a channel that's all on our side
+#[cfg(feature = "lightning")]
+#[utoipa::path(
+    post,
+    path = "/v1/channel/inbound",
+    tag = "lightning",
+    request_body = GetInboundChannel,
+    responses(
+        (status = 200, body = Channel),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn open_inbound_channel<B: ChainBackend>(
+    req: HttpRequest,
+    params: web::Json<GetInboundChannel>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Channel>, Error> {
+    let GetInboundChannel {
+        node_id,
+        address,
+        amount,
+    } = params.into_inner();
+
+    let capacity = Amount::from_sat(amount);
+    check_channel(
+        &req,
+        &data,
+        node_id,
+        address.as_deref(),
+        capacity,
+        Amount::ZERO,
+        &[STATIC_REMOTE_KEY],
+    )
+    .await?;
+
+    let channel = ChannelRequest {
+        capacity,
+        push: Amount::ZERO,
+        zero_conf: false,
+    };
+    let channel = data.lightning.open_channel(node_id, channel).await?;
+    record_channel(&req, &data, node_id, &channel, capacity);
+
+    Ok(web::Json(Channel { channel }))
+}
+
+/// The data passed to /channel/dual
+///
+/// This will open a dual-funded channel to `node_id`, where we put in `capacity` sats, or our
+/// default channel size, and the node may add its own funds
+#[cfg(feature = "lightning")]
+#[derive(Deserialize, ToSchema)]
+pub struct GetDualFundedChannel {
+    #[schema(value_type = String)]
+    node_id: PublicKey,
+    address: Option<String>,
+    capacity: Option<u64>,
+}
+
+/// Opens a dual-funded channel, which the other node may add to
+#[cfg(feature = "lightning")]
+#[utoipa::path(
+    post,
+    path = "/v1/channel/dual",
+    tag = "lightning",
+    request_body = GetDualFundedChannel,
+    responses(
+        (status = 200, body = DualFundedChannel),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn open_dual_funded_channel<B: ChainBackend>(
+    req: HttpRequest,
+    params: web::Json<GetDualFundedChannel>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<DualFundedChannel>, Error> {
+    let GetDualFundedChannel {
+        node_id,
+        address,
+        capacity,
+    } = params.into_inner();
+
+    let capacity = capacity.map_or(data.channel_limits.default_capacity, Amount::from_sat);
+    check_channel(
+        &req,
+        &data,
+        node_id,
+        address.as_deref(),
+        capacity,
+        Amount::ZERO,
+        &[STATIC_REMOTE_KEY, DUAL_FUND],
+    )
+    .await?;
+
+    let channel = data
+        .lightning
+        .open_dual_funded_channel(node_id, capacity)
+        .await?;
+    record_channel(&req, &data, node_id, &channel.channel_id, capacity);
 
-    Address::from_str(&address)
-        .map_err(|_| Error::InvalidAddress)?
-        .require_network(bitcoin::Network::Signet)
-        .map_err(|_| Error::InvalidAddress)?
-        .to_string();
+    Ok(web::Json(channel))
+}
+
+/// Checks whoever sent `req` may have us pay `amount` over Lightning: they aren't blocked, it
+/// fits in our daily budget and in their allowance, which it's taken from. Callers give it back
+/// with [ratelimit::give_sats_back] if the payment fails
+#[cfg(feature = "lightning")]
+pub fn check_lightning_payment<B: ChainBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    amount: Amount,
+) -> Result<(), Error> {
+    data.access.check(client_ip(req), None)?;
+    check_budget(data, amount)?;
+    ratelimit::take_sats(req, data, amount.to_sat())
+}
+
+/// Writes down that we paid `amount` to `destination`, so it counts towards our daily budget
+#[cfg(feature = "lightning")]
+pub fn record_lightning_payment<B: ChainBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    destination: &str,
+    amount: Amount,
+) {
+    if let Err(e) = data
+        .db
+        .record_lightning_payment(destination, amount, client_ip(req))
+    {
+        error!("couldn't record a lightning payment of {amount}: {e}");
+    }
+    record_spent(data, amount);
+}
+
+/// The data passed to /payinvoice
+///
+/// This will pay a BOLT11 `invoice` or BOLT12 offer, if it's asking for an amount we can send.
+/// Offers without an amount are paid `amount` sats
+#[cfg(feature = "lightning")]
+#[derive(Deserialize, ToSchema)]
+pub struct PayInvoice {
+    invoice: String,
+    amount: Option<u64>,
+}
+
+/// Pays a BOLT11 invoice or BOLT12 offer
+#[cfg(feature = "lightning")]
+#[utoipa::path(
+    post,
+    path = "/v1/payinvoice",
+    tag = "lightning",
+    request_body = PayInvoice,
+    responses(
+        (status = 200, body = Payment),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn pay_invoice<B: ChainBackend>(
+    req: HttpRequest,
+    params: web::Json<PayInvoice>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Payment>, Error> {
+    let PayInvoice { invoice, amount } = params.into_inner();
+    data.controls.check_running()?;
+    let limits = data.controls.limits();
+
+    if invoice.to_lowercase().starts_with("lno") {
+        let (offer, amount_msat) = check_offer(
+            &invoice,
+            amount.map(Amount::from_sat),
+            limits.min_sendable,
+            limits.max_sendable,
+        )?;
+        let amount = Amount::from_sat(amount_msat.div_ceil(1_000));
+        check_lightning_payment(&req, &data, amount)?;
+
+        let preimage = data
+            .lightning
+            .pay_offer(&offer, amount_msat)
+            .await
+            .inspect_err(|_| ratelimit::give_sats_back(&req, &data, amount.to_sat()))?;
+        record_lightning_payment(&req, &data, &invoice, amount);
+        return Ok(web::Json(Payment { preimage }));
+    }
+
+    let parsed = check_invoice(&invoice, limits.min_sendable, limits.max_sendable)?;
+    let amount_msat = parsed.amount_milli_satoshis().unwrap_or_default();
+    let amount = Amount::from_sat(amount_msat.div_ceil(1_000));
+    check_lightning_payment(&req, &data, amount)?;
 
-    if amount > data.max_sendable_amount {
+    let preimage = data
+        .lightning
+        .pay_invoice(&invoice)
+        .await
+        .inspect_err(|_| ratelimit::give_sats_back(&req, &data, amount.to_sat()))?;
+    record_lightning_payment(&req, &data, &invoice, amount);
+    Ok(web::Json(Payment { preimage }))
+}
+
+/// The data passed to /keysend
+///
+/// This will push `amount` sats to the node with `node_id`, without an invoice
+#[cfg(feature = "lightning")]
+#[derive(Deserialize, ToSchema)]
+pub struct Keysend {
+    #[schema(value_type = String)]
+    node_id: PublicKey,
+    amount: u64,
+}
+
+/// Pushes sats to a node, without an invoice
+#[cfg(feature = "lightning")]
+#[utoipa::path(
+    post,
+    path = "/v1/keysend",
+    tag = "lightning",
+    request_body = Keysend,
+    responses(
+        (status = 200, body = Payment),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn keysend<B: ChainBackend>(
+    req: HttpRequest,
+    params: web::Json<Keysend>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Payment>, Error> {
+    let Keysend { node_id, amount } = params.into_inner();
+    let amount = Amount::from_sat(amount);
+    data.controls.check_running()?;
+    let limits = data.controls.limits();
+
+    if amount > limits.max_sendable {
         return Err(Error::AmountTooLarge);
     }
 
-    if amount > data.min_sendable_amount {
+    if amount < limits.min_sendable {
         return Err(Error::Dust);
     }
+    check_lightning_payment(&req, &data, amount)?;
+
+    let preimage = data
+        .lightning
+        .keysend(node_id, amount.to_sat() * 1_000)
+        .await
+        .inspect_err(|_| ratelimit::give_sats_back(&req, &data, amount.to_sat()))?;
+    record_lightning_payment(&req, &data, &node_id.to_string(), amount);
+    Ok(web::Json(Payment { preimage }))
+}
+
+/// Our offer for refills, created the first time it's asked for
+#[cfg(feature = "lightning")]
+async fn refill_offer<B: ChainBackend>(data: &AppState<B>) -> Result<&String, Error> {
+    if let Some(offer) = data.bolt12_offer.get() {
+        return Ok(offer);
+    }
+
+    let offer = data.lightning.create_offer("Refill the faucet").await?;
+    Ok(data.bolt12_offer.get_or_init(|| offer))
+}
+
+/// Returns our BOLT12 offer, which can be used to refill the faucet
+#[cfg(feature = "lightning")]
+#[utoipa::path(
+    get,
+    path = "/v1/offer",
+    tag = "lightning",
+    responses(
+        (status = 200, body = Offer),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn offer<B: ChainBackend>(data: web::Data<AppState<B>>) -> Result<web::Json<Offer>, Error> {
+    let offer = refill_offer(&data).await?;
+
+    Ok(web::Json(Offer {
+        offer: offer.clone(),
+        qr: qr::link(&offer.to_uppercase()),
+    }))
+}
+
+/// Pays, queues or asks for a hold invoice for a /send/ request
+#[utoipa::path(
+    post,
+    path = "/v1/send/",
+    tag = "faucet",
+    request_body = SendMoney,
+    params(("Idempotency-Key" = Option<String>, Header, description = "Answers retries with this key like the first try")),
+    responses(
+        (status = 200, body = SendResponse),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn send_to_address<B: ChainBackend>(
+    req: HttpRequest,
+    params: web::Json<SendMoney>,
+    data: web::Data<AppState<B>>,
+) -> Result<HttpResponse, Error> {
+    let Some(key) = idempotency::key(&req, &data.api_keys)? else {
+        let response = pay_request(req, params.into_inner(), data).await?;
+        return Ok(HttpResponse::Ok().json(response));
+    };
+
+    // retries get what the first try got, instead of another payout
+    let request = params
+        .recipients
+        .outputs()
+        .iter()
+        .map(|(address, amount)| format!("{address}:{amount}"))
+        .collect::<Vec<_>>()
+        .join(",");
+    let response = match data.idempotency.begin(&data.db, key, request)? {
+        Started::Replay(response) => response,
+        Started::New(in_flight) => {
+            let response = pay_request(req.clone(), params.into_inner(), data.clone()).await?;
+            let response = serde_json::to_string(&response).expect("responses serialize");
+            in_flight.finish(&data.db, &response);
+            response
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(response))
+}
+
+/// Checks and pays a /send/ request, returning what to answer
+pub async fn pay_request<B: ChainBackend>(
+    req: HttpRequest,
+    params: SendMoney,
+    data: web::Data<AppState<B>>,
+) -> Result<SendResponse, Error> {
+    let SendMoney {
+        recipients,
+        signature,
+        callback_url,
+        memo,
+        memo_hex,
+        fee_target,
+        proof,
+    } = params;
+    let memo = parse_memo(memo, memo_hex)?;
+    if fee_target == Some(0) {
+        return Err(Error::InvalidRequest("fee_target must be positive".into()));
+    }
+
+    #[cfg(feature = "webhooks")]
+    let callback = match (callback_url, &data.webhooks) {
+        (Some(url), Some(_)) => Some(webhook::check_url(url).await?),
+        (Some(_), None) => return Err(Error::CallbacksUnavailable),
+        (None, _) => None,
+    };
+    #[cfg(not(feature = "webhooks"))]
+    if callback_url.is_some() {
+        return Err(Error::CallbacksUnavailable);
+    }
+
+    let outputs = recipients.outputs();
+    if outputs.is_empty() {
+        return Err(Error::InvalidRequest("the request has no outputs".into()));
+    }
+    if outputs.len() > data.max_recipients {
+        return Err(Error::TooManyRecipients {
+            max: data.max_recipients,
+        });
+    }
+    // each payout waits for its own hold invoice
+    #[cfg(feature = "lightning")]
+    if outputs.len() > 1 && data.hold_payouts.is_some() {
+        return Err(Error::TooManyRecipients { max: 1 });
+    }
+
+    let mut payouts = check_payouts(&req, &data, outputs, signature, false, proof).await?;
+    // a transaction only has room for one memo
+    payouts[0].memo = memo;
+    for payout in &mut payouts {
+        payout.fee_target = fee_target;
+        #[cfg(feature = "webhooks")]
+        {
+            payout.callback = callback.clone();
+        }
+    }
+    if payouts.len() > 1 {
+        return blocking(&data, move |data| pay_recipients(data, &payouts)).await;
+    }
+    let payout = payouts.pop().expect("we asked for one payout");
+
+    #[cfg(feature = "lightning")]
+    if let Some(payouts) = &data.hold_payouts {
+        let (invoice, payment_hash) = hold::request_payout(&data, payouts, payout).await?;
+        return Ok(SendResponse::HoldInvoice {
+            qr: qr::invoice_link(&invoice),
+            invoice,
+            payment_hash,
+        });
+    }
+
+    if let Some(queue) = &data.payout_queue {
+        return Ok(SendResponse::Queued {
+            request_id: queue.push(&data.db, payout)?,
+        });
+    }
+
+    let amount = payout.amount;
+    let sent = blocking(&data, move |data| send_coins(data, payout)).await?;
+    Ok(SendResponse::Sent {
+        txid: sent.txid,
+        vout: sent.vouts.first().copied(),
+        vouts: sent.vouts,
+        amount: amount.to_sat(),
+        fee: sent.fee.to_sat(),
+    })
+}
+
+/// Pays a /send/ request for several addresses in a single transaction. Like batches, they're
+/// paid right away, since the payout queue pays one request per client at a time
+fn pay_recipients<B: ChainBackend>(
+    data: &AppState<B>,
+    payouts: &[Payout],
+) -> Result<SendResponse, Error> {
+    let amount = payouts.iter().map(|payout| payout.amount.to_sat()).sum();
+    let sent = send_batch(data, payouts)?;
+    Ok(SendResponse::Sent {
+        txid: sent.txid,
+        vout: None,
+        vouts: sent.vouts,
+        amount,
+        fee: sent.fee.to_sat(),
+    })
+}
+
+/// Pays every output of a /send/batch request in a single transaction. Batches are paid right
+/// away, since the payout queue only pays one request per client at a time
+#[utoipa::path(
+    post,
+    path = "/v1/send/batch",
+    tag = "faucet",
+    request_body = SendBatch,
+    responses(
+        (status = 200, body = SendResponse),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn send_batch_request<B: ChainBackend>(
+    req: HttpRequest,
+    params: web::Json<SendBatch>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<SendResponse>, Error> {
+    let SendBatch { outputs, proof } = params.into_inner();
+
+    let max_outputs = data.max_batch_outputs.ok_or(Error::BatchUnavailable)?;
+    // each payout waits for its own hold invoice
+    #[cfg(feature = "lightning")]
+    if data.hold_payouts.is_some() {
+        return Err(Error::BatchUnavailable);
+    }
+    if outputs.is_empty() {
+        return Err(Error::InvalidRequest("the batch has no outputs".into()));
+    }
+    if outputs.len() > max_outputs {
+        return Err(Error::BatchTooLarge { max: max_outputs });
+    }
+    let addresses = outputs
+        .iter()
+        .map(|output| &output.address)
+        .collect::<HashSet<_>>();
+    if addresses.len() < outputs.len() {
+        return Err(Error::InvalidRequest(
+            "the batch pays the same address twice".into(),
+        ));
+    }
 
-    let mut unspents = rpc.list_unspent(None, None, None, None, None)?;
-    let mut available = 0;
-    let mut inputs = vec![];
+    let outputs = outputs
+        .into_iter()
+        .map(|output| (output.address, output.amount))
+        .collect();
+    let payouts = check_payouts(&req, &data, outputs, None, true, proof).await?;
 
-    while available < (amount.to_sat() + 1_000) {
-        let unspent = unspents.pop().ok_or(Error::OutOfMoney)?;
-        let utxo = CreateRawTransactionInput {
-            sequence: None,
-            txid: unspent.txid,
-            vout: unspent.vout,
+    let amount = payouts.iter().map(|payout| payout.amount.to_sat()).sum();
+    let sent = blocking(&data, move |data| send_batch(data, &payouts)).await?;
+    Ok(web::Json(SendResponse::Sent {
+        txid: sent.txid,
+        vout: None,
+        vouts: sent.vouts,
+        amount,
+        fee: sent.fee.to_sat(),
+    }))
+}
+
+/// Checks whoever sent `req` may get `outputs`, as `(address, amount)` pairs, and returns the
+/// payouts to make. The most we send applies to each output if `each` is set, and to all of
+/// them together if not, while budgets always apply to them together. A `signature` only
+/// counts for a single output
+async fn check_payouts<B: ChainBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    outputs: Vec<(String, u64)>,
+    signature: Option<String>,
+    each: bool,
+    proof: Proof,
+) -> Result<Vec<Payout>, Error> {
+    let Proof {
+        challenge,
+        nonce,
+        #[cfg(feature = "captcha")]
+        captcha,
+    } = proof;
+
+    data.controls.check_running()?;
+    let limits = data.controls.limits();
+
+    let mut scripts = HashSet::new();
+    let mut verdict = Verdict::default();
+    let mut outs = Vec::with_capacity(outputs.len());
+    let mut silent_payments = Vec::with_capacity(outputs.len());
+    for (address, amount) in outputs {
+        let amount = Amount::from_sat(amount);
+        // silent payment addresses go by a stand-in until we know what we pay them to
+        let silent_payment = SilentPaymentAddress::from_str(&address).ok();
+        let address = match &silent_payment {
+            Some(silent_payment) => silent_payment.stand_in(),
+            None => Address::from_str(&address)
+                .map_err(|_| Error::InvalidAddress)?
+                .require_network(bitcoin::Network::Signet)
+                .map_err(|_| Error::InvalidAddress)?,
         };
+        check_address_type(data, &address)?;
+        data.access
+            .check(client_ip(req), Some(&address.script_pubkey()))?;
 
-        inputs.push(utxo);
+        // cooldowns only know about past payouts
+        if !scripts.insert(address.script_pubkey()) {
+            return Err(Error::InvalidRequest(format!(
+                "{address} is paid more than once"
+            )));
+        }
 
-        available += unspent.amount.to_sat();
+        if let Some(scorer) = &data.abuse {
+            let scored = scorer.score(&data.db, req, &address, amount, limits.max_sendable)?;
+            verdict.challenge |= scored.challenge;
+            verdict.downgrade |= scored.downgrade;
+        }
+
+        outs.push((address, amount));
+        silent_payments.push(silent_payment);
     }
+    let total: Amount = outs.iter().map(|(_, amount)| *amount).sum();
 
-    let mut outs = HashMap::new();
+    // each check the request passes may get it a higher tier
+    let mut max_sendable = limits.max_sendable;
 
-    outs.insert(address, amount);
+    #[cfg(feature = "tor")]
+    let tor = data
+        .tor
+        .as_ref()
+        .and_then(|tor| tor.policy_for(client_ip(req)));
+    #[cfg(feature = "tor")]
+    let tor_challenge = matches!(tor, Some(TorPolicy::Challenge));
+    #[cfg(not(feature = "tor"))]
+    let tor_challenge = false;
 
-    // change
-    outs.insert(
-        data.change_address.to_string(),
-        Amount::from_sat(available - (amount.to_sat() + 1_000)),
+    // a proof of work is as good as a captcha, for those who can't solve one. Solving either is
+    // required, unless it's only how users get to the captcha tier and they don't look abusive
+    let optional = data.tiers.captcha.is_some() && !verdict.challenge && !tor_challenge;
+    let solved = match (&data.challenges, challenge, nonce) {
+        (Some(challenges), Some(challenge), Some(nonce)) => {
+            challenges.verify(&challenge, &nonce)?;
+            true
+        }
+        #[cfg(feature = "captcha")]
+        _ if data.captcha.is_some() && !(optional && captcha.is_none()) => {
+            let checker = data.captcha.as_ref().unwrap();
+            checker.verify(captcha, client_ip(req)).await?;
+            true
+        }
+        (Some(_), _, _) if !optional => return Err(Error::InvalidProofOfWork),
+        _ => false,
+    };
+    if let (true, Some(captcha_max)) = (solved, data.tiers.captcha) {
+        max_sendable = max_sendable.max(captcha_max);
+    }
+
+    #[cfg(feature = "nostr")]
+    let nostr = match &data.nostr {
+        Some(tier) => match tier.authenticate(req)? {
+            Some(pubkey) if tier.is_established(pubkey).await => Some(tier),
+            _ => None,
+        },
+        None => None,
+    };
+    #[cfg(feature = "nostr")]
+    if let Some(tier) = nostr {
+        max_sendable = max_sendable.max(tier.max_sendable);
+    }
+    #[cfg(feature = "nostr")]
+    let cooldown = nostr.and_then(|tier| tier.cooldown);
+    #[cfg(not(feature = "nostr"))]
+    let cooldown = None;
+
+    // logged in users may get more, as long as their account didn't get too much today
+    #[cfg(feature = "github")]
+    let account = data.github.as_ref().and_then(|tier| tier.account(req));
+    #[cfg(feature = "github")]
+    if let (Some(tier), Some(account)) = (&data.github, &account) {
+        match data.db.account_total(account, github::ACCOUNT_WINDOW) {
+            Ok(got) if got + total <= tier.max_sendable => {
+                max_sendable = max_sendable.max(tier.max_sendable);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("couldn't tell how much {account} got: {e}"),
+        }
+    }
+    #[cfg(not(feature = "github"))]
+    let account = None;
+
+    // only bother with the signature if it would let them have more
+    if let ([(address, amount)], Some(verified_max), Some(signature)) =
+        (&outs[..], data.tiers.signed_message, signature)
+    {
+        if *amount > max_sendable {
+            if !bip322::verify(address, &bip322::payout_message(address), &signature) {
+                return Err(Error::InvalidSignature);
+            }
+            max_sendable = max_sendable.max(verified_max);
+        }
+    }
+
+    if verdict.downgrade {
+        max_sendable = limits.max_sendable;
+    }
+    #[cfg(feature = "tor")]
+    if let Some(TorPolicy::Limit(tor_max)) = tor {
+        max_sendable = max_sendable.min(tor_max);
+    }
+
+    if !each && total > max_sendable {
+        return Err(Error::AmountTooLarge);
+    }
+    for (_, amount) in &outs {
+        if *amount > max_sendable {
+            return Err(Error::AmountTooLarge);
+        }
+
+        if *amount < limits.min_sendable {
+            return Err(Error::Dust);
+        }
+    }
+
+    check_budget(data, total)?;
+    for (address, _) in &outs {
+        check_address_cooldown(data, address, cooldown)?;
+    }
+
+    ratelimit::take_sats(req, data, total.to_sat())?;
+
+    Ok(outs
+        .into_iter()
+        .zip(silent_payments)
+        .map(|((address, amount), silent_payment)| Payout {
+            address,
+            amount,
+            silent_payment,
+            client: client_ip(req),
+            cooldown,
+            account: account.clone(),
+            memo: None,
+            fee_target: None,
+            #[cfg(feature = "webhooks")]
+            callback: None,
+        })
+        .collect())
+}
+
+/// Refuses to pay `address` if it isn't of a kind we pay. Silent payments go to taproot outputs,
+/// like their stand-ins
+fn check_address_type<B: ChainBackend>(data: &AppState<B>, address: &Address) -> Result<(), Error> {
+    let Some(allowed) = &data.address_types else {
+        return Ok(());
+    };
+    if address
+        .address_type()
+        .is_some_and(|kind| allowed.contains(&kind))
+    {
+        return Ok(());
+    }
+
+    Err(Error::AddressTypeNotAllowed {
+        allowed: allowed
+            .iter()
+            .map(|kind| kind.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+    })
+}
+
+/// The bytes of a /send/ request's memo, given as text or hex, if it has one
+fn parse_memo(memo: Option<String>, memo_hex: Option<String>) -> Result<Option<Vec<u8>>, Error> {
+    let memo = match (memo, memo_hex) {
+        (Some(_), Some(_)) => {
+            return Err(Error::InvalidRequest(
+                "give either a memo or a memo_hex, not both".into(),
+            ))
+        }
+        (Some(memo), None) => memo.into_bytes(),
+        (None, Some(memo)) => {
+            Vec::from_hex(&memo).map_err(|_| Error::InvalidRequest("memo_hex isn't hex".into()))?
+        }
+        (None, None) => return Ok(None),
+    };
+    if memo.len() > MAX_MEMO_LENGTH {
+        return Err(Error::InvalidRequest(format!(
+            "the memo is longer than {MAX_MEMO_LENGTH} bytes"
+        )));
+    }
+
+    Ok(Some(memo))
+}
+
+/// Refuses to send `amount` if that would take what we gave out in the last day, on-chain and
+/// over Lightning, over our daily budget, telling when enough of it will be back. Faucets
+/// sharing a Redis server share the budget too
+pub fn check_budget<B: ChainBackend>(data: &AppState<B>, amount: Amount) -> Result<(), Error> {
+    let Some(budget) = data.controls.limits().daily_budget else {
+        return Ok(());
+    };
+
+    #[cfg(feature = "redis")]
+    let payouts = match &data.shared {
+        Some(store) => store.spent_since(BUDGET_WINDOW)?,
+        None => data.db.spent_since(BUDGET_WINDOW)?,
+    };
+    #[cfg(not(feature = "redis"))]
+    let payouts = data.db.spent_since(BUDGET_WINDOW)?;
+    let mut spent: Amount = payouts.iter().map(|(_, paid)| *paid).sum();
+    if spent + amount <= budget {
+        return Ok(());
+    }
+
+    // payouts leave the window oldest first, we wait for enough of them to
+    let now = db::now();
+    let retry_after = payouts
+        .iter()
+        .find(|(_, paid)| {
+            spent -= *paid;
+            spent + amount <= budget
+        })
+        .map_or(BUDGET_WINDOW.as_secs(), |(created_at, _)| {
+            (created_at + BUDGET_WINDOW.as_secs()).saturating_sub(now)
+        });
+
+    Err(Error::BudgetExhausted {
+        retry_after: std::time::Duration::from_secs(retry_after),
+    })
+}
+
+/// Counts `amount` we just paid towards the budget we share with other faucets, if we do. Our
+/// own budget is counted from the database
+fn record_spent<B: ChainBackend>(data: &AppState<B>, amount: Amount) {
+    #[cfg(feature = "redis")]
+    if let Some(store) = &data.shared {
+        if let Err(e) = store.record_spent(amount, BUDGET_WINDOW) {
+            error!("couldn't count {amount} towards the shared budget: {e}");
+        }
+    }
+    #[cfg(not(feature = "redis"))]
+    let _ = (data, amount);
+}
+
+/// Refuses to pay `address` while its script is cooling down from the last payout. We go by
+/// script so the same output can't be asked for with another encoding of its address. Some
+/// users have a shorter `cooldown` than the others
+pub fn check_address_cooldown<B: ChainBackend>(
+    data: &AppState<B>,
+    address: &Address,
+    cooldown: Option<std::time::Duration>,
+) -> Result<(), Error> {
+    let Some(cooldowns) = &data.address_cooldowns else {
+        return Ok(());
+    };
+
+    let key = address.script_pubkey().to_hex_string();
+    let cooling = match cooldown {
+        Some(window) => cooldowns.check_for(&key, window)?,
+        None => cooldowns.check(&key)?,
+    };
+    match cooling {
+        Some((grant, retry_after)) => Err(Error::Cooldown {
+            what: format!("coins at this address in {}", grant.what),
+            retry_after,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// A payout we were asked for, and who asked for it
+#[derive(Debug, Clone)]
+pub struct Payout {
+    /// For silent payments, the stand-in address until we send it, and what we paid after
+    pub address: Address,
+    pub amount: Amount,
+    /// The silent payment address we pay, if that's what we were given
+    pub silent_payment: Option<SilentPaymentAddress>,
+    /// Who asked for it, if we know
+    pub client: Option<IpAddr>,
+    /// How long the address waits before getting paid again, if not as long as usual
+    pub cooldown: Option<std::time::Duration>,
+    /// The account of the logged in user asking for it, as `<provider>:<id>`
+    pub account: Option<String>,
+    /// What to put in an OP_RETURN output of its transaction, if anything
+    pub memo: Option<Vec<u8>>,
+    /// How many blocks it should confirm within, if not as many as usual
+    pub fee_target: Option<u16>,
+    /// Where to post once it confirms, if anywhere
+    #[cfg(feature = "webhooks")]
+    pub callback: Option<String>,
+}
+
+/// A transaction we made payouts in
+pub struct Sent {
+    pub txid: Txid,
+    pub fee: Amount,
+    /// Which of its outputs pays each payout, in the order they were given
+    pub vouts: Vec<u32>,
+}
+
+/// Runs `f` on actix's blocking pool. Our backends answer synchronously, and a slow node would
+/// otherwise hold up every request the worker serves while we wait for it
+pub async fn blocking<B: ChainBackend, T: Send + 'static>(
+    data: &web::Data<AppState<B>>,
+    f: impl FnOnce(&AppState<B>) -> Result<T, Error> + Send + 'static,
+) -> Result<T, Error> {
+    let data = data.clone();
+    web::block(move || f(&data))
+        .await
+        .map_err(|_| Error::JsonRpcNotWorking)?
+}
+
+/// Makes `payout`, with the change going back to our change address
+pub fn send_coins<B: ChainBackend>(data: &AppState<B>, payout: Payout) -> Result<Sent, Error> {
+    // payouts gated by a hold invoice may have been asked for before the last one went out
+    check_budget(data, payout.amount)?;
+    check_address_cooldown(data, &payout.address, payout.cooldown)?;
+
+    send_batch(data, &[payout])
+}
+
+/// Makes every one of `payouts` in a single transaction, with the change going back to our
+/// change address. The caller checks they may be made
+pub fn send_batch<B: ChainBackend>(data: &AppState<B>, payouts: &[Payout]) -> Result<Sent, Error> {
+    let total: u64 = payouts.iter().map(|payout| payout.amount.to_sat()).sum();
+
+    let mut memos = payouts.iter().filter_map(|payout| payout.memo.clone());
+    let memo = memos.next();
+    if memos.next().is_some() {
+        return Err(Error::InvalidRequest(
+            "a transaction only has room for one memo".into(),
+        ));
+    }
+
+    let backend = &data.backend;
+    // the transaction pays for the soonest any of them asked for
+    let target = payouts
+        .iter()
+        .filter_map(|payout| payout.fee_target)
+        .min()
+        .unwrap_or(data.fees.target);
+    let feerate = data.fees.feerate_for(backend, target)?;
+    // a memo's output is about the size of two others
+    let memo_outputs = if memo.is_some() { 2 } else { 0 };
+    // another payout going out at the same time may spend the coins we select before we
+    // broadcast, so we select others when that happens
+    let mut attempt = 1;
+    let (selection, resolved, payout_outputs, change, vouts, txid) = loop {
+        let selection = data.coin_selection.select(
+            backend.list_unspent()?,
+            Amount::from_sat(total),
+            payouts.len() + memo_outputs,
+            feerate,
+        )?;
+
+        let resolved = pay_silent_payments(backend, payouts, &selection.inputs)?;
+        let mut outs = resolved
+            .iter()
+            .map(|payout| (payout.address.clone(), payout.amount))
+            .collect::<Vec<_>>();
+        let change = selection
+            .change
+            .map(|change| (data.change_address.clone(), change));
+        let payout_outputs = outs.clone();
+        outs.extend(change.clone());
+
+        let raw_tx = make_transaction(backend, &selection.inputs, &outs, memo.as_deref())?;
+        // backends may put the outputs in any order, but they must all be there
+        let mut vouts = vec![];
+        for payout in &resolved {
+            let script = payout.address.script_pubkey();
+            let vout = (0..raw_tx.output.len())
+                .find(|vout| {
+                    let output = &raw_tx.output[*vout];
+                    output.script_pubkey == script
+                        && output.value == payout.amount
+                        && !vouts.contains(&(*vout as u32))
+                })
+                .ok_or(Error::SigningFailed)?;
+            vouts.push(vout as u32);
+        }
+
+        match backend.broadcast_transaction(&raw_tx) {
+            Ok(txid) => break (selection, resolved, payout_outputs, change, vouts, txid),
+            Err(Error::InputsSpent) if attempt < MAX_BROADCAST_ATTEMPTS => {
+                warn!(
+                    attempt,
+                    "the coins for a payout were spent already, selecting others"
+                );
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    };
+    let requested = payouts;
+    let payouts = &resolved;
+    info!(%txid, payouts = payouts.len(), amount = total, "broadcast a payout");
+    let fee = selection.fee;
+
+    #[cfg(feature = "zmq")]
+    data.tracker.track(txid);
+    data.bumper.watch(
+        txid,
+        Pending::new(
+            selection.inputs,
+            payout_outputs,
+            change,
+            memo,
+            selection.fee,
+        ),
     );
 
-    let raw_tx = rpc.create_raw_transaction(&inputs, &outs, None, Some(true))?;
-    let raw_tx = rpc
-        .sign_raw_transaction_with_wallet(&raw_tx, None, None)?
-        .transaction()
-        .map_err(|_| Error::JsonRpcNotWorking)?;
+    for ((payout, requested), vout) in payouts.iter().zip(requested).zip(&vouts) {
+        // silent payments cool down by their stand-in, which we check them by
+        if let Some(cooldowns) = &data.address_cooldowns {
+            let key = requested.address.script_pubkey().to_hex_string();
+            match payout.cooldown {
+                Some(window) => cooldowns.record_for(&key, txid.to_string(), window),
+                None => cooldowns.record(&key, txid.to_string()),
+            }
+        }
+        if let Err(e) = data.db.record_payout(payout, txid, *vout) {
+            error!("couldn't record payout {txid}: {e}");
+        }
+        #[cfg(feature = "webhooks")]
+        if let Some(webhooks) = &data.webhooks {
+            webhooks.watch(txid, payout);
+        }
+        data.activity.publish(Activity::Payout {
+            txid,
+            address: truncate_address(&payout.address.to_string()),
+            amount: payout.amount.to_sat(),
+        });
+    }
+    record_spent(data, Amount::from_sat(total));
 
-    Ok(rpc
-        .send_raw_transaction(&raw_tx)
-        .map(|txid| txid.to_string() + "\n")?)
+    Ok(Sent { txid, fee, vouts })
 }
 
-pub async fn index() -> HttpResponse {
-    let body = std::fs::read_to_string("static/index.html").unwrap();
-    HttpResponse::Ok().body(body)
+/// `payouts`, with the silent payments among them going to the outputs we owe them for spending
+/// `inputs`
+fn pay_silent_payments<B: ChainBackend>(
+    backend: &B,
+    payouts: &[Payout],
+    inputs: &[Utxo],
+) -> Result<Vec<Payout>, Error> {
+    let recipients = payouts
+        .iter()
+        .filter_map(|payout| payout.silent_payment)
+        .collect::<Vec<_>>();
+    if recipients.is_empty() {
+        return Ok(payouts.to_vec());
+    }
+
+    let outpoints = inputs
+        .iter()
+        .map(|utxo| OutPoint::new(utxo.txid, utxo.vout))
+        .collect::<Vec<_>>();
+    let mut outputs = silentpayments::derive_outputs(
+        backend.silent_payment_key(inputs)?,
+        &outpoints,
+        &recipients,
+    )?
+    .into_iter();
+
+    Ok(payouts
+        .iter()
+        .map(|payout| {
+            let mut payout = payout.clone();
+            if payout.silent_payment.is_some() {
+                payout.address = outputs.next().expect("one output per silent payment");
+            }
+            payout
+        })
+        .collect())
 }
 
-#[cfg(feature = "ln")]
-/// This function creates the actix-web server and returns a future that can be awaited.
-pub async fn create_api(
-    client: Client,
-    cln: CLNDaemon,
-    max_sendable_amount: Option<Amount>,
-    min_sendable_amount: Option<Amount>,
-    change_address: Address,
-) -> std::io::Result<()> {
-    let app_state = web::Data::new(AppState {
-        rpc: client,
-        cln,
-        min_sendable_amount,
-        max_sendable_amount,
-        change_address,
+/// A signed transaction spending `inputs` to `outputs`, with `memo` in an OP_RETURN output after
+/// them if there's one
+pub fn make_transaction<B: ChainBackend>(
+    backend: &B,
+    inputs: &[Utxo],
+    outputs: &[(Address, Amount)],
+    memo: Option<&[u8]>,
+) -> Result<Transaction, Error> {
+    let mut tx = backend.create_transaction(inputs, outputs)?;
+    if let Some(memo) = memo {
+        let memo = PushBytesBuf::try_from(memo.to_vec())
+            .map_err(|_| Error::InvalidRequest("the memo is too long".into()))?;
+        tx.output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::new_op_return(memo),
+        });
+    }
+
+    backend.sign_transaction(&tx)
+}
+
+/// Tells how a hold-invoice gated payout is doing, by the invoice's payment hash
+#[cfg(feature = "lightning")]
+#[utoipa::path(
+    get,
+    path = "/v1/send/{payment_hash}",
+    tag = "faucet",
+    params(("payment_hash" = String, Path, description = "The hold invoice's payment hash")),
+    responses(
+        (status = 200, body = PayoutProgress),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn hold_payout_status<B: ChainBackend>(
+    payment_hash: web::Path<sha256::Hash>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<PayoutProgress>, Error> {
+    let status = data
+        .hold_payouts
+        .as_ref()
+        .and_then(|payouts| payouts.status(&payment_hash))
+        .ok_or(Error::UnknownPayout)?;
+
+    Ok(web::Json(match status {
+        HoldStatus::WaitingForPayment => PayoutProgress::WaitingForPayment,
+        HoldStatus::Paid(txid) => PayoutProgress::Paid { txid, vout: None },
+        HoldStatus::Failed(reason) => PayoutProgress::Failed { reason },
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct FeeQuery {
+    target: Option<u16>,
+}
+
+/// Tells what fees look like, and what our payouts pay
+#[utoipa::path(
+    get,
+    path = "/v1/fee",
+    tag = "faucet",
+    params(FeeQuery),
+    responses(
+        (status = 200, body = Fees),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn fee<B: ChainBackend>(
+    query: web::Query<FeeQuery>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Fees>, Error> {
+    // core estimates for up to 1008 blocks
+    let target = query.target.unwrap_or(DEFAULT_FEE_TARGET).clamp(1, 1_008);
+    let (feerate, payout_feerate) = blocking(&data, move |data| {
+        Ok((
+            data.backend.estimate_fee(target)?,
+            data.fees.feerate(&data.backend)?,
+        ))
+    })
+    .await?;
+
+    Ok(web::Json(Fees {
+        target,
+        feerate: feerate.map(|rate| rate.to_sat_per_vb_ceil()),
+        payout_fee: fees::transaction_fee(payout_feerate, 1, 2).to_sat(),
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct DonateQuery {
+    /// In sats, for the invoice and the URI
+    amount: Option<u64>,
+}
+
+/// A BIP21 URI paying `address` and, for wallets that can, `invoice` or `offer`
+fn bip21(
+    address: &Address,
+    amount: Option<Amount>,
+    invoice: Option<&str>,
+    offer: Option<&str>,
+    payjoin: Option<&str>,
+) -> String {
+    let mut params = vec![];
+    if let Some(amount) = amount {
+        params.push(format!(
+            "amount={}",
+            amount.to_string_in(Denomination::Bitcoin)
+        ));
+    }
+    if let Some(invoice) = invoice {
+        params.push(format!("lightning={invoice}"));
+    }
+    if let Some(offer) = offer {
+        params.push(format!("lno={offer}"));
+    }
+    if let Some(url) = payjoin {
+        params.push(format!("pj={url}"));
+    }
+
+    match params.is_empty() {
+        true => format!("bitcoin:{address}"),
+        false => format!("bitcoin:{address}?{}", params.join("&")),
+    }
+}
+
+/// Tells where to send coins to refill the faucet: a fresh address and, with Lightning, an
+/// invoice for `amount` and our offer, all in a BIP21 URI for wallets to pick from. The URI
+/// takes payjoins too, if we do
+#[utoipa::path(
+    get,
+    path = "/v1/donate",
+    tag = "faucet",
+    params(DonateQuery),
+    responses(
+        (status = 200, body = Donation),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn donate<B: ChainBackend>(
+    req: HttpRequest,
+    query: web::Query<DonateQuery>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Donation>, Error> {
+    let amount = match query.amount {
+        Some(0) => return Err(Error::InvalidRequest("amount must be positive".into())),
+        Some(sats) if sats > Amount::MAX_MONEY.to_sat() => {
+            return Err(Error::InvalidRequest("amount is too large".into()))
+        }
+        amount => amount.map(Amount::from_sat),
+    };
+    let address = blocking(&data, |data| data.backend.receive_address()).await?;
+
+    #[cfg(feature = "lightning")]
+    let invoice = match amount {
+        Some(amount) => Some(
+            data.lightning
+                .create_invoice(amount.to_sat() * 1_000, "Refill the faucet")
+                .await?,
+        ),
+        None => None,
+    };
+    #[cfg(feature = "lightning")]
+    let offer = match refill_offer(&data).await {
+        Ok(offer) => Some(offer.clone()),
+        Err(Error::NotSupported) => None,
+        Err(e) => return Err(e),
+    };
+    #[cfg(not(feature = "lightning"))]
+    let (invoice, offer) = (None, None);
+
+    let payjoin = data.payjoins.as_ref().map(|payjoins| {
+        payjoins.expect(address.script_pubkey());
+        let info = req.connection_info();
+        format!("{}://{}/payjoin", info.scheme(), info.host())
     });
 
-    HttpServer::new(move || {
-        let cors = Cors::permissive();
-        App::new()
-            .wrap(cors)
-            .app_data(app_state.clone())
-            .route("/send/", web::post().to(send_to_address))
-            .route("/channel/", web::post().to(open_channel))
-            .route("/", web::get().to(index))
+    let uri = bip21(
+        &address,
+        amount,
+        invoice.as_deref(),
+        offer.as_deref(),
+        payjoin.as_deref(),
+    );
+    Ok(web::Json(Donation {
+        address: address.to_string(),
+        invoice,
+        offer,
+        qr: qr::link(&uri),
+        uri,
+    }))
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct HistoryQuery {
+    /// Starting from 1
+    page: Option<u32>,
+    limit: Option<u32>,
+    address: Option<String>,
+}
+
+/// Cuts `address` short, so our history doesn't tell who got paid, but people can still spot
+/// their own payouts
+fn truncate_address(address: &str) -> String {
+    if address.len() <= 12 {
+        return address.to_string();
+    }
+
+    format!("{}…{}", &address[..8], &address[address.len() - 4..])
+}
+
+/// Lists our latest payouts, newest first, or only those to `address`
+#[utoipa::path(
+    get,
+    path = "/v1/history",
+    tag = "faucet",
+    params(HistoryQuery),
+    responses(
+        (status = 200, body = History),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn history<B: ChainBackend>(
+    query: web::Query<HistoryQuery>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<History>, Error> {
+    let page = query.page.unwrap_or(1).max(1);
+    let limit = query
+        .limit
+        .unwrap_or(HISTORY_PAGE_SIZE)
+        .clamp(1, MAX_HISTORY_PAGE_SIZE);
+    let address = query
+        .address
+        .as_deref()
+        .map(|address| {
+            Address::from_str(address)
+                .map_err(|_| Error::InvalidAddress)?
+                .require_network(bitcoin::Network::Signet)
+                .map_err(|_| Error::InvalidAddress)
+        })
+        .transpose()?;
+
+    let offset = (page - 1).saturating_mul(limit);
+    let (total, mut payouts) = data.db.payout_history(address.as_ref(), limit, offset)?;
+    for payout in &mut payouts {
+        payout.address = truncate_address(&payout.address);
+    }
+
+    Ok(web::Json(History {
+        page,
+        limit,
+        total,
+        payouts,
+    }))
+}
+
+/// Tells what users may ask for, and what gets them more
+#[utoipa::path(get, path = "/v1/info", tag = "faucet", responses((status = 200, body = Info)))]
+async fn info<B: ChainBackend>(data: web::Data<AppState<B>>) -> web::Json<Info> {
+    let limits = data.controls.limits();
+    let mut tiers = vec![TierInfo::new(Tier::Anonymous, limits.max_sendable)];
+    if let Some(max) = data.tiers.captcha {
+        tiers.push(TierInfo::new(Tier::Captcha, max));
+    }
+    if let Some(max) = data.tiers.signed_message {
+        tiers.push(TierInfo::new(Tier::SignedMessage, max));
+    }
+    #[cfg(feature = "nostr")]
+    if let Some(nostr) = &data.nostr {
+        tiers.push(TierInfo::new(Tier::Authenticated, nostr.max_sendable).via("nostr"));
+    }
+    #[cfg(feature = "github")]
+    if let Some(github) = &data.github {
+        tiers.push(TierInfo::new(Tier::Authenticated, github.max_sendable).via("github"));
+    }
+
+    let (requests_per_hour, sats_per_hour) = data
+        .rate_limiter
+        .as_ref()
+        .map_or((None, None), RateLimiter::limits);
+    #[cfg(feature = "captcha")]
+    let captcha = data.captcha.is_some();
+    #[cfg(not(feature = "captcha"))]
+    let captcha = false;
+    #[cfg(feature = "webhooks")]
+    let callback_confirmations = data.webhooks.as_ref().map(Webhooks::confirmations);
+    #[cfg(not(feature = "webhooks"))]
+    let callback_confirmations = None;
+
+    web::Json(Info {
+        network: bitcoin::Network::Signet,
+        mode: data.controls.mode(),
+        min_sendable: limits.min_sendable.to_sat(),
+        max_sendable: limits.max_sendable.to_sat(),
+        tiers,
+        proof_of_work: data.challenges.is_some(),
+        captcha,
+        daily_budget: limits.daily_budget.map(Amount::to_sat),
+        rate_limits: RateLimits {
+            requests_per_hour,
+            sats_per_hour,
+        },
+        cooldowns: CooldownInfo {
+            address: data
+                .address_cooldowns
+                .as_ref()
+                .map(|cooldowns| cooldowns.window().as_secs()),
+            #[cfg(feature = "lightning")]
+            channel: data
+                .channel_cooldowns
+                .as_ref()
+                .map(|cooldowns| cooldowns.window().as_secs()),
+        },
+        max_batch_outputs: data.max_batch_outputs,
+        callback_confirmations,
+        queue: data.payout_queue.as_ref().map(|queue| {
+            let (depth, capacity) = queue.depth();
+            QueueInfo { depth, capacity }
+        }),
+        lightning: cfg!(feature = "lightning"),
+        #[cfg(feature = "lightning")]
+        channels: ChannelPolicy {
+            min_capacity: data.channel_limits.min_capacity.to_sat(),
+            max_capacity: data.channel_limits.max_capacity.to_sat(),
+            default_capacity: data.channel_limits.default_capacity.to_sat(),
+            default_push: data.channel_limits.default_push.to_sat(),
+            max_push: data.channel_limits.max_push.to_sat(),
+            zero_conf: data.allow_zero_conf,
+        },
+        #[cfg(feature = "lightning")]
+        hold_invoices: data.hold_payouts.is_some(),
     })
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await
 }
 
-#[cfg(not(feature = "ln"))]
+/// Liveness: we answer as long as we're up, without asking anyone else
+#[utoipa::path(get, path = "/v1/health", tag = "faucet", responses((status = 200, body = Health)))]
+async fn health() -> web::Json<Health> {
+    web::Json(Health { status: "ok" })
+}
+
+/// Readiness: asks the services we depend on how they're doing. We're unavailable without the
+/// chain or our database, and only degraded without Lightning, since on-chain payouts still work
+#[utoipa::path(
+    get,
+    path = "/v1/ready",
+    tag = "faucet",
+    responses(
+        (status = 200, body = Readiness),
+        (status = 503, body = Readiness),
+    )
+)]
+async fn ready<B: ChainBackend>(data: web::Data<AppState<B>>) -> HttpResponse {
+    let chain = Dependency::new(blocking(&data, |data| data.backend.block_height()).await);
+    let database = Dependency::new(data.db.ping().map(|_| None));
+    #[cfg(feature = "lightning")]
+    let lightning = Dependency::new(
+        data.lightning
+            .node_info()
+            .await
+            .map(|info| info.block_height),
+    );
+
+    let mut status = ReadyStatus::Ready;
+    #[cfg(feature = "lightning")]
+    if !lightning.ok {
+        status = ReadyStatus::Degraded;
+    }
+    if !chain.ok || !database.ok {
+        status = ReadyStatus::Unavailable;
+    }
+
+    let readiness = Readiness {
+        status,
+        chain,
+        database,
+        #[cfg(feature = "lightning")]
+        lightning,
+    };
+    match status {
+        ReadyStatus::Unavailable => HttpResponse::ServiceUnavailable().json(readiness),
+        _ => HttpResponse::Ok().json(readiness),
+    }
+}
+
+/// Tells how much we have left, so frontends and monitoring can tell when we're running low
+#[utoipa::path(
+    get,
+    path = "/v1/balance",
+    tag = "faucet",
+    responses(
+        (status = 200, body = Balance),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn balance<B: ChainBackend>(
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Balance>, Error> {
+    let onchain = blocking(&data, |data| data.backend.get_balance()).await?;
+
+    Ok(web::Json(Balance {
+        onchain: onchain.to_sat(),
+        #[cfg(feature = "lightning")]
+        lightning: lightning_balance(&data).await?,
+    }))
+}
+
+/// What we can send and receive through our active channels
+#[cfg(feature = "lightning")]
+pub async fn lightning_balance<B: ChainBackend>(
+    data: &AppState<B>,
+) -> Result<LightningBalance, Error> {
+    let channels = data.lightning.list_channels().await?;
+    let active = channels.iter().filter(|channel| channel.active);
+
+    Ok(LightningBalance {
+        spendable: active
+            .clone()
+            .map(|channel| channel.local_balance.to_sat())
+            .sum(),
+        receivable: active
+            .map(|channel| {
+                channel
+                    .capacity
+                    .to_sat()
+                    .saturating_sub(channel.local_balance.to_sat())
+            })
+            .sum(),
+    })
+}
+
+/// Tells how a queued /send/ request is doing, by the id /send/ returned
+#[utoipa::path(
+    get,
+    path = "/v1/queue/{id}",
+    tag = "faucet",
+    params(("id" = String, Path, description = "The request_id /send/ answered with")),
+    responses(
+        (status = 200, body = PayoutProgress),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn queued_payout_status<B: ChainBackend>(
+    id: web::Path<String>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<PayoutProgress>, Error> {
+    let status = data
+        .payout_queue
+        .as_ref()
+        .and_then(|queue| queue.status(&id))
+        .ok_or(Error::UnknownRequest)?;
+
+    Ok(web::Json(match status {
+        QueueStatus::Queued { ahead } => PayoutProgress::Queued { ahead },
+        QueueStatus::Paid { txid, vout } => PayoutProgress::Paid {
+            txid,
+            vout: Some(vout),
+        },
+        QueueStatus::Failed(reason) => PayoutProgress::Failed { reason },
+    }))
+}
+
+/// Gives out a proof of work challenge, to be solved before asking for coins
+#[utoipa::path(
+    get,
+    path = "/v1/challenge",
+    tag = "faucet",
+    responses(
+        (status = 200, body = Challenge),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn challenge<B: ChainBackend>(
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Challenge>, Error> {
+    let challenges = data.challenges.as_ref().ok_or(Error::ProofOfWorkDisabled)?;
+
+    Ok(web::Json(challenges.issue()))
+}
+
+/// Tells how one of our payouts is doing, so users can follow it without a block explorer
+#[utoipa::path(
+    get,
+    path = "/v1/tx/{txid}",
+    tag = "faucet",
+    params(("txid" = String, Path, description = "The payout's txid")),
+    responses(
+        (status = 200, body = TxStatus),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn tx_status<B: ChainBackend>(
+    txid: web::Path<Txid>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<TxStatus>, Error> {
+    let txid = txid.into_inner();
+    if !data.db.is_payout(&txid)? {
+        return Err(Error::UnknownTransaction);
+    }
+
+    // zmq tells us about blocks as they come, so we don't need to ask for confirmed payouts
+    #[cfg(feature = "zmq")]
+    if let Some((PayoutStatus::Confirmed { block_hash, height }, confirmations)) =
+        data.tracker.status(&txid)
+    {
+        return Ok(web::Json(TxStatus::Confirmed {
+            block_hash,
+            height,
+            confirmations,
+        }));
+    }
+
+    let status = blocking(&data, move |data| data.backend.transaction_status(&txid)).await?;
+    Ok(web::Json(match status {
+        TxState::Unconfirmed => TxStatus::InMempool,
+        TxState::Confirmed {
+            block_hash,
+            height,
+            confirmations,
+        } => TxStatus::Confirmed {
+            block_hash,
+            height,
+            confirmations,
+        },
+        // we know what replaced the payouts we bumped, even when the backend doesn't
+        TxState::Replaced { by } => TxStatus::Replaced {
+            replaced_by: by.or(data.db.replaced_by(&txid)?),
+        },
+        TxState::Evicted => TxStatus::Evicted,
+    }))
+}
+
+pub async fn index<B: ChainBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> HttpResponse {
+    let Some(body) = data.assets.page("index.html") else {
+        return HttpResponse::NotFound().finish();
+    };
+
+    #[cfg(feature = "captcha")]
+    let body = match &data.captcha {
+        Some(captcha) => body.replace("<!-- captcha -->", &captcha.widget()),
+        None => body,
+    };
+
+    #[cfg(feature = "github")]
+    let body = match &data.github {
+        Some(_) => body.replace(
+            "<!-- github -->",
+            "<p><a href=\"/auth/github\">Log in with GitHub</a> to get more</p>",
+        ),
+        None => body,
+    };
+
+    assets::respond(&req, "index.html", body)
+}
+
+/// Returns the roots of our node's utreexo accumulator, useful for debugging
+#[cfg(feature = "utreexod")]
+#[utoipa::path(
+    get,
+    path = "/v1/utreexo/roots",
+    tag = "utreexo",
+    responses(
+        (status = 200, body = Object),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+async fn utreexo_roots<B: ChainBackend>(
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<serde_json::Value>, Error> {
+    let utreexo = data.utreexo.as_ref().ok_or(Error::NotUtreexo)?;
+    Ok(web::Json(utreexo.roots()?))
+}
+
+/// Registers the first version of our API, served under /v1. A change that breaks clients ships
+/// as a new version, with its own function registering the routes that changed and configuring
+/// this one for the rest
+fn v1<B: ChainBackend>(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/send/")
+            .wrap_fn(ratelimit::limit_requests::<B, _>)
+            .route(web::post().to(send_to_address::<B>)),
+    );
+    cfg.service(
+        web::resource("/send/batch")
+            .wrap_fn(ratelimit::limit_requests::<B, _>)
+            .route(web::post().to(send_batch_request::<B>)),
+    );
+
+    // rate limited, so asking for challenges can't keep the faucet busy
+    cfg.service(
+        web::resource("/challenge")
+            .wrap_fn(ratelimit::limit_requests::<B, _>)
+            .route(web::get().to(challenge::<B>)),
+    );
+    cfg.route("/info", web::get().to(info::<B>));
+    cfg.route("/balance", web::get().to(balance::<B>));
+    cfg.route("/fee", web::get().to(fee::<B>));
+    cfg.route("/donate", web::get().to(donate::<B>));
+    cfg.route("/health", web::get().to(health));
+    cfg.route("/ready", web::get().to(ready::<B>));
+    cfg.route("/queue/{id}", web::get().to(queued_payout_status::<B>));
+    cfg.route("/tx/{txid}", web::get().to(tx_status::<B>));
+    cfg.route("/history", web::get().to(history::<B>));
+    cfg.route("/stats", web::get().to(stats::stats::<B>));
+    cfg.route("/events", web::get().to(events::events::<B>));
+    cfg.route("/ws", web::get().to(ws::connect::<B>));
+    cfg.route("/qr", web::get().to(qr::qr));
+
+    cfg.service(
+        web::scope("/admin")
+            .wrap_fn(admin::admin_only::<B, _>)
+            .configure(admin_routes::<B>),
+    );
+
+    #[cfg(feature = "lightning")]
+    cfg.route(
+        "/send/{payment_hash}",
+        web::get().to(hold_payout_status::<B>),
+    );
+
+    #[cfg(feature = "lightning")]
+    cfg.service(
+        web::resource("/channel/")
+            .wrap_fn(ratelimit::limit_requests::<B, _>)
+            .route(web::post().to(open_channel::<B>)),
+    )
+    .service(
+        web::resource("/channel/dual")
+            .wrap_fn(ratelimit::limit_requests::<B, _>)
+            .route(web::post().to(open_dual_funded_channel::<B>)),
+    )
+    .route("/channels", web::get().to(list_channels::<B>))
+    .service(
+        web::resource("/channel/inbound")
+            .wrap_fn(ratelimit::limit_requests::<B, _>)
+            .route(web::post().to(open_inbound_channel::<B>)),
+    )
+    .service(
+        web::resource("/payinvoice")
+            .wrap_fn(ratelimit::limit_requests::<B, _>)
+            .route(web::post().to(pay_invoice::<B>)),
+    )
+    .route("/offer", web::get().to(offer::<B>))
+    .service(
+        web::resource("/keysend")
+            .wrap_fn(ratelimit::limit_requests::<B, _>)
+            .route(web::post().to(keysend::<B>)),
+    );
+
+    #[cfg(feature = "utreexod")]
+    cfg.route("/utreexo/roots", web::get().to(utreexo_roots::<B>));
+}
+
+/// Registers the routes under /admin, which all take our admin token
+fn admin_routes<B: ChainBackend>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/status", web::get().to(admin::get_status::<B>));
+    cfg.route("/pause", web::post().to(admin::pause::<B>));
+    cfg.route("/drain", web::post().to(admin::drain::<B>));
+    cfg.route("/resume", web::post().to(admin::resume::<B>));
+    cfg.route("/limits", web::post().to(admin::set_limits::<B>));
+    cfg.route("/sweep", web::post().to(admin::sweep::<B>));
+    cfg.route("/psbt", web::post().to(admin::export_psbt::<B>));
+    cfg.route("/jobs", web::get().to(scheduler::list_jobs::<B>));
+    cfg.route("/export", web::get().to(export::export::<B>));
+    cfg.route("/bump/{txid}", web::post().to(bump::bump_payout::<B>));
+    cfg.route(
+        "/cpfp/{txid}",
+        web::post().to(cpfp::accelerate_transaction::<B>),
+    );
+
+    cfg.service(
+        web::resource("/access")
+            .route(web::get().to(access::list_rules::<B>))
+            .route(web::post().to(access::add_rule::<B>)),
+    )
+    .route("/access/{id}", web::delete().to(access::remove_rule::<B>))
+    .route("/abuse", web::get().to(abuse::list_decisions::<B>));
+
+    #[cfg(feature = "lightning")]
+    cfg.route("/channel/close", web::post().to(close_channel::<B>));
+}
+
+/// A middleware for the unversioned aliases of /v1, for `wrap_fn`. They work as before, but
+/// tell clients to move with the `Deprecation` header and a `Link` to the /v1 route
+fn deprecated<S>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let successor = format!("</v1{}>; rel=\"successor-version\"", req.path());
+    let response = srv.call(req);
+
+    async move {
+        let mut response = response.await?;
+        let headers = response.headers_mut();
+        headers.insert(
+            header::HeaderName::from_static("deprecation"),
+            HeaderValue::from_static("true"),
+        );
+        if let Ok(link) = HeaderValue::from_str(&successor) {
+            headers.insert(header::LINK, link);
+        }
+        Ok(response)
+    }
+}
+
+/// Registers all our routes
+fn routes<B: ChainBackend>(cfg: &mut web::ServiceConfig) {
+    cfg.service(web::scope("/v1").configure(v1::<B>));
+
+    // These aren't versioned: the API description and the pages are for browsers, and the LNURL
+    // and payjoin routes follow their own specs
+    cfg.route("/openapi.json", web::get().to(openapi::openapi_json));
+    cfg.route("/docs", web::get().to(openapi::docs::<B>));
+    cfg.route("/static/{path:.*}", web::get().to(assets::serve::<B>));
+
+    #[cfg(feature = "github")]
+    cfg.route("/auth/github", web::get().to(github::login::<B>))
+        .route(
+            "/auth/github/callback",
+            web::get().to(github::callback::<B>),
+        );
+
+    #[cfg(feature = "lightning")]
+    cfg.route(
+        "/.well-known/lnurlp/faucet",
+        web::get().to(lnurl::pay_request),
+    )
+    .route("/lnurlp/callback", web::get().to(lnurl::pay_callback::<B>))
+    .route("/lnurlw", web::get().to(lnurl::withdraw_link::<B>))
+    .route(
+        "/lnurlw/request",
+        web::get().to(lnurl::withdraw_request::<B>),
+    )
+    .route(
+        "/lnurlw/callback",
+        web::get().to(lnurl::withdraw_callback::<B>),
+    );
+
+    cfg.route("/payjoin", web::post().to(payjoin::receive::<B>));
+
+    cfg.route("/", web::get().to(index::<B>));
+
+    // The routes from before /v1, for scripts that still use them. This scope matches every path,
+    // so it has to come last
+    cfg.service(web::scope("").wrap_fn(deprecated).configure(v1::<B>));
+}
+
 /// This function creates the actix-web server and returns a future that can be awaited.
-pub async fn create_api(
-    client: Client,
-    max_sendable_amount: Amount,
-    min_sendable_amount: Amount,
-    change_address: Address,
+/// Serves the API until we're asked to stop with SIGINT or SIGTERM. We then stop taking
+/// requests, give the ones we're answering, and what's left in the payout queue,
+/// `shutdown_timeout` to finish, and let go of our Lightning node
+pub async fn create_api<B: ChainBackend>(
+    app_state: AppState<B>,
+    shutdown_timeout: std::time::Duration,
 ) -> std::io::Result<()> {
-    let app_state = web::Data::new(AppState {
-        rpc: client,
-        min_sendable_amount,
-        max_sendable_amount,
-        change_address,
-    });
+    let app_state = web::Data::new(app_state);
+
+    #[cfg(feature = "lightning")]
+    if let Some(after) = app_state.reclaim_after {
+        reclaim::spawn_reclaimer(app_state.clone(), after);
+    }
+
+    #[cfg(feature = "lightning")]
+    if app_state.hold_payouts.is_some() {
+        hold::spawn_settlement_worker(app_state.clone());
+    }
+
+    if app_state.payout_queue.is_some() {
+        queue::spawn_worker(app_state.clone());
+    }
+
+    #[cfg(feature = "tor")]
+    if app_state.tor.is_some() {
+        tor::spawn_refresher(app_state.clone());
+    }
+
+    #[cfg(feature = "webhooks")]
+    if app_state.webhooks.is_some() {
+        webhook::spawn_tracker(app_state.clone());
+    }
+
+    #[cfg(feature = "tls")]
+    if app_state.tls.is_some() {
+        tls::spawn_reloader(app_state.clone());
+    }
+
+    #[cfg(feature = "alerts")]
+    if app_state.alerts.is_some() {
+        alerts::spawn_monitor(app_state.clone());
+    }
+
+    stats::spawn_reporter(&app_state);
+    bump::spawn_bumper(&app_state);
+
+    cpfp::spawn_accelerator(&app_state);
 
-    HttpServer::new(move || {
-        let cors = Cors::permissive();
+    if let Some(consolidation) = app_state.consolidation {
+        consolidate::spawn_consolidator(&app_state, consolidation);
+    }
+
+    #[cfg(feature = "systemd")]
+    systemd::spawn_watchdog(app_state.clone());
+
+    let state = app_state.clone();
+    let server = HttpServer::new(move || {
         App::new()
-            .wrap(cors)
+            .wrap(app_state.cors.middleware())
+            .wrap_fn(accesslog::log_requests)
+            // a span for each request, so what a handler logs tells which request it was for
+            .wrap(TracingLogger::<RequestSpan>::new())
             .app_data(app_state.clone())
-            .route("/send/", web::post().to(send_to_address))
-            .route("/", web::get().to(index))
-    })
-    .bind("0.0.0.0:8080")?
-    .run()
-    .await
+            // on its own too, since client_ip doesn't know which backend we're using
+            .app_data(app_state.trusted_proxies.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(|e, _| Error::InvalidRequest(e.to_string()).into()),
+            )
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(|e, _| Error::InvalidRequest(e.to_string()).into()),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|e, _| Error::InvalidRequest(e.to_string()).into()),
+            )
+            .configure(routes::<B>)
+    });
+
+    #[cfg(feature = "tls")]
+    let server = match &state.tls {
+        Some(certificates) => server.bind_rustls_021(ADDRESS, certificates.server_config())?,
+        None => server.bind(ADDRESS)?,
+    };
+    #[cfg(not(feature = "tls"))]
+    let server = server.bind(ADDRESS)?;
+
+    let server = server
+        // actix stops taking requests on SIGINT and SIGTERM, and waits this long for the ones it
+        // has before dropping them
+        .shutdown_timeout(shutdown_timeout.as_secs())
+        .run();
+
+    // we're bound to our address by now, so requests get through
+    #[cfg(feature = "systemd")]
+    systemd::notify_ready();
+    let served = server.await;
+
+    #[cfg(feature = "systemd")]
+    systemd::notify_stopping();
+    info!("stopped taking requests, waiting for our jobs and paying what's left in the queue");
+    state.scheduler.stop(shutdown_timeout).await;
+    queue::drain(&state, shutdown_timeout);
+
+    #[cfg(feature = "lightning")]
+    state.lightning.shutdown().await;
+
+    info!("shut down");
+    served
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use actix_web::test;
+    use futures::future::join_all;
+
+    use super::*;
+    use crate::testing::app_state;
+    use crate::testing::MockNode;
+
+    /// How long our node takes to take a transaction
+    const BROADCAST_DELAY: Duration = Duration::from_millis(200);
+    const REQUESTS: u32 = 16;
+    const ADDRESS: &str = "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7";
+
+    /// Backends answer synchronously, so a slow node used to hold up every request the worker
+    /// was serving, and these were paid one by one
+    #[actix_web::test]
+    async fn concurrent_sends_dont_wait_for_each_other() {
+        let node = MockNode {
+            broadcast_delay: BROADCAST_DELAY,
+            ..Default::default()
+        };
+        let data = web::Data::new(app_state(node));
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .app_data(data.trusted_proxies.clone())
+                .configure(routes::<MockNode>),
+        )
+        .await;
+
+        let responses = join_all((0..REQUESTS).map(|i| {
+            let req = test::TestRequest::post()
+                .uri("/v1/send/")
+                .peer_addr(([10, 0, 0, i as u8], 1234).into())
+                .set_json(serde_json::json!({ "address": ADDRESS, "amount": 10_000 }))
+                .to_request();
+            test::call_service(&app, req)
+        }))
+        .await;
+
+        for response in responses {
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        assert_eq!(
+            data.backend.broadcasts.load(Ordering::SeqCst),
+            REQUESTS as u64
+        );
+        let peak = data.backend.peak_in_flight.load(Ordering::SeqCst);
+        assert!(peak > 1, "we only ever broadcast {peak} payout at a time");
+    }
+
+    /// Payments our node fails to make don't count against the client's allowance
+    #[cfg(feature = "lightning")]
+    #[actix_web::test]
+    async fn failed_payments_give_the_sats_back() {
+        let mut state = app_state(MockNode::default());
+        state.rate_limiter = Some(RateLimiter::new(None, Some(10_000)));
+        let data = web::Data::new(state);
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .app_data(data.trusted_proxies.clone())
+                .configure(routes::<MockNode>),
+        )
+        .await;
+
+        // the whole allowance each time, our node fails to pay all of them
+        for _ in 0..3 {
+            let req = test::TestRequest::post()
+                .uri("/v1/keysend")
+                .peer_addr(([10, 0, 0, 1], 1234).into())
+                .set_json(serde_json::json!({
+                    "node_id": "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+                    "amount": 10_000,
+                }))
+                .to_request();
+            let response = test::call_service(&app, req).await;
+            assert_eq!(response.status(), Error::NotSupported.status_code());
+        }
+    }
 }
@@ -0,0 +1,246 @@
+//SPDX-License-Identifier: MIT
+
+//! API keys, for integrations we trust more than anonymous clients, like CI pipelines funding
+//! test wallets. Clients send their key as `X-Api-Key: <key>`.
+//!
+//! We only know the SHA256 of each key, from API_KEYS, so whoever reads our config can't use
+//! them. Each key has a scope: `public` keys are rate limited by their own limits on top of the
+//! per-IP ones, `partner` keys by their own limits instead of the per-IP ones, and `admin` keys
+//! may also use the /admin routes.
+
+use std::str::FromStr;
+
+use actix_web::HttpRequest;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+
+use crate::api::Error;
+use crate::ratelimit::Quota;
+use crate::ratelimit::RateLimiter;
+
+/// The header clients send their key in
+const HEADER: &str = "X-Api-Key";
+
+/// What a key lets its holder do, each scope may do what the ones before it may
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Scope {
+    Public,
+    Partner,
+    Admin,
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        match scope {
+            "public" => Ok(Scope::Public),
+            "partner" => Ok(Scope::Partner),
+            "admin" => Ok(Scope::Admin),
+            scope => Err(format!("there's no {scope} scope")),
+        }
+    }
+}
+
+pub struct ApiKey {
+    /// Who holds the key, for our logs
+    pub name: String,
+    hash: sha256::Hash,
+    pub scope: Scope,
+    /// The key's own limits, if it has any
+    limiter: Option<RateLimiter>,
+}
+
+impl ApiKey {
+    /// Counts one request against the key's limits, returning what's left of its quota
+    pub fn take_request(&self) -> Result<Option<Quota>, Error> {
+        match &self.limiter {
+            Some(limiter) => limiter.take_request(&format!("key:{}", self.name)),
+            None => Ok(None),
+        }
+    }
+
+    /// How many requests the key may make per hour, if it's limited
+    pub fn requests_per_hour(&self) -> Option<u64> {
+        self.limiter.as_ref().and_then(|limiter| limiter.limits().0)
+    }
+
+    /// Takes `sats` from what the key may get this hour
+    pub fn take_sats(&self, sats: u64) -> Result<(), Error> {
+        match &self.limiter {
+            Some(limiter) => limiter.take_sats(&format!("key:{}", self.name), sats),
+            None => Ok(()),
+        }
+    }
+
+    /// Gives `sats` we took back to the key, for payments that didn't go through
+    #[cfg_attr(not(feature = "lightning"), allow(dead_code))]
+    pub fn give_sats(&self, sats: u64) -> Result<(), Error> {
+        match &self.limiter {
+            Some(limiter) => limiter.give_sats(&format!("key:{}", self.name), sats),
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+impl ApiKeys {
+    /// Parses API_KEYS, like `ci:partner:<hex sha256 of the key>:60:1000000`, with a comma
+    /// between keys. The last two, how many requests and sats the key may take per hour, are
+    /// optional, and empty means no limit. `limiter` makes the limiters for them
+    pub fn parse(
+        keys: &str,
+        limiter: impl Fn(Option<u64>, Option<u64>) -> RateLimiter,
+    ) -> Result<Self, String> {
+        let mut parsed = vec![];
+        for key in keys.split(',').filter(|key| !key.trim().is_empty()) {
+            let mut fields = key.trim().split(':');
+            let (Some(name), Some(scope), Some(hash)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                return Err(format!("{key} isn't name:scope:hash"));
+            };
+
+            let per_hour = |limit: Option<&str>| match limit {
+                None | Some("") => Ok(None),
+                Some(limit) => limit
+                    .parse::<u64>()
+                    .map(Some)
+                    .map_err(|e| format!("{name}: {e}")),
+            };
+            let requests = per_hour(fields.next())?;
+            let sats = per_hour(fields.next())?;
+
+            if parsed.iter().any(|key: &ApiKey| key.name == name) {
+                return Err(format!("there's more than one {name} key"));
+            }
+            parsed.push(ApiKey {
+                name: name.to_string(),
+                hash: hash.parse().map_err(|e| format!("{name}: {e}"))?,
+                scope: scope.parse()?,
+                limiter: (requests.is_some() || sats.is_some()).then(|| limiter(requests, sats)),
+            });
+        }
+
+        Ok(Self { keys: parsed })
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    /// The key `req` carries, if any. A key we don't know is an error, rather than a client
+    /// without a key, so a typo doesn't quietly get the anonymous limits
+    pub fn authenticate(&self, req: &HttpRequest) -> Result<Option<&ApiKey>, Error> {
+        let Some(given) = req.headers().get(HEADER) else {
+            return Ok(None);
+        };
+
+        // we compare hashes, so how long that takes tells nothing about the keys
+        let hash = sha256::Hash::hash(given.as_bytes());
+        self.keys
+            .iter()
+            .find(|key| key.hash == hash)
+            .map(Some)
+            .ok_or(Error::InvalidApiKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    /// The sha256 of `key`, as API_KEYS has it
+    fn hash(key: &str) -> String {
+        sha256::Hash::hash(key.as_bytes()).to_string()
+    }
+
+    fn parse(keys: &str) -> Result<ApiKeys, String> {
+        ApiKeys::parse(keys, RateLimiter::new)
+    }
+
+    fn request(key: Option<&str>) -> HttpRequest {
+        match key {
+            Some(key) => TestRequest::default().insert_header((HEADER, key)),
+            None => TestRequest::default(),
+        }
+        .to_http_request()
+    }
+
+    #[test]
+    fn parses_keys() {
+        let keys = parse(&format!(
+            "ci:partner:{}:60:1000000, ops:admin:{}, bot:public:{}::5000",
+            hash("ci secret"),
+            hash("ops secret"),
+            hash("bot secret"),
+        ))
+        .unwrap();
+        assert_eq!(keys.len(), 3);
+
+        let ci = &keys.keys[0];
+        assert_eq!((ci.name.as_str(), ci.scope), ("ci", Scope::Partner));
+        assert_eq!(ci.requests_per_hour(), Some(60));
+        assert!(keys.keys[1].limiter.is_none());
+        assert_eq!(keys.keys[2].requests_per_hour(), None);
+        assert_eq!(
+            keys.keys[2].limiter.as_ref().unwrap().limits(),
+            (None, Some(5_000))
+        );
+
+        assert_eq!(parse("").unwrap().len(), 0);
+    }
+
+    #[test]
+    fn refuses_keys_it_cant_parse() {
+        let hash = hash("secret");
+        for invalid in [
+            "ci:partner".to_string(),
+            format!("ci:owner:{hash}"),
+            "ci:partner:not a hash".to_string(),
+            format!("ci:partner:{hash}:lots"),
+            format!("ci:partner:{hash},ci:public:{hash}"),
+        ] {
+            assert!(parse(&invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn scopes_include_the_ones_before() {
+        assert!(Scope::Admin > Scope::Partner && Scope::Partner > Scope::Public);
+    }
+
+    #[test]
+    fn authenticates_keys_we_know() {
+        let keys = parse(&format!("ci:partner:{}", hash("ci secret"))).unwrap();
+
+        assert!(keys.authenticate(&request(None)).unwrap().is_none());
+        let key = keys.authenticate(&request(Some("ci secret"))).unwrap();
+        assert_eq!(key.unwrap().name, "ci");
+        // a typo isn't taken for no key
+        assert!(matches!(
+            keys.authenticate(&request(Some("ci secrte"))),
+            Err(Error::InvalidApiKey)
+        ));
+    }
+
+    #[test]
+    fn limits_keys_by_their_own_limits() {
+        let keys = parse(&format!("ci:partner:{}:1:10000", hash("ci secret"))).unwrap();
+        let key = &keys.keys[0];
+
+        assert_eq!(key.take_request().unwrap().unwrap().remaining, 0);
+        assert!(matches!(key.take_request(), Err(Error::RateLimited { .. })));
+
+        key.take_sats(10_000).unwrap();
+        assert!(key.take_sats(1).is_err());
+        key.give_sats(5_000).unwrap();
+        key.take_sats(5_000).unwrap();
+    }
+}
@@ -0,0 +1,127 @@
+//SPDX-License-Identifier: MIT
+
+//! The pages we serve, and the styles, scripts and images they load. They're built into the
+//! binary, so the faucet runs from any directory. With `STATIC_DIR` set, files there take the
+//! place of the built in ones, so operators can restyle the faucet without rebuilding it.
+//!
+//! Everything has an ETag, so browsers only download what changed. They check pages every time,
+//! as we fill them in while serving them, and may keep the rest for an hour.
+
+use std::borrow::Cow;
+use std::io::ErrorKind;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+
+use actix_web::http::header;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use mime_guess::mime;
+use rust_embed::RustEmbed;
+use tracing::warn;
+
+use crate::api::AppState;
+use crate::backend::ChainBackend;
+
+/// How long browsers may keep what isn't a page, in seconds
+const MAX_AGE: u32 = 3_600;
+
+#[derive(RustEmbed)]
+#[folder = "static/"]
+struct Embedded;
+
+#[derive(Debug, Default)]
+pub struct Assets {
+    /// Where operators keep the files to serve instead of ours
+    dir: Option<PathBuf>,
+}
+
+impl Assets {
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { dir }
+    }
+
+    /// The file at `path`, relative to the static directory, if there's one
+    pub fn get(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        // nothing outside the directory
+        if !Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return None;
+        }
+
+        if let Some(dir) = &self.dir {
+            match std::fs::read(dir.join(path)) {
+                Ok(file) => return Some(Cow::Owned(file)),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => warn!("couldn't read {path} from STATIC_DIR, serving ours: {e}"),
+            }
+        }
+
+        Embedded::get(path).map(|file| file.data)
+    }
+
+    /// The page at `path`, as text
+    pub fn page(&self, path: &str) -> Option<String> {
+        self.get(path)
+            .map(|page| String::from_utf8_lossy(&page).into_owned())
+    }
+}
+
+/// Answers `req` with `body`, typed after the file at `path`, or tells the client the copy it
+/// has is still good
+pub fn respond(req: &HttpRequest, path: &str, body: impl Into<Vec<u8>>) -> HttpResponse {
+    let body = body.into();
+    let etag = format!("\"{}\"", sha256::Hash::hash(&body));
+    let fresh = req
+        .headers()
+        .get(header::IF_NONE_MATCH)
+        .and_then(|tags| tags.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .map(|tag| tag.trim().trim_start_matches("W/"))
+                .any(|tag| tag == etag || tag == "*")
+        });
+
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let cache_control = if mime.subtype() == mime::HTML {
+        "no-cache".to_string()
+    } else {
+        format!("public, max-age={MAX_AGE}")
+    };
+    let mut response = if fresh {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response
+        .insert_header((header::ETAG, etag))
+        .insert_header((header::CACHE_CONTROL, cache_control));
+    if fresh {
+        return response.finish();
+    }
+
+    // text is all utf-8
+    let content_type = if mime.type_() == mime::TEXT || mime.subtype() == mime::JAVASCRIPT {
+        format!("{}; charset=utf-8", mime.essence_str())
+    } else {
+        mime.essence_str().to_string()
+    };
+    response.content_type(content_type).body(body)
+}
+
+/// Serves the styles, scripts and images under /static
+pub async fn serve<B: ChainBackend>(
+    req: HttpRequest,
+    path: web::Path<String>,
+    data: web::Data<AppState<B>>,
+) -> HttpResponse {
+    match data.assets.get(&path) {
+        Some(file) => respond(&req, &path, file),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
@@ -0,0 +1,475 @@
+//SPDX-License-Identifier: MIT
+
+//! A [ChainBackend] where the faucet manages its own descriptor wallet with BDK. Keys never
+//! leave the faucet, signing happens locally and we only use bitcoind or Esplora to learn
+//! about the chain and broadcast, so there's no need for Core's wallet RPCs.
+//!
+//! BDK uses a newer version of rust-bitcoin than we do, so every type crossing this module's
+//! boundary is converted using its consensus encoding.
+
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use bdk_bitcoind_rpc::bitcoincore_rpc;
+use bdk_bitcoind_rpc::bitcoincore_rpc::RpcApi;
+use bdk_bitcoind_rpc::Emitter;
+use bdk_esplora::esplora_client;
+use bdk_esplora::EsploraExt;
+use bdk_wallet::bitcoin as bdk_bitcoin;
+use bdk_wallet::chain::ChainPosition;
+use bdk_wallet::KeychainKind;
+use bdk_wallet::SignOptions;
+use bdk_wallet::Wallet;
+use bitcoin::consensus::deserialize;
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::BlockHash;
+use bitcoin::FeeRate;
+use bitcoin::Network;
+use bitcoin::Psbt;
+use bitcoin::ScriptBuf;
+use bitcoin::Transaction;
+use bitcoin::Txid;
+
+use super::anti_fee_sniping;
+use super::is_conflict;
+use super::ChainBackend;
+use super::MempoolEntry;
+use super::TxState;
+use super::Utxo;
+use crate::api::Error;
+
+/// How many unused addresses we look ahead when scanning with Esplora
+const STOP_GAP: usize = 20;
+
+/// How many parallel requests we make to Esplora while scanning
+const PARALLEL_REQUESTS: usize = 4;
+
+/// Where we get chain data from and broadcast to
+pub enum ChainSource {
+    Bitcoind {
+        client: bitcoincore_rpc::Client,
+        /// We don't care about blocks before our wallet was created
+        start_height: u32,
+    },
+    Esplora(esplora_client::BlockingClient),
+}
+
+pub struct BdkWallet {
+    wallet: Mutex<Wallet>,
+    chain: ChainSource,
+}
+
+fn bdk_error(e: impl ToString) -> Error {
+    Error::BdkError(e.to_string())
+}
+
+fn tx_to_bdk(tx: &Transaction) -> bdk_bitcoin::Transaction {
+    bdk_bitcoin::consensus::deserialize(&serialize(tx)).expect("both encodings are the same")
+}
+
+fn psbt_to_bdk(psbt: &Psbt) -> bdk_bitcoin::Psbt {
+    bdk_bitcoin::Psbt::deserialize(&psbt.serialize()).expect("both encodings are the same")
+}
+
+fn psbt_from_bdk(psbt: &bdk_bitcoin::Psbt) -> Psbt {
+    Psbt::deserialize(&psbt.serialize()).expect("both encodings are the same")
+}
+
+fn tx_from_bdk(tx: &bdk_bitcoin::Transaction) -> Transaction {
+    deserialize(&bdk_bitcoin::consensus::serialize(tx)).expect("both encodings are the same")
+}
+
+fn txid_from_bdk(txid: bdk_bitcoin::Txid) -> Txid {
+    use bdk_bitcoin::hashes::Hash;
+    Txid::from_byte_array(txid.to_byte_array())
+}
+
+fn txid_to_bdk(txid: &Txid) -> bdk_bitcoin::Txid {
+    use bdk_bitcoin::hashes::Hash;
+    bdk_bitcoin::Txid::from_byte_array(txid.to_byte_array())
+}
+
+fn block_hash_from_bdk(hash: bdk_bitcoin::BlockHash) -> BlockHash {
+    use bdk_bitcoin::hashes::Hash;
+    BlockHash::from_byte_array(hash.to_byte_array())
+}
+
+fn outpoint_to_bdk(utxo: &Utxo) -> bdk_bitcoin::OutPoint {
+    use bdk_bitcoin::hashes::Hash;
+    bdk_bitcoin::OutPoint::new(
+        bdk_bitcoin::Txid::from_byte_array(utxo.txid.to_byte_array()),
+        utxo.vout,
+    )
+}
+
+fn network_to_bdk(network: Network) -> bdk_bitcoin::Network {
+    network
+        .to_string()
+        .parse()
+        .expect("rust-bitcoin networks have stable names")
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+impl BdkWallet {
+    pub fn new(
+        descriptor: String,
+        change_descriptor: String,
+        network: Network,
+        chain: ChainSource,
+    ) -> anyhow::Result<Self> {
+        let wallet = Wallet::create(descriptor, change_descriptor)
+            .network(network_to_bdk(network))
+            .create_wallet_no_persist()?;
+
+        let wallet = Self {
+            wallet: Mutex::new(wallet),
+            chain,
+        };
+        wallet.sync()?;
+
+        Ok(wallet)
+    }
+
+    /// Brings our wallet up to date with the chain and mempool
+    fn sync(&self) -> Result<(), Error> {
+        let mut wallet = self.wallet.lock().unwrap();
+
+        match &self.chain {
+            ChainSource::Bitcoind {
+                client,
+                start_height,
+            } => {
+                let mut emitter = Emitter::new(client, wallet.latest_checkpoint(), *start_height);
+                while let Some(block) = emitter.next_block().map_err(bdk_error)? {
+                    let height = block.block_height();
+                    wallet
+                        .apply_block_connected_to(&block.block, height, block.connected_to())
+                        .map_err(bdk_error)?;
+                }
+
+                let mempool = emitter.mempool().map_err(bdk_error)?;
+                wallet.apply_unconfirmed_txs(mempool);
+            }
+            ChainSource::Esplora(client) => {
+                let request = wallet.start_full_scan().build();
+                let update = client
+                    .full_scan(request, STOP_GAP, PARALLEL_REQUESTS)
+                    .map_err(bdk_error)?;
+
+                wallet.apply_update(update).map_err(bdk_error)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds a PSBT for `tx` with all the data we need to sign it
+    fn psbt_for(&self, wallet: &Wallet, tx: &Transaction) -> Result<bdk_bitcoin::Psbt, Error> {
+        let mut psbt =
+            bdk_bitcoin::Psbt::from_unsigned_tx(tx_to_bdk(tx)).map_err(|_| Error::SigningFailed)?;
+
+        for (input, psbt_input) in psbt.unsigned_tx.input.iter().zip(psbt.inputs.iter_mut()) {
+            let utxo = wallet
+                .get_utxo(input.previous_output)
+                .ok_or(Error::SigningFailed)?;
+
+            *psbt_input = wallet
+                .get_psbt_input(utxo, None, false)
+                .map_err(|_| Error::SigningFailed)?;
+        }
+
+        Ok(psbt)
+    }
+}
+
+impl ChainBackend for BdkWallet {
+    fn list_unspent(&self) -> Result<Vec<Utxo>, Error> {
+        self.sync()?;
+
+        let wallet = self.wallet.lock().unwrap();
+        let height = wallet.latest_checkpoint().height();
+        Ok(wallet
+            .list_unspent()
+            .map(|output| Utxo {
+                txid: txid_from_bdk(output.outpoint.txid),
+                vout: output.outpoint.vout,
+                amount: Amount::from_sat(output.txout.value.to_sat()),
+                confirmations: match output.chain_position {
+                    ChainPosition::Confirmed { anchor, .. } => {
+                        height.saturating_sub(anchor.block_id.height) + 1
+                    }
+                    ChainPosition::Unconfirmed { .. } => 0,
+                },
+                coinbase: wallet
+                    .get_tx(output.outpoint.txid)
+                    .is_some_and(|tx| tx.tx_node.tx.is_coinbase()),
+            })
+            .collect())
+    }
+
+    fn create_transaction(
+        &self,
+        inputs: &[Utxo],
+        outputs: &[(Address, Amount)],
+    ) -> Result<Transaction, Error> {
+        let mut wallet = self.wallet.lock().unwrap();
+
+        let input_value: Amount = inputs.iter().map(|utxo| utxo.amount).sum();
+        let output_value: Amount = outputs.iter().map(|(_, amount)| *amount).sum();
+        let fee = input_value
+            .checked_sub(output_value)
+            .ok_or(Error::OutOfMoney)?;
+
+        let outpoints = inputs.iter().map(outpoint_to_bdk).collect::<Vec<_>>();
+        let lock_time = anti_fee_sniping(self.block_height()?).to_consensus_u32();
+
+        let mut builder = wallet.build_tx();
+        builder
+            .add_utxos(&outpoints)
+            .map_err(bdk_error)?
+            .manually_selected_only()
+            .nlocktime(bdk_bitcoin::absolute::LockTime::from_consensus(lock_time))
+            // the caller already accounted for change, whatever is left goes to fees
+            .fee_absolute(bdk_bitcoin::Amount::from_sat(fee.to_sat()));
+
+        for (address, amount) in outputs {
+            let script = bdk_bitcoin::ScriptBuf::from_bytes(address.script_pubkey().to_bytes());
+            builder.add_recipient(script, bdk_bitcoin::Amount::from_sat(amount.to_sat()));
+        }
+
+        let psbt = builder.finish().map_err(bdk_error)?;
+        Ok(tx_from_bdk(&psbt.unsigned_tx))
+    }
+
+    fn sign_transaction(&self, tx: &Transaction) -> Result<Transaction, Error> {
+        let wallet = self.wallet.lock().unwrap();
+        let mut psbt = self.psbt_for(&wallet, tx)?;
+
+        let finalized = wallet
+            .sign(&mut psbt, SignOptions::default())
+            .map_err(bdk_error)?;
+
+        if !finalized {
+            return Err(Error::SigningFailed);
+        }
+
+        let signed = psbt.extract_tx().map_err(|_| Error::SigningFailed)?;
+        Ok(tx_from_bdk(&signed))
+    }
+
+    fn create_psbt(&self, tx: &Transaction) -> Result<Psbt, Error> {
+        let wallet = self.wallet.lock().unwrap();
+        Ok(psbt_from_bdk(&self.psbt_for(&wallet, tx)?))
+    }
+
+    fn finalize_psbt(&self, psbt: Psbt) -> Result<Transaction, Error> {
+        let wallet = self.wallet.lock().unwrap();
+        let mut psbt = psbt_to_bdk(&psbt);
+
+        let finalized = wallet
+            .finalize_psbt(&mut psbt, SignOptions::default())
+            .map_err(bdk_error)?;
+
+        if !finalized {
+            return Err(Error::SigningFailed);
+        }
+
+        let signed = psbt.extract_tx().map_err(|_| Error::SigningFailed)?;
+        Ok(tx_from_bdk(&signed))
+    }
+
+    fn sign_psbt(&self, psbt: Psbt) -> Result<Psbt, Error> {
+        let wallet = self.wallet.lock().unwrap();
+        let mut psbt = psbt_to_bdk(&psbt);
+
+        for (input, psbt_input) in psbt.unsigned_tx.input.iter().zip(psbt.inputs.iter_mut()) {
+            let Some(utxo) = wallet.get_utxo(input.previous_output) else {
+                continue;
+            };
+            *psbt_input = wallet
+                .get_psbt_input(utxo, None, false)
+                .map_err(|_| Error::SigningFailed)?;
+        }
+
+        // it's only finalized if every input is ours
+        wallet
+            .sign(&mut psbt, SignOptions::default())
+            .map_err(bdk_error)?;
+
+        Ok(psbt_from_bdk(&psbt))
+    }
+
+    fn broadcast_transaction(&self, tx: &Transaction) -> Result<Txid, Error> {
+        match &self.chain {
+            ChainSource::Bitcoind { client, .. } => {
+                client.send_raw_transaction(&tx_to_bdk(tx)).map_err(|e| {
+                    match is_conflict(&e.to_string()) {
+                        true => Error::InputsSpent,
+                        false => Error::JsonRpcNotWorking,
+                    }
+                })?;
+            }
+            ChainSource::Esplora(client) => {
+                client.broadcast(&tx_to_bdk(tx)).map_err(|e| {
+                    match is_conflict(&e.to_string()) {
+                        true => Error::InputsSpent,
+                        false => bdk_error(e),
+                    }
+                })?;
+            }
+        }
+
+        // make sure we don't try to spend those coins again before the next sync
+        self.wallet
+            .lock()
+            .unwrap()
+            .apply_unconfirmed_txs([(tx_to_bdk(tx), now())]);
+
+        Ok(tx.txid())
+    }
+
+    fn get_balance(&self) -> Result<Amount, Error> {
+        self.sync()?;
+
+        let balance = self.wallet.lock().unwrap().balance();
+        Ok(Amount::from_sat(balance.trusted_spendable().to_sat()))
+    }
+
+    fn block_height(&self) -> Result<u32, Error> {
+        match &self.chain {
+            ChainSource::Bitcoind { client, .. } => client
+                .get_block_count()
+                .map(|height| height as u32)
+                .map_err(|_| Error::JsonRpcNotWorking),
+            ChainSource::Esplora(client) => client.get_height().map_err(bdk_error),
+        }
+    }
+
+    fn transaction_status(&self, txid: &Txid) -> Result<TxState, Error> {
+        self.sync()?;
+
+        let wallet = self.wallet.lock().unwrap();
+        if let Some(tx) = wallet.get_tx(txid_to_bdk(txid)) {
+            return Ok(match tx.chain_position {
+                ChainPosition::Confirmed { anchor, .. } => {
+                    let height = anchor.block_id.height;
+                    TxState::Confirmed {
+                        block_hash: block_hash_from_bdk(anchor.block_id.hash),
+                        height,
+                        confirmations: wallet.latest_checkpoint().height().saturating_sub(height)
+                            + 1,
+                    }
+                }
+                ChainPosition::Unconfirmed { .. } => TxState::Unconfirmed,
+            });
+        }
+
+        // bdk only leaves out the transactions it knows about if something conflicting won
+        let graph = wallet.tx_graph();
+        let Some(tx) = graph.get_tx(txid_to_bdk(txid)) else {
+            return Ok(TxState::Evicted);
+        };
+        let by = graph
+            .direct_conflicts(&tx)
+            .map(|(_, conflict)| conflict)
+            .find(|conflict| wallet.get_tx(*conflict).is_some())
+            .map(txid_from_bdk);
+
+        Ok(TxState::Replaced { by })
+    }
+
+    fn estimate_fee(&self, target: u16) -> Result<Option<FeeRate>, Error> {
+        match &self.chain {
+            ChainSource::Bitcoind { client, .. } => {
+                let estimate = client
+                    .estimate_smart_fee(target, None)
+                    .map_err(|_| Error::JsonRpcNotWorking)?;
+
+                Ok(estimate
+                    .fee_rate
+                    .map(|rate| FeeRate::from_sat_per_kwu(rate.to_sat() / 4)))
+            }
+            ChainSource::Esplora(client) => {
+                let estimates = client.get_fee_estimates().map_err(bdk_error)?;
+                let Some(rate) = esplora_client::convert_fee_rate(target as usize, estimates)
+                else {
+                    return Ok(None);
+                };
+
+                // esplora gives us sat/vB
+                Ok(Some(
+                    FeeRate::from_sat_per_kwu((rate * 250.0).ceil() as u64),
+                ))
+            }
+        }
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Error> {
+        self.sync()?;
+
+        let wallet = self.wallet.lock().unwrap();
+        let Some(tx) = wallet.get_tx(txid_to_bdk(txid)) else {
+            return Ok(None);
+        };
+        if !matches!(tx.chain_position, ChainPosition::Unconfirmed { .. }) {
+            return Ok(None);
+        }
+
+        // bdk only knows the fee if it knows every coin the transaction spends
+        let tx = tx.tx_node.tx.clone();
+        let Ok(fee) = wallet.calculate_fee(&tx) else {
+            return Ok(None);
+        };
+        Ok(Some(MempoolEntry {
+            fee: Amount::from_sat(fee.to_sat()),
+            vsize: tx.vsize() as u64,
+        }))
+    }
+
+    fn mempool_rejects(&self, tx: &Transaction) -> Result<Option<String>, Error> {
+        match &self.chain {
+            ChainSource::Bitcoind { client, .. } => {
+                let result = client
+                    .test_mempool_accept(&[tx_to_bdk(tx)])
+                    .map_err(|_| Error::JsonRpcNotWorking)?
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::JsonRpcNotWorking)?;
+
+                Ok(match result.allowed {
+                    true => None,
+                    false => Some(result.reject_reason.unwrap_or_default()),
+                })
+            }
+            // esplora can only broadcast
+            ChainSource::Esplora(_) => Ok(None),
+        }
+    }
+
+    // our keys are buried in the wallet's descriptors
+    fn silent_payment_key(&self, _inputs: &[Utxo]) -> Result<SecretKey, Error> {
+        Err(Error::SilentPaymentsUnsupported)
+    }
+
+    fn receive_address(&self) -> Result<Address, Error> {
+        let info = self
+            .wallet
+            .lock()
+            .unwrap()
+            .reveal_next_address(KeychainKind::External);
+        let script = ScriptBuf::from_bytes(info.address.script_pubkey().to_bytes());
+
+        Address::from_script(&script, Network::Signet).map_err(|e| Error::BdkError(e.to_string()))
+    }
+}
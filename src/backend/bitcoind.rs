@@ -0,0 +1,399 @@
+//SPDX-License-Identifier: MIT
+
+//! A [ChainBackend] that uses a bitcoin core node and its wallet for everything
+//!
+//! More than one node may be configured, in which case we use the first healthy one and
+//! fail over to the next whenever the current one stops answering. All nodes should have
+//! the same wallet loaded, otherwise we'll see different coins after a failover.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use bitcoin::consensus::deserialize;
+use bitcoin::secp256k1::Scalar;
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::Address;
+use bitcoin::AddressType;
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::Network;
+use bitcoin::Psbt;
+use bitcoin::Transaction;
+use bitcoin::Txid;
+use bitcoincore_rpc::bitcoincore_rpc_json::CreateRawTransactionInput;
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::jsonrpc::simple_http::SimpleHttpTransport;
+use bitcoincore_rpc::Auth;
+use bitcoincore_rpc::Client;
+use bitcoincore_rpc::RpcApi;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+use super::anti_fee_sniping;
+use super::is_conflict;
+use super::ChainBackend;
+use super::MempoolEntry;
+use super::TxState;
+use super::Utxo;
+use crate::api::Error;
+use crate::retry;
+
+/// The RPC error code core returns while it's still starting up
+const RPC_IN_WARMUP: i32 = -28;
+
+/// Whether `error` means the node is gone, rather than it refusing what we asked
+fn is_unavailable(error: &bitcoincore_rpc::Error) -> bool {
+    match error {
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Transport(_)) => true,
+        bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e)) => e.code == RPC_IN_WARMUP,
+        _ => false,
+    }
+}
+
+/// Creates a client for the node at `url` that gives up after `timeout`
+pub fn connect(url: &str, cookie_file: PathBuf, timeout: Duration) -> anyhow::Result<Client> {
+    let (Some(user), Some(pass)) = Auth::CookieFile(cookie_file).get_user_pass()? else {
+        anyhow::bail!("invalid cookie file");
+    };
+
+    let transport = SimpleHttpTransport::builder()
+        .url(url)?
+        .timeout(timeout)
+        .auth(user, Some(pass))
+        .build();
+
+    Ok(Client::from_jsonrpc(jsonrpc::Client::with_transport(
+        transport,
+    )))
+}
+
+pub struct BitcoinCore {
+    /// All nodes we know about, in order of preference
+    nodes: Arc<Vec<Client>>,
+    /// The node we are currently using
+    active: Arc<AtomicUsize>,
+}
+
+impl BitcoinCore {
+    pub fn new(nodes: Vec<Client>) -> Self {
+        Self {
+            nodes: Arc::new(nodes),
+            active: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Periodically checks our nodes and switches to the first one that's healthy. This means
+    /// we'll go back to the primary node once it's up again.
+    pub fn spawn_health_check(&self, interval: Duration) {
+        let nodes = self.nodes.clone();
+        let active = self.active.clone();
+
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+
+            let healthy = nodes
+                .iter()
+                .position(|node| node.get_blockchain_info().is_ok());
+
+            match healthy {
+                Some(index) => {
+                    if active.swap(index, Ordering::SeqCst) != index {
+                        info!("switching to bitcoind node #{index}");
+                    }
+                }
+                None => warn!("none of our bitcoind nodes is healthy"),
+            }
+        });
+    }
+
+    /// Runs `call`, the RPC `method`, on the active node, trying all other nodes if it's
+    /// unavailable. If they all are, we try them all again a few times, backing off in
+    /// between, as they may be restarting
+    fn rpc<T>(
+        &self,
+        method: &'static str,
+        call: impl Fn(&Client) -> Result<T, bitcoincore_rpc::Error>,
+    ) -> Result<T, Error> {
+        for attempt in 0..retry::ATTEMPTS {
+            if attempt > 0 {
+                thread::sleep(retry::backoff(attempt - 1));
+            }
+            let first = self.active.load(Ordering::SeqCst);
+
+            for offset in 0..self.nodes.len() {
+                let index = (first + offset) % self.nodes.len();
+
+                let started = Instant::now();
+                let result = call(&self.nodes[index]);
+                debug!(
+                    method,
+                    node = index,
+                    attempt,
+                    elapsed_ms = started.elapsed().as_millis() as u64,
+                    ok = result.is_ok(),
+                    "bitcoind rpc call"
+                );
+
+                match result {
+                    Err(e) if is_unavailable(&e) => {
+                        warn!("bitcoind node #{index} is unavailable: {e}");
+                    }
+                    result => {
+                        self.active.store(index, Ordering::SeqCst);
+                        return Ok(result?);
+                    }
+                }
+            }
+        }
+
+        Err(Error::JsonRpcNotWorking)
+    }
+
+    /// Whether `txid` is in our node's mempool
+    fn in_mempool(&self, txid: &Txid) -> Result<bool, Error> {
+        self.rpc("getmempoolentry", |rpc| match rpc.get_mempool_entry(txid) {
+            Ok(_) => Ok(true),
+            Err(e) if is_unavailable(&e) => Err(e),
+            Err(_) => Ok(false),
+        })
+    }
+}
+
+impl ChainBackend for BitcoinCore {
+    fn list_unspent(&self) -> Result<Vec<Utxo>, Error> {
+        Ok(self
+            // like the other backends, we list the coins that didn't confirm yet too
+            .rpc("listunspent", |rpc| {
+                rpc.list_unspent(Some(0), None, None, None, None)
+            })?
+            .into_iter()
+            .map(|unspent| Utxo {
+                txid: unspent.txid,
+                vout: unspent.vout,
+                amount: unspent.amount,
+                confirmations: unspent.confirmations,
+                // core leaves immature coinbases out of listunspent on its own
+                coinbase: false,
+            })
+            .collect())
+    }
+
+    fn create_transaction(
+        &self,
+        inputs: &[Utxo],
+        outputs: &[(Address, Amount)],
+    ) -> Result<Transaction, Error> {
+        let inputs = inputs
+            .iter()
+            .map(|utxo| CreateRawTransactionInput {
+                sequence: None,
+                txid: utxo.txid,
+                vout: utxo.vout,
+            })
+            .collect::<Vec<_>>();
+
+        let outs = outputs
+            .iter()
+            .map(|(address, amount)| (address.to_string(), *amount))
+            .collect::<HashMap<_, _>>();
+
+        let lock_time = anti_fee_sniping(self.block_height()?).to_consensus_u32();
+        self.rpc("createrawtransaction", |rpc| {
+            rpc.create_raw_transaction(&inputs, &outs, Some(lock_time.into()), Some(true))
+        })
+    }
+
+    fn sign_transaction(&self, tx: &Transaction) -> Result<Transaction, Error> {
+        let signed = self.rpc("signrawtransactionwithwallet", |rpc| {
+            rpc.sign_raw_transaction_with_wallet(tx, None, None)
+        })?;
+        if !signed.complete {
+            return Err(Error::SigningFailed);
+        }
+
+        signed.transaction().map_err(|_| Error::JsonRpcNotWorking)
+    }
+
+    fn create_psbt(&self, tx: &Transaction) -> Result<Psbt, Error> {
+        let psbt = Psbt::from_unsigned_tx(tx.clone()).map_err(|_| Error::SigningFailed)?;
+
+        // let core fill in the utxos and key origins without signing anything
+        let processed = self.rpc("walletprocesspsbt", |rpc| {
+            rpc.wallet_process_psbt(&psbt.to_string(), Some(false), None, Some(true))
+        })?;
+
+        processed.psbt.parse().map_err(|_| Error::JsonRpcNotWorking)
+    }
+
+    fn sign_psbt(&self, psbt: Psbt) -> Result<Psbt, Error> {
+        // core finalizes the inputs it signs, the psbt is only complete if they're all ours
+        let processed = self.rpc("walletprocesspsbt", |rpc| {
+            rpc.wallet_process_psbt(&psbt.to_string(), Some(true), None, None)
+        })?;
+
+        processed.psbt.parse().map_err(|_| Error::JsonRpcNotWorking)
+    }
+
+    fn finalize_psbt(&self, psbt: Psbt) -> Result<Transaction, Error> {
+        let finalized = self.rpc("finalizepsbt", |rpc| {
+            rpc.finalize_psbt(&psbt.to_string(), Some(true))
+        })?;
+
+        let (true, Some(hex)) = (finalized.complete, finalized.hex) else {
+            return Err(Error::SigningFailed);
+        };
+
+        deserialize(&hex).map_err(|_| Error::JsonRpcNotWorking)
+    }
+
+    fn broadcast_transaction(&self, tx: &Transaction) -> Result<Txid, Error> {
+        self.rpc("sendrawtransaction", |rpc| {
+            match rpc.send_raw_transaction(tx) {
+                Ok(txid) => Ok(Some(txid)),
+                Err(e) if is_conflict(&e.to_string()) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })?
+        .ok_or(Error::InputsSpent)
+    }
+
+    fn get_balance(&self) -> Result<Amount, Error> {
+        self.rpc("getbalance", |rpc| rpc.get_balance(None, None))
+    }
+
+    fn block_height(&self) -> Result<u32, Error> {
+        let info = self.rpc("getblockchaininfo", |rpc| rpc.get_blockchain_info())?;
+        Ok(info.blocks as u32)
+    }
+
+    fn transaction_status(&self, txid: &Txid) -> Result<TxState, Error> {
+        let tx = self
+            .rpc("gettransaction", |rpc| {
+                rpc.get_transaction(txid, Some(true))
+            })?
+            .info;
+
+        // core gives transactions that conflict with a confirmed one negative confirmations
+        if tx.confirmations < 0 {
+            return Ok(TxState::Replaced {
+                by: tx.wallet_conflicts.first().copied(),
+            });
+        }
+
+        if tx.confirmations > 0 {
+            let (Some(block_hash), Some(height)) = (tx.blockhash, tx.blockheight) else {
+                return Err(Error::JsonRpcNotWorking);
+            };
+            return Ok(TxState::Confirmed {
+                block_hash,
+                height,
+                confirmations: tx.confirmations as u32,
+            });
+        }
+
+        if self.in_mempool(txid)? {
+            return Ok(TxState::Unconfirmed);
+        }
+
+        // if something conflicting is in the mempool, it was most likely bumped
+        for conflict in &tx.wallet_conflicts {
+            if self.in_mempool(conflict)? {
+                return Ok(TxState::Replaced {
+                    by: Some(*conflict),
+                });
+            }
+        }
+
+        Ok(TxState::Evicted)
+    }
+
+    fn estimate_fee(&self, target: u16) -> Result<Option<FeeRate>, Error> {
+        let estimate = self.rpc("estimatesmartfee", |rpc| {
+            rpc.estimate_smart_fee(target, None)
+        })?;
+
+        // core gives us BTC/kvB, we want sat/kwu
+        Ok(estimate
+            .fee_rate
+            .map(|rate| FeeRate::from_sat_per_kwu(rate.to_sat() / 4)))
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Error> {
+        self.rpc("getmempoolentry", |rpc| match rpc.get_mempool_entry(txid) {
+            Ok(entry) => Ok(Some(MempoolEntry {
+                fee: entry.fees.base,
+                vsize: entry.vsize,
+            })),
+            Err(e) if is_unavailable(&e) => Err(e),
+            Err(_) => Ok(None),
+        })
+    }
+
+    fn mempool_rejects(&self, tx: &Transaction) -> Result<Option<String>, Error> {
+        let results = self.rpc("testmempoolaccept", |rpc| rpc.test_mempool_accept(&[tx]))?;
+        let result = results.into_iter().next().ok_or(Error::JsonRpcNotWorking)?;
+
+        Ok(match result.allowed {
+            true => None,
+            false => Some(result.reject_reason.unwrap_or_default()),
+        })
+    }
+
+    /// Core only gives out its keys with dumpprivkey, which descriptor wallets don't have, so
+    /// only legacy wallets can pay silent payment addresses
+    fn silent_payment_key(&self, inputs: &[Utxo]) -> Result<SecretKey, Error> {
+        let mut sum: Option<SecretKey> = None;
+        for utxo in inputs {
+            let prevout = self
+                .rpc("gettxout", |rpc| {
+                    rpc.get_tx_out(&utxo.txid, utxo.vout, Some(true))
+                })?
+                .ok_or(Error::OutOfMoney)?;
+            let Some(address) = prevout.script_pub_key.address else {
+                continue;
+            };
+            let address = address.assume_checked();
+            match address.address_type() {
+                // p2sh coins of ours are p2sh-p2wpkh
+                Some(AddressType::P2pkh | AddressType::P2sh | AddressType::P2wpkh) => {}
+                // taproot keys are tweaked, dumpprivkey doesn't give the key spending them
+                Some(AddressType::P2tr) => return Err(Error::SilentPaymentsUnsupported),
+                // BIP352 leaves the others out
+                _ => continue,
+            }
+
+            let key = self
+                .rpc("dumpprivkey", |rpc| match rpc.dump_private_key(&address) {
+                    Ok(key) => Ok(Some(key)),
+                    Err(e) if is_unavailable(&e) => Err(e),
+                    Err(_) => Ok(None),
+                })?
+                .ok_or(Error::SilentPaymentsUnsupported)?;
+            if !key.compressed {
+                continue;
+            }
+            sum = Some(match sum {
+                Some(sum) => sum
+                    .add_tweak(&Scalar::from(key.inner))
+                    .map_err(|_| Error::SigningFailed)?,
+                None => key.inner,
+            });
+        }
+
+        sum.ok_or(Error::SilentPaymentsUnsupported)
+    }
+
+    fn receive_address(&self) -> Result<Address, Error> {
+        let address = self.rpc("getnewaddress", |rpc| rpc.get_new_address(None, None))?;
+        address
+            .require_network(Network::Signet)
+            .map_err(|_| Error::JsonRpcNotWorking)
+    }
+}
@@ -0,0 +1,222 @@
+//SPDX-License-Identifier: MIT
+
+//! A [ChainBackend] that uses an Electrum server (e.g. electrs) to find our coins and
+//! broadcast. Like Esplora, Electrum has no wallet, so keys are held by a [LocalWallet].
+
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::Psbt;
+use bitcoin::Transaction;
+use bitcoin::TxOut;
+use bitcoin::Txid;
+use electrum_client::ElectrumApi;
+
+use super::anti_fee_sniping;
+use super::is_conflict;
+use super::wallet::LocalWallet;
+use super::ChainBackend;
+use super::MempoolEntry;
+use super::TxState;
+use super::Utxo;
+use super::COINBASE_MATURITY;
+use crate::api::Error;
+
+impl From<electrum_client::Error> for Error {
+    fn from(value: electrum_client::Error) -> Self {
+        Error::ElectrumError(value.to_string())
+    }
+}
+
+pub struct Electrum {
+    client: electrum_client::Client,
+    wallet: LocalWallet,
+}
+
+impl Electrum {
+    pub fn new(url: &str, wallet: LocalWallet) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: electrum_client::Client::new(url)?,
+            wallet,
+        })
+    }
+
+    /// Fetches the output being spent by one of our inputs, we need it for the sighash
+    fn get_prevout(&self, txid: &Txid, vout: u32) -> Result<TxOut, Error> {
+        self.client
+            .transaction_get(txid)?
+            .output
+            .get(vout as usize)
+            .cloned()
+            .ok_or(Error::ElectrumError(format!("{txid}:{vout} doesn't exist")))
+    }
+
+    /// Fetches all outputs spent by `tx`
+    fn get_prevouts(&self, tx: &Transaction) -> Result<Vec<TxOut>, Error> {
+        tx.input
+            .iter()
+            .map(|input| self.get_prevout(&input.previous_output.txid, input.previous_output.vout))
+            .collect()
+    }
+}
+
+impl ChainBackend for Electrum {
+    fn list_unspent(&self) -> Result<Vec<Utxo>, Error> {
+        let height = self.block_height()?;
+        self.client
+            .script_list_unspent(&self.wallet.address().script_pubkey())?
+            .into_iter()
+            .map(|unspent| {
+                // electrum servers put unconfirmed coins at height 0
+                let confirmations = match unspent.height as u32 {
+                    0 => 0,
+                    block => height.saturating_sub(block) + 1,
+                };
+                // only the coins that may still be immature are worth a request
+                let coinbase = (1..=COINBASE_MATURITY).contains(&confirmations)
+                    && self.client.transaction_get(&unspent.tx_hash)?.is_coinbase();
+
+                Ok(Utxo {
+                    txid: unspent.tx_hash,
+                    vout: unspent.tx_pos as u32,
+                    amount: Amount::from_sat(unspent.value),
+                    confirmations,
+                    coinbase,
+                })
+            })
+            .collect()
+    }
+
+    fn create_transaction(
+        &self,
+        inputs: &[Utxo],
+        outputs: &[(Address, Amount)],
+    ) -> Result<Transaction, Error> {
+        let lock_time = anti_fee_sniping(self.block_height()?);
+        Ok(self.wallet.create_transaction(inputs, outputs, lock_time))
+    }
+
+    fn sign_transaction(&self, tx: &Transaction) -> Result<Transaction, Error> {
+        self.wallet.sign_transaction(tx, &self.get_prevouts(tx)?)
+    }
+
+    fn create_psbt(&self, tx: &Transaction) -> Result<Psbt, Error> {
+        self.wallet.create_psbt(tx, &self.get_prevouts(tx)?)
+    }
+
+    fn finalize_psbt(&self, psbt: Psbt) -> Result<Transaction, Error> {
+        self.wallet.finalize_psbt(psbt)
+    }
+
+    fn sign_psbt(&self, mut psbt: Psbt) -> Result<Psbt, Error> {
+        let script = self.wallet.address().script_pubkey();
+        for (txin, input) in psbt.unsigned_tx.input.iter().zip(psbt.inputs.iter_mut()) {
+            if input.witness_utxo.is_none() {
+                let outpoint = txin.previous_output;
+                let prevout = self.get_prevout(&outpoint.txid, outpoint.vout)?;
+                input.witness_utxo =
+                    Some(prevout).filter(|prevout| prevout.script_pubkey == script);
+            }
+        }
+
+        self.wallet.sign_psbt(psbt)
+    }
+
+    fn broadcast_transaction(&self, tx: &Transaction) -> Result<Txid, Error> {
+        match self.client.transaction_broadcast(tx) {
+            Ok(txid) => Ok(txid),
+            Err(e) if is_conflict(&e.to_string()) => Err(Error::InputsSpent),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_balance(&self) -> Result<Amount, Error> {
+        let balance = self
+            .client
+            .script_get_balance(&self.wallet.address().script_pubkey())?;
+
+        // unconfirmed may be negative if we are spending confirmed coins
+        Ok(Amount::from_sat(
+            (balance.confirmed as i64 + balance.unconfirmed).max(0) as u64,
+        ))
+    }
+
+    fn block_height(&self) -> Result<u32, Error> {
+        Ok(self.client.block_headers_subscribe()?.height as u32)
+    }
+
+    fn transaction_status(&self, txid: &Txid) -> Result<TxState, Error> {
+        let history = self
+            .client
+            .script_get_history(&self.wallet.address().script_pubkey())?;
+
+        // like esplora, electrum servers only know what's in the chain or mempool, so we
+        // can't tell whether a transaction that's gone was replaced
+        let Some(entry) = history.iter().find(|entry| entry.tx_hash == *txid) else {
+            return Ok(TxState::Evicted);
+        };
+        if entry.height <= 0 {
+            return Ok(TxState::Unconfirmed);
+        }
+
+        let height = entry.height as u32;
+        Ok(TxState::Confirmed {
+            block_hash: self.client.block_header(height as usize)?.block_hash(),
+            height,
+            confirmations: self.block_height()?.saturating_sub(height) + 1,
+        })
+    }
+
+    fn estimate_fee(&self, target: u16) -> Result<Option<FeeRate>, Error> {
+        // electrum gives us BTC/kvB and -1 if it doesn't know
+        let rate = self.client.estimate_fee(target as usize)?;
+        if rate <= 0.0 {
+            return Ok(None);
+        }
+
+        let sat_per_kvb = (rate * 100_000_000.0).ceil() as u64;
+        Ok(Some(FeeRate::from_sat_per_kwu(sat_per_kvb / 4)))
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Error> {
+        // we can only look up the transactions touching our address
+        let unconfirmed = self
+            .client
+            .script_get_history(&self.wallet.address().script_pubkey())?
+            .iter()
+            .any(|entry| entry.tx_hash == *txid && entry.height <= 0);
+        if !unconfirmed {
+            return Ok(None);
+        }
+
+        let tx = self.client.transaction_get(txid)?;
+        let spent: Amount = self
+            .get_prevouts(&tx)?
+            .iter()
+            .map(|prevout| prevout.value)
+            .sum();
+        let sent: Amount = tx.output.iter().map(|output| output.value).sum();
+        let fee = spent.checked_sub(sent).ok_or(Error::ElectrumError(format!(
+            "{txid} sends more than it spends"
+        )))?;
+
+        Ok(Some(MempoolEntry {
+            fee,
+            vsize: tx.vsize() as u64,
+        }))
+    }
+
+    // electrum servers can only broadcast
+    fn mempool_rejects(&self, _tx: &Transaction) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    fn silent_payment_key(&self, inputs: &[Utxo]) -> Result<SecretKey, Error> {
+        self.wallet.silent_payment_key(inputs.len())
+    }
+
+    fn receive_address(&self) -> Result<Address, Error> {
+        Ok(self.wallet.address().clone())
+    }
+}
@@ -0,0 +1,286 @@
+//SPDX-License-Identifier: MIT
+
+//! A [ChainBackend] that talks to an Esplora HTTP server. Esplora has no wallet, so coins
+//! are held by a [LocalWallet] and we only use the server to find UTXOs and broadcast.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use bitcoin::consensus::deserialize;
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::BlockHash;
+use bitcoin::FeeRate;
+use bitcoin::Psbt;
+use bitcoin::Transaction;
+use bitcoin::TxOut;
+use bitcoin::Txid;
+use serde::Deserialize;
+
+use super::anti_fee_sniping;
+use super::is_conflict;
+use super::wallet::LocalWallet;
+use super::ChainBackend;
+use super::MempoolEntry;
+use super::TxState;
+use super::Utxo;
+use super::COINBASE_MATURITY;
+use crate::api::Error;
+
+/// An entry returned by `GET /address/:address/utxo`
+#[derive(Deserialize)]
+struct EsploraUtxo {
+    txid: Txid,
+    vout: u32,
+    value: u64,
+    status: EsploraTxStatus,
+}
+
+/// The parts of what `GET /tx/:txid` returns we care about
+#[derive(Deserialize)]
+struct EsploraTx {
+    fee: u64,
+    weight: u64,
+    status: EsploraTxStatus,
+}
+
+/// What `GET /tx/:txid/status` returns
+#[derive(Deserialize)]
+struct EsploraTxStatus {
+    confirmed: bool,
+    block_height: Option<u32>,
+    block_hash: Option<BlockHash>,
+}
+
+pub struct Esplora {
+    url: String,
+    agent: ureq::Agent,
+    wallet: LocalWallet,
+}
+
+impl Esplora {
+    pub fn new(url: String, wallet: LocalWallet) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            agent: ureq::Agent::new(),
+            wallet,
+        }
+    }
+
+    fn get(&self, path: &str) -> Result<ureq::Response, Error> {
+        self.agent
+            .get(&format!("{}{path}", self.url))
+            .call()
+            .map_err(|e| Error::EsploraError(e.to_string()))
+    }
+
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction, Error> {
+        let mut raw = vec![];
+        self.get(&format!("/tx/{txid}/raw"))?
+            .into_reader()
+            .read_to_end(&mut raw)
+            .map_err(|e| Error::EsploraError(e.to_string()))?;
+
+        deserialize(&raw).map_err(|e| Error::EsploraError(e.to_string()))
+    }
+
+    /// Fetches the output being spent by one of our inputs, we need it for the sighash
+    fn get_prevout(&self, txid: &Txid, vout: u32) -> Result<TxOut, Error> {
+        self.get_transaction(txid)?
+            .output
+            .get(vout as usize)
+            .cloned()
+            .ok_or(Error::EsploraError(format!("{txid}:{vout} doesn't exist")))
+    }
+
+    /// Fetches all outputs spent by `tx`
+    fn get_prevouts(&self, tx: &Transaction) -> Result<Vec<TxOut>, Error> {
+        tx.input
+            .iter()
+            .map(|input| self.get_prevout(&input.previous_output.txid, input.previous_output.vout))
+            .collect()
+    }
+}
+
+impl ChainBackend for Esplora {
+    fn list_unspent(&self) -> Result<Vec<Utxo>, Error> {
+        let utxos: Vec<EsploraUtxo> = self
+            .get(&format!("/address/{}/utxo", self.wallet.address()))?
+            .into_json()
+            .map_err(|e| Error::EsploraError(e.to_string()))?;
+
+        let height = self.block_height()?;
+        utxos
+            .into_iter()
+            .map(|utxo| {
+                let confirmations = utxo
+                    .status
+                    .block_height
+                    .map_or(0, |block| height.saturating_sub(block) + 1);
+                // only the coins that may still be immature are worth a request
+                let coinbase = (1..=COINBASE_MATURITY).contains(&confirmations)
+                    && self.get_transaction(&utxo.txid)?.is_coinbase();
+
+                Ok(Utxo {
+                    txid: utxo.txid,
+                    vout: utxo.vout,
+                    amount: Amount::from_sat(utxo.value),
+                    confirmations,
+                    coinbase,
+                })
+            })
+            .collect()
+    }
+
+    fn create_transaction(
+        &self,
+        inputs: &[Utxo],
+        outputs: &[(Address, Amount)],
+    ) -> Result<Transaction, Error> {
+        let lock_time = anti_fee_sniping(self.block_height()?);
+        Ok(self.wallet.create_transaction(inputs, outputs, lock_time))
+    }
+
+    fn sign_transaction(&self, tx: &Transaction) -> Result<Transaction, Error> {
+        self.wallet.sign_transaction(tx, &self.get_prevouts(tx)?)
+    }
+
+    fn create_psbt(&self, tx: &Transaction) -> Result<Psbt, Error> {
+        self.wallet.create_psbt(tx, &self.get_prevouts(tx)?)
+    }
+
+    fn finalize_psbt(&self, psbt: Psbt) -> Result<Transaction, Error> {
+        self.wallet.finalize_psbt(psbt)
+    }
+
+    fn sign_psbt(&self, mut psbt: Psbt) -> Result<Psbt, Error> {
+        let script = self.wallet.address().script_pubkey();
+        for (txin, input) in psbt.unsigned_tx.input.iter().zip(psbt.inputs.iter_mut()) {
+            if input.witness_utxo.is_none() {
+                let outpoint = txin.previous_output;
+                let prevout = self.get_prevout(&outpoint.txid, outpoint.vout)?;
+                input.witness_utxo =
+                    Some(prevout).filter(|prevout| prevout.script_pubkey == script);
+            }
+        }
+
+        self.wallet.sign_psbt(psbt)
+    }
+
+    fn broadcast_transaction(&self, tx: &Transaction) -> Result<Txid, Error> {
+        let txid = self
+            .agent
+            .post(&format!("{}/tx", self.url))
+            .send_string(&serialize_hex(tx))
+            .map_err(|e| match e {
+                // the reason is in the body
+                ureq::Error::Status(_, response) => {
+                    let reason = response.into_string().unwrap_or_default();
+                    if is_conflict(&reason) {
+                        Error::InputsSpent
+                    } else {
+                        Error::EsploraError(reason)
+                    }
+                }
+                e => Error::EsploraError(e.to_string()),
+            })?
+            .into_string()
+            .map_err(|e| Error::EsploraError(e.to_string()))?;
+
+        txid.trim()
+            .parse()
+            .map_err(|_| Error::EsploraError(format!("invalid txid {txid}")))
+    }
+
+    fn get_balance(&self) -> Result<Amount, Error> {
+        Ok(self.list_unspent()?.iter().map(|utxo| utxo.amount).sum())
+    }
+
+    fn block_height(&self) -> Result<u32, Error> {
+        let height = self
+            .get("/blocks/tip/height")?
+            .into_string()
+            .map_err(|e| Error::EsploraError(e.to_string()))?;
+
+        height
+            .trim()
+            .parse()
+            .map_err(|_| Error::EsploraError(format!("invalid height {height}")))
+    }
+
+    fn transaction_status(&self, txid: &Txid) -> Result<TxState, Error> {
+        let response = match self
+            .agent
+            .get(&format!("{}/tx/{txid}/status", self.url))
+            .call()
+        {
+            Ok(response) => response,
+            // esplora forgets transactions once they leave the mempool, so we can't tell
+            // whether it was replaced
+            Err(ureq::Error::Status(404, _)) => return Ok(TxState::Evicted),
+            Err(e) => return Err(Error::EsploraError(e.to_string())),
+        };
+        let status: EsploraTxStatus = response
+            .into_json()
+            .map_err(|e| Error::EsploraError(e.to_string()))?;
+
+        match (status.confirmed, status.block_hash, status.block_height) {
+            (true, Some(block_hash), Some(height)) => Ok(TxState::Confirmed {
+                block_hash,
+                height,
+                confirmations: self.block_height()?.saturating_sub(height) + 1,
+            }),
+            _ => Ok(TxState::Unconfirmed),
+        }
+    }
+
+    fn estimate_fee(&self, target: u16) -> Result<Option<FeeRate>, Error> {
+        // esplora returns a map from confirmation target to sat/vB
+        let estimates: HashMap<String, f64> = self
+            .get("/fee-estimates")?
+            .into_json()
+            .map_err(|e| Error::EsploraError(e.to_string()))?;
+
+        let best = estimates
+            .into_iter()
+            .filter_map(|(blocks, rate)| Some((blocks.parse::<u16>().ok()?, rate)))
+            .filter(|(blocks, _)| *blocks <= target)
+            .max_by_key(|(blocks, _)| *blocks);
+
+        Ok(best.map(|(_, rate)| FeeRate::from_sat_per_kwu((rate * 250.0).ceil() as u64)))
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Error> {
+        let response = match self.agent.get(&format!("{}/tx/{txid}", self.url)).call() {
+            Ok(response) => response,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(e) => return Err(Error::EsploraError(e.to_string())),
+        };
+        let tx: EsploraTx = response
+            .into_json()
+            .map_err(|e| Error::EsploraError(e.to_string()))?;
+        if tx.status.confirmed {
+            return Ok(None);
+        }
+
+        Ok(Some(MempoolEntry {
+            fee: Amount::from_sat(tx.fee),
+            vsize: tx.weight.div_ceil(4),
+        }))
+    }
+
+    // esplora can only broadcast
+    fn mempool_rejects(&self, _tx: &Transaction) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    fn silent_payment_key(&self, inputs: &[Utxo]) -> Result<SecretKey, Error> {
+        self.wallet.silent_payment_key(inputs.len())
+    }
+
+    fn receive_address(&self) -> Result<Address, Error> {
+        Ok(self.wallet.address().clone())
+    }
+}
@@ -0,0 +1,58 @@
+//SPDX-License-Identifier: MIT
+
+//! Signs PSBTs with a hardware wallet through the HWI command line tool. HWI must be
+//! installed and the device must be connected (and unlocked) on the faucet's host.
+
+use std::process::Command;
+
+use bitcoin::Psbt;
+use serde::Deserialize;
+
+use crate::api::Error;
+
+/// What `hwi signtx` prints on success
+#[derive(Deserialize)]
+struct SignTxResult {
+    psbt: String,
+}
+
+/// What `hwi` prints when something goes wrong
+#[derive(Deserialize)]
+struct HwiError {
+    error: String,
+}
+
+pub struct Hwi {
+    /// Which device to use, as printed by `hwi enumerate`
+    fingerprint: String,
+    /// The chain name HWI expects, like signet or test
+    chain: String,
+}
+
+impl Hwi {
+    pub fn new(fingerprint: String, chain: String) -> Self {
+        Self { fingerprint, chain }
+    }
+
+    /// Asks the device to sign `psbt`, the user may need to confirm it on the device
+    pub fn sign_psbt(&self, psbt: &Psbt) -> Result<Psbt, Error> {
+        let output = Command::new("hwi")
+            .args(["--fingerprint", &self.fingerprint])
+            .args(["--chain", &self.chain])
+            .arg("signtx")
+            .arg(psbt.to_string())
+            .output()
+            .map_err(|e| Error::SignerError(format!("couldn't run hwi: {e}")))?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        if let Ok(HwiError { error }) = serde_json::from_str(&stdout) {
+            return Err(Error::SignerError(error));
+        }
+
+        let SignTxResult { psbt } = serde_json::from_str(&stdout)
+            .map_err(|_| Error::SignerError(format!("unexpected hwi output: {stdout}")))?;
+
+        psbt.parse()
+            .map_err(|_| Error::SignerError("hwi returned an invalid psbt".into()))
+    }
+}
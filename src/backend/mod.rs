@@ -0,0 +1,247 @@
+//SPDX-License-Identifier: MIT
+
+//! Chain backends used by the faucet to find coins, build and sign transactions and
+//! talk to the network. The API only depends on the [ChainBackend] trait, so swapping
+//! bitcoin core for something else (or a mock) doesn't touch the route handlers.
+
+#[cfg(feature = "bdk")]
+pub mod bdk;
+pub mod bitcoind;
+#[cfg(feature = "electrum")]
+pub mod electrum;
+#[cfg(feature = "esplora")]
+pub mod esplora;
+#[cfg(feature = "hwi")]
+pub mod hwi;
+#[cfg(feature = "external-signer")]
+pub mod signer;
+#[cfg(feature = "utreexod")]
+pub mod utreexod;
+#[cfg(any(feature = "esplora", feature = "electrum", feature = "utreexod"))]
+pub mod wallet;
+
+use bitcoin::absolute::LockTime;
+use bitcoin::secp256k1::rand;
+use bitcoin::secp256k1::rand::Rng;
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::BlockHash;
+use bitcoin::FeeRate;
+use bitcoin::Psbt;
+use bitcoin::Transaction;
+use bitcoin::Txid;
+
+use crate::api::Error;
+
+/// How many blocks must build on a coinbase before its coins can be spent
+pub const COINBASE_MATURITY: u32 = 100;
+
+/// A coin owned by the faucet that can be used as a transaction input
+#[derive(Debug, Clone)]
+pub struct Utxo {
+    pub txid: Txid,
+    pub vout: u32,
+    pub amount: Amount,
+    /// How many blocks confirmed it, 0 while it waits in the mempool
+    pub confirmations: u32,
+    /// Whether a coinbase made it. We only tell for coins that may still be immature, like those
+    /// of blocks the faucet mines on signet
+    pub coinbase: bool,
+}
+
+impl Utxo {
+    /// Whether it can go in the next block. Coinbase coins can't until [COINBASE_MATURITY]
+    /// blocks built on theirs
+    pub fn is_mature(&self) -> bool {
+        !self.coinbase || self.confirmations > COINBASE_MATURITY
+    }
+}
+
+/// What a transaction waiting in the mempool pays, and for how much space
+#[derive(Debug, Clone, Copy)]
+pub struct MempoolEntry {
+    pub fee: Amount,
+    pub vsize: u64,
+}
+
+impl MempoolEntry {
+    /// What it pays per vbyte
+    pub fn feerate(&self) -> FeeRate {
+        FeeRate::from_sat_per_kwu(self.fee.to_sat() * 250 / self.vsize.max(1))
+    }
+}
+
+/// Where one of our transactions is at, as the chain sees it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxState {
+    /// Waiting in the mempool
+    Unconfirmed,
+    Confirmed {
+        block_hash: BlockHash,
+        height: u32,
+        confirmations: u32,
+    },
+    /// Something spending the same coins took its place, `by` if we know what
+    Replaced { by: Option<Txid> },
+    /// It's neither in a block nor in the mempool, and we don't know of anything that replaced
+    /// it. Mempools drop transactions that pay too little, or that waited for too long
+    Evicted,
+}
+
+/// What nodes say when rejecting a transaction because something else spent its coins, in the
+/// mempool or in a block. Bitcoin Core's first, then btcd's, which utreexod is built on
+const CONFLICTS: &[&str] = &[
+    "missingorspent",
+    "missing-inputs",
+    "txn-mempool-conflict",
+    "rejecting replacement",
+    "already spent",
+    "fully-spent",
+];
+
+/// Whether a node rejected a transaction, for `reason`, because some of its coins were spent
+pub fn is_conflict(reason: &str) -> bool {
+    CONFLICTS.iter().any(|conflict| reason.contains(conflict))
+}
+
+/// The locktime for a transaction made at `height`, like Bitcoin Core's wallet picks it. Miners
+/// can't put it in a block replacing the tip, so they gain nothing by reorging the tip to take its
+/// fee. One in ten go some blocks back, so those sent late look like the others
+pub fn anti_fee_sniping(height: u32) -> LockTime {
+    let mut rng = rand::thread_rng();
+    let height = if rng.gen_range(0..10) == 0 {
+        height.saturating_sub(rng.gen_range(0..100))
+    } else {
+        height
+    };
+
+    LockTime::from_height(height).unwrap_or(LockTime::ZERO)
+}
+
+/// Everything the faucet needs from the chain and its wallet
+pub trait ChainBackend: Send + Sync + 'static {
+    /// Returns all coins we may spend, including those that haven't confirmed
+    fn list_unspent(&self) -> Result<Vec<Utxo>, Error>;
+
+    /// Builds an unsigned transaction spending `inputs` and paying to `outputs`, locked to the
+    /// chain's tip with [anti_fee_sniping]
+    fn create_transaction(
+        &self,
+        inputs: &[Utxo],
+        outputs: &[(Address, Amount)],
+    ) -> Result<Transaction, Error>;
+
+    /// Signs all inputs of `tx` that belong to us
+    fn sign_transaction(&self, tx: &Transaction) -> Result<Transaction, Error>;
+
+    /// Wraps `tx` into a PSBT with everything a signer needs to know about our inputs
+    fn create_psbt(&self, tx: &Transaction) -> Result<Psbt, Error>;
+
+    /// Turns a PSBT with all signatures in place into a transaction ready for broadcast
+    #[allow(dead_code)]
+    fn finalize_psbt(&self, psbt: Psbt) -> Result<Transaction, Error>;
+
+    /// Signs and finalizes the inputs of `psbt` spending our coins, filling in what we know
+    /// about them, and leaves the others alone. For transactions with someone else's inputs too
+    fn sign_psbt(&self, psbt: Psbt) -> Result<Psbt, Error>;
+
+    /// Sends a fully signed transaction to the network. Fails with [Error::InputsSpent] if some
+    /// of its coins were spent already
+    fn broadcast_transaction(&self, tx: &Transaction) -> Result<Txid, Error>;
+
+    /// Our total spendable balance
+    fn get_balance(&self) -> Result<Amount, Error>;
+
+    /// The height of the chain's tip, as whatever we get the chain from sees it. Also tells
+    /// whether we can reach it at all
+    fn block_height(&self) -> Result<u32, Error>;
+
+    /// Where `txid`, which we sent, is at
+    fn transaction_status(&self, txid: &Txid) -> Result<TxState, Error>;
+
+    /// Returns the feerate needed to confirm within `target` blocks, if the backend knows it
+    fn estimate_fee(&self, target: u16) -> Result<Option<FeeRate>, Error>;
+
+    /// What `txid`, one of ours or one paying us, pays, if it's waiting in the mempool and the
+    /// backend can tell
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Error>;
+
+    /// Why the mempool would turn `tx` down, without broadcasting it. Backends that can't ask
+    /// say it would take it
+    fn mempool_rejects(&self, tx: &Transaction) -> Result<Option<String>, Error>;
+
+    /// The sum of the private keys spending `inputs`, as BIP352 adds them up to derive silent
+    /// payment outputs. Only backends holding the keys themselves can tell
+    fn silent_payment_key(&self, inputs: &[Utxo]) -> Result<SecretKey, Error>;
+
+    /// Returns an address to receive coins at, like donations. Wallets give a fresh one each
+    /// time, while backends holding a single key always give theirs
+    fn receive_address(&self) -> Result<Address, Error>;
+}
+
+impl ChainBackend for Box<dyn ChainBackend> {
+    fn list_unspent(&self) -> Result<Vec<Utxo>, Error> {
+        (**self).list_unspent()
+    }
+
+    fn create_transaction(
+        &self,
+        inputs: &[Utxo],
+        outputs: &[(Address, Amount)],
+    ) -> Result<Transaction, Error> {
+        (**self).create_transaction(inputs, outputs)
+    }
+
+    fn sign_transaction(&self, tx: &Transaction) -> Result<Transaction, Error> {
+        (**self).sign_transaction(tx)
+    }
+
+    fn create_psbt(&self, tx: &Transaction) -> Result<Psbt, Error> {
+        (**self).create_psbt(tx)
+    }
+
+    fn finalize_psbt(&self, psbt: Psbt) -> Result<Transaction, Error> {
+        (**self).finalize_psbt(psbt)
+    }
+
+    fn sign_psbt(&self, psbt: Psbt) -> Result<Psbt, Error> {
+        (**self).sign_psbt(psbt)
+    }
+
+    fn broadcast_transaction(&self, tx: &Transaction) -> Result<Txid, Error> {
+        (**self).broadcast_transaction(tx)
+    }
+
+    fn get_balance(&self) -> Result<Amount, Error> {
+        (**self).get_balance()
+    }
+
+    fn block_height(&self) -> Result<u32, Error> {
+        (**self).block_height()
+    }
+
+    fn transaction_status(&self, txid: &Txid) -> Result<TxState, Error> {
+        (**self).transaction_status(txid)
+    }
+
+    fn estimate_fee(&self, target: u16) -> Result<Option<FeeRate>, Error> {
+        (**self).estimate_fee(target)
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Error> {
+        (**self).mempool_entry(txid)
+    }
+
+    fn mempool_rejects(&self, tx: &Transaction) -> Result<Option<String>, Error> {
+        (**self).mempool_rejects(tx)
+    }
+
+    fn silent_payment_key(&self, inputs: &[Utxo]) -> Result<SecretKey, Error> {
+        (**self).silent_payment_key(inputs)
+    }
+
+    fn receive_address(&self) -> Result<Address, Error> {
+        (**self).receive_address()
+    }
+}
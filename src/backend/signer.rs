@@ -0,0 +1,185 @@
+//SPDX-License-Identifier: MIT
+
+//! Lets the faucet run with a watch-only wallet while keys live somewhere else. Transactions
+//! are turned into PSBTs and sent to an external signer, which must answer with the same PSBT
+//! carrying its signatures. Everything else is delegated to the wrapped backend.
+//!
+//! The signer may be reached over HTTP, where we POST the base64 PSBT and read the signed one
+//! from the response body, or over a unix socket, where we write the base64 PSBT followed by
+//! a newline and read one line back. With the `hwi` feature, the signer may also be a
+//! hardware wallet connected to this machine.
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::Psbt;
+use bitcoin::Transaction;
+use bitcoin::Txid;
+
+#[cfg(feature = "hwi")]
+use super::hwi::Hwi;
+use super::ChainBackend;
+use super::MempoolEntry;
+use super::TxState;
+use super::Utxo;
+use crate::api::Error;
+
+/// Where our signer lives
+pub enum SignerEndpoint {
+    Http(String),
+    Unix(PathBuf),
+    #[cfg(feature = "hwi")]
+    Hwi(Hwi),
+}
+
+impl FromStr for SignerEndpoint {
+    type Err = std::convert::Infallible;
+
+    /// Anything starting with `unix:` is a socket path, `hwi:<fingerprint>` is a hardware
+    /// wallet and everything else is an url
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(SignerEndpoint::Unix(path.into()));
+        }
+
+        #[cfg(feature = "hwi")]
+        if let Some(fingerprint) = s.strip_prefix("hwi:") {
+            return Ok(SignerEndpoint::Hwi(Hwi::new(
+                fingerprint.to_string(),
+                "signet".into(),
+            )));
+        }
+
+        Ok(SignerEndpoint::Http(s.to_string()))
+    }
+}
+
+pub struct ExternalSigner<B: ChainBackend> {
+    inner: B,
+    endpoint: SignerEndpoint,
+}
+
+impl<B: ChainBackend> ExternalSigner<B> {
+    pub fn new(inner: B, endpoint: SignerEndpoint) -> Self {
+        Self { inner, endpoint }
+    }
+
+    /// Sends `psbt` to the signer and returns what it sent back
+    fn request_signatures(&self, psbt: &Psbt) -> Result<Psbt, Error> {
+        let signed = match &self.endpoint {
+            SignerEndpoint::Http(url) => ureq::post(url)
+                .set("Content-Type", "text/plain")
+                .send_string(&psbt.to_string())
+                .map_err(|e| Error::SignerError(e.to_string()))?
+                .into_string()
+                .map_err(|e| Error::SignerError(e.to_string()))?,
+            SignerEndpoint::Unix(path) => {
+                let mut stream =
+                    UnixStream::connect(path).map_err(|e| Error::SignerError(e.to_string()))?;
+                writeln!(stream, "{psbt}").map_err(|e| Error::SignerError(e.to_string()))?;
+
+                let mut line = String::new();
+                BufReader::new(stream)
+                    .read_line(&mut line)
+                    .map_err(|e| Error::SignerError(e.to_string()))?;
+                line
+            }
+            #[cfg(feature = "hwi")]
+            SignerEndpoint::Hwi(hwi) => hwi.sign_psbt(psbt)?.to_string(),
+        };
+
+        let signed: Psbt = signed
+            .trim()
+            .parse()
+            .map_err(|_| Error::SignerError("the signer returned an invalid psbt".into()))?;
+
+        // make sure the signer didn't change what we are paying for
+        if signed.unsigned_tx != psbt.unsigned_tx {
+            return Err(Error::SignerError(
+                "the signer returned a different transaction".into(),
+            ));
+        }
+
+        Ok(signed)
+    }
+}
+
+impl<B: ChainBackend> ChainBackend for ExternalSigner<B> {
+    fn list_unspent(&self) -> Result<Vec<Utxo>, Error> {
+        self.inner.list_unspent()
+    }
+
+    fn create_transaction(
+        &self,
+        inputs: &[Utxo],
+        outputs: &[(Address, Amount)],
+    ) -> Result<Transaction, Error> {
+        self.inner.create_transaction(inputs, outputs)
+    }
+
+    fn sign_transaction(&self, tx: &Transaction) -> Result<Transaction, Error> {
+        let psbt = self.inner.create_psbt(tx)?;
+        let signed = self.request_signatures(&psbt)?;
+
+        self.inner.finalize_psbt(signed)
+    }
+
+    fn create_psbt(&self, tx: &Transaction) -> Result<Psbt, Error> {
+        self.inner.create_psbt(tx)
+    }
+
+    fn finalize_psbt(&self, psbt: Psbt) -> Result<Transaction, Error> {
+        self.inner.finalize_psbt(psbt)
+    }
+
+    fn sign_psbt(&self, psbt: Psbt) -> Result<Psbt, Error> {
+        // the inner backend finalizes what the signer signed
+        let signed = self.request_signatures(&psbt)?;
+        self.inner.sign_psbt(signed)
+    }
+
+    fn broadcast_transaction(&self, tx: &Transaction) -> Result<Txid, Error> {
+        self.inner.broadcast_transaction(tx)
+    }
+
+    fn get_balance(&self) -> Result<Amount, Error> {
+        self.inner.get_balance()
+    }
+
+    fn block_height(&self) -> Result<u32, Error> {
+        self.inner.block_height()
+    }
+
+    fn transaction_status(&self, txid: &Txid) -> Result<TxState, Error> {
+        self.inner.transaction_status(txid)
+    }
+
+    fn estimate_fee(&self, target: u16) -> Result<Option<FeeRate>, Error> {
+        self.inner.estimate_fee(target)
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Error> {
+        self.inner.mempool_entry(txid)
+    }
+
+    fn mempool_rejects(&self, tx: &Transaction) -> Result<Option<String>, Error> {
+        self.inner.mempool_rejects(tx)
+    }
+
+    // our keys are with the signer
+    fn silent_payment_key(&self, _inputs: &[Utxo]) -> Result<SecretKey, Error> {
+        Err(Error::SilentPaymentsUnsupported)
+    }
+
+    fn receive_address(&self) -> Result<Address, Error> {
+        self.inner.receive_address()
+    }
+}
@@ -0,0 +1,341 @@
+//SPDX-License-Identifier: MIT
+
+//! A [ChainBackend] for utreexod, a btcd fork that keeps a utreexo accumulator instead of the
+//! full UTXO set. Like btcd, utreexod has no wallet of its own, so our keys are held by a
+//! [LocalWallet] and we find our coins with the address index.
+//!
+//! utreexod must run as a bridge node with `--addrindex` and `--notls`. Bridge nodes attach
+//! UData (the utreexo proofs for each input) to every transaction they relay, so our payouts
+//! also reach compact state nodes that can't validate a transaction without them.
+
+use std::sync::Arc;
+use std::time::Instant;
+
+use bitcoin::consensus::deserialize;
+use bitcoin::consensus::encode::serialize_hex;
+use bitcoin::hex::FromHex;
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::BlockHash;
+use bitcoin::FeeRate;
+use bitcoin::Psbt;
+use bitcoin::Transaction;
+use bitcoin::TxOut;
+use bitcoin::Txid;
+use bitcoincore_rpc::jsonrpc;
+use bitcoincore_rpc::jsonrpc::serde_json;
+use bitcoincore_rpc::jsonrpc::serde_json::json;
+use bitcoincore_rpc::Client;
+use bitcoincore_rpc::RpcApi;
+use serde::de::DeserializeOwned;
+use tracing::debug;
+
+use super::anti_fee_sniping;
+use super::is_conflict;
+use super::wallet::LocalWallet;
+use super::ChainBackend;
+use super::MempoolEntry;
+use super::TxState;
+use super::Utxo;
+use crate::api::Error;
+
+/// How many transactions we ask for in each `searchrawtransactions` call
+const SEARCH_PAGE_SIZE: usize = 1_000;
+
+/// The RPC error code btcd returns for transactions it doesn't know
+const RPC_NO_TX_INFO: i32 = -5;
+
+/// The RPC error code btcd returns for methods it doesn't have
+const RPC_METHOD_NOT_FOUND: i32 = -32601;
+
+/// Makes the RPC call `method`, logging how long it took
+fn call<T: DeserializeOwned>(
+    rpc: &Client,
+    method: &str,
+    args: &[serde_json::Value],
+) -> Result<T, bitcoincore_rpc::Error> {
+    let started = Instant::now();
+    let result = rpc.call(method, args);
+    debug!(
+        method,
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        ok = result.is_ok(),
+        "utreexod rpc call"
+    );
+    result
+}
+
+fn transaction_from_hex(hex: &str) -> Result<Transaction, Error> {
+    let bytes = Vec::<u8>::from_hex(hex).map_err(|_| Error::JsonRpcNotWorking)?;
+    deserialize(&bytes).map_err(|_| Error::JsonRpcNotWorking)
+}
+
+/// A handle to utreexod that we keep around to answer utreexo specific queries
+#[derive(Clone)]
+pub struct UtreexoInfo {
+    rpc: Arc<Client>,
+}
+
+impl UtreexoInfo {
+    /// Returns the accumulator roots at our node's tip, exactly as utreexod returns them
+    pub fn roots(&self) -> Result<serde_json::Value, Error> {
+        let best: BlockHash = call(&self.rpc, "getbestblockhash", &[])?;
+        Ok(call(&self.rpc, "getutreexoroots", &[json!(best)])?)
+    }
+}
+
+pub struct Utreexod {
+    rpc: Arc<Client>,
+    wallet: LocalWallet,
+}
+
+impl Utreexod {
+    pub fn new(rpc: Client, wallet: LocalWallet) -> Self {
+        Self {
+            rpc: Arc::new(rpc),
+            wallet,
+        }
+    }
+
+    pub fn info(&self) -> UtreexoInfo {
+        UtreexoInfo {
+            rpc: self.rpc.clone(),
+        }
+    }
+
+    fn get_transaction(&self, txid: &Txid) -> Result<Transaction, Error> {
+        let hex: String = call(&self.rpc, "getrawtransaction", &[json!(txid), json!(0)])?;
+        transaction_from_hex(&hex)
+    }
+
+    /// Returns all transactions touching our address
+    fn our_transactions(&self) -> Result<Vec<Transaction>, Error> {
+        let address = self.wallet.address().to_string();
+        let mut transactions = vec![];
+
+        loop {
+            let page: Vec<String> = call(
+                &self.rpc,
+                "searchrawtransactions",
+                &[
+                    json!(address),
+                    json!(0),
+                    json!(transactions.len()),
+                    json!(SEARCH_PAGE_SIZE),
+                ],
+            )?;
+
+            let count = page.len();
+            for hex in page {
+                transactions.push(transaction_from_hex(&hex)?);
+            }
+
+            if count < SEARCH_PAGE_SIZE {
+                return Ok(transactions);
+            }
+        }
+    }
+
+    /// Fetches all outputs spent by `tx`
+    fn get_prevouts(&self, tx: &Transaction) -> Result<Vec<TxOut>, Error> {
+        tx.input
+            .iter()
+            .map(|input| {
+                let prev = self.get_transaction(&input.previous_output.txid)?;
+                prev.output
+                    .get(input.previous_output.vout as usize)
+                    .cloned()
+                    .ok_or(Error::JsonRpcNotWorking)
+            })
+            .collect()
+    }
+}
+
+impl ChainBackend for Utreexod {
+    fn list_unspent(&self) -> Result<Vec<Utxo>, Error> {
+        let script = self.wallet.address().script_pubkey();
+        let mut utxos = vec![];
+
+        for tx in self.our_transactions()? {
+            let txid = tx.txid();
+            for (vout, output) in tx.output.iter().enumerate() {
+                if output.script_pubkey != script {
+                    continue;
+                }
+
+                // gettxout returns null for spent outputs
+                let unspent: Option<serde_json::Value> = call(
+                    &self.rpc,
+                    "gettxout",
+                    &[json!(txid), json!(vout), json!(true)],
+                )?;
+
+                if let Some(unspent) = unspent {
+                    utxos.push(Utxo {
+                        txid,
+                        vout: vout as u32,
+                        amount: output.value,
+                        confirmations: unspent["confirmations"].as_u64().unwrap_or(0) as u32,
+                        coinbase: tx.is_coinbase(),
+                    });
+                }
+            }
+        }
+
+        Ok(utxos)
+    }
+
+    fn create_transaction(
+        &self,
+        inputs: &[Utxo],
+        outputs: &[(Address, Amount)],
+    ) -> Result<Transaction, Error> {
+        let lock_time = anti_fee_sniping(self.block_height()?);
+        Ok(self.wallet.create_transaction(inputs, outputs, lock_time))
+    }
+
+    fn sign_transaction(&self, tx: &Transaction) -> Result<Transaction, Error> {
+        self.wallet.sign_transaction(tx, &self.get_prevouts(tx)?)
+    }
+
+    fn create_psbt(&self, tx: &Transaction) -> Result<Psbt, Error> {
+        self.wallet.create_psbt(tx, &self.get_prevouts(tx)?)
+    }
+
+    fn finalize_psbt(&self, psbt: Psbt) -> Result<Transaction, Error> {
+        self.wallet.finalize_psbt(psbt)
+    }
+
+    fn sign_psbt(&self, mut psbt: Psbt) -> Result<Psbt, Error> {
+        let script = self.wallet.address().script_pubkey();
+        for (txin, input) in psbt.unsigned_tx.input.iter().zip(psbt.inputs.iter_mut()) {
+            if input.witness_utxo.is_none() {
+                let outpoint = txin.previous_output;
+                let prevout = self
+                    .get_transaction(&outpoint.txid)?
+                    .output
+                    .get(outpoint.vout as usize)
+                    .cloned()
+                    .ok_or(Error::JsonRpcNotWorking)?;
+                input.witness_utxo =
+                    Some(prevout).filter(|prevout| prevout.script_pubkey == script);
+            }
+        }
+
+        self.wallet.sign_psbt(psbt)
+    }
+
+    fn broadcast_transaction(&self, tx: &Transaction) -> Result<Txid, Error> {
+        match call(&self.rpc, "sendrawtransaction", &[json!(serialize_hex(tx))]) {
+            Ok(txid) => Ok(txid),
+            Err(e) if is_conflict(&e.to_string()) => Err(Error::InputsSpent),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn get_balance(&self) -> Result<Amount, Error> {
+        Ok(self.list_unspent()?.iter().map(|utxo| utxo.amount).sum())
+    }
+
+    fn block_height(&self) -> Result<u32, Error> {
+        let height: u64 = call(&self.rpc, "getblockcount", &[])?;
+        Ok(height as u32)
+    }
+
+    fn transaction_status(&self, txid: &Txid) -> Result<TxState, Error> {
+        let tx: serde_json::Value =
+            match call(&self.rpc, "getrawtransaction", &[json!(txid), json!(1)]) {
+                Ok(tx) => tx,
+                // we have no wallet to remember what conflicted with it
+                Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e)))
+                    if e.code == RPC_NO_TX_INFO =>
+                {
+                    return Ok(TxState::Evicted)
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+        let confirmations = tx["confirmations"].as_u64().unwrap_or(0) as u32;
+        let block_hash = tx["blockhash"].as_str().and_then(|hash| hash.parse().ok());
+
+        match (confirmations, block_hash) {
+            // btcd doesn't tell the height, but we can work it out
+            (1.., Some(block_hash)) => Ok(TxState::Confirmed {
+                block_hash,
+                height: (self.block_height()? + 1).saturating_sub(confirmations),
+                confirmations,
+            }),
+            _ => Ok(TxState::Unconfirmed),
+        }
+    }
+
+    fn estimate_fee(&self, target: u16) -> Result<Option<FeeRate>, Error> {
+        // like btcd, this returns BTC/kvB and -1 when it doesn't know
+        let rate: f64 = call(&self.rpc, "estimatefee", &[json!(target)])?;
+        if rate <= 0.0 {
+            return Ok(None);
+        }
+
+        let sat_per_kvb = (rate * 100_000_000.0).ceil() as u64;
+        Ok(Some(FeeRate::from_sat_per_kwu(sat_per_kvb / 4)))
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Error> {
+        let entry: serde_json::Value = match call(&self.rpc, "getmempoolentry", &[json!(txid)]) {
+            Ok(entry) => entry,
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e)))
+                if e.code == RPC_NO_TX_INFO =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // btcd gives the fee in BTC
+        let (Some(fee), Some(vsize)) = (entry["fee"].as_f64(), entry["vsize"].as_u64()) else {
+            return Err(Error::JsonRpcNotWorking);
+        };
+        Ok(Some(MempoolEntry {
+            fee: Amount::from_btc(fee).map_err(|_| Error::JsonRpcNotWorking)?,
+            vsize,
+        }))
+    }
+
+    fn mempool_rejects(&self, tx: &Transaction) -> Result<Option<String>, Error> {
+        let results: serde_json::Value = match call(
+            &self.rpc,
+            "testmempoolaccept",
+            &[json!([serialize_hex(tx)])],
+        ) {
+            Ok(results) => results,
+            // older versions don't have it
+            Err(bitcoincore_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(e)))
+                if e.code == RPC_METHOD_NOT_FOUND =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let result = &results[0];
+        match result["allowed"].as_bool() {
+            Some(true) => Ok(None),
+            Some(false) => Ok(Some(
+                result["reject-reason"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            )),
+            None => Err(Error::JsonRpcNotWorking),
+        }
+    }
+
+    fn silent_payment_key(&self, inputs: &[Utxo]) -> Result<SecretKey, Error> {
+        self.wallet.silent_payment_key(inputs.len())
+    }
+
+    fn receive_address(&self) -> Result<Address, Error> {
+        Ok(self.wallet.address().clone())
+    }
+}
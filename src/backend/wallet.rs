@@ -0,0 +1,204 @@
+//SPDX-License-Identifier: MIT
+
+//! A tiny single-key wallet for backends that don't have a wallet of their own, like
+//! Esplora. All coins live in a P2WPKH address derived from a WIF private key.
+
+use bitcoin::absolute::LockTime;
+use bitcoin::ecdsa;
+use bitcoin::secp256k1::All;
+use bitcoin::secp256k1::Message;
+use bitcoin::secp256k1::Scalar;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::sighash::EcdsaSighashType;
+use bitcoin::sighash::SighashCache;
+use bitcoin::transaction::Version;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::Network;
+use bitcoin::OutPoint;
+use bitcoin::PrivateKey;
+use bitcoin::Psbt;
+use bitcoin::ScriptBuf;
+use bitcoin::Sequence;
+use bitcoin::Transaction;
+use bitcoin::TxIn;
+use bitcoin::TxOut;
+use bitcoin::Witness;
+
+use super::Utxo;
+use crate::api::Error;
+
+pub struct LocalWallet {
+    secp: Secp256k1<All>,
+    key: PrivateKey,
+    address: Address,
+}
+
+impl LocalWallet {
+    /// Creates a wallet from a WIF encoded private key
+    pub fn from_wif(wif: &str, network: Network) -> anyhow::Result<Self> {
+        let secp = Secp256k1::new();
+        let key = PrivateKey::from_wif(wif)?;
+        let address = Address::p2wpkh(&key.public_key(&secp), network)?;
+
+        Ok(Self { secp, key, address })
+    }
+
+    /// The address holding all our coins
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// Our key, added up once for each of `inputs` spending our coins, as silent payments want it
+    pub fn silent_payment_key(&self, inputs: usize) -> Result<SecretKey, Error> {
+        let key = self.key.inner;
+        if inputs == 0 {
+            return Err(Error::OutOfMoney);
+        }
+
+        (1..inputs)
+            .try_fold(key, |sum, _| sum.add_tweak(&Scalar::from(key)))
+            .map_err(|_| Error::SigningFailed)
+    }
+
+    /// Builds an unsigned, replaceable transaction, that can't be mined before `lock_time`
+    pub fn create_transaction(
+        &self,
+        inputs: &[Utxo],
+        outputs: &[(Address, Amount)],
+        lock_time: LockTime,
+    ) -> Transaction {
+        let input = inputs
+            .iter()
+            .map(|utxo| TxIn {
+                previous_output: OutPoint::new(utxo.txid, utxo.vout),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            })
+            .collect();
+
+        let output = outputs
+            .iter()
+            .map(|(address, amount)| TxOut {
+                value: *amount,
+                script_pubkey: address.script_pubkey(),
+            })
+            .collect();
+
+        Transaction {
+            version: Version::TWO,
+            lock_time,
+            input,
+            output,
+        }
+    }
+
+    /// Signs every input of `tx`. `prevouts` must be the outputs being spent, in the same
+    /// order as the inputs.
+    pub fn sign_transaction(
+        &self,
+        tx: &Transaction,
+        prevouts: &[TxOut],
+    ) -> Result<Transaction, Error> {
+        let mut signed = tx.clone();
+        let pubkey = self.key.public_key(&self.secp);
+        let mut cache = SighashCache::new(&mut signed);
+
+        for (index, prevout) in prevouts.iter().enumerate() {
+            if prevout.script_pubkey != self.address.script_pubkey() {
+                return Err(Error::SigningFailed);
+            }
+
+            let sighash = cache
+                .p2wpkh_signature_hash(
+                    index,
+                    &prevout.script_pubkey,
+                    prevout.value,
+                    EcdsaSighashType::All,
+                )
+                .map_err(|_| Error::SigningFailed)?;
+
+            let signature = self.secp.sign_ecdsa(
+                &Message::from_digest_slice(sighash.as_ref()).map_err(|_| Error::SigningFailed)?,
+                &self.key.inner,
+            );
+
+            let signature = ecdsa::Signature {
+                sig: signature,
+                hash_ty: EcdsaSighashType::All,
+            };
+
+            *cache.witness_mut(index).ok_or(Error::SigningFailed)? =
+                Witness::p2wpkh(&signature, &pubkey.inner);
+        }
+
+        Ok(signed)
+    }
+
+    /// Wraps `tx` into a PSBT, `prevouts` must be the outputs being spent, in the same
+    /// order as the inputs.
+    pub fn create_psbt(&self, tx: &Transaction, prevouts: &[TxOut]) -> Result<Psbt, Error> {
+        let mut psbt = Psbt::from_unsigned_tx(tx.clone()).map_err(|_| Error::SigningFailed)?;
+
+        for (input, prevout) in psbt.inputs.iter_mut().zip(prevouts) {
+            input.witness_utxo = Some(prevout.clone());
+        }
+
+        Ok(psbt)
+    }
+
+    /// Signs the inputs of `psbt` spending our coins, which must have their `witness_utxo`, and
+    /// leaves the others alone
+    pub fn sign_psbt(&self, mut psbt: Psbt) -> Result<Psbt, Error> {
+        let script = self.address.script_pubkey();
+        let pubkey = self.key.public_key(&self.secp);
+        let mut cache = SighashCache::new(&psbt.unsigned_tx);
+
+        let mut witnesses = vec![];
+        for (index, input) in psbt.inputs.iter().enumerate() {
+            let Some(prevout) = &input.witness_utxo else {
+                continue;
+            };
+            if prevout.script_pubkey != script {
+                continue;
+            }
+
+            let sighash = cache
+                .p2wpkh_signature_hash(index, &script, prevout.value, EcdsaSighashType::All)
+                .map_err(|_| Error::SigningFailed)?;
+            let signature = self.secp.sign_ecdsa(
+                &Message::from_digest_slice(sighash.as_ref()).map_err(|_| Error::SigningFailed)?,
+                &self.key.inner,
+            );
+            let signature = ecdsa::Signature {
+                sig: signature,
+                hash_ty: EcdsaSighashType::All,
+            };
+            witnesses.push((index, Witness::p2wpkh(&signature, &pubkey.inner)));
+        }
+
+        for (index, witness) in witnesses {
+            psbt.inputs[index].final_script_witness = Some(witness);
+        }
+        Ok(psbt)
+    }
+
+    /// Builds the final witnesses from the signatures someone else put in `psbt`
+    pub fn finalize_psbt(&self, psbt: Psbt) -> Result<Transaction, Error> {
+        let pubkey = self.key.public_key(&self.secp);
+        let mut tx = psbt.unsigned_tx;
+
+        for (txin, input) in tx.input.iter_mut().zip(psbt.inputs) {
+            let signature = input
+                .partial_sigs
+                .get(&pubkey)
+                .ok_or(Error::SigningFailed)?;
+
+            txin.witness = Witness::p2wpkh(signature, &pubkey.inner);
+        }
+
+        Ok(tx)
+    }
+}
@@ -0,0 +1,283 @@
+//SPDX-License-Identifier: MIT
+
+//! Checks that whoever asks for coins owns the address they go to, with a signed message.
+//! Segwit addresses sign with the BIP322 "simple" format: a base64 witness spending a virtual
+//! output locked to the address. We understand P2WPKH and key-path P2TR witnesses, which is what
+//! wallets sign with. P2PKH addresses use the old signmessage format, which BIP322 accepts for
+//! them.
+
+use bitcoin::absolute::LockTime;
+use bitcoin::base64::engine::general_purpose::STANDARD;
+use bitcoin::base64::Engine;
+use bitcoin::consensus::Decodable;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::HashEngine;
+use bitcoin::opcodes::all::OP_RETURN;
+use bitcoin::opcodes::OP_0;
+use bitcoin::script::Builder;
+use bitcoin::secp256k1::Message;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::secp256k1::XOnlyPublicKey;
+use bitcoin::sighash::Prevouts;
+use bitcoin::sighash::SighashCache;
+use bitcoin::sign_message::signed_msg_hash;
+use bitcoin::sign_message::MessageSignature;
+use bitcoin::transaction::Version;
+use bitcoin::Address;
+use bitcoin::AddressType;
+use bitcoin::Amount;
+use bitcoin::OutPoint;
+use bitcoin::PublicKey;
+use bitcoin::ScriptBuf;
+use bitcoin::Sequence;
+use bitcoin::Transaction;
+use bitcoin::TxIn;
+use bitcoin::TxOut;
+use bitcoin::Witness;
+
+const TAG: &[u8] = b"BIP0322-signed-message";
+
+/// What users sign to prove they own `address`
+pub fn payout_message(address: &Address) -> String {
+    format!("faucet payout to {address}")
+}
+
+/// The BIP340 tagged hash of `message`
+fn message_hash(message: &str) -> [u8; 32] {
+    let tag = sha256::Hash::hash(TAG);
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_byte_array());
+    engine.input(tag.as_byte_array());
+    engine.input(message.as_bytes());
+
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// The virtual transaction paying to `script_pubkey`, committing to `message`
+fn to_spend(script_pubkey: ScriptBuf, message: &str) -> Transaction {
+    Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new()
+                .push_opcode(OP_0)
+                .push_slice(message_hash(message))
+                .into_script(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey,
+        }],
+    }
+}
+
+/// The virtual transaction the signature is for, spending `to_spend`
+fn to_sign(to_spend: &Transaction) -> Transaction {
+    Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(to_spend.txid(), 0),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+        }],
+    }
+}
+
+/// Whether `signature` signs `message` for `address`. Anything we can't parse is not
+pub fn verify(address: &Address, message: &str, signature: &str) -> bool {
+    if address.address_type() == Some(AddressType::P2pkh) {
+        return MessageSignature::from_base64(signature)
+            .and_then(|signature| {
+                signature.is_signed_by_address(
+                    &Secp256k1::verification_only(),
+                    address,
+                    signed_msg_hash(message),
+                )
+            })
+            .unwrap_or(false);
+    }
+
+    let Ok(witness) = STANDARD.decode(signature) else {
+        return false;
+    };
+    let Ok(witness) = Witness::consensus_decode(&mut witness.as_slice()) else {
+        return false;
+    };
+
+    let script_pubkey = address.script_pubkey();
+    let to_spend = to_spend(script_pubkey.clone(), message);
+    let mut cache = SighashCache::new(to_sign(&to_spend));
+    let secp = Secp256k1::verification_only();
+
+    match address.address_type() {
+        Some(AddressType::P2wpkh) => {
+            let (Some(signature), Some(pubkey), 2) =
+                (witness.nth(0), witness.nth(1), witness.len())
+            else {
+                return false;
+            };
+            let (Ok(signature), Ok(pubkey)) = (
+                bitcoin::ecdsa::Signature::from_slice(signature),
+                PublicKey::from_slice(pubkey),
+            ) else {
+                return false;
+            };
+            if pubkey
+                .wpubkey_hash()
+                .map(|hash| ScriptBuf::new_p2wpkh(&hash))
+                != Some(script_pubkey.clone())
+            {
+                return false;
+            }
+
+            let Ok(sighash) =
+                cache.p2wpkh_signature_hash(0, &script_pubkey, Amount::ZERO, signature.hash_ty)
+            else {
+                return false;
+            };
+
+            secp.verify_ecdsa(
+                &Message::from_digest(sighash.to_byte_array()),
+                &signature.sig,
+                &pubkey.inner,
+            )
+            .is_ok()
+        }
+        Some(AddressType::P2tr) => {
+            let (Some(signature), 1) = (witness.nth(0), witness.len()) else {
+                return false;
+            };
+            let (Ok(signature), Ok(output_key)) = (
+                bitcoin::taproot::Signature::from_slice(signature),
+                XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..]),
+            ) else {
+                return false;
+            };
+
+            let Ok(sighash) = cache.taproot_key_spend_signature_hash(
+                0,
+                &Prevouts::All(&to_spend.output),
+                signature.hash_ty,
+            ) else {
+                return false;
+            };
+
+            secp.verify_schnorr(
+                &signature.sig,
+                &Message::from_digest(sighash.to_byte_array()),
+                &output_key,
+            )
+            .is_ok()
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::hex::DisplayHex;
+    use bitcoin::Network;
+
+    use super::*;
+
+    // BIP322's test vectors, for mainnet addresses we pay the signet ones of
+    const P2WPKH: &str = "bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l";
+    const P2WPKH_EMPTY: &str = "AkcwRAIgM2gBAQqvZX15ZiysmKmQpDrG83avLIT492QBzLnQIxYCIBaTpOaD20qRlEylyxFSeEA2ba9YOixpX8z46TSDtS40ASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+    const P2WPKH_HELLO: &str = "AkcwRAIgZRfIY3p7/DoVTty6YZbWS71bc5Vct9p9Fia83eRmw2QCICK/ENGfwLtptFluMGs2KsqoNSk89pO7F29zJLUx9a/sASECx/EgAxlkQpQ9hYjgGu6EBCPMVPwVIVJqO4XCsMvViHI=";
+    const P2TR: &str = "bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3";
+    const P2TR_HELLO: &str =
+        "AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==";
+
+    // from Bitcoin Core's signmessage tests
+    const P2PKH: &str = "mpLQjfK79b7CCV4VMJWEWAj5Mpx8Up5zxB";
+    const P2PKH_MESSAGE: &str = "This is just a test message";
+    const P2PKH_SIGNATURE: &str =
+        "INbVnW4e6PeRmsv2Qgu8NuopvrVjkcxob+sX8OcZG0SALhWybUjzMLPdAsXI46YZGb0KQTRii+wWIQzRpG/U+S0=";
+
+    /// `address`, for signet
+    fn signet(address: &str) -> Address {
+        let address = Address::from_str(address).unwrap().assume_checked();
+        Address::from_script(&address.script_pubkey(), Network::Signet).unwrap()
+    }
+
+    #[test]
+    fn hashes_messages() {
+        assert_eq!(
+            message_hash("").to_lower_hex_string(),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            message_hash("Hello World").to_lower_hex_string(),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+    }
+
+    #[test]
+    fn verifies_p2wpkh_signatures() {
+        let address = signet(P2WPKH);
+        assert!(address.to_string().starts_with("tb1q"));
+
+        assert!(verify(&address, "", P2WPKH_EMPTY));
+        assert!(verify(&address, "Hello World", P2WPKH_HELLO));
+    }
+
+    #[test]
+    fn verifies_p2tr_signatures() {
+        assert!(verify(&signet(P2TR), "Hello World", P2TR_HELLO));
+    }
+
+    #[test]
+    fn verifies_legacy_p2pkh_signatures() {
+        let address = Address::from_str(P2PKH)
+            .unwrap()
+            .require_network(Network::Signet)
+            .unwrap();
+
+        assert!(verify(&address, P2PKH_MESSAGE, P2PKH_SIGNATURE));
+        assert!(!verify(&address, "another message", P2PKH_SIGNATURE));
+    }
+
+    #[test]
+    fn refuses_signatures_for_other_messages() {
+        assert!(!verify(&signet(P2WPKH), "Hello World", P2WPKH_EMPTY));
+        assert!(!verify(&signet(P2WPKH), "", P2WPKH_HELLO));
+        assert!(!verify(&signet(P2TR), "", P2TR_HELLO));
+    }
+
+    #[test]
+    fn refuses_signatures_by_other_addresses() {
+        // the same key, but for another address
+        assert!(!verify(&signet(P2TR), "Hello World", P2WPKH_HELLO));
+        assert!(!verify(&signet(P2WPKH), "Hello World", P2TR_HELLO));
+
+        let other = signet("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4");
+        assert!(!verify(&other, "Hello World", P2WPKH_HELLO));
+    }
+
+    #[test]
+    fn refuses_malformed_signatures() {
+        for address in [signet(P2WPKH), signet(P2TR)] {
+            assert!(!verify(&address, "Hello World", ""));
+            assert!(!verify(&address, "Hello World", "not base64!"));
+            assert!(!verify(&address, "Hello World", "AAAA"));
+            // cut short
+            assert!(!verify(&address, "Hello World", &P2WPKH_HELLO[..40]));
+        }
+
+        let address = Address::from_str(P2PKH).unwrap().assume_checked();
+        assert!(!verify(&address, P2PKH_MESSAGE, "not base64!"));
+        assert!(!verify(&address, P2PKH_MESSAGE, P2WPKH_HELLO));
+    }
+}
@@ -0,0 +1,392 @@
+//SPDX-License-Identifier: MIT
+
+//! Fee bumping for payouts stuck in the mempool. Our payouts signal replaceability, and we keep
+//! track of the ones that haven't confirmed. Those that are still waiting `after` blocks after
+//! we first saw them get replaced by the same transaction paying more, the difference taken
+//! from its change. Admins can bump a payout right away at /admin/bump/{txid}.
+//!
+//! Replacements pay what we'd pay for a new payout now, but at least what BIP125 asks of them:
+//! the old fee plus 1 sat/vB. Payouts without change can't be bumped. We keep what we need to
+//! replace a payout in memory, so a restart forgets them.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::web;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::Txid;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::api::blocking;
+use crate::api::make_transaction;
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+use crate::backend::TxState;
+use crate::backend::Utxo;
+use crate::fees;
+use crate::scheduler;
+use crate::scheduler::Schedule;
+
+/// How often we look at the payouts that haven't confirmed, unless operators pick another
+/// schedule
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What replacements pay over the transaction they replace, per vbyte
+const INCREMENTAL_FEE_RATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(1);
+
+/// What we need to replace a payout
+pub struct Pending {
+    inputs: Vec<Utxo>,
+    /// The payout's outputs, without the change
+    outputs: Vec<(Address, Amount)>,
+    change: Option<(Address, Amount)>,
+    /// What goes in its OP_RETURN output, if it has one
+    memo: Option<Vec<u8>>,
+    fee: Amount,
+    /// The height we first saw it unconfirmed at, once we looked
+    since: Option<u32>,
+}
+
+impl Pending {
+    pub fn new(
+        inputs: Vec<Utxo>,
+        outputs: Vec<(Address, Amount)>,
+        change: Option<(Address, Amount)>,
+        memo: Option<Vec<u8>>,
+        fee: Amount,
+    ) -> Self {
+        Self {
+            inputs,
+            outputs,
+            change,
+            memo,
+            fee,
+            since: None,
+        }
+    }
+}
+
+pub struct Bumper {
+    /// How many blocks a payout may wait before we bump it, if we do on our own
+    after: Option<u32>,
+    pending: Mutex<HashMap<Txid, Pending>>,
+}
+
+/// What a bump did
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Bumped {
+    /// The replacement's txid
+    #[schema(value_type = String)]
+    pub txid: Txid,
+    #[schema(value_type = String)]
+    pub replaces: Txid,
+    /// What the replacement pays, in sats
+    pub fee: u64,
+}
+
+impl Bumper {
+    pub fn new(after: Option<u32>) -> Self {
+        Self {
+            after,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Keeps what we need to bump `txid`, until it confirms
+    pub fn watch(&self, txid: Txid, pending: Pending) {
+        self.pending.lock().unwrap().insert(txid, pending);
+    }
+
+    /// Whether `txid` is a payout we're waiting on
+    pub fn watches(&self, txid: &Txid) -> bool {
+        self.pending.lock().unwrap().contains_key(txid)
+    }
+
+    /// Whether every payout we know about confirmed
+    pub fn is_idle(&self) -> bool {
+        self.pending.lock().unwrap().is_empty()
+    }
+}
+
+/// Replaces `txid` with a transaction paying more, and tells everyone following it
+pub fn bump<B: ChainBackend>(data: &AppState<B>, txid: Txid) -> Result<Bumped, Error> {
+    let mut pending = data
+        .bumper
+        .pending
+        .lock()
+        .unwrap()
+        .remove(&txid)
+        .ok_or(Error::UnknownTransaction)?;
+
+    let (replacement, fee) = match replace(data, &mut pending) {
+        Ok(replaced) => replaced,
+        Err(e) => {
+            // we may be asked again, once there's something to bump it with
+            data.bumper.watch(txid, pending);
+            return Err(e);
+        }
+    };
+    info!("bumped payout {txid} to {replacement}, paying {fee}");
+
+    if let Err(e) = data.db.replace_payout(&txid, &replacement) {
+        warn!("couldn't record that {replacement} replaced payout {txid}: {e}");
+    }
+    #[cfg(feature = "zmq")]
+    data.tracker.track(replacement);
+    #[cfg(feature = "webhooks")]
+    if let Some(webhooks) = &data.webhooks {
+        webhooks.replace(txid, replacement);
+    }
+
+    pending.since = None;
+    data.bumper.watch(replacement, pending);
+
+    Ok(Bumped {
+        txid: replacement,
+        replaces: txid,
+        fee: fee.to_sat(),
+    })
+}
+
+/// Broadcasts `pending` again, paying more, and updates it to what we sent. Returns the
+/// replacement's txid and fee
+fn replace<B: ChainBackend>(
+    data: &AppState<B>,
+    pending: &mut Pending,
+) -> Result<(Txid, Amount), Error> {
+    let (change_address, change) = pending
+        .change
+        .clone()
+        .ok_or_else(|| Error::CantBump("it has no change to pay more with".into()))?;
+
+    // a memo's output is about the size of two others
+    let outputs = pending.outputs.len() + 1 + if pending.memo.is_some() { 2 } else { 0 };
+    let feerate = data.fees.feerate(&data.backend)?;
+    let fee = fees::transaction_fee(feerate, pending.inputs.len(), outputs).max(
+        pending.fee + fees::transaction_fee(INCREMENTAL_FEE_RATE, pending.inputs.len(), outputs),
+    );
+    let ceiling = fees::transaction_fee(data.fees.ceiling, pending.inputs.len(), outputs);
+    if fee > ceiling {
+        return Err(Error::CantBump(
+            "it would pay more than our fee ceiling".into(),
+        ));
+    }
+
+    // what's left of the change once it pays the difference, if it's still worth an output, like
+    // when we select coins
+    let kept = change
+        .checked_sub(fee - pending.fee)
+        .ok_or_else(|| Error::CantBump("its change can't pay the new fee".into()))?;
+    let kept = Some(kept).filter(|kept| *kept >= data.coin_selection.min_change);
+    let mut outputs = pending.outputs.clone();
+    if let Some(kept) = kept {
+        outputs.push((change_address.clone(), kept));
+    }
+
+    let tx = make_transaction(
+        &data.backend,
+        &pending.inputs,
+        &outputs,
+        pending.memo.as_deref(),
+    )?;
+    let txid = data.backend.broadcast_transaction(&tx)?;
+
+    let fee = pending.fee + change - kept.unwrap_or(Amount::ZERO);
+    pending.change = kept.map(|kept| (change_address, kept));
+    pending.fee = fee;
+
+    Ok((txid, fee))
+}
+
+/// Bumps `txid` right away, if it hasn't confirmed
+#[utoipa::path(
+    post,
+    path = "/v1/admin/bump/{txid}",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    params(("txid" = String, Path, description = "The payout's txid")),
+    responses(
+        (status = 200, body = Bumped),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn bump_payout<B: ChainBackend>(
+    txid: web::Path<Txid>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Bumped>, Error> {
+    let txid = txid.into_inner();
+    Ok(web::Json(
+        blocking(&data, move |data| bump(data, txid)).await?,
+    ))
+}
+
+/// Looks at the payouts that haven't confirmed, forgetting those that did and bumping those
+/// that waited too long
+fn check_pending<B: ChainBackend>(data: &AppState<B>) -> Result<(), Error> {
+    let height = data.backend.block_height()?;
+
+    let txids = data
+        .bumper
+        .pending
+        .lock()
+        .unwrap()
+        .keys()
+        .copied()
+        .collect::<Vec<_>>();
+    for txid in txids {
+        let waited = match data.backend.transaction_status(&txid) {
+            // our wallet may broadcast evicted transactions again, so they're still pending, and
+            // may need a bump to stay in the mempool
+            Ok(TxState::Unconfirmed | TxState::Evicted) => {
+                let mut pending = data.bumper.pending.lock().unwrap();
+                let Some(pending) = pending.get_mut(&txid) else {
+                    continue;
+                };
+                height.saturating_sub(*pending.since.get_or_insert(height))
+            }
+            Ok(TxState::Confirmed { .. }) => {
+                data.bumper.pending.lock().unwrap().remove(&txid);
+                continue;
+            }
+            Ok(TxState::Replaced { .. }) => {
+                data.bumper.pending.lock().unwrap().remove(&txid);
+                continue;
+            }
+            Err(e) => {
+                warn!("couldn't check payout {txid}: {e}");
+                continue;
+            }
+        };
+
+        if data.bumper.after.is_some_and(|after| waited >= after) {
+            if let Err(e) = bump(data, txid) {
+                warn!("couldn't bump payout {txid}, waiting for {waited} blocks: {e}");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks our unconfirmed payouts every so often
+pub fn spawn_bumper<B: ChainBackend>(data: &web::Data<AppState<B>>) {
+    scheduler::register(data, "bump", Schedule::Every(CHECK_INTERVAL), check_pending);
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+    use crate::testing::address;
+    use crate::testing::app_state;
+    use crate::testing::coin;
+    use crate::testing::MockNode;
+    use crate::testing::CHANGE_ADDRESS;
+
+    const PAYOUT: &str = "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7";
+    const FEE: Amount = Amount::from_sat(200);
+
+    fn txid() -> Txid {
+        Txid::from_byte_array([7; 32])
+    }
+
+    fn pending(change: Option<u64>) -> Pending {
+        Pending::new(
+            vec![coin(0, Amount::ONE_BTC)],
+            vec![(address(PAYOUT), Amount::from_sat(10_000))],
+            change.map(|change| (address(CHANGE_ADDRESS), Amount::from_sat(change))),
+            None,
+            FEE,
+        )
+    }
+
+    /// What a payout with change pays at `feerate` sat/vB
+    fn fee(feerate: u64) -> Amount {
+        fees::transaction_fee(FeeRate::from_sat_per_vb_unchecked(feerate), 1, 2)
+    }
+
+    #[test]
+    fn pays_more_from_the_change() {
+        let data = app_state(MockNode::default());
+        data.bumper.watch(txid(), pending(Some(50_000)));
+
+        let bumped = bump(&data, txid()).unwrap();
+        // our feerate is 1 sat/vB, so BIP125 asks for more
+        assert_eq!(bumped.fee, (FEE + fee(1)).to_sat());
+        assert_eq!(bumped.replaces, txid());
+        assert!(!data.bumper.watches(&txid()));
+        assert!(data.bumper.watches(&bumped.txid));
+
+        let pending = data.bumper.pending.lock().unwrap();
+        let replacement = &pending[&bumped.txid];
+        assert_eq!(
+            replacement.change.as_ref().unwrap().1.to_sat(),
+            50_000 - fee(1).to_sat()
+        );
+        assert_eq!(replacement.fee.to_sat(), bumped.fee);
+    }
+
+    #[test]
+    fn gives_small_change_to_the_miners() {
+        let data = app_state(MockNode::default());
+        let change = fee(1) + data.coin_selection.min_change - Amount::from_sat(1);
+        data.bumper.watch(txid(), pending(Some(change.to_sat())));
+
+        let bumped = bump(&data, txid()).unwrap();
+        assert_eq!(bumped.fee, (FEE + change).to_sat());
+        let pending = data.bumper.pending.lock().unwrap();
+        assert!(pending[&bumped.txid].change.is_none());
+    }
+
+    #[test]
+    fn keeps_what_it_cant_bump() {
+        let mut data = app_state(MockNode::default());
+        assert!(matches!(
+            bump(&data, txid()),
+            Err(Error::UnknownTransaction)
+        ));
+
+        for change in [None, Some(fee(1).to_sat() - 1)] {
+            data.bumper.watch(txid(), pending(change));
+            assert!(matches!(bump(&data, txid()), Err(Error::CantBump(_))));
+            assert!(data.bumper.watches(&txid()));
+        }
+
+        // nor pays more than our ceiling
+        data.fees.ceiling = FeeRate::from_sat_per_vb_unchecked(1);
+        data.bumper.watch(txid(), pending(Some(50_000)));
+        assert!(matches!(bump(&data, txid()), Err(Error::CantBump(_))));
+        assert_eq!(
+            data.backend
+                .broadcasts
+                .load(std::sync::atomic::Ordering::SeqCst),
+            0
+        );
+    }
+
+    #[test]
+    fn bumps_payouts_that_waited_too_long() {
+        let mut data = app_state(MockNode::default());
+        data.bumper = Bumper::new(Some(1));
+        data.bumper.watch(txid(), pending(Some(50_000)));
+
+        // our node is stuck at the same height, so it never waits a block
+        check_pending(&data).unwrap();
+        assert!(data.bumper.watches(&txid()));
+
+        data.bumper = Bumper::new(Some(0));
+        data.bumper.watch(txid(), pending(Some(50_000)));
+        check_pending(&data).unwrap();
+        assert!(!data.bumper.watches(&txid()));
+        assert!(!data.bumper.is_idle());
+    }
+}
@@ -0,0 +1,135 @@
+//SPDX-License-Identifier: MIT
+
+//! CAPTCHAs for /send/, with hCaptcha or Cloudflare Turnstile. The index page shows the
+//! provider's widget, which gives the user a token once they solve it. /send/ then asks the
+//! provider whether that token is good before paying. Both providers take the same form and
+//! answer in the same way, so they only differ in their urls.
+
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+use crate::api::Error;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Provider {
+    HCaptcha,
+    Turnstile,
+}
+
+impl Provider {
+    /// Parses the value of CAPTCHA_PROVIDER
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "hcaptcha" => Some(Provider::HCaptcha),
+            "turnstile" => Some(Provider::Turnstile),
+            _ => None,
+        }
+    }
+
+    fn verify_url(&self) -> &'static str {
+        match self {
+            Provider::HCaptcha => "https://api.hcaptcha.com/siteverify",
+            Provider::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+        }
+    }
+
+    fn script_url(&self) -> &'static str {
+        match self {
+            Provider::HCaptcha => "https://js.hcaptcha.com/1/api.js",
+            Provider::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/api.js",
+        }
+    }
+
+    /// The class the provider's script looks for to render its widget
+    fn widget_class(&self) -> &'static str {
+        match self {
+            Provider::HCaptcha => "h-captcha",
+            Provider::Turnstile => "cf-turnstile",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct VerifyResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+pub struct Captcha {
+    provider: Provider,
+    site_key: String,
+    secret_key: String,
+    agent: ureq::Agent,
+}
+
+impl Captcha {
+    pub fn new(provider: Provider, site_key: String, secret_key: String) -> Self {
+        Self {
+            provider,
+            site_key,
+            secret_key,
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// The html showing the widget, for the index page
+    pub fn widget(&self) -> String {
+        format!(
+            "<div class=\"{}\" data-sitekey=\"{}\"></div>\n<script src=\"{}\" async defer></script>",
+            self.provider.widget_class(),
+            self.site_key,
+            self.provider.script_url(),
+        )
+    }
+
+    /// Asks our provider whether `token` was given to someone who solved the CAPTCHA, and who
+    /// did it from `client`, if we know
+    pub async fn verify(&self, token: Option<String>, client: Option<IpAddr>) -> Result<(), Error> {
+        let Some(token) = token.filter(|token| !token.is_empty()) else {
+            return Err(Error::CaptchaFailed);
+        };
+
+        let request = self.agent.post(self.provider.verify_url());
+        let secret_key = self.secret_key.clone();
+        let response = actix_web::web::block(move || {
+            let client = client.map(|ip| ip.to_string()).unwrap_or_default();
+            let mut form = vec![
+                ("secret", secret_key.as_str()),
+                ("response", token.as_str()),
+            ];
+            if !client.is_empty() {
+                form.push(("remoteip", client.as_str()));
+            }
+
+            request
+                .send_form(&form)
+                .map_err(|e| Error::CaptchaError(e.to_string()))?
+                .into_json::<VerifyResponse>()
+                .map_err(|e| Error::CaptchaError(e.to_string()))
+        })
+        .await
+        .map_err(|e| Error::CaptchaError(e.to_string()))??;
+
+        if !response.success {
+            // these are about our own configuration, not the user's answer
+            let ours = [
+                "missing-input-secret",
+                "invalid-input-secret",
+                "sitekey-secret-mismatch",
+            ];
+            if let Some(e) = response
+                .error_codes
+                .iter()
+                .find(|e| ours.contains(&e.as_str()))
+            {
+                return Err(Error::CaptchaError(e.clone()));
+            }
+
+            return Err(Error::CaptchaFailed);
+        }
+
+        Ok(())
+    }
+}
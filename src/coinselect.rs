@@ -0,0 +1,315 @@
+//SPDX-License-Identifier: MIT
+
+//! Picking which of our coins pay for a transaction. Grabbing coins until there's enough makes
+//! change we don't need and, once the wallet is fragmented, transactions with dozens of inputs.
+//!
+//! Branch and bound, like Bitcoin Core's, looks for a set of coins adding up to what we send
+//! and its fee, or to a bit more than that, no more than a change output would cost us. Then we
+//! don't make change at all and what's left over goes to fees. When there's no such set, we fall
+//! back to largest first: the biggest coins until there's enough for the payouts plus a change
+//! target, so the change we make is big enough to pay for payouts of its own later.
+
+use std::cmp::Reverse;
+use std::str::FromStr;
+
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+
+use crate::api::Error;
+use crate::backend::Utxo;
+use crate::fees::fee;
+use crate::fees::transaction_fee;
+use crate::fees::INPUT_VSIZE;
+use crate::fees::OUTPUT_VSIZE;
+
+/// How many branches branch and bound looks at before giving up
+const MAX_TRIES: usize = 100_000;
+
+/// The least change we make, leftovers under this go to fees. It's about dust anyway
+pub const MIN_CHANGE: Amount = Amount::from_sat(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// The biggest coins first
+    LargestFirst,
+    /// An exact match if there's one, largest first if not
+    BranchAndBound,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        match strategy {
+            "largest-first" => Ok(Strategy::LargestFirst),
+            "bnb" | "branch-and-bound" => Ok(Strategy::BranchAndBound),
+            strategy => Err(format!("there's no {strategy} coin selection")),
+        }
+    }
+}
+
+/// How we select coins
+#[derive(Debug, Clone, Copy)]
+pub struct CoinSelection {
+    pub strategy: Strategy,
+    /// How much change we aim for, when we make change
+    pub change_target: Amount,
+    /// The least change we make, leftovers under it go to fees. Never under [MIN_CHANGE], nor
+    /// under what the change address considers dust
+    pub min_change: Amount,
+    /// How many confirmations a coin needs before we spend it, 0 spends those in the mempool
+    pub min_confirmations: u32,
+}
+
+/// The coins paying for a transaction
+#[derive(Debug)]
+pub struct Selection {
+    pub inputs: Vec<Utxo>,
+    /// What goes back to us, if it's worth an output
+    pub change: Option<Amount>,
+    /// What the transaction pays in fees
+    pub fee: Amount,
+}
+
+impl CoinSelection {
+    /// Whether we may spend `utxo`: it confirmed enough and, if it's from a coinbase, matured
+    pub fn spendable(&self, utxo: &Utxo) -> bool {
+        utxo.confirmations >= self.min_confirmations && utxo.is_mature()
+    }
+
+    /// Picks coins out of `utxos` paying for `outputs` outputs worth `amount`, plus the fee at
+    /// `feerate`. `outputs` doesn't count change, we only pay for its output if we make it.
+    /// Coins are counted for what they're worth once the fee for spending them is taken out, and
+    /// those costing more than that, or we may not spend, are left alone
+    pub fn select(
+        &self,
+        utxos: Vec<Utxo>,
+        amount: Amount,
+        outputs: usize,
+        feerate: FeeRate,
+    ) -> Result<Selection, Error> {
+        let input_fee = fee(feerate, INPUT_VSIZE);
+        let mut utxos = utxos
+            .into_iter()
+            .filter(|utxo| self.spendable(utxo))
+            .filter_map(|utxo| Some((utxo.amount.checked_sub(input_fee)?, utxo)))
+            .filter(|(effective, _)| *effective > Amount::ZERO)
+            .collect::<Vec<_>>();
+        utxos.sort_by_key(|(effective, _)| Reverse(*effective));
+
+        let target = amount + transaction_fee(feerate, 0, outputs);
+        let available: Amount = utxos.iter().map(|(effective, _)| *effective).sum();
+        if available < target {
+            return Err(Error::OutOfMoney);
+        }
+
+        if self.strategy == Strategy::BranchAndBound {
+            // what making change costs us, now and once we spend it
+            let cost_of_change = fee(feerate, OUTPUT_VSIZE) + input_fee;
+            if let Some(inputs) = branch_and_bound(&utxos, target, cost_of_change) {
+                let selected: Amount = inputs.iter().map(|utxo| utxo.amount).sum();
+                return Ok(Selection {
+                    inputs,
+                    change: None,
+                    fee: selected - amount,
+                });
+            }
+        }
+
+        let change_fee = fee(feerate, OUTPUT_VSIZE);
+        let mut effective = Amount::ZERO;
+        let mut inputs = vec![];
+        for (value, utxo) in utxos {
+            if effective >= target + change_fee + self.change_target {
+                break;
+            }
+            effective += value;
+            inputs.push(utxo);
+        }
+
+        let selected: Amount = inputs.iter().map(|utxo| utxo.amount).sum();
+        let change = effective
+            .checked_sub(target + change_fee)
+            .filter(|change| *change >= self.min_change);
+        Ok(Selection {
+            inputs,
+            change,
+            fee: selected - amount - change.unwrap_or(Amount::ZERO),
+        })
+    }
+}
+
+/// The set of `utxos`, with what each is worth to us and sorted by it, adding up to at least
+/// `target` with the least left over, if any leaves less than `cost_of_change`
+fn branch_and_bound(
+    utxos: &[(Amount, Utxo)],
+    target: Amount,
+    cost_of_change: Amount,
+) -> Option<Vec<Utxo>> {
+    let upper = target + cost_of_change;
+    // what the coins after each one add up to, to drop branches that can't reach the target
+    let mut remaining = vec![Amount::ZERO; utxos.len() + 1];
+    for i in (0..utxos.len()).rev() {
+        remaining[i] = remaining[i + 1] + utxos[i].0;
+    }
+
+    let mut best: Option<(Amount, Vec<usize>)> = None;
+    let mut selected = vec![];
+    let mut value = Amount::ZERO;
+    let mut tries = 0;
+    let mut i = 0;
+    loop {
+        tries += 1;
+        let backtrack = if value > upper || value + remaining[i] < target {
+            true
+        } else if value >= target {
+            let excess = value - target;
+            if best.as_ref().is_none_or(|(least, _)| excess < *least) {
+                best = Some((excess, selected.clone()));
+            }
+            true
+        } else {
+            i == utxos.len()
+        };
+
+        if tries > MAX_TRIES
+            || best
+                .as_ref()
+                .is_some_and(|(least, _)| *least == Amount::ZERO)
+        {
+            break;
+        }
+
+        if backtrack {
+            // take out the last coin we put in, and try going on without it
+            let Some(last) = selected.pop() else {
+                break;
+            };
+            value -= utxos[last].0;
+            i = last + 1;
+        } else {
+            selected.push(i);
+            value += utxos[i].0;
+            i += 1;
+        }
+    }
+
+    best.map(|(_, selected)| selected.into_iter().map(|i| utxos[i].1.clone()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::Txid;
+
+    use super::*;
+
+    const FEERATE: FeeRate = FeeRate::from_sat_per_vb_unchecked(1);
+    const AMOUNT: Amount = Amount::from_sat(10_000);
+
+    fn coin_selection(strategy: Strategy) -> CoinSelection {
+        CoinSelection {
+            strategy,
+            change_target: Amount::ZERO,
+            min_change: MIN_CHANGE,
+            min_confirmations: 0,
+        }
+    }
+
+    fn utxos(amounts: &[u64]) -> Vec<Utxo> {
+        amounts
+            .iter()
+            .enumerate()
+            .map(|(vout, amount)| Utxo {
+                txid: Txid::all_zeros(),
+                vout: vout as u32,
+                amount: Amount::from_sat(*amount),
+                confirmations: 1,
+                coinbase: false,
+            })
+            .collect()
+    }
+
+    /// A coin paying for [AMOUNT] and the fee of spending it alone into `outputs` outputs, with
+    /// `left` left over
+    fn coin_leaving(left: Amount, outputs: usize) -> u64 {
+        (AMOUNT + transaction_fee(FEERATE, 1, outputs) + left).to_sat()
+    }
+
+    #[test]
+    fn exact_match_makes_no_change() {
+        let exact = coin_leaving(Amount::ZERO, 1);
+        let selection = coin_selection(Strategy::BranchAndBound)
+            .select(utxos(&[1_000_000, exact]), AMOUNT, 1, FEERATE)
+            .unwrap();
+
+        assert_eq!(selection.inputs.len(), 1);
+        assert_eq!(selection.inputs[0].amount.to_sat(), exact);
+        assert_eq!(selection.change, None);
+        assert_eq!(selection.fee, transaction_fee(FEERATE, 1, 1));
+    }
+
+    #[test]
+    fn change_under_min_change_goes_to_fees() {
+        let coin = coin_leaving(MIN_CHANGE - Amount::from_sat(1), 2);
+        let selection = coin_selection(Strategy::LargestFirst)
+            .select(utxos(&[coin]), AMOUNT, 1, FEERATE)
+            .unwrap();
+
+        assert_eq!(selection.change, None);
+        assert_eq!(selection.fee, Amount::from_sat(coin) - AMOUNT);
+    }
+
+    #[test]
+    fn change_over_min_change_is_made() {
+        let change = MIN_CHANGE + Amount::from_sat(1);
+        let coin = coin_leaving(change, 2);
+        let selection = coin_selection(Strategy::LargestFirst)
+            .select(utxos(&[coin]), AMOUNT, 1, FEERATE)
+            .unwrap();
+
+        assert_eq!(selection.change, Some(change));
+    }
+
+    #[test]
+    fn fee_counts_change_only_when_made() {
+        let without = coin_selection(Strategy::LargestFirst)
+            .select(utxos(&[coin_leaving(Amount::ZERO, 1)]), AMOUNT, 1, FEERATE)
+            .unwrap();
+        assert_eq!(without.change, None);
+        assert_eq!(without.fee, transaction_fee(FEERATE, 1, 1));
+
+        let with = coin_selection(Strategy::LargestFirst)
+            .select(utxos(&[1_000_000]), AMOUNT, 1, FEERATE)
+            .unwrap();
+        assert!(with.change.is_some());
+        assert_eq!(with.fee, transaction_fee(FEERATE, 1, 2));
+        assert_eq!(
+            with.fee + with.change.unwrap() + AMOUNT,
+            Amount::from_sat(1_000_000)
+        );
+    }
+
+    #[test]
+    fn insufficient_funds() {
+        let short = coin_leaving(Amount::ZERO, 1) - 1;
+        for strategy in [Strategy::LargestFirst, Strategy::BranchAndBound] {
+            let selection = coin_selection(strategy).select(utxos(&[short]), AMOUNT, 1, FEERATE);
+            assert!(matches!(selection, Err(Error::OutOfMoney)));
+        }
+    }
+
+    #[test]
+    fn coins_worth_less_than_their_fee_are_left_alone() {
+        // spending these costs more than they're worth, which mustn't underflow
+        let dust = fee(FEERATE, INPUT_VSIZE).to_sat();
+        let selection =
+            coin_selection(Strategy::BranchAndBound).select(utxos(&[dust, 1]), AMOUNT, 1, FEERATE);
+        assert!(matches!(selection, Err(Error::OutOfMoney)));
+
+        let selection = coin_selection(Strategy::LargestFirst)
+            .select(utxos(&[dust, 1, 1_000_000]), AMOUNT, 1, FEERATE)
+            .unwrap();
+        assert_eq!(selection.inputs.len(), 1);
+    }
+}
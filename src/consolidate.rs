@@ -0,0 +1,116 @@
+//SPDX-License-Identifier: MIT
+
+//! Merging our small coins. Change and donations leave the wallet with lots of small coins, and
+//! every one of them a payout spends makes it bigger. So, while fees are low and nobody asked
+//! for coins in a while, we spend the coins under a threshold to a single one of our own.
+//!
+//! We only do that once there are enough of them to be worth a transaction, never while a
+//! payout we may have to bump is waiting to confirm, since its change may be among them, and
+//! never while the faucet is paused, as admins may be sweeping it. Consolidations pay little and
+//! may take a while to confirm, so we don't speed them up like stuck donations.
+
+use std::time::Duration;
+
+use actix_web::web;
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::Txid;
+use tracing::info;
+
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+use crate::fees;
+use crate::scheduler;
+use crate::scheduler::Schedule;
+
+/// How often we see whether we should consolidate, unless operators pick another schedule
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// How many blocks we're fine waiting for a consolidation to confirm within
+const CONFIRMATION_TARGET: u16 = 144;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Consolidation {
+    /// Coins worth less than this get merged
+    pub threshold: Amount,
+    /// How many of them there must be before we bother
+    pub min_inputs: usize,
+    /// How many we merge at once, to keep the transaction standard
+    pub max_inputs: usize,
+    /// The most we pay, per vbyte, we wait for fees to go down if they're over it
+    pub max_feerate: FeeRate,
+    /// How long it has been since our last payout, before we call it a quiet time
+    pub quiet_for: Duration,
+}
+
+/// Merges our small coins into one, if it's a good time to. Returns the consolidation's txid,
+/// if we made one
+fn consolidate<B: ChainBackend>(
+    data: &AppState<B>,
+    consolidation: &Consolidation,
+) -> Result<Option<Txid>, Error> {
+    if data.controls.check_running().is_err()
+        || !data.bumper.is_idle()
+        || !data.db.payouts_since(consolidation.quiet_for)?.is_empty()
+    {
+        return Ok(None);
+    }
+
+    let backend = &data.backend;
+    let feerate = backend
+        .estimate_fee(CONFIRMATION_TARGET)?
+        .unwrap_or(data.fees.floor)
+        .max(data.fees.floor);
+    if feerate > consolidation.max_feerate {
+        return Ok(None);
+    }
+
+    // the smallest ones first, leaving out those costing more to spend than they're worth
+    let input_fee = fees::fee(feerate, fees::INPUT_VSIZE);
+    let mut inputs = backend
+        .list_unspent()?
+        .into_iter()
+        .filter(|utxo| utxo.amount < consolidation.threshold && utxo.amount > input_fee)
+        .filter(|utxo| data.coin_selection.spendable(utxo))
+        .collect::<Vec<_>>();
+    if inputs.len() < consolidation.min_inputs {
+        return Ok(None);
+    }
+    inputs.sort_by_key(|utxo| utxo.amount);
+    inputs.truncate(consolidation.max_inputs);
+
+    let total: Amount = inputs.iter().map(|utxo| utxo.amount).sum();
+    let fee = fees::transaction_fee(feerate, inputs.len(), 1);
+    let Some(amount) = total
+        .checked_sub(fee)
+        .filter(|amount| *amount >= data.change_address.script_pubkey().dust_value())
+    else {
+        return Ok(None);
+    };
+
+    let tx = backend.create_transaction(&inputs, &[(data.change_address.clone(), amount)])?;
+    let tx = backend.sign_transaction(&tx)?;
+    let txid = backend.broadcast_transaction(&tx)?;
+    // it's meant to wait for fees to go down
+    data.accelerator.ignore(txid);
+    info!(
+        "consolidated {} coins into {amount} in {txid}, paying {fee}",
+        inputs.len()
+    );
+
+    Ok(Some(txid))
+}
+
+/// Consolidates our small coins whenever it's a good time to
+pub fn spawn_consolidator<B: ChainBackend>(
+    data: &web::Data<AppState<B>>,
+    consolidation: Consolidation,
+) {
+    scheduler::register(
+        data,
+        "consolidate",
+        Schedule::Every(CHECK_INTERVAL),
+        move |data| consolidate(data, &consolidation).map(|_| ()),
+    );
+}
@@ -0,0 +1,251 @@
+//SPDX-License-Identifier: MIT
+
+//! Remembers who got something from the faucet recently, so they can't ask again until their
+//! cooldown is over. Keys are whatever identifies the requester, like a node id or an address.
+//!
+//! Cooldowns may be saved to a json file, which is rewritten every time someone gets something,
+//! so they survive restarts, or kept in a Redis server shared by several faucets.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+#[cfg(feature = "redis")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use bitcoincore_rpc::jsonrpc::serde_json;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::api::Error;
+#[cfg(feature = "redis")]
+use crate::shared::SharedStore;
+
+/// Something we gave out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    /// When we gave it, in seconds since the epoch
+    pub at: u64,
+    /// What we gave, like a channel id or a txid
+    pub what: String,
+    /// How long this cooldown lasts, in seconds, if not as long as the others
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window: Option<u64>,
+}
+
+pub struct Cooldowns {
+    window: Duration,
+    path: Option<PathBuf>,
+    grants: Mutex<HashMap<String, Grant>>,
+    /// If set, the cooldowns live there, under `name`, instead of in `grants`
+    #[cfg(feature = "redis")]
+    shared: Option<(Arc<SharedStore>, &'static str)>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("we're past 1970")
+        .as_secs()
+}
+
+impl Cooldowns {
+    /// How long cooldowns last, unless they were given their own window
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    /// How long `grant`'s cooldown lasts
+    fn window_of(&self, grant: &Grant) -> Duration {
+        grant.window.map_or(self.window, Duration::from_secs)
+    }
+
+    /// Creates cooldowns lasting `window`, loading the ones saved to `path`, if any
+    pub fn load(window: Duration, path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let grants = match &path {
+            Some(path) if path.exists() => serde_json::from_slice(&std::fs::read(path)?)?,
+            _ => HashMap::new(),
+        };
+
+        Ok(Self {
+            window,
+            path,
+            grants: Mutex::new(grants),
+            #[cfg(feature = "redis")]
+            shared: None,
+        })
+    }
+
+    /// Creates cooldowns lasting `window`, kept in `store`. `name` tells them apart from other
+    /// cooldowns in the same store
+    #[cfg(feature = "redis")]
+    pub fn shared(window: Duration, store: Arc<SharedStore>, name: &'static str) -> Self {
+        Self {
+            window,
+            path: None,
+            grants: Mutex::new(HashMap::new()),
+            shared: Some((store, name)),
+        }
+    }
+
+    /// If `key` is still cooling down, returns what it got and how long until it's over
+    pub fn check(&self, key: &str) -> Result<Option<(Grant, Duration)>, Error> {
+        #[cfg(feature = "redis")]
+        if let Some((store, name)) = &self.shared {
+            return store.check_cooldown(&format!("{name}:{key}"));
+        }
+
+        let grants = self.grants.lock().unwrap();
+        let Some(grant) = grants.get(key) else {
+            return Ok(None);
+        };
+
+        let elapsed = Duration::from_secs(now().saturating_sub(grant.at));
+        Ok(self
+            .window_of(grant)
+            .checked_sub(elapsed)
+            .map(|remaining| (grant.clone(), remaining)))
+    }
+
+    /// Like [Cooldowns::check], but for someone whose cooldowns last at most `window`
+    pub fn check_for(
+        &self,
+        key: &str,
+        window: Duration,
+    ) -> Result<Option<(Grant, Duration)>, Error> {
+        let Some((grant, remaining)) = self.check(key)? else {
+            return Ok(None);
+        };
+
+        let elapsed = Duration::from_secs(now().saturating_sub(grant.at));
+        Ok(window
+            .checked_sub(elapsed)
+            .map(|shorter| (grant, remaining.min(shorter))))
+    }
+
+    /// Starts `key`'s cooldown, now that it got `what`
+    pub fn record(&self, key: &str, what: String) {
+        self.record_for(key, what, self.window)
+    }
+
+    /// Starts a cooldown for `key` lasting `window` rather than our usual one, now that it got
+    /// `what`
+    pub fn record_for(&self, key: &str, what: String, window: Duration) {
+        let now = now();
+        let grant = Grant {
+            at: now,
+            what,
+            window: (window != self.window).then_some(window.as_secs()),
+        };
+
+        #[cfg(feature = "redis")]
+        if let Some((store, name)) = &self.shared {
+            if let Err(e) = store.record_cooldown(&format!("{name}:{key}"), &grant, window) {
+                warn!("couldn't save the cooldown for {key}: {e}");
+            }
+            return;
+        }
+
+        let mut grants = self.grants.lock().unwrap();
+
+        grants.retain(|_, grant| {
+            Duration::from_secs(now.saturating_sub(grant.at)) < self.window_of(grant)
+        });
+        grants.insert(key.to_string(), grant);
+
+        let Some(path) = &self.path else {
+            return;
+        };
+
+        let saved = serde_json::to_vec(&*grants)
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(path, json));
+        if let Err(e) = saved {
+            warn!("couldn't save cooldowns to {}: {e}", path.display());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: Duration = Duration::from_secs(3_600);
+
+    /// Cooldowns which `key` got `what` from `ago` ago
+    fn granted(key: &str, what: &str, ago: Duration) -> Cooldowns {
+        let cooldowns = Cooldowns::load(HOUR, None).unwrap();
+        cooldowns.grants.lock().unwrap().insert(
+            key.to_string(),
+            Grant {
+                at: now() - ago.as_secs(),
+                what: what.to_string(),
+                window: None,
+            },
+        );
+        cooldowns
+    }
+
+    #[test]
+    fn cools_down_who_got_something() {
+        let cooldowns = Cooldowns::load(HOUR, None).unwrap();
+        assert!(cooldowns.check("alice").unwrap().is_none());
+
+        cooldowns.record("alice", "txid".to_string());
+        let (grant, remaining) = cooldowns.check("alice").unwrap().unwrap();
+        assert_eq!(grant.what, "txid");
+        assert_eq!(grant.window, None);
+        assert!(remaining <= HOUR && remaining > HOUR - Duration::from_secs(5));
+
+        assert!(cooldowns.check("bob").unwrap().is_none());
+    }
+
+    #[test]
+    fn cooldowns_run_out() {
+        let cooldowns = granted("alice", "txid", HOUR + Duration::from_secs(1));
+        assert!(cooldowns.check("alice").unwrap().is_none());
+
+        let cooldowns = granted("alice", "txid", HOUR / 2);
+        let (_, remaining) = cooldowns.check("alice").unwrap().unwrap();
+        assert!(remaining <= HOUR / 2);
+    }
+
+    #[test]
+    fn some_cooldowns_are_shorter() {
+        let cooldowns = Cooldowns::load(HOUR, None).unwrap();
+        cooldowns.record_for("alice", "txid".to_string(), HOUR / 4);
+
+        let (grant, remaining) = cooldowns.check("alice").unwrap().unwrap();
+        assert_eq!(grant.window, Some(900));
+        assert!(remaining <= HOUR / 4);
+
+        let cooldowns = granted("alice", "txid", HOUR / 2);
+        assert!(cooldowns.check_for("alice", HOUR / 4).unwrap().is_none());
+        let (_, remaining) = cooldowns.check_for("alice", HOUR).unwrap().unwrap();
+        assert!(remaining <= HOUR / 2);
+    }
+
+    #[test]
+    fn forgets_what_ran_out() {
+        let cooldowns = granted("alice", "txid", 2 * HOUR);
+        cooldowns.record("bob", "txid".to_string());
+
+        let grants = cooldowns.grants.lock().unwrap();
+        assert_eq!(grants.keys().collect::<Vec<_>>(), ["bob"]);
+    }
+
+    #[test]
+    fn survives_restarts() {
+        let path = std::env::temp_dir().join(format!("cooldowns-{}.json", std::process::id()));
+        let cooldowns = Cooldowns::load(HOUR, Some(path.clone())).unwrap();
+        cooldowns.record("alice", "txid".to_string());
+
+        let cooldowns = Cooldowns::load(HOUR, Some(path.clone())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let (grant, _) = cooldowns.check("alice").unwrap().unwrap();
+        assert_eq!(grant.what, "txid");
+    }
+}
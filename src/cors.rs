@@ -0,0 +1,242 @@
+//SPDX-License-Identifier: MIT
+
+//! Which websites may call us from a browser. By default any may, which is handy while
+//! developing a frontend, but a faucet embedded in its operator's own page can take requests
+//! from that page's origins only, with the methods and headers it uses.
+//!
+//! Either way, browsers let pages read the headers we send for them: the request id, the rate
+//! limit ones and `Retry-After`.
+
+use std::str::FromStr;
+
+use actix_cors::Cors;
+use actix_web::http::header::HeaderName;
+use actix_web::http::Method;
+use actix_web::http::Uri;
+
+/// The methods pages may use, unless told otherwise
+const DEFAULT_METHODS: &[Method] = &[Method::GET, Method::POST];
+
+/// The headers pages may send, unless told otherwise
+const DEFAULT_HEADERS: &[&str] = &[
+    "content-type",
+    "authorization",
+    "idempotency-key",
+    "x-api-key",
+];
+
+/// The headers pages may read
+const EXPOSED_HEADERS: &[&str] = &[
+    "x-request-id",
+    "retry-after",
+    "ratelimit-limit",
+    "ratelimit-remaining",
+    "ratelimit-reset",
+    "deprecation",
+    "link",
+];
+
+#[derive(Debug, Clone)]
+pub enum CorsPolicy {
+    /// Any origin, method and header
+    Permissive,
+    Restricted {
+        origins: Vec<String>,
+        methods: Vec<Method>,
+        headers: Vec<HeaderName>,
+    },
+}
+
+impl CorsPolicy {
+    /// Takes the origins, like `https://faucet.example.com`, and optionally the methods and
+    /// headers, all comma separated. `*` as the only origin is the permissive policy
+    pub fn new(
+        origins: &str,
+        methods: Option<&str>,
+        headers: Option<&str>,
+    ) -> Result<Self, String> {
+        if origins.trim() == "*" {
+            return Ok(CorsPolicy::Permissive);
+        }
+
+        let origins: Vec<String> = list(origins)
+            .map(|origin| match Uri::from_str(origin) {
+                Ok(uri)
+                    if uri.scheme().is_some()
+                        && uri.host().is_some()
+                        && matches!(uri.path(), "" | "/")
+                        && uri.query().is_none() =>
+                {
+                    Ok(origin.trim_end_matches('/').to_string())
+                }
+                _ => Err(format!(
+                    "{origin} isn't an origin, like https://example.com"
+                )),
+            })
+            .collect::<Result<_, _>>()?;
+        if origins.is_empty() {
+            return Err("no origins given".into());
+        }
+
+        let methods = match methods {
+            Some(methods) => list(methods)
+                .map(|method| {
+                    Method::from_str(&method.to_uppercase())
+                        .map_err(|_| format!("{method} isn't a method"))
+                })
+                .collect::<Result<_, _>>()?,
+            None => DEFAULT_METHODS.to_vec(),
+        };
+        let headers = match headers {
+            Some(headers) => list(headers)
+                .map(|name| {
+                    HeaderName::from_str(name).map_err(|_| format!("{name} isn't a header name"))
+                })
+                .collect::<Result<_, _>>()?,
+            None => DEFAULT_HEADERS
+                .iter()
+                .map(|name| HeaderName::from_static(name))
+                .collect(),
+        };
+
+        Ok(CorsPolicy::Restricted {
+            origins,
+            methods,
+            headers,
+        })
+    }
+
+    /// The middleware enforcing this policy, one for each worker
+    pub fn middleware(&self) -> Cors {
+        match self {
+            CorsPolicy::Permissive => Cors::permissive(),
+            CorsPolicy::Restricted {
+                origins,
+                methods,
+                headers,
+            } => origins
+                .iter()
+                .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+                .allowed_methods(methods.clone())
+                .allowed_headers(headers.clone())
+                .expose_headers(
+                    EXPOSED_HEADERS
+                        .iter()
+                        .map(|name| HeaderName::from_static(name)),
+                )
+                .max_age(3_600),
+        }
+    }
+}
+
+/// The non-empty items in a comma separated list
+fn list(items: &str) -> impl Iterator<Item = &str> {
+    items
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::TestRequest;
+    use actix_web::web;
+    use actix_web::App;
+    use actix_web::HttpResponse;
+
+    use super::*;
+
+    #[test]
+    fn star_is_permissive() {
+        assert!(matches!(
+            CorsPolicy::new(" * ", Some("PUT"), None),
+            Ok(CorsPolicy::Permissive)
+        ));
+    }
+
+    #[test]
+    fn parses_origins_methods_and_headers() {
+        let CorsPolicy::Restricted {
+            origins,
+            methods,
+            headers,
+        } = CorsPolicy::new(
+            "https://faucet.example.com/, http://localhost:3000",
+            Some("get, delete"),
+            Some("content-type,x-custom"),
+        )
+        .unwrap()
+        else {
+            panic!("a restricted policy");
+        };
+
+        assert_eq!(
+            origins,
+            ["https://faucet.example.com", "http://localhost:3000"]
+        );
+        assert_eq!(methods, [Method::GET, Method::DELETE]);
+        assert_eq!(headers, ["content-type", "x-custom"]);
+    }
+
+    #[test]
+    fn defaults_methods_and_headers() {
+        let CorsPolicy::Restricted {
+            methods, headers, ..
+        } = CorsPolicy::new("https://faucet.example.com", None, None).unwrap()
+        else {
+            panic!("a restricted policy");
+        };
+
+        assert_eq!(methods, DEFAULT_METHODS);
+        assert_eq!(headers.len(), DEFAULT_HEADERS.len());
+    }
+
+    #[test]
+    fn refuses_what_isnt_an_origin() {
+        for origins in [
+            "",
+            " , ",
+            "faucet.example.com",
+            "https://faucet.example.com/send",
+            "https://faucet.example.com/?a=b",
+        ] {
+            assert!(CorsPolicy::new(origins, None, None).is_err(), "{origins}");
+        }
+        assert!(CorsPolicy::new("https://example.com", Some("not a method"), None).is_err());
+        assert!(CorsPolicy::new("https://example.com", None, Some("not a header")).is_err());
+    }
+
+    #[actix_web::test]
+    async fn lets_only_our_origins_in() {
+        let policy = CorsPolicy::new("https://faucet.example.com", None, None).unwrap();
+        let app = actix_web::test::init_service(
+            App::new()
+                .wrap(policy.middleware())
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let preflight = |origin: &str| {
+            TestRequest::default()
+                .method(Method::OPTIONS)
+                .insert_header((header::ORIGIN, origin))
+                .insert_header((header::ACCESS_CONTROL_REQUEST_METHOD, "GET"))
+                .to_request()
+        };
+
+        let response =
+            actix_web::test::call_service(&app, preflight("https://faucet.example.com")).await;
+        assert!(response.status().is_success());
+        assert_eq!(
+            response
+                .headers()
+                .get(header::ACCESS_CONTROL_ALLOW_ORIGIN)
+                .unwrap(),
+            "https://faucet.example.com"
+        );
+
+        let response =
+            actix_web::test::try_call_service(&app, preflight("https://evil.example.com")).await;
+        assert!(response.map_or(true, |response| !response.status().is_success()));
+    }
+}
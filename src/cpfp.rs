@@ -0,0 +1,315 @@
+//SPDX-License-Identifier: MIT
+
+//! Speeding up donations stuck in the mempool. Donors may pay too little for their refill to
+//! confirm any time soon, and we can't replace their transaction like we do our payouts. What we
+//! can do is spend what it pays us with a child paying enough for both: miners take the two
+//! together, as a package, so the parent confirms along with the child.
+//!
+//! The child sends the donation back to us, paying what makes the package's feerate the one we'd
+//! pay for a payout now. Set `after` and we do that for donations that waited that many blocks,
+//! and admins can speed up any of them right away at /admin/cpfp/{txid}.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::web;
+use bitcoin::Amount;
+use bitcoin::Txid;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::api::blocking;
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+use crate::fees;
+use crate::scheduler;
+use crate::scheduler::Schedule;
+
+/// How often we look for stuck donations, unless operators pick another schedule
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+pub struct Accelerator {
+    /// How many blocks a donation may wait before we speed it up, if we do on our own
+    after: Option<u32>,
+    /// The height we first saw each donation waiting at
+    seen: Mutex<HashMap<Txid, u32>>,
+    /// Transactions of ours that are meant to wait, like consolidations
+    ignored: Mutex<HashSet<Txid>>,
+}
+
+/// What speeding up a transaction did
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Accelerated {
+    /// The child's txid
+    #[schema(value_type = String)]
+    pub txid: Txid,
+    #[schema(value_type = String)]
+    pub parent: Txid,
+    /// What the child pays, in sats
+    pub fee: u64,
+}
+
+impl Accelerator {
+    pub fn new(after: Option<u32>) -> Self {
+        Self {
+            after,
+            seen: Mutex::new(HashMap::new()),
+            ignored: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Leaves `txid` alone, however long it waits
+    pub fn ignore(&self, txid: Txid) {
+        self.ignored.lock().unwrap().insert(txid);
+    }
+}
+
+/// Spends our coins from `txid`, which is waiting in the mempool, with a child paying for both
+pub fn accelerate<B: ChainBackend>(data: &AppState<B>, txid: Txid) -> Result<Accelerated, Error> {
+    let backend = &data.backend;
+    let parent = backend
+        .mempool_entry(&txid)?
+        .ok_or_else(|| Error::CantAccelerate("it isn't waiting in the mempool".into()))?;
+    let inputs = backend
+        .list_unspent()?
+        .into_iter()
+        .filter(|utxo| utxo.txid == txid)
+        .collect::<Vec<_>>();
+    if inputs.is_empty() {
+        return Err(Error::CantAccelerate(
+            "none of its outputs are ours to spend".into(),
+        ));
+    }
+
+    let feerate = data.fees.feerate(backend)?;
+    if parent.feerate() >= feerate {
+        return Err(Error::CantAccelerate("it already pays enough".into()));
+    }
+
+    // the child pays for its own size at our feerate, and for what the parent is missing
+    let child = fees::transaction_fee(feerate, inputs.len(), 1);
+    let fee = child
+        + fees::fee(feerate, parent.vsize)
+            .checked_sub(parent.fee)
+            .unwrap_or(Amount::ZERO);
+    let total: Amount = inputs.iter().map(|utxo| utxo.amount).sum();
+    let amount = total
+        .checked_sub(fee)
+        .filter(|amount| *amount >= data.change_address.script_pubkey().dust_value())
+        .ok_or_else(|| Error::CantAccelerate("what it pays us can't pay for it".into()))?;
+
+    let tx = backend.create_transaction(&inputs, &[(data.change_address.clone(), amount)])?;
+    let tx = backend.sign_transaction(&tx)?;
+    let child = backend.broadcast_transaction(&tx)?;
+    info!("sped up {txid} with {child}, paying {fee}");
+
+    data.accelerator.seen.lock().unwrap().remove(&txid);
+
+    Ok(Accelerated {
+        txid: child,
+        parent: txid,
+        fee: fee.to_sat(),
+    })
+}
+
+/// Speeds up a transaction paying us right away, if it's waiting in the mempool
+#[utoipa::path(
+    post,
+    path = "/v1/admin/cpfp/{txid}",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    params(("txid" = String, Path, description = "The stuck transaction's txid")),
+    responses(
+        (status = 200, body = Accelerated),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn accelerate_transaction<B: ChainBackend>(
+    txid: web::Path<Txid>,
+    data: web::Data<AppState<B>>,
+) -> Result<web::Json<Accelerated>, Error> {
+    let txid = txid.into_inner();
+    Ok(web::Json(
+        blocking(&data, move |data| accelerate(data, txid)).await?,
+    ))
+}
+
+/// Looks at the coins paying us that haven't confirmed, and speeds up those from donations that
+/// waited `after` blocks. Our own payouts are the bumper's
+fn accelerate_stuck<B: ChainBackend>(data: &AppState<B>, after: u32) -> Result<(), Error> {
+    let height = data.backend.block_height()?;
+    let mut txids = data
+        .backend
+        .list_unspent()?
+        .into_iter()
+        .map(|utxo| utxo.txid)
+        .collect::<HashSet<_>>();
+    // we're done ignoring those we spent
+    let mut ignored = data.accelerator.ignored.lock().unwrap();
+    ignored.retain(|txid| txids.contains(txid));
+    txids.retain(|txid| !ignored.contains(txid) && !data.bumper.watches(txid));
+    drop(ignored);
+
+    let mut waiting = HashMap::new();
+    for txid in txids {
+        match data.backend.mempool_entry(&txid) {
+            Ok(Some(_)) => {
+                let since = data
+                    .accelerator
+                    .seen
+                    .lock()
+                    .unwrap()
+                    .get(&txid)
+                    .copied()
+                    .unwrap_or(height);
+                waiting.insert(txid, since);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("couldn't check {txid}: {e}"),
+        }
+    }
+    // forget those that confirmed
+    *data.accelerator.seen.lock().unwrap() = waiting.clone();
+
+    for (txid, since) in waiting {
+        let waited = height.saturating_sub(since);
+        if waited < after {
+            continue;
+        }
+        match accelerate(data, txid) {
+            Ok(_) => {}
+            // those paying enough may still confirm on their own
+            Err(Error::CantAccelerate(e)) => {
+                info!("not speeding up {txid}, waiting for {waited} blocks: {e}")
+            }
+            Err(e) => warn!("couldn't speed up {txid}: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks for stuck donations every so often, if we speed them up on our own
+pub fn spawn_accelerator<B: ChainBackend>(data: &web::Data<AppState<B>>) {
+    let Some(after) = data.accelerator.after else {
+        return;
+    };
+
+    scheduler::register(data, "cpfp", Schedule::Every(CHECK_INTERVAL), move |data| {
+        accelerate_stuck(data, after)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use bitcoin::hashes::Hash;
+    use bitcoin::FeeRate;
+
+    use super::*;
+    use crate::backend::MempoolEntry;
+    use crate::bump::Bumper;
+    use crate::bump::Pending;
+    use crate::testing::app_state;
+    use crate::testing::coin;
+    use crate::testing::MockNode;
+
+    /// A donation paying 1 sat/vB, for the one coin of ours
+    fn donation() -> MockNode {
+        let mut node = MockNode::default();
+        node.mempool.insert(
+            Txid::all_zeros(),
+            MempoolEntry {
+                fee: Amount::from_sat(200),
+                vsize: 200,
+            },
+        );
+        node
+    }
+
+    /// A faucet paying 10 sat/vB, speeding up donations that wait `after` blocks
+    fn faucet(node: MockNode, after: Option<u32>) -> AppState<MockNode> {
+        let mut data = app_state(node);
+        data.fees.floor = FeeRate::from_sat_per_vb_unchecked(10);
+        data.accelerator = Accelerator::new(after);
+        data
+    }
+
+    #[test]
+    fn pays_for_the_parent_and_the_child() {
+        let data = faucet(donation(), None);
+
+        let accelerated = accelerate(&data, Txid::all_zeros()).unwrap();
+        let feerate = FeeRate::from_sat_per_vb_unchecked(10);
+        let child = fees::transaction_fee(feerate, 1, 1);
+        let missing = fees::fee(feerate, 200) - Amount::from_sat(200);
+        assert_eq!(accelerated.fee, (child + missing).to_sat());
+        assert_eq!(accelerated.parent, Txid::all_zeros());
+        assert_eq!(data.backend.broadcasts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn leaves_alone_what_it_cant_speed_up() {
+        let cant = |data: &AppState<MockNode>, txid| {
+            matches!(accelerate(data, txid), Err(Error::CantAccelerate(_)))
+        };
+
+        // it isn't waiting
+        assert!(cant(&faucet(MockNode::default(), None), Txid::all_zeros()));
+
+        // it doesn't pay us
+        let mut node = donation();
+        let other = Txid::from_byte_array([1; 32]);
+        node.mempool.insert(other, node.mempool[&Txid::all_zeros()]);
+        assert!(cant(&faucet(node, None), other));
+
+        // it pays enough
+        let mut data = faucet(donation(), None);
+        data.fees.floor = FeeRate::from_sat_per_vb_unchecked(1);
+        assert!(cant(&data, Txid::all_zeros()));
+
+        // or too little for a child
+        let mut node = donation();
+        node.coins = vec![coin(0, Amount::from_sat(3_000))];
+        let data = faucet(node, None);
+        assert!(cant(&data, Txid::all_zeros()));
+        assert_eq!(data.backend.broadcasts.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn speeds_up_donations_that_waited() {
+        // our node is stuck at the same height, so they never wait a block
+        let data = faucet(donation(), Some(1));
+        accelerate_stuck(&data, 1).unwrap();
+        assert_eq!(data.backend.broadcasts.load(Ordering::SeqCst), 0);
+        assert_eq!(data.accelerator.seen.lock().unwrap().len(), 1);
+
+        let data = faucet(donation(), Some(0));
+        accelerate_stuck(&data, 0).unwrap();
+        assert_eq!(data.backend.broadcasts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn leaves_our_own_transactions_alone() {
+        let data = faucet(donation(), Some(0));
+        data.accelerator.ignore(Txid::all_zeros());
+        accelerate_stuck(&data, 0).unwrap();
+        assert_eq!(data.backend.broadcasts.load(Ordering::SeqCst), 0);
+
+        let mut data = faucet(donation(), Some(0));
+        data.bumper = Bumper::new(None);
+        data.bumper.watch(
+            Txid::all_zeros(),
+            Pending::new(vec![], vec![], None, None, Amount::ZERO),
+        );
+        accelerate_stuck(&data, 0).unwrap();
+        assert_eq!(data.backend.broadcasts.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,1168 @@
+//SPDX-License-Identifier: MIT
+
+//! The faucet's database, an SQLite file recording every payout, Lightning payment and
+//! channel we gave out.
+//!
+//! We don't keep client IPs around, only a salted hash of them, which is enough to tell whether
+//! two requests came from the same place. The salt is made when the database is created and
+//! never leaves it. Payouts made to logged in users also record their account, so we can tell
+//! how much each account got.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::HashEngine;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::Txid;
+use bitcoincore_rpc::jsonrpc::serde_json;
+use rusqlite::params;
+use rusqlite::Connection;
+use rusqlite::OptionalExtension;
+
+use crate::abuse::Decision;
+use crate::abuse::Signals;
+use crate::abuse::Verdict;
+use crate::access::Kind;
+use crate::access::List;
+use crate::access::Rule;
+use crate::api::Error;
+use crate::api::Payout;
+use crate::export;
+use crate::export::Record;
+use crate::idempotency;
+use crate::response::PastPayout;
+use crate::stats::DailyReport;
+use crate::stats::DayStats;
+use crate::stats::Stats;
+use crate::stats::Totals;
+use crate::stats::DAY;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS meta (
+    key TEXT PRIMARY KEY,
+    value BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS payouts (
+    id INTEGER PRIMARY KEY,
+    address TEXT NOT NULL,
+    amount INTEGER NOT NULL,
+    txid TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    client TEXT
+);
+CREATE INDEX IF NOT EXISTS payouts_created_at ON payouts (created_at);
+CREATE TABLE IF NOT EXISTS channels (
+    id INTEGER PRIMARY KEY,
+    node_id TEXT NOT NULL,
+    channel TEXT NOT NULL,
+    capacity INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    client TEXT
+);
+CREATE INDEX IF NOT EXISTS channels_created_at ON channels (created_at);
+CREATE TABLE IF NOT EXISTS lightning_payments (
+    id INTEGER PRIMARY KEY,
+    destination TEXT NOT NULL,
+    amount INTEGER NOT NULL,
+    created_at INTEGER NOT NULL,
+    client TEXT
+);
+CREATE INDEX IF NOT EXISTS lightning_payments_created_at ON lightning_payments (created_at);
+";
+
+/// What changed since the first schema, one step per version. `PRAGMA user_version` tells how
+/// many of these a database went through already
+const MIGRATIONS: &[&str] = &[
+    "
+ALTER TABLE payouts ADD COLUMN account TEXT;
+CREATE INDEX payouts_account ON payouts (account, created_at);
+",
+    "
+CREATE TABLE access_rules (
+    id INTEGER PRIMARY KEY,
+    list TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    value TEXT NOT NULL,
+    note TEXT,
+    created_at INTEGER NOT NULL
+);
+",
+    "
+CREATE INDEX payouts_client ON payouts (client, created_at);
+CREATE INDEX payouts_address ON payouts (address, created_at);
+CREATE TABLE abuse_decisions (
+    id INTEGER PRIMARY KEY,
+    created_at INTEGER NOT NULL,
+    client TEXT,
+    address TEXT NOT NULL,
+    amount INTEGER NOT NULL,
+    signals TEXT NOT NULL,
+    score REAL NOT NULL,
+    challenge INTEGER NOT NULL,
+    downgrade INTEGER NOT NULL
+);
+",
+    "
+CREATE TABLE idempotency_keys (
+    key TEXT PRIMARY KEY,
+    request TEXT NOT NULL,
+    response TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+",
+    "
+CREATE INDEX payouts_txid ON payouts (txid);
+",
+    "
+CREATE TABLE rejections (
+    id INTEGER PRIMARY KEY,
+    reason TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX rejections_created_at ON rejections (created_at);
+CREATE TABLE daily_stats (
+    day INTEGER PRIMARY KEY,
+    payouts INTEGER NOT NULL,
+    sats_out INTEGER NOT NULL,
+    unique_addresses INTEGER NOT NULL,
+    channels_opened INTEGER NOT NULL,
+    rejections TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+",
+    "
+CREATE TABLE replacements (
+    txid TEXT PRIMARY KEY,
+    replaced_by TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+",
+    "
+ALTER TABLE payouts ADD COLUMN vout INTEGER;
+CREATE TABLE queued_requests (
+    id TEXT PRIMARY KEY,
+    state TEXT NOT NULL,
+    payout TEXT,
+    txid TEXT,
+    vout INTEGER,
+    error TEXT,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+",
+    "
+ALTER TABLE payouts ADD COLUMN confirmed_at INTEGER;
+",
+];
+
+/// A request in the payout queue, as we stored it
+pub struct StoredRequest {
+    pub id: String,
+    pub state: String,
+    /// The payout, as json, until we're done with it
+    pub payout: Option<String>,
+    pub txid: Option<Txid>,
+    pub vout: Option<u32>,
+    pub error: Option<String>,
+    /// When it last changed state, in unix time
+    pub updated_at: u64,
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(value: rusqlite::Error) -> Self {
+        Error::DatabaseError(value.to_string())
+    }
+}
+
+pub struct Database {
+    conn: Mutex<Connection>,
+    salt: [u8; 32],
+}
+
+/// Reads `txid`, from column `column`
+fn parse_txid(txid: &str, column: usize) -> rusqlite::Result<Txid> {
+    txid.parse().map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(column, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("we're past 1970")
+        .as_secs()
+}
+
+impl Database {
+    /// Opens the database at `path`, creating it if it doesn't exist yet
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            conn.execute_batch(&format!(
+                "BEGIN; {migration} PRAGMA user_version = {}; COMMIT;",
+                i + 1
+            ))?;
+        }
+
+        let salt = conn
+            .query_row("SELECT value FROM meta WHERE key = 'salt'", [], |row| {
+                row.get::<_, [u8; 32]>(0)
+            })
+            .optional()?;
+        let salt = match salt {
+            Some(salt) => salt,
+            None => {
+                let salt = bitcoin::secp256k1::rand::random::<[u8; 32]>();
+                conn.execute(
+                    "INSERT INTO meta (key, value) VALUES ('salt', ?1)",
+                    params![salt],
+                )?;
+                salt
+            }
+        };
+
+        Ok(Self {
+            conn: Mutex::new(conn),
+            salt,
+        })
+    }
+
+    /// Tells whether the database still answers
+    pub fn ping(&self) -> rusqlite::Result<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT 1", [], |row| row.get::<_, i64>(0))?;
+
+        Ok(())
+    }
+
+    /// What we store instead of `ip`
+    pub fn hash_ip(&self, ip: IpAddr) -> String {
+        let mut engine = sha256::Hash::engine();
+        engine.input(&self.salt);
+        engine.input(ip.to_string().as_bytes());
+
+        sha256::Hash::from_engine(engine).to_string()
+    }
+
+    /// Records that we made `payout` in output `vout` of `txid`
+    pub fn record_payout(&self, payout: &Payout, txid: Txid, vout: u32) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO payouts (address, amount, txid, created_at, client, account, vout)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                payout.address.to_string(),
+                payout.amount.to_sat(),
+                txid.to_string(),
+                now(),
+                payout.client.map(|ip| self.hash_ip(ip)),
+                payout.account,
+                vout,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// The latest payout of `amount` to `address` since `since`, in unix time, as the txid and
+    /// output paying it, if there is one
+    pub fn find_payout(
+        &self,
+        address: &Address,
+        amount: Amount,
+        since: u64,
+    ) -> rusqlite::Result<Option<(Txid, Option<u32>)>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT txid, vout FROM payouts
+                 WHERE address = ?1 AND amount = ?2 AND created_at >= ?3
+                 ORDER BY id DESC LIMIT 1",
+                params![address.to_string(), amount.to_sat(), since],
+                |row| Ok((row.get::<_, String>(0)?, row.get(1)?)),
+            )
+            .optional()?
+            .map(|(txid, vout)| Ok((parse_txid(&txid, 0)?, vout)))
+            .transpose()
+    }
+
+    /// When we made each payout in the last `window`, as unix time, and how much it was, oldest
+    /// first
+    pub fn payouts_since(&self, window: Duration) -> rusqlite::Result<Vec<(u64, Amount)>> {
+        let since = now().saturating_sub(window.as_secs());
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT created_at, amount FROM payouts WHERE created_at >= ?1 ORDER BY created_at",
+        )?;
+        let payouts = statement
+            .query_map(params![since], |row| {
+                Ok((row.get(0)?, Amount::from_sat(row.get(1)?)))
+            })?
+            .collect();
+
+        payouts
+    }
+
+    /// When we paid anyone in the last `window`, on-chain or over Lightning, as unix time, and
+    /// how much, oldest first
+    pub fn spent_since(&self, window: Duration) -> rusqlite::Result<Vec<(u64, Amount)>> {
+        let since = now().saturating_sub(window.as_secs());
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT created_at, amount FROM payouts WHERE created_at >= ?1
+             UNION ALL
+             SELECT created_at, amount FROM lightning_payments WHERE created_at >= ?1
+             ORDER BY created_at",
+        )?;
+        let spent = statement
+            .query_map(params![since], |row| {
+                Ok((row.get(0)?, Amount::from_sat(row.get(1)?)))
+            })?
+            .collect();
+
+        spent
+    }
+
+    /// How much we sent to `account` in the last `window`
+    #[cfg(feature = "github")]
+    pub fn account_total(&self, account: &str, window: Duration) -> rusqlite::Result<Amount> {
+        let since = now().saturating_sub(window.as_secs());
+        let total: u64 = self.conn.lock().unwrap().query_row(
+            "SELECT COALESCE(SUM(amount), 0) FROM payouts WHERE account = ?1 AND created_at >= ?2",
+            params![account, since],
+            |row| row.get(0),
+        )?;
+
+        Ok(Amount::from_sat(total))
+    }
+
+    /// Records that we paid `amount` over Lightning to `destination`, the invoice, offer or node
+    /// we paid, at `client`'s request
+    #[cfg(feature = "lightning")]
+    pub fn record_lightning_payment(
+        &self,
+        destination: &str,
+        amount: Amount,
+        client: Option<IpAddr>,
+    ) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO lightning_payments (destination, amount, created_at, client)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                destination,
+                amount.to_sat(),
+                now(),
+                client.map(|ip| self.hash_ip(ip)),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records that we opened, or grew, `channel` with `node_id`, at `client`'s request
+    #[cfg(feature = "lightning")]
+    pub fn record_channel(
+        &self,
+        node_id: &str,
+        channel: &str,
+        capacity: Amount,
+        client: Option<IpAddr>,
+    ) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO channels (node_id, channel, capacity, created_at, client)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                node_id,
+                channel,
+                capacity.to_sat(),
+                now(),
+                client.map(|ip| self.hash_ip(ip)),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Every block and allow rule
+    pub fn access_rules(&self) -> rusqlite::Result<Vec<Rule>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement =
+            conn.prepare("SELECT id, list, kind, value, note, created_at FROM access_rules")?;
+        let rules = statement
+            .query_map([], |row| {
+                let list: String = row.get(1)?;
+                let kind: String = row.get(2)?;
+                let unknown = |column, value: &str| {
+                    rusqlite::Error::FromSqlConversionFailure(
+                        column,
+                        rusqlite::types::Type::Text,
+                        format!("unknown {value}").into(),
+                    )
+                };
+
+                Ok(Rule {
+                    id: row.get(0)?,
+                    list: List::from_name(&list).ok_or_else(|| unknown(1, &list))?,
+                    kind: Kind::from_name(&kind).ok_or_else(|| unknown(2, &kind))?,
+                    value: row.get(3)?,
+                    note: row.get(4)?,
+                    created_at: row.get(5)?,
+                })
+            })?
+            .collect();
+
+        rules
+    }
+
+    /// Saves a new rule, returning it
+    pub fn add_access_rule(
+        &self,
+        list: List,
+        kind: Kind,
+        value: &str,
+        note: Option<&str>,
+    ) -> rusqlite::Result<Rule> {
+        let conn = self.conn.lock().unwrap();
+        let created_at = now();
+        conn.execute(
+            "INSERT INTO access_rules (list, kind, value, note, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![list.as_str(), kind.as_str(), value, note, created_at],
+        )?;
+
+        Ok(Rule {
+            id: conn.last_insert_rowid(),
+            list,
+            kind,
+            value: value.to_string(),
+            note: note.map(str::to_string),
+            created_at,
+        })
+    }
+
+    /// Removes the rule with `id`, telling whether there was one
+    pub fn remove_access_rule(&self, id: i64) -> rusqlite::Result<bool> {
+        let removed = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM access_rules WHERE id = ?1", params![id])?;
+
+        Ok(removed > 0)
+    }
+
+    /// How many payouts `client` got in the last `window`
+    pub fn client_payouts(&self, client: IpAddr, window: Duration) -> rusqlite::Result<u64> {
+        let since = now().saturating_sub(window.as_secs());
+        self.conn.lock().unwrap().query_row(
+            "SELECT COUNT(*) FROM payouts WHERE client = ?1 AND created_at >= ?2",
+            params![self.hash_ip(client), since],
+            |row| row.get(0),
+        )
+    }
+
+    /// Whether we made a payout in `txid`
+    pub fn is_payout(&self, txid: &Txid) -> rusqlite::Result<bool> {
+        self.conn.lock().unwrap().query_row(
+            "SELECT EXISTS (SELECT 1 FROM payouts WHERE txid = ?1)
+                 OR EXISTS (SELECT 1 FROM replacements WHERE txid = ?1)",
+            params![txid.to_string()],
+            |row| row.get(0),
+        )
+    }
+
+    /// Records that we bumped payout `txid` to `replacement`, which is now what it's paid in
+    pub fn replace_payout(&self, txid: &Txid, replacement: &Txid) -> rusqlite::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO replacements (txid, replaced_by, created_at) VALUES (?1, ?2, ?3)",
+            params![txid.to_string(), replacement.to_string(), now()],
+        )?;
+        tx.execute(
+            "UPDATE payouts SET txid = ?2 WHERE txid = ?1",
+            params![txid.to_string(), replacement.to_string()],
+        )?;
+
+        tx.commit()
+    }
+
+    /// Records that the payouts in `txid` confirmed, unless we saw that already
+    pub fn confirm_payout(&self, txid: &Txid) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE payouts SET confirmed_at = ?2 WHERE txid = ?1 AND confirmed_at IS NULL",
+            params![txid.to_string(), now()],
+        )?;
+
+        Ok(())
+    }
+
+    /// The transactions of payouts made in the last `window` we haven't seen confirm yet
+    pub fn unconfirmed_payouts(&self, window: Duration) -> rusqlite::Result<Vec<Txid>> {
+        let since = now().saturating_sub(window.as_secs());
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT DISTINCT txid FROM payouts WHERE confirmed_at IS NULL AND created_at >= ?1",
+        )?;
+        let txids = statement
+            .query_map(params![since], |row| row.get::<_, String>(0))?
+            .filter_map(|txid| txid.map(|txid| txid.parse().ok()).transpose())
+            .collect();
+
+        txids
+    }
+
+    /// What we replaced payout `txid` with, if we bumped it
+    pub fn replaced_by(&self, txid: &Txid) -> rusqlite::Result<Option<Txid>> {
+        let replacement: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT replaced_by FROM replacements WHERE txid = ?1",
+                params![txid.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+
+        Ok(replacement.and_then(|txid| txid.parse().ok()))
+    }
+
+    /// Our payouts, newest first, skipping the first `offset`, and how many there are in all.
+    /// Only those to `address`, if set
+    pub fn payout_history(
+        &self,
+        address: Option<&Address>,
+        limit: u32,
+        offset: u32,
+    ) -> rusqlite::Result<(u64, Vec<PastPayout>)> {
+        let address = address.map(|address| address.to_string());
+        let conn = self.conn.lock().unwrap();
+
+        let total = conn.query_row(
+            "SELECT COUNT(*) FROM payouts WHERE ?1 IS NULL OR address = ?1",
+            params![address],
+            |row| row.get(0),
+        )?;
+
+        let mut statement = conn.prepare(
+            "SELECT address, amount, txid, created_at FROM payouts
+             WHERE ?1 IS NULL OR address = ?1 ORDER BY id DESC LIMIT ?2 OFFSET ?3",
+        )?;
+        let payouts = statement
+            .query_map(params![address, limit, offset], |row| {
+                let txid: String = row.get(2)?;
+                Ok(PastPayout {
+                    address: row.get(0)?,
+                    amount: row.get(1)?,
+                    txid: txid.parse().map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            2,
+                            rusqlite::types::Type::Text,
+                            Box::new(e),
+                        )
+                    })?,
+                    created_at: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok((total, payouts))
+    }
+
+    /// How many payouts `address` got in the last `window`
+    pub fn address_payouts(&self, address: &Address, window: Duration) -> rusqlite::Result<u64> {
+        let since = now().saturating_sub(window.as_secs());
+        self.conn.lock().unwrap().query_row(
+            "SELECT COUNT(*) FROM payouts WHERE address = ?1 AND created_at >= ?2",
+            params![address.to_string(), since],
+            |row| row.get(0),
+        )
+    }
+
+    /// The request first sent with `key` and what we answered to it, if it was in the last
+    /// `window`
+    pub fn idempotent_response(
+        &self,
+        key: &str,
+        window: Duration,
+    ) -> rusqlite::Result<Option<(String, String)>> {
+        let since = now().saturating_sub(window.as_secs());
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT request, response FROM idempotency_keys
+                 WHERE key = ?1 AND created_at >= ?2",
+                params![key, since],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+    }
+
+    /// Remembers we answered `response` to `request`, sent with `key`. Keys older than
+    /// [idempotency::KEY_WINDOW] are forgotten on the way, so they may be used again
+    pub fn record_idempotent_response(
+        &self,
+        key: &str,
+        request: &str,
+        response: &str,
+    ) -> rusqlite::Result<()> {
+        let now = now();
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "DELETE FROM idempotency_keys WHERE created_at < ?1",
+            params![now.saturating_sub(idempotency::KEY_WINDOW.as_secs())],
+        )?;
+        conn.execute(
+            "INSERT OR REPLACE INTO idempotency_keys (key, request, response, created_at)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![key, request, response, now],
+        )?;
+
+        Ok(())
+    }
+
+    /// Logs what we decided about `client`'s request for `amount` at `address`
+    pub fn record_decision(
+        &self,
+        client: Option<IpAddr>,
+        address: &Address,
+        amount: Amount,
+        signals: &Signals,
+        verdict: &Verdict,
+    ) -> rusqlite::Result<()> {
+        let signals = serde_json::to_string(signals).expect("signals serialize");
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO abuse_decisions
+             (created_at, client, address, amount, signals, score, challenge, downgrade)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                now(),
+                client.map(|ip| self.hash_ip(ip)),
+                address.to_string(),
+                amount.to_sat(),
+                signals,
+                verdict.score,
+                verdict.challenge,
+                verdict.downgrade,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// The latest `limit` decisions, newest first
+    pub fn decisions(&self, limit: u32) -> rusqlite::Result<Vec<Decision>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT id, created_at, client, address, amount, signals, score, challenge, downgrade
+             FROM abuse_decisions ORDER BY id DESC LIMIT ?1",
+        )?;
+        let decisions = statement
+            .query_map(params![limit], |row| {
+                let signals: String = row.get(5)?;
+                Ok(Decision {
+                    id: row.get(0)?,
+                    created_at: row.get(1)?,
+                    client: row.get(2)?,
+                    address: row.get(3)?,
+                    amount: row.get(4)?,
+                    signals: serde_json::from_str(&signals).unwrap_or_default(),
+                    verdict: Verdict {
+                        score: row.get(6)?,
+                        challenge: row.get(7)?,
+                        downgrade: row.get(8)?,
+                    },
+                })
+            })?
+            .collect();
+
+        decisions
+    }
+
+    /// Records that we turned down a request for money, for `reason`, one of our error codes
+    pub fn record_rejection(&self, reason: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO rejections (reason, created_at) VALUES (?1, ?2)",
+            params![reason, now()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Adds up what we did in the day starting at `day`, in unix time
+    pub fn daily_report(&self, day: u64) -> rusqlite::Result<DailyReport> {
+        let conn = self.conn.lock().unwrap();
+        let (payouts, sats_out, unique_addresses) = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(amount), 0), COUNT(DISTINCT address)
+             FROM payouts WHERE created_at >= ?1 AND created_at < ?2",
+            params![day, day + DAY],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )?;
+        let channels_opened = conn.query_row(
+            "SELECT COUNT(*) FROM channels WHERE created_at >= ?1 AND created_at < ?2",
+            params![day, day + DAY],
+            |row| row.get(0),
+        )?;
+
+        let mut statement = conn.prepare(
+            "SELECT reason, COUNT(*) FROM rejections WHERE created_at >= ?1 AND created_at < ?2
+             GROUP BY reason",
+        )?;
+        let rejections = statement
+            .query_map(params![day, day + DAY], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(DailyReport {
+            day,
+            payouts,
+            sats_out,
+            unique_addresses,
+            channels_opened,
+            rejections,
+        })
+    }
+
+    /// What we did since we started, and in each day from the one starting at `since`, in unix
+    /// time, until today
+    pub fn stats(&self, since: u64) -> rusqlite::Result<Stats> {
+        let conn = self.conn.lock().unwrap();
+        let payouts = |row: &rusqlite::Row, first: usize| {
+            let average: Option<f64> = row.get(first + 3)?;
+            Ok(Totals {
+                payouts: row.get(first)?,
+                sats_out: row.get(first + 1)?,
+                unique_addresses: row.get(first + 2)?,
+                average_confirmation_seconds: average.map(|average| average.round() as u64),
+                ..Default::default()
+            })
+        };
+
+        let mut total = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(amount), 0), COUNT(DISTINCT address),
+             AVG(confirmed_at - created_at) FROM payouts",
+            [],
+            |row| payouts(row, 0),
+        )?;
+        total.channels_opened =
+            conn.query_row("SELECT COUNT(*) FROM channels", [], |row| row.get(0))?;
+        let mut statement =
+            conn.prepare("SELECT reason, COUNT(*) FROM rejections GROUP BY reason")?;
+        total.rejections = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        // days we did nothing in are there too, so charts don't skip them
+        let mut days = (since..=now())
+            .step_by(DAY as usize)
+            .map(|day| (day, Totals::default()))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut statement = conn.prepare(
+            "SELECT created_at / ?2 * ?2 AS day, COUNT(*), COALESCE(SUM(amount), 0),
+             COUNT(DISTINCT address), AVG(confirmed_at - created_at)
+             FROM payouts WHERE created_at >= ?1 GROUP BY day",
+        )?;
+        let mut rows = statement.query(params![since, DAY])?;
+        while let Some(row) = rows.next()? {
+            if let Some(totals) = days.get_mut(&row.get(0)?) {
+                *totals = payouts(row, 1)?;
+            }
+        }
+
+        let mut statement = conn.prepare(
+            "SELECT created_at / ?2 * ?2 AS day, COUNT(*)
+             FROM channels WHERE created_at >= ?1 GROUP BY day",
+        )?;
+        let mut rows = statement.query(params![since, DAY])?;
+        while let Some(row) = rows.next()? {
+            if let Some(totals) = days.get_mut(&row.get(0)?) {
+                totals.channels_opened = row.get(1)?;
+            }
+        }
+
+        let mut statement = conn.prepare(
+            "SELECT created_at / ?2 * ?2 AS day, reason, COUNT(*)
+             FROM rejections WHERE created_at >= ?1 GROUP BY day, reason",
+        )?;
+        let mut rows = statement.query(params![since, DAY])?;
+        while let Some(row) = rows.next()? {
+            if let Some(totals) = days.get_mut(&row.get(0)?) {
+                totals.rejections.insert(row.get(1)?, row.get(2)?);
+            }
+        }
+
+        Ok(Stats {
+            total,
+            days: days
+                .into_iter()
+                .map(|(day, totals)| DayStats { day, totals })
+                .collect(),
+        })
+    }
+
+    /// Stores `report`, replacing the one we had for its day, if any
+    pub fn record_daily_report(&self, report: &DailyReport) -> rusqlite::Result<()> {
+        let rejections = serde_json::to_string(&report.rejections).expect("rejections serialize");
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO daily_stats
+             (day, payouts, sats_out, unique_addresses, channels_opened, rejections, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                report.day,
+                report.payouts,
+                report.sats_out,
+                report.unique_addresses,
+                report.channels_opened,
+                rejections,
+                now(),
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    /// Keeps the queued request `id`, paying `payout`, as json, so it survives a restart
+    pub fn queue_request(&self, id: &str, payout: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT INTO queued_requests (id, state, payout, created_at, updated_at)
+             VALUES (?1, 'queued', ?2, ?3, ?3)",
+            params![id, payout, now()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Moves the queued request `id` to `state`, one we aren't done with
+    pub fn update_request(&self, id: &str, state: &str) -> rusqlite::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "UPDATE queued_requests SET state = ?2, updated_at = ?3 WHERE id = ?1",
+            params![id, state, now()],
+        )?;
+
+        Ok(())
+    }
+
+    /// Records how the queued request `id` ended, forgetting its payout and those of requests
+    /// that ended before `forget_before`, in unix time
+    pub fn finish_request(
+        &self,
+        id: &str,
+        state: &str,
+        paid: Option<(Txid, u32)>,
+        error: Option<&str>,
+        forget_before: u64,
+    ) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "UPDATE queued_requests
+             SET state = ?2, payout = NULL, txid = ?3, vout = ?4, error = ?5, updated_at = ?6
+             WHERE id = ?1",
+            params![
+                id,
+                state,
+                paid.map(|(txid, _)| txid.to_string()),
+                paid.map(|(_, vout)| vout),
+                error,
+                now(),
+            ],
+        )?;
+        conn.execute(
+            "DELETE FROM queued_requests WHERE payout IS NULL AND updated_at < ?1",
+            params![forget_before],
+        )?;
+
+        Ok(())
+    }
+
+    /// Every request in the payout queue we know about, oldest first
+    pub fn queued_requests(&self) -> rusqlite::Result<Vec<StoredRequest>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT id, state, payout, txid, vout, error, updated_at FROM queued_requests
+             ORDER BY rowid",
+        )?;
+        let requests = statement
+            .query_map([], |row| {
+                let txid: Option<String> = row.get(3)?;
+                Ok(StoredRequest {
+                    id: row.get(0)?,
+                    state: row.get(1)?,
+                    payout: row.get(2)?,
+                    txid: txid.map(|txid| parse_txid(&txid, 3)).transpose()?,
+                    vout: row.get(4)?,
+                    error: row.get(5)?,
+                    updated_at: row.get(6)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(requests)
+    }
+
+    /// Up to `limit` payouts or channels made from `from` until before `to`, in unix time, after
+    /// the one with id `after`, oldest first
+    pub fn export(
+        &self,
+        kind: export::Kind,
+        from: u64,
+        to: u64,
+        after: i64,
+        limit: u32,
+    ) -> rusqlite::Result<Vec<Record>> {
+        let (name, query) = match kind {
+            export::Kind::Payouts => (
+                "payout",
+                "SELECT id, created_at, address, amount, txid, vout, client, account FROM payouts
+                 WHERE created_at >= ?1 AND created_at < ?2 AND id > ?3 ORDER BY id LIMIT ?4",
+            ),
+            export::Kind::Channels => (
+                "channel",
+                "SELECT id, created_at, node_id, capacity, channel, NULL, client, NULL FROM channels
+                 WHERE created_at >= ?1 AND created_at < ?2 AND id > ?3 ORDER BY id LIMIT ?4",
+            ),
+        };
+
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(query)?;
+        let records = statement
+            .query_map(params![from, to, after, limit], |row| {
+                Ok(Record {
+                    kind: name,
+                    id: row.get(0)?,
+                    created_at: row.get(1)?,
+                    destination: row.get(2)?,
+                    amount: row.get(3)?,
+                    reference: row.get(4)?,
+                    vout: row.get(5)?,
+                    client: row.get(6)?,
+                    account: row.get(7)?,
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()?;
+
+        Ok(records)
+    }
+
+    /// The latest day we stored a report for, if any
+    pub fn last_daily_report(&self) -> rusqlite::Result<Option<u64>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT MAX(day) FROM daily_stats", [], |row| row.get(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Network;
+    use bitcoin::ScriptBuf;
+
+    use super::*;
+
+    const DAY_WINDOW: Duration = Duration::from_secs(DAY);
+
+    fn memory() -> Database {
+        Database::open(":memory:".as_ref()).unwrap()
+    }
+
+    /// A different address for each `n`
+    fn address(n: u8) -> Address {
+        Address::p2wsh(&ScriptBuf::from(vec![n]), Network::Signet)
+    }
+
+    fn txid(n: u8) -> Txid {
+        Txid::from_byte_array([n; 32])
+    }
+
+    fn payout(n: u8, amount: u64, client: Option<&str>) -> Payout {
+        Payout {
+            address: address(n),
+            amount: Amount::from_sat(amount),
+            silent_payment: None,
+            client: client.map(|ip| ip.parse().unwrap()),
+            cooldown: None,
+            account: None,
+            memo: None,
+            fee_target: None,
+            #[cfg(feature = "webhooks")]
+            callback: None,
+        }
+    }
+
+    #[test]
+    fn migrates_old_databases() {
+        let path = std::env::temp_dir().join(format!("faucet-{}.sqlite", std::process::id()));
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(SCHEMA).unwrap();
+        conn.execute(
+            "INSERT INTO payouts (address, amount, txid, created_at) VALUES (?1, 10000, ?2, ?3)",
+            params![address(0).to_string(), txid(1).to_string(), now()],
+        )
+        .unwrap();
+        drop(conn);
+
+        let db = Database::open(&path).unwrap();
+        let hash = db.hash_ip("192.0.2.1".parse().unwrap());
+        let version: usize = db
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+        assert_eq!(db.payout_history(None, 10, 0).unwrap().0, 1);
+        assert_eq!(db.unconfirmed_payouts(DAY_WINDOW).unwrap(), [txid(1)]);
+        drop(db);
+
+        // opening it again changes nothing, not even the salt
+        let db = Database::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(db.hash_ip("192.0.2.1".parse().unwrap()), hash);
+        assert_eq!(db.payout_history(None, 10, 0).unwrap().0, 1);
+    }
+
+    #[test]
+    fn salts_ip_hashes() {
+        let (db, other) = (memory(), memory());
+        let ip = "192.0.2.1".parse().unwrap();
+
+        assert_eq!(db.hash_ip(ip), db.hash_ip(ip));
+        assert_ne!(db.hash_ip(ip), other.hash_ip(ip));
+        assert_ne!(db.hash_ip(ip), db.hash_ip("192.0.2.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn records_payouts() {
+        let db = memory();
+        db.record_payout(&payout(0, 10_000, Some("192.0.2.1")), txid(1), 0)
+            .unwrap();
+        db.record_payout(&payout(1, 20_000, Some("192.0.2.1")), txid(1), 1)
+            .unwrap();
+        db.record_payout(&payout(0, 30_000, None), txid(2), 0)
+            .unwrap();
+
+        let client = "192.0.2.1".parse().unwrap();
+        assert_eq!(db.client_payouts(client, DAY_WINDOW).unwrap(), 2);
+        assert_eq!(db.address_payouts(&address(0), DAY_WINDOW).unwrap(), 2);
+        assert!(db.is_payout(&txid(1)).unwrap());
+        assert!(!db.is_payout(&txid(3)).unwrap());
+        assert_eq!(
+            db.find_payout(&address(1), Amount::from_sat(20_000), 0)
+                .unwrap(),
+            Some((txid(1), Some(1)))
+        );
+        assert_eq!(
+            db.find_payout(&address(1), Amount::from_sat(10_000), 0)
+                .unwrap(),
+            None
+        );
+
+        let (total, payouts) = db.payout_history(Some(&address(0)), 1, 0).unwrap();
+        assert_eq!(total, 2);
+        assert_eq!(payouts.len(), 1);
+        assert_eq!(payouts[0].amount, 30_000);
+        let (_, payouts) = db.payout_history(None, 10, 1).unwrap();
+        assert_eq!(
+            payouts
+                .iter()
+                .map(|payout| payout.amount)
+                .collect::<Vec<_>>(),
+            [20_000, 10_000]
+        );
+
+        let spent = db.payouts_since(DAY_WINDOW).unwrap();
+        assert_eq!(
+            spent.iter().map(|(_, amount)| amount.to_sat()).sum::<u64>(),
+            60_000
+        );
+    }
+
+    #[test]
+    fn follows_replacements_and_confirmations() {
+        let db = memory();
+        db.record_payout(&payout(0, 10_000, None), txid(1), 0)
+            .unwrap();
+
+        db.replace_payout(&txid(1), &txid(2)).unwrap();
+        assert_eq!(db.replaced_by(&txid(1)).unwrap(), Some(txid(2)));
+        assert_eq!(db.replaced_by(&txid(2)).unwrap(), None);
+        // both are ours
+        assert!(db.is_payout(&txid(1)).unwrap() && db.is_payout(&txid(2)).unwrap());
+        assert_eq!(db.unconfirmed_payouts(DAY_WINDOW).unwrap(), [txid(2)]);
+
+        db.confirm_payout(&txid(2)).unwrap();
+        assert!(db.unconfirmed_payouts(DAY_WINDOW).unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_queued_requests_until_they_end() {
+        let db = memory();
+        db.queue_request("a", "{}").unwrap();
+        db.queue_request("b", "{}").unwrap();
+        db.update_request("a", "sending").unwrap();
+
+        let requests = db.queued_requests().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(
+            (requests[0].id.as_str(), requests[0].state.as_str()),
+            ("a", "sending")
+        );
+        assert_eq!(requests[1].payout.as_deref(), Some("{}"));
+
+        db.finish_request("a", "paid", Some((txid(1), 2)), None, 0)
+            .unwrap();
+        let requests = db.queued_requests().unwrap();
+        assert_eq!(requests[0].payout, None);
+        assert_eq!(
+            (requests[0].txid, requests[0].vout),
+            (Some(txid(1)), Some(2))
+        );
+
+        // those that ended long enough ago are forgotten when another ends
+        db.finish_request("b", "failed", None, Some("no"), now() + 1)
+            .unwrap();
+        assert!(db.queued_requests().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reports_the_day() {
+        let db = memory();
+        db.record_payout(&payout(0, 10_000, None), txid(1), 0)
+            .unwrap();
+        db.record_payout(&payout(0, 20_000, None), txid(2), 0)
+            .unwrap();
+        db.record_rejection("rate_limited").unwrap();
+        db.record_rejection("rate_limited").unwrap();
+        db.record_rejection("blocked").unwrap();
+
+        let today = now() / DAY * DAY;
+        let report = db.daily_report(today).unwrap();
+        assert_eq!(report.payouts, 2);
+        assert_eq!(report.sats_out, 30_000);
+        assert_eq!(report.unique_addresses, 1);
+        assert_eq!(report.channels_opened, 0);
+        assert_eq!(report.rejections["rate_limited"], 2);
+        assert_eq!(report.rejections["blocked"], 1);
+
+        let yesterday = db.daily_report(today - DAY).unwrap();
+        assert_eq!(yesterday.payouts, 0);
+        assert!(yesterday.rejections.is_empty());
+    }
+}
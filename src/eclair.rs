@@ -0,0 +1,276 @@
+//SPDX-License-Identifier: MIT
+
+//! A client for Eclair's HTTP API. Eclair takes form-encoded parameters and uses basic auth
+//! with an empty user and the password set in `eclair.api.password`.
+//!
+//! ureq is blocking, so calls run on actix's blocking thread pool rather than on the workers.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bitcoin::base64::engine::general_purpose::STANDARD;
+use bitcoin::base64::Engine;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::Amount;
+use lightning::offers::offer::Offer;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::info;
+
+use crate::api::Error;
+use crate::ln::ChannelInfo;
+use crate::ln::ChannelRequest;
+use crate::ln::LightningBackend;
+use crate::ln::NodeInfo;
+use crate::retry;
+use crate::retry::Failure;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GetInfo {
+    node_id: String,
+    alias: String,
+    block_height: u32,
+}
+
+/// What we need to make requests, cheap to clone into the blocking pool
+#[derive(Clone)]
+struct EclairApi {
+    agent: ureq::Agent,
+    url: String,
+    authorization: String,
+}
+
+impl EclairApi {
+    fn post(
+        &self,
+        method: &str,
+        params: &[(&str, &str)],
+    ) -> Result<ureq::Response, Failure<Error>> {
+        self.agent
+            .post(&format!("{}/{method}", self.url))
+            .set("Authorization", &self.authorization)
+            .send_form(params)
+            .map_err(|e| match e {
+                // eclair explains what went wrong in the body
+                ureq::Error::Status(_, response) => Failure::Answered(Error::EclairError(
+                    response.into_string().unwrap_or_else(|e| e.to_string()),
+                )),
+                e => Failure::Unanswered(Error::EclairError(e.to_string())),
+            })
+    }
+}
+
+/// The methods eclair may act on without us hearing back, which we don't call twice
+const NOT_IDEMPOTENT: &[&str] = &["open", "payinvoice", "payoffer", "sendtonode"];
+
+pub struct EclairDaemon {
+    api: EclairApi,
+}
+
+impl EclairDaemon {
+    pub fn new(url: String, password: &str) -> Result<Self> {
+        let api = EclairApi {
+            agent: ureq::Agent::new(),
+            url: url.trim_end_matches('/').to_string(),
+            authorization: format!("Basic {}", STANDARD.encode(format!(":{password}"))),
+        };
+
+        let info: GetInfo = api
+            .post("getinfo", &[])
+            .map_err(Failure::into_inner)?
+            .into_json()?;
+        info!("connected to eclair node {} ({})", info.alias, info.node_id);
+
+        Ok(Self { api })
+    }
+
+    /// Runs `method` on actix's blocking pool and parses its json response, trying again a few
+    /// times if eclair didn't get it. Those eclair may have acted on without us hearing back we
+    /// only try once
+    async fn request<T: serde::de::DeserializeOwned + Send + 'static>(
+        &self,
+        method: &'static str,
+        params: Vec<(&'static str, String)>,
+    ) -> Result<T, Error> {
+        retry::with_backoff("eclair", !NOT_IDEMPOTENT.contains(&method), || {
+            let api = self.api.clone();
+            let params = params.clone();
+            async move {
+                actix_web::web::block(move || {
+                    let params = params
+                        .iter()
+                        .map(|(name, value)| (*name, value.as_str()))
+                        .collect::<Vec<_>>();
+
+                    api.post(method, &params)?
+                        .into_json::<T>()
+                        .map_err(|e| Failure::Answered(Error::EclairError(e.to_string())))
+                })
+                .await
+                .map_err(|e| Failure::Answered(Error::EclairError(e.to_string())))?
+            }
+        })
+        .await
+    }
+}
+
+#[async_trait(?Send)]
+impl LightningBackend for EclairDaemon {
+    /// Without an address, eclair looks for one in the network graph
+    async fn connect(
+        &self,
+        id: PublicKey,
+        address: Option<&str>,
+        _required_features: &[usize],
+    ) -> Result<(), Error> {
+        let params = match address {
+            Some(address) => vec![("uri", format!("{id}@{address}"))],
+            None => vec![("nodeId", id.to_string())],
+        };
+
+        self.request::<String>("connect", params)
+            .await
+            .map(|_| ())
+            .map_err(|e| Error::PeerUnreachable(e.to_string()))
+    }
+
+    /// Opens a channel to `id` and returns eclair's description of it, which includes the
+    /// channel id and funding txid
+    /// Eclair only opens zero-conf channels to peers it's configured to trust, which we can't
+    /// ask for from here
+    async fn open_channel(&self, id: PublicKey, channel: ChannelRequest) -> Result<String, Error> {
+        if channel.zero_conf {
+            return Err(Error::NotSupported);
+        }
+
+        let params = vec![
+            ("nodeId", id.to_string()),
+            ("fundingSatoshis", channel.capacity.to_sat().to_string()),
+            ("pushMsat", (channel.push.to_sat() * 1_000).to_string()),
+        ];
+
+        self.request("open", params).await
+    }
+
+    /// Eclair doesn't tell us the closing transaction, so this returns what it answered
+    async fn close_channel(&self, channel_id: &str, force: bool) -> Result<String, Error> {
+        let params = vec![("channelId", channel_id.to_string())];
+        let closed = match self.request::<Value>("close", params.clone()).await {
+            Err(_) if force => self.request::<Value>("forceclose", params).await?,
+            closed => closed?,
+        };
+
+        // eclair answers with the result for each channel we asked to close
+        match closed[channel_id].as_str() {
+            Some("ok") => Ok(String::new()),
+            Some(error) => Err(Error::EclairError(error.to_string())),
+            None => Ok(closed.to_string()),
+        }
+    }
+
+    async fn create_invoice(&self, amount_msat: u64, description: &str) -> Result<String, Error> {
+        let params = vec![
+            ("amountMsat", amount_msat.to_string()),
+            (
+                "descriptionHash",
+                sha256::Hash::hash(description.as_bytes()).to_string(),
+            ),
+        ];
+
+        let invoice: Value = self.request("createinvoice", params).await?;
+        match invoice["serialized"].as_str() {
+            Some(invoice) => Ok(invoice.to_string()),
+            None => Err(Error::EclairError(invoice.to_string())),
+        }
+    }
+
+    async fn pay_invoice(&self, invoice: &str) -> Result<String, Error> {
+        let params = vec![
+            ("invoice", invoice.to_string()),
+            ("blocking", "true".to_string()),
+        ];
+
+        // a blocking payment returns either a payment-sent or a payment-failed event
+        let event: Value = self.request("payinvoice", params).await?;
+        match event["paymentPreimage"].as_str() {
+            Some(preimage) => Ok(preimage.to_string()),
+            None => Err(Error::EclairError(event.to_string())),
+        }
+    }
+
+    /// Eclair doesn't wait for keysend payments to complete, so this returns the payment id
+    async fn keysend(&self, id: PublicKey, amount_msat: u64) -> Result<String, Error> {
+        let params = vec![
+            ("nodeId", id.to_string()),
+            ("amountMsat", amount_msat.to_string()),
+        ];
+
+        self.request("sendtonode", params).await
+    }
+
+    async fn pay_offer(&self, offer: &Offer, amount_msat: u64) -> Result<String, Error> {
+        let params = vec![
+            ("offer", offer.to_string()),
+            ("amountMsat", amount_msat.to_string()),
+            ("blocking", "true".to_string()),
+        ];
+
+        let event: Value = self.request("payoffer", params).await?;
+        match event["paymentPreimage"].as_str() {
+            Some(preimage) => Ok(preimage.to_string()),
+            None => Err(Error::EclairError(event.to_string())),
+        }
+    }
+
+    async fn node_info(&self) -> Result<NodeInfo, Error> {
+        let info: GetInfo = self.request("getinfo", vec![]).await?;
+
+        Ok(NodeInfo {
+            id: info
+                .node_id
+                .parse()
+                .map_err(|_| Error::EclairError("invalid node id".into()))?,
+            alias: info.alias,
+            block_height: info.block_height,
+        })
+    }
+
+    async fn list_channels(&self) -> Result<Vec<ChannelInfo>, Error> {
+        let channels: Vec<Value> = self.request("channels", vec![]).await?;
+
+        channels
+            .into_iter()
+            .map(|channel| {
+                // the channel's data depends on its state, but all open channels have an
+                // active commitment with the funding amount and our balance
+                let commitment = &channel["data"]["commitments"]["active"][0];
+                let capacity = commitment["fundingTx"]["amountSatoshis"]
+                    .as_u64()
+                    .unwrap_or_default();
+                let local_msat = commitment["localCommit"]["spec"]["toLocal"]
+                    .as_u64()
+                    .unwrap_or_default();
+
+                Ok(ChannelInfo {
+                    peer: channel["nodeId"]
+                        .as_str()
+                        .and_then(|id| id.parse().ok())
+                        .ok_or(Error::EclairError("invalid peer id".into()))?,
+                    channel_id: channel["channelId"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .to_string(),
+                    capacity: Amount::from_sat(capacity),
+                    local_balance: Amount::from_sat(local_msat / 1_000),
+                    state: channel["state"].as_str().unwrap_or_default().to_string(),
+                    funding_outpoint: commitment["fundingTx"]["outPoint"]
+                        .as_str()
+                        .map(|outpoint| outpoint.to_string()),
+                    active: channel["state"] == "NORMAL",
+                })
+            })
+            .collect()
+    }
+}
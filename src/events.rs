@@ -0,0 +1,124 @@
+//SPDX-License-Identifier: MIT
+
+//! A live feed of what the faucet does, served at /events as Server-Sent Events. The index page
+//! and dashboards listen to it to show payouts and channels as they happen, instead of polling
+//! /history.
+//!
+//! Each event is named after what happened, like `payout`, with its json as the data. Listeners
+//! that fall too far behind miss events, we don't wait for them.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::web;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+#[cfg(feature = "lightning")]
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::Txid;
+use bitcoincore_rpc::jsonrpc::serde_json;
+use futures::channel::mpsc;
+use futures::StreamExt;
+use serde::Serialize;
+
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+
+/// How many events we hold for a listener that isn't keeping up
+const BACKLOG: usize = 64;
+
+/// How many listeners we take at once
+const MAX_LISTENERS: usize = 1_000;
+
+/// How often we send listeners a comment, so proxies don't think the stream died
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Something the faucet did
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Activity {
+    /// We paid `address`, cut short like in /history
+    Payout {
+        txid: Txid,
+        address: String,
+        /// In sats
+        amount: u64,
+    },
+    /// We opened a channel to `node_id`
+    #[cfg(feature = "lightning")]
+    ChannelOpened {
+        node_id: PublicKey,
+        channel: String,
+        /// In sats
+        capacity: u64,
+    },
+}
+
+impl Activity {
+    /// The event's name, for listeners to pick what they care about
+    fn name(&self) -> &'static str {
+        match self {
+            Activity::Payout { .. } => "payout",
+            #[cfg(feature = "lightning")]
+            Activity::ChannelOpened { .. } => "channel_opened",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct ActivityFeed {
+    listeners: Mutex<Vec<mpsc::Sender<Bytes>>>,
+}
+
+impl ActivityFeed {
+    /// Tells everyone listening about `activity`
+    pub fn publish(&self, activity: Activity) {
+        let data = serde_json::to_string(&activity).expect("events serialize");
+        let event = Bytes::from(format!("event: {}\ndata: {data}\n\n", activity.name()));
+
+        // listeners that went away are dropped, and those that are full miss this one
+        self.listeners.lock().unwrap().retain_mut(|listener| {
+            match listener.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(e) => e.is_full(),
+            }
+        });
+    }
+
+    fn listen(&self) -> Result<mpsc::Receiver<Bytes>, Error> {
+        let mut listeners = self.listeners.lock().unwrap();
+        listeners.retain(|listener| !listener.is_closed());
+        if listeners.len() >= MAX_LISTENERS {
+            return Err(Error::TooManyListeners);
+        }
+
+        let (sender, receiver) = mpsc::channel(BACKLOG);
+        listeners.push(sender);
+        Ok(receiver)
+    }
+}
+
+/// Streams what the faucet does, as Server-Sent Events
+#[utoipa::path(
+    get,
+    path = "/v1/events",
+    tag = "faucet",
+    responses(
+        (status = 200, description = "An event stream of `payout` and `channel_opened` events", content_type = "text/event-stream", body = String),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn events<B: ChainBackend>(data: web::Data<AppState<B>>) -> Result<HttpResponse, Error> {
+    let events = data.activity.listen()?;
+    let keepalive = futures::stream::unfold((), |_| async {
+        actix::clock::sleep(KEEPALIVE_INTERVAL).await;
+        Some((Bytes::from_static(b": keepalive\n\n"), ()))
+    });
+    let stream = futures::stream::select(events, keepalive).map(Ok::<_, actix_web::Error>);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream))
+}
@@ -0,0 +1,235 @@
+//SPDX-License-Identifier: MIT
+
+//! Exporting what we paid out and the channels we opened, for bookkeeping and for looking into
+//! abuse. Admins get every record made between `from` and `to`, in unix time, as CSV or JSON.
+//!
+//! There may be lots of them, so we read them a page at a time and stream each one as we go,
+//! instead of holding them all in memory, or the database's lock while the client downloads.
+//! Records made while exporting may or may not make it in.
+
+use actix_web::web;
+use actix_web::web::Bytes;
+use actix_web::HttpResponse;
+use bitcoincore_rpc::jsonrpc::serde_json;
+use futures::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+use utoipa::IntoParams;
+use utoipa::ToSchema;
+
+use crate::api::blocking;
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+
+/// How many records we read from the database at once
+const PAGE_SIZE: u32 = 500;
+
+/// The columns of our CSV exports
+const CSV_HEADER: &str = "kind,id,created_at,destination,amount,reference,vout,client,account\n";
+
+/// What we export, one after the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Payouts,
+    Channels,
+}
+
+impl Kind {
+    /// What we export after this
+    fn next(self) -> Option<Self> {
+        match self {
+            Kind::Payouts => Some(Kind::Channels),
+            Kind::Channels => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Csv,
+    #[default]
+    Json,
+}
+
+/// A payout or a channel we opened
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Record {
+    /// `payout` or `channel`
+    pub kind: &'static str,
+    pub id: i64,
+    /// In unix time
+    pub created_at: u64,
+    /// The address we paid, or the node we opened the channel to
+    pub destination: String,
+    /// How much we paid, or the channel's capacity, in sats
+    pub amount: u64,
+    /// The transaction paying it, or the channel
+    pub reference: String,
+    /// The output paying it, if we know it. Older payouts and channels don't have one
+    pub vout: Option<u32>,
+    /// The hash of the client's IP, if it was a client asking
+    pub client: Option<String>,
+    pub account: Option<String>,
+}
+
+impl Record {
+    /// This record as a line of CSV
+    fn to_csv(&self) -> String {
+        let fields = [
+            self.kind.to_string(),
+            self.id.to_string(),
+            self.created_at.to_string(),
+            self.destination.clone(),
+            self.amount.to_string(),
+            self.reference.clone(),
+            self.vout.map(|vout| vout.to_string()).unwrap_or_default(),
+            self.client.clone().unwrap_or_default(),
+            self.account.clone().unwrap_or_default(),
+        ];
+        let mut line = fields.map(|field| csv_field(&field)).join(",");
+        line.push('\n');
+
+        line
+    }
+}
+
+/// Quotes `field` if it has anything that would break the CSV. Spreadsheets also run fields
+/// starting like a formula, which accounts we don't control might
+fn csv_field(field: &str) -> String {
+    let formula = field.starts_with(['=', '+', '-', '@']);
+    if !formula && !field.contains([',', '"', '\n', '\r']) {
+        return field.to_string();
+    }
+
+    let field = field.replace('"', "\"\"");
+    if formula {
+        format!("\"'{field}\"")
+    } else {
+        format!("\"{field}\"")
+    }
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct ExportQuery {
+    /// `csv` or `json`, the default
+    #[param(value_type = Option<String>)]
+    format: Option<Format>,
+    /// Only records made at or after this, in unix time
+    from: Option<u64>,
+    /// Only records made before this, in unix time
+    to: Option<u64>,
+}
+
+/// Where we are in an export
+struct Cursor {
+    kind: Option<Kind>,
+    /// The last record we exported of this kind
+    after: i64,
+    /// Whether we exported anything yet
+    started: bool,
+}
+
+/// Exports our payouts, then the channels we opened, made between `from` and `to`, as CSV or a
+/// JSON array, oldest first
+#[utoipa::path(
+    get,
+    path = "/v1/admin/export",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    params(ExportQuery),
+    responses(
+        (status = 200, body = [Record], content_type = ["application/json", "text/csv"]),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn export<B: ChainBackend>(
+    query: web::Query<ExportQuery>,
+    data: web::Data<AppState<B>>,
+) -> Result<HttpResponse, Error> {
+    let format = query.format.unwrap_or_default();
+    // sqlite integers are signed
+    let from = query.from.unwrap_or(0).min(i64::MAX as u64);
+    let to = query.to.unwrap_or(i64::MAX as u64).min(i64::MAX as u64);
+    if from > to {
+        return Err(Error::InvalidRequest("from is after to".into()));
+    }
+
+    let cursor = Cursor {
+        kind: Some(Kind::Payouts),
+        after: 0,
+        started: false,
+    };
+    let pages = futures::stream::unfold(cursor, move |cursor| {
+        let data = data.clone();
+        async move {
+            let kind = cursor.kind?;
+            let after = cursor.after;
+            let records = match blocking(&data, move |data| {
+                Ok(data.db.export(kind, from, to, after, PAGE_SIZE)?)
+            })
+            .await
+            {
+                Ok(records) => records,
+                Err(e) => {
+                    // the download breaks off, so it isn't mistaken for a whole export
+                    warn!("couldn't export {kind:?}: {e}");
+                    let done = Cursor {
+                        kind: None,
+                        ..cursor
+                    };
+                    return Some((Err(actix_web::Error::from(e)), done));
+                }
+            };
+
+            let mut chunk = String::new();
+            for (i, record) in records.iter().enumerate() {
+                match format {
+                    Format::Csv => chunk.push_str(&record.to_csv()),
+                    Format::Json => {
+                        if cursor.started || i > 0 {
+                            chunk.push(',');
+                        }
+                        chunk.push_str(&serde_json::to_string(record).unwrap_or_default());
+                    }
+                }
+            }
+
+            let next = match records.last() {
+                Some(last) if records.len() == PAGE_SIZE as usize => Cursor {
+                    kind: Some(kind),
+                    after: last.id,
+                    started: true,
+                },
+                _ => Cursor {
+                    kind: kind.next(),
+                    after: 0,
+                    started: cursor.started || !records.is_empty(),
+                },
+            };
+
+            Some((Ok(Bytes::from(chunk)), next))
+        }
+    });
+
+    let (content_type, header, footer, extension) = match format {
+        Format::Csv => ("text/csv", CSV_HEADER, "", "csv"),
+        Format::Json => ("application/json", "[", "]\n", "json"),
+    };
+    let stream = futures::stream::once(async move { Ok(Bytes::from_static(header.as_bytes())) })
+        .chain(pages)
+        .chain(futures::stream::once(async move {
+            Ok(Bytes::from_static(footer.as_bytes()))
+        }));
+
+    Ok(HttpResponse::Ok()
+        .content_type(content_type)
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"faucet-export.{extension}\""),
+        ))
+        .streaming(stream))
+}
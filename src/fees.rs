@@ -0,0 +1,108 @@
+//SPDX-License-Identifier: MIT
+
+//! What our transactions pay in fees. Rather than a flat amount per payout, which overpays
+//! when mempools are empty and gets stuck when they fill up, payouts pay the feerate the
+//! backend estimates for confirming within a few blocks, for their actual size. Operators set a
+//! floor and a ceiling on it: the floor is what we pay when the backend can't estimate, as is
+//! common on signet, and the ceiling keeps a spike from draining the faucet in fees.
+
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+
+use crate::api::Error;
+use crate::backend::ChainBackend;
+
+/// How many vbytes we count for each input and output, and for the rest of a transaction. We
+/// don't know what scripts the wallet uses, so these are enough for any of the common ones
+pub const INPUT_VSIZE: u64 = 150;
+pub const OUTPUT_VSIZE: u64 = 50;
+pub const OVERHEAD_VSIZE: u64 = 11;
+
+/// The most blocks nodes estimate fees for
+pub const MAX_TARGET: u16 = 1_008;
+
+#[derive(Debug, Clone, Copy)]
+pub struct FeePolicy {
+    /// How many blocks our transactions aim to confirm within
+    pub target: u16,
+    /// The fewest blocks a request may ask its payout to confirm within, paying more for it
+    pub min_target: u16,
+    pub floor: FeeRate,
+    pub ceiling: FeeRate,
+}
+
+impl FeePolicy {
+    /// The feerate our transactions pay right now
+    pub fn feerate<B: ChainBackend>(&self, backend: &B) -> Result<FeeRate, Error> {
+        self.feerate_for(backend, self.target)
+    }
+
+    /// The feerate for confirming within `target` blocks right now, or within `min_target` if
+    /// that's sooner
+    pub fn feerate_for<B: ChainBackend>(&self, backend: &B, target: u16) -> Result<FeeRate, Error> {
+        let target = target.clamp(self.min_target, MAX_TARGET);
+        let estimate = backend.estimate_fee(target)?.unwrap_or(self.floor);
+
+        Ok(estimate.clamp(self.floor, self.ceiling))
+    }
+}
+
+/// What `vsize` vbytes cost at `feerate`
+pub fn fee(feerate: FeeRate, vsize: u64) -> Amount {
+    feerate.fee_vb(vsize).unwrap_or(Amount::MAX_MONEY)
+}
+
+/// What a transaction with `inputs` and `outputs` costs at `feerate`
+pub fn transaction_fee(feerate: FeeRate, inputs: usize, outputs: usize) -> Amount {
+    fee(
+        feerate,
+        OVERHEAD_VSIZE + INPUT_VSIZE * inputs as u64 + OUTPUT_VSIZE * outputs as u64,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockNode;
+
+    const POLICY: FeePolicy = FeePolicy {
+        target: 6,
+        min_target: 2,
+        floor: FeeRate::from_sat_per_vb_unchecked(2),
+        ceiling: FeeRate::from_sat_per_vb_unchecked(50),
+    };
+
+    fn estimating(sat_per_vb: Option<u64>) -> MockNode {
+        MockNode {
+            estimate: sat_per_vb.map(FeeRate::from_sat_per_vb_unchecked),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pays_what_the_node_estimates() {
+        let feerate = POLICY.feerate(&estimating(Some(10))).unwrap();
+        assert_eq!(feerate, FeeRate::from_sat_per_vb_unchecked(10));
+    }
+
+    #[test]
+    fn keeps_between_the_floor_and_the_ceiling() {
+        assert_eq!(POLICY.feerate(&estimating(Some(1))).unwrap(), POLICY.floor);
+        assert_eq!(POLICY.feerate(&estimating(None)).unwrap(), POLICY.floor);
+        assert_eq!(
+            POLICY.feerate(&estimating(Some(1_000))).unwrap(),
+            POLICY.ceiling
+        );
+    }
+
+    #[test]
+    fn prices_transactions_by_their_size() {
+        let feerate = FeeRate::from_sat_per_vb_unchecked(2);
+        assert_eq!(fee(feerate, 100), Amount::from_sat(200));
+        assert_eq!(
+            transaction_fee(feerate, 2, 3),
+            Amount::from_sat(2 * (OVERHEAD_VSIZE + 2 * INPUT_VSIZE + 3 * OUTPUT_VSIZE))
+        );
+        assert_eq!(fee(FeeRate::MAX, u64::MAX), Amount::MAX_MONEY);
+    }
+}
@@ -0,0 +1,306 @@
+//SPDX-License-Identifier: MIT
+
+//! A payout tier for GitHub users. /auth/github sends the user through GitHub's OAuth flow, and
+//! once they come back to /auth/github/callback we look their account up. If it's old enough,
+//! they get a session cookie, and /send/ lets them ask for more while the cookie lasts. What
+//! each account got is recorded in the database, so they can only get that much a day, however
+//! many addresses they use.
+//!
+//! We only ask GitHub who the user is, so the login asks for no scopes and we drop the access
+//! token right after.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use actix_web::cookie::Cookie;
+use actix_web::cookie::SameSite;
+use actix_web::http::header;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use bitcoin::hex::DisplayHex;
+use bitcoin::Amount;
+use serde::Deserialize;
+
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+
+const AUTHORIZE_URL: &str = "https://github.com/login/oauth/authorize";
+const ACCESS_TOKEN_URL: &str = "https://github.com/login/oauth/access_token";
+const USER_URL: &str = "https://api.github.com/user";
+
+/// The cookie holding our session id
+const SESSION_COOKIE: &str = "faucet_session";
+
+/// The cookie holding the state of a login in progress, so logins can't be started for someone
+/// else
+const STATE_COOKIE: &str = "faucet_github_state";
+
+/// How long users have to log in with GitHub
+const STATE_TTL: Duration = Duration::from_secs(600);
+
+/// How long a session lasts
+const SESSION_TTL: Duration = Duration::from_secs(24 * 3_600);
+
+/// How far back we look at what an account got, for its daily limit
+pub const ACCOUNT_WINDOW: Duration = Duration::from_secs(24 * 3_600);
+
+#[derive(Deserialize)]
+pub struct CallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    /// Set instead of `code` if the user didn't authorize us
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AccessToken {
+    access_token: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct User {
+    id: u64,
+    login: String,
+    created_at: String,
+}
+
+struct Session {
+    /// The account's id, as `github:<id>`
+    account: String,
+    created: Instant,
+}
+
+pub struct GithubTier {
+    client_id: String,
+    client_secret: String,
+    /// Where GitHub sends users back to, our /auth/github/callback
+    redirect_url: String,
+    /// How old accounts have to be
+    min_age: Duration,
+    /// How much logged in users may get a day
+    pub max_sendable: Amount,
+    /// The logins in progress, with when they started
+    states: Mutex<HashMap<String, Instant>>,
+    /// The logged in users, by session id
+    sessions: Mutex<HashMap<String, Session>>,
+    agent: ureq::Agent,
+}
+
+/// Parses the `YYYY-MM-DDTHH:MM:SSZ` timestamps GitHub uses into unix time
+fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.splitn(3, ':').map(str::parse::<i64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    // days since 1970-01-01, from Howard Hinnant's days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    u64::try_from(days * 86_400 + hour * 3_600 + minute * 60 + second).ok()
+}
+
+fn random_id() -> String {
+    bitcoin::secp256k1::rand::random::<[u8; 32]>().to_lower_hex_string()
+}
+
+/// Percent-encodes `value` for a query string
+fn urlencoding(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+impl GithubTier {
+    pub fn new(
+        client_id: String,
+        client_secret: String,
+        redirect_url: String,
+        min_age: Duration,
+        max_sendable: Amount,
+    ) -> Self {
+        Self {
+            client_id,
+            client_secret,
+            redirect_url,
+            min_age,
+            max_sendable,
+            states: Mutex::new(HashMap::new()),
+            sessions: Mutex::new(HashMap::new()),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// Makes a cookie for `value`, only sent over https if we're served over https
+    fn cookie<'a>(&self, name: &'a str, value: String, max_age: Duration) -> Cookie<'a> {
+        Cookie::build(name, value)
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(self.redirect_url.starts_with("https://"))
+            .max_age(actix_web::cookie::time::Duration::seconds(
+                max_age.as_secs() as i64,
+            ))
+            .finish()
+    }
+
+    /// The account `req`'s session cookie belongs to, if it has a live one
+    pub fn account(&self, req: &HttpRequest) -> Option<String> {
+        let session = req.cookie(SESSION_COOKIE)?;
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| session.created.elapsed() < SESSION_TTL);
+        sessions
+            .get(session.value())
+            .map(|session| session.account.clone())
+    }
+
+    /// Asks GitHub who the user that gave us `code` is
+    async fn user(&self, code: String) -> Result<User, Error> {
+        let token_request = self
+            .agent
+            .post(ACCESS_TOKEN_URL)
+            .set("Accept", "application/json");
+        let user_request = self
+            .agent
+            .get(USER_URL)
+            .set("Accept", "application/vnd.github+json")
+            .set("User-Agent", "yet-another-faucet");
+        let client_id = self.client_id.clone();
+        let client_secret = self.client_secret.clone();
+        let redirect_url = self.redirect_url.clone();
+
+        actix_web::web::block(move || {
+            let token = token_request
+                .send_form(&[
+                    ("client_id", client_id.as_str()),
+                    ("client_secret", client_secret.as_str()),
+                    ("code", code.as_str()),
+                    ("redirect_uri", redirect_url.as_str()),
+                ])
+                .map_err(|e| Error::GithubError(e.to_string()))?
+                .into_json::<AccessToken>()
+                .map_err(|e| Error::GithubError(e.to_string()))?;
+            // GitHub answers bad codes with a 200 and an error
+            let Some(token) = token.access_token else {
+                return Err(Error::GithubLoginFailed(
+                    token
+                        .error_description
+                        .unwrap_or("GitHub didn't give us a token".into()),
+                ));
+            };
+
+            user_request
+                .set("Authorization", &format!("Bearer {token}"))
+                .call()
+                .map_err(|e| Error::GithubError(e.to_string()))?
+                .into_json::<User>()
+                .map_err(|e| Error::GithubError(e.to_string()))
+        })
+        .await
+        .map_err(|e| Error::GithubError(e.to_string()))?
+    }
+}
+
+/// Sends the user to GitHub, to log in
+pub async fn login<B: ChainBackend>(data: web::Data<AppState<B>>) -> Result<HttpResponse, Error> {
+    let tier = data.github.as_ref().ok_or(Error::GithubDisabled)?;
+
+    let state = random_id();
+    {
+        let mut states = tier.states.lock().unwrap();
+        states.retain(|_, started| started.elapsed() < STATE_TTL);
+        states.insert(state.clone(), Instant::now());
+    }
+
+    let url = format!(
+        "{AUTHORIZE_URL}?client_id={}&redirect_uri={}&state={state}&allow_signup=false",
+        tier.client_id,
+        urlencoding(&tier.redirect_url),
+    );
+
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, url))
+        .cookie(tier.cookie(STATE_COOKIE, state, STATE_TTL))
+        .finish())
+}
+
+/// Where GitHub sends users back to. If their account is old enough, they get a session
+pub async fn callback<B: ChainBackend>(
+    req: HttpRequest,
+    query: web::Query<CallbackQuery>,
+    data: web::Data<AppState<B>>,
+) -> Result<HttpResponse, Error> {
+    let tier = data.github.as_ref().ok_or(Error::GithubDisabled)?;
+    let CallbackQuery { code, state, error } = query.into_inner();
+
+    if let Some(error) = error {
+        return Err(Error::GithubLoginFailed(error));
+    }
+    let (Some(code), Some(state)) = (code, state) else {
+        return Err(Error::GithubLoginFailed("GitHub didn't send a code".into()));
+    };
+
+    // the state has to be one we made, for this browser
+    if req.cookie(STATE_COOKIE).as_ref().map(Cookie::value) != Some(state.as_str()) {
+        return Err(Error::GithubLoginFailed(
+            "this login was started somewhere else".into(),
+        ));
+    }
+    match tier.states.lock().unwrap().remove(&state) {
+        Some(started) if started.elapsed() < STATE_TTL => {}
+        _ => return Err(Error::GithubLoginFailed("this login expired".into())),
+    }
+
+    let user = tier.user(code).await?;
+    let created_at = parse_timestamp(&user.created_at).ok_or(Error::GithubError(format!(
+        "bad timestamp {}",
+        user.created_at
+    )))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("we're past 1970")
+        .as_secs();
+    if now.saturating_sub(created_at) < tier.min_age.as_secs() {
+        return Err(Error::GithubLoginFailed(format!(
+            "{} is younger than {} days",
+            user.login,
+            tier.min_age.as_secs() / 86_400
+        )));
+    }
+
+    let session = random_id();
+    tier.sessions.lock().unwrap().insert(
+        session.clone(),
+        Session {
+            account: format!("github:{}", user.id),
+            created: Instant::now(),
+        },
+    );
+
+    let mut expired_state = tier.cookie(STATE_COOKIE, String::new(), Duration::ZERO);
+    expired_state.make_removal();
+    Ok(HttpResponse::Found()
+        .insert_header((header::LOCATION, "/"))
+        .cookie(tier.cookie(SESSION_COOKIE, session, SESSION_TTL))
+        .cookie(expired_state)
+        .finish())
+}
@@ -0,0 +1,266 @@
+//SPDX-License-Identifier: MIT
+
+//! Hold-invoice gated payouts. When they're enabled, /send/ doesn't pay right away: it answers
+//! with a tiny hold invoice, and only once the user pays it, proving they run a working
+//! Lightning node, we send their coins and settle the invoice. If we can't send the coins, we
+//! cancel the invoice and the user gets their sat back.
+//!
+//! We make the preimages ourselves, so the user's payment stays locked until we decide what to
+//! do with it.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use actix_web::web;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::Txid;
+use tracing::warn;
+
+use crate::api::blocking;
+use crate::api::send_coins;
+use crate::api::AppState;
+use crate::api::Error;
+use crate::api::Payout;
+use crate::backend::ChainBackend;
+use crate::ln::HoldInvoiceState;
+
+/// What users pay to prove they have a Lightning node, in msats
+const HOLD_INVOICE_AMOUNT: u64 = 1_000;
+
+/// How long users have to pay the hold invoice, in seconds
+const HOLD_INVOICE_EXPIRY: u32 = 600;
+
+/// How often we check whether the hold invoices were paid
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long we remember payouts after they're done, so users can check on them
+const FORGET_AFTER: Duration = Duration::from_secs(3_600);
+
+/// Where a hold-invoice gated payout is at
+#[derive(Debug, Clone)]
+pub enum HoldStatus {
+    /// We're waiting for the user to pay the hold invoice
+    WaitingForPayment,
+    /// The invoice was paid and we sent the coins
+    Paid(Txid),
+    /// We couldn't send the coins, or the invoice expired
+    Failed(String),
+}
+
+#[derive(Clone)]
+struct HoldPayout {
+    payout: Payout,
+    preimage: [u8; 32],
+    created: Instant,
+    status: HoldStatus,
+}
+
+/// The payouts waiting for their hold invoice, by payment hash
+#[derive(Default)]
+pub struct HoldPayouts {
+    payouts: Mutex<HashMap<sha256::Hash, HoldPayout>>,
+}
+
+impl HoldPayouts {
+    pub fn status(&self, payment_hash: &sha256::Hash) -> Option<HoldStatus> {
+        self.payouts
+            .lock()
+            .unwrap()
+            .get(payment_hash)
+            .map(|payout| payout.status.clone())
+    }
+
+    fn waiting(&self) -> Vec<(sha256::Hash, HoldPayout)> {
+        self.payouts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, payout)| matches!(payout.status, HoldStatus::WaitingForPayment))
+            .map(|(hash, payout)| (*hash, payout.clone()))
+            .collect()
+    }
+
+    fn set_status(&self, payment_hash: &sha256::Hash, status: HoldStatus) {
+        if let Some(payout) = self.payouts.lock().unwrap().get_mut(payment_hash) {
+            payout.status = status;
+        }
+    }
+
+    fn forget_old(&self) {
+        self.payouts
+            .lock()
+            .unwrap()
+            .retain(|_, payout| payout.created.elapsed() < FORGET_AFTER);
+    }
+}
+
+/// Creates the hold invoice users must pay before we make `payout`, returning it and its
+/// payment hash
+pub async fn request_payout<B: ChainBackend>(
+    data: &AppState<B>,
+    payouts: &HoldPayouts,
+    payout: Payout,
+) -> Result<(String, sha256::Hash), Error> {
+    let preimage = bitcoin::secp256k1::rand::random::<[u8; 32]>();
+    let payment_hash = sha256::Hash::hash(&preimage);
+
+    let invoice = data
+        .lightning
+        .create_hold_invoice(
+            preimage,
+            HOLD_INVOICE_AMOUNT,
+            &format!("faucet payout to {}", payout.address),
+            HOLD_INVOICE_EXPIRY,
+        )
+        .await?;
+
+    payouts.payouts.lock().unwrap().insert(
+        payment_hash,
+        HoldPayout {
+            payout,
+            preimage,
+            created: Instant::now(),
+            status: HoldStatus::WaitingForPayment,
+        },
+    );
+
+    Ok((invoice, payment_hash))
+}
+
+/// Sends the coins for every payout whose hold invoice was paid, and settles the invoice
+pub fn spawn_settlement_worker<B: ChainBackend>(data: web::Data<AppState<B>>) {
+    actix::spawn(async move {
+        loop {
+            actix::clock::sleep(CHECK_INTERVAL).await;
+
+            let Some(payouts) = &data.hold_payouts else {
+                return;
+            };
+            payouts.forget_old();
+
+            for (hash, payout) in payouts.waiting() {
+                let hash_bytes = hash.to_byte_array();
+                let state = match data.lightning.hold_invoice_state(hash_bytes).await {
+                    Ok(state) => state,
+                    Err(e) => {
+                        warn!("couldn't check on hold invoice {hash}: {e}");
+                        continue;
+                    }
+                };
+
+                match state {
+                    // they wait until an admin resumes payouts, as long as their HTLC lasts
+                    HoldInvoiceState::Accepted if !data.controls.may_pay() => continue,
+                    HoldInvoiceState::Accepted => {}
+                    HoldInvoiceState::Open
+                        if payout.created.elapsed().as_secs() > HOLD_INVOICE_EXPIRY as u64 =>
+                    {
+                        payouts.set_status(&hash, HoldStatus::Failed("the invoice expired".into()));
+                        continue;
+                    }
+                    HoldInvoiceState::Open => continue,
+                    HoldInvoiceState::Settled | HoldInvoiceState::Canceled => {
+                        payouts.set_status(
+                            &hash,
+                            HoldStatus::Failed("the invoice isn't payable anymore".into()),
+                        );
+                        continue;
+                    }
+                }
+
+                let sent = blocking(&data, move |data| send_coins(data, payout.payout)).await;
+                match sent {
+                    Ok(sent) => {
+                        payouts.set_status(&hash, HoldStatus::Paid(sent.txid));
+                        if let Err(e) = data.lightning.settle_hold_invoice(payout.preimage).await {
+                            warn!("couldn't settle hold invoice {hash}: {e}");
+                        }
+                    }
+                    Err(e) => {
+                        payouts.set_status(&hash, HoldStatus::Failed(e.to_string()));
+                        if let Err(e) = data.lightning.cancel_hold_invoice(hash_bytes).await {
+                            warn!("couldn't cancel hold invoice {hash}: {e}");
+                        }
+                    }
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::Amount;
+
+    use super::*;
+    use crate::testing::address;
+    use crate::testing::app_state;
+    use crate::testing::MockNode;
+    use crate::testing::CHANGE_ADDRESS;
+
+    fn payout() -> Payout {
+        Payout {
+            address: address(CHANGE_ADDRESS),
+            amount: Amount::from_sat(10_000),
+            silent_payment: None,
+            client: None,
+            cooldown: None,
+            account: None,
+            memo: None,
+            fee_target: None,
+            #[cfg(feature = "webhooks")]
+            callback: None,
+        }
+    }
+
+    /// Holds a payout, returning its payment hash
+    fn hold(payouts: &HoldPayouts, preimage: u8) -> sha256::Hash {
+        let payment_hash = sha256::Hash::hash(&[preimage; 32]);
+        payouts.payouts.lock().unwrap().insert(
+            payment_hash,
+            HoldPayout {
+                payout: payout(),
+                preimage: [preimage; 32],
+                created: Instant::now(),
+                status: HoldStatus::WaitingForPayment,
+            },
+        );
+        payment_hash
+    }
+
+    #[test]
+    fn tells_what_is_waiting() {
+        let payouts = HoldPayouts::default();
+        let paid = hold(&payouts, 1);
+        let waiting = hold(&payouts, 2);
+
+        payouts.set_status(&paid, HoldStatus::Paid(Txid::all_zeros()));
+        assert!(matches!(payouts.status(&paid), Some(HoldStatus::Paid(_))));
+        assert!(matches!(
+            payouts.status(&waiting),
+            Some(HoldStatus::WaitingForPayment)
+        ));
+        assert!(payouts.status(&sha256::Hash::hash(&[3; 32])).is_none());
+
+        let waiting_for = payouts.waiting();
+        assert_eq!(waiting_for.len(), 1);
+        assert_eq!(waiting_for[0].0, waiting);
+        assert_eq!(waiting_for[0].1.preimage, [2; 32]);
+    }
+
+    #[actix_web::test]
+    async fn holds_nothing_without_an_invoice() {
+        // our Lightning node can't make hold invoices
+        let data = app_state(MockNode::default());
+        let payouts = HoldPayouts::default();
+
+        assert!(matches!(
+            request_payout(&data, &payouts, payout()).await,
+            Err(Error::NotSupported)
+        ));
+        assert!(payouts.payouts.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,242 @@
+//SPDX-License-Identifier: MIT
+
+//! Idempotency keys for /send/. Clients retrying a request, after a timeout say, can't tell
+//! whether the first try paid them, so they may send an `Idempotency-Key` header with a value
+//! of their choosing. We remember what we answered to each key for a day, and answer retries
+//! with the same key the same way, instead of paying them again.
+//!
+//! A key belongs to the request it was first sent with: reusing it for another address or
+//! amount is refused, and so is a retry while the first try is still being handled.
+//!
+//! Keys are kept per client, under the API key they came with or else the client's IP, so a key
+//! someone else guessed or happened to pick can't get them our answer to another client, nor
+//! keep that client from using it.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::HttpRequest;
+use tracing::warn;
+
+use crate::api::Error;
+use crate::apikeys::ApiKeys;
+use crate::db::Database;
+use crate::ratelimit::client_ip;
+
+pub const HEADER: &str = "Idempotency-Key";
+
+/// For how long we remember what we answered to a key
+pub const KEY_WINDOW: Duration = Duration::from_secs(24 * 3_600);
+
+/// How long keys may be, so clients can use UUIDs or whatever they like, but not fill our disk
+const MAX_KEY_LEN: usize = 255;
+
+/// The idempotency key `req` carries, if any, as we keep it: after whoever sent it, by their API
+/// key if they have one, or their IP
+pub fn key(req: &HttpRequest, api_keys: &ApiKeys) -> Result<Option<String>, Error> {
+    let Some(key) = req.headers().get(HEADER) else {
+        return Ok(None);
+    };
+    let key = match key.to_str().map(str::trim) {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key,
+        _ => return Err(Error::InvalidIdempotencyKey),
+    };
+
+    let client = match api_keys.authenticate(req)? {
+        Some(api_key) => format!("key:{}", api_key.name),
+        None => match client_ip(req) {
+            Some(ip) => format!("ip:{ip}"),
+            None => "unknown".to_string(),
+        },
+    };
+    Ok(Some(format!("{client} {key}")))
+}
+
+/// What to do with a request carrying a key
+pub enum Started<'a> {
+    /// We answered this already, this is what we said
+    Replay(String),
+    /// It's new, answer it and [InFlight::finish] it
+    New(InFlight<'a>),
+}
+
+/// A key whose request we're handling. Until it's dropped, retries are refused
+pub struct InFlight<'a> {
+    keys: &'a IdempotencyKeys,
+    key: String,
+    request: String,
+}
+
+impl InFlight<'_> {
+    /// Remembers `response` as the answer to the key
+    pub fn finish(self, db: &Database, response: &str) {
+        if let Err(e) = db.record_idempotent_response(&self.key, &self.request, response) {
+            warn!("couldn't remember the answer to {}: {e}", self.key);
+        }
+    }
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.keys.in_flight.lock().unwrap().remove(&self.key);
+    }
+}
+
+#[derive(Default)]
+pub struct IdempotencyKeys {
+    /// The keys whose first request we're handling right now
+    in_flight: Mutex<HashSet<String>>,
+}
+
+impl IdempotencyKeys {
+    /// Starts handling `request`, sent with `key`. `request` is what the key has to stick to,
+    /// like the address and amount asked for
+    pub fn begin(&self, db: &Database, key: String, request: String) -> Result<Started<'_>, Error> {
+        if !self.in_flight.lock().unwrap().insert(key.clone()) {
+            return Err(Error::IdempotencyKeyInUse);
+        }
+        let in_flight = InFlight {
+            keys: self,
+            key,
+            request,
+        };
+
+        match db.idempotent_response(&in_flight.key, KEY_WINDOW)? {
+            Some((request, response)) if request == in_flight.request => {
+                Ok(Started::Replay(response))
+            }
+            Some(_) => Err(Error::IdempotencyKeyReused),
+            None => Ok(Started::New(in_flight)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use bitcoin::hashes::sha256;
+    use bitcoin::hashes::Hash;
+
+    use super::*;
+    use crate::ratelimit::RateLimiter;
+
+    fn database() -> Database {
+        Database::open(":memory:".as_ref()).unwrap()
+    }
+
+    fn request(ip: [u8; 4], headers: &[(&str, &str)]) -> HttpRequest {
+        let mut req = TestRequest::default().peer_addr((ip, 1234).into());
+        for header in headers {
+            req = req.insert_header(*header);
+        }
+        req.to_http_request()
+    }
+
+    fn api_keys() -> ApiKeys {
+        let hash = sha256::Hash::hash(b"secret");
+        ApiKeys::parse(&format!("ci:public:{hash}"), RateLimiter::new).unwrap()
+    }
+
+    /// Starts `request` with `key`, answering `response` if it's new
+    fn send(
+        keys: &IdempotencyKeys,
+        db: &Database,
+        key: &str,
+        request: &str,
+        response: &str,
+    ) -> Result<String, Error> {
+        match keys.begin(db, key.to_string(), request.to_string())? {
+            Started::Replay(response) => Ok(response),
+            Started::New(in_flight) => {
+                in_flight.finish(db, response);
+                Ok(response.to_string())
+            }
+        }
+    }
+
+    #[test]
+    fn reads_keys_by_client() {
+        let keys = api_keys();
+        let req = request([198, 51, 100, 7], &[(HEADER, " abc ")]);
+        assert_eq!(key(&req, &keys).unwrap().unwrap(), "ip:198.51.100.7 abc");
+
+        let req = request(
+            [198, 51, 100, 7],
+            &[(HEADER, "abc"), ("X-Api-Key", "secret")],
+        );
+        assert_eq!(key(&req, &keys).unwrap().unwrap(), "key:ci abc");
+
+        let req = request([198, 51, 100, 7], &[]);
+        assert_eq!(key(&req, &keys).unwrap(), None);
+    }
+
+    #[test]
+    fn refuses_bad_keys() {
+        let keys = ApiKeys::default();
+        let long = "a".repeat(MAX_KEY_LEN + 1);
+        for bad in ["", "   ", long.as_str()] {
+            let req = request([198, 51, 100, 7], &[(HEADER, bad)]);
+            assert!(matches!(
+                key(&req, &keys),
+                Err(Error::InvalidIdempotencyKey)
+            ));
+        }
+    }
+
+    #[test]
+    fn replays_the_first_answer() {
+        let (keys, db) = (IdempotencyKeys::default(), database());
+
+        let first = send(&keys, &db, "ip:1.2.3.4 abc", "tb1q:1000", "paid");
+        assert_eq!(first.unwrap(), "paid");
+        let retry = send(&keys, &db, "ip:1.2.3.4 abc", "tb1q:1000", "paid again");
+        assert_eq!(retry.unwrap(), "paid");
+    }
+
+    #[test]
+    fn refuses_keys_reused_for_other_requests() {
+        let (keys, db) = (IdempotencyKeys::default(), database());
+
+        send(&keys, &db, "ip:1.2.3.4 abc", "tb1q:1000", "paid").unwrap();
+        assert!(matches!(
+            send(&keys, &db, "ip:1.2.3.4 abc", "tb1q:2000", "paid"),
+            Err(Error::IdempotencyKeyReused)
+        ));
+    }
+
+    #[test]
+    fn refuses_retries_while_the_first_try_is_in_flight() {
+        let (keys, db) = (IdempotencyKeys::default(), database());
+
+        let Ok(Started::New(in_flight)) =
+            keys.begin(&db, "ip:1.2.3.4 abc".into(), "tb1q:1000".into())
+        else {
+            panic!("the key is new");
+        };
+        assert!(matches!(
+            send(&keys, &db, "ip:1.2.3.4 abc", "tb1q:1000", "paid"),
+            Err(Error::IdempotencyKeyInUse)
+        ));
+
+        // a first try that failed doesn't keep the key
+        drop(in_flight);
+        let retry = send(&keys, &db, "ip:1.2.3.4 abc", "tb1q:1000", "paid");
+        assert_eq!(retry.unwrap(), "paid");
+    }
+
+    #[test]
+    fn keeps_clients_apart() {
+        let (keys, db) = (IdempotencyKeys::default(), database());
+        let api_keys = ApiKeys::default();
+
+        let first = request([198, 51, 100, 7], &[(HEADER, "abc")]);
+        let second = request([198, 51, 100, 8], &[(HEADER, "abc")]);
+        let first = key(&first, &api_keys).unwrap().unwrap();
+        let second = key(&second, &api_keys).unwrap().unwrap();
+
+        send(&keys, &db, &first, "tb1q:1000", "paid the first").unwrap();
+        let other = send(&keys, &db, &second, "tb1q:2000", "paid the second");
+        assert_eq!(other.unwrap(), "paid the second");
+    }
+}
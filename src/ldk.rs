@@ -0,0 +1,380 @@
+//SPDX-License-Identifier: MIT
+
+//! An embedded Lightning node, built with ldk-node. It keeps its keys and state in
+//! `LDK_DATA_DIR` and gets chain data from bitcoind or Esplora, so the faucet doesn't need a
+//! separate Lightning daemon. Fund it by sending coins to the address printed on startup.
+//!
+//! ldk-node runs its own tokio runtime and blocks on it from its sync API, which can't be done
+//! from actix's workers, so every call into the node happens on actix's blocking thread pool.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bitcoin::hex::DisplayHex;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::Amount;
+use ldk_node::bitcoin::hashes::sha256;
+use ldk_node::bitcoin::hashes::Hash;
+use ldk_node::bitcoin::secp256k1::PublicKey as LdkPublicKey;
+use ldk_node::bitcoin::Network;
+use ldk_node::lightning::ln::channelmanager::PaymentId;
+use ldk_node::lightning::ln::msgs::SocketAddress;
+use ldk_node::lightning::offers::offer::Offer;
+use ldk_node::lightning::routing::gossip::NodeId;
+use ldk_node::lightning_invoice::Bolt11Invoice;
+use ldk_node::lightning_invoice::Bolt11InvoiceDescription;
+use ldk_node::lightning_invoice::Sha256;
+use ldk_node::payment::PaymentKind;
+use ldk_node::payment::PaymentStatus;
+use ldk_node::Builder;
+use ldk_node::Node;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::Error;
+use crate::ln::ChannelInfo;
+use crate::ln::ChannelRequest;
+use crate::ln::LightningBackend;
+use crate::ln::NodeInfo;
+
+/// How long the invoices we create are valid for, in seconds
+const INVOICE_EXPIRY: u32 = 3_600;
+
+/// How long we wait for a payment to succeed or fail
+const PAYMENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sending only starts a payment, this waits for it to settle and returns its preimage
+fn wait_for_payment(node: &Node, id: PaymentId) -> Result<String, Error> {
+    let started = std::time::Instant::now();
+    while started.elapsed() < PAYMENT_TIMEOUT {
+        let Some(payment) = node.payment(&id) else {
+            break;
+        };
+
+        match (payment.status, payment.kind) {
+            (
+                PaymentStatus::Succeeded,
+                PaymentKind::Bolt11 {
+                    preimage: Some(preimage),
+                    ..
+                }
+                | PaymentKind::Bolt12Offer {
+                    preimage: Some(preimage),
+                    ..
+                }
+                | PaymentKind::Spontaneous {
+                    preimage: Some(preimage),
+                    ..
+                },
+            ) => return Ok(preimage.0.to_lower_hex_string()),
+            (PaymentStatus::Failed, _) => return Err(Error::LDKError("the payment failed".into())),
+            _ => std::thread::sleep(Duration::from_millis(500)),
+        }
+    }
+
+    Err(Error::LDKError("the payment didn't settle in time".into()))
+}
+
+/// ldk-node uses a newer version of secp256k1
+fn from_ldk_key(key: LdkPublicKey) -> PublicKey {
+    PublicKey::from_slice(&key.serialize()).expect("a valid key is valid for any secp256k1 version")
+}
+
+/// Where the embedded node gets its view of the chain from
+pub enum LdkChainSource {
+    Bitcoind {
+        host: String,
+        port: u16,
+        user: String,
+        password: String,
+    },
+    Esplora(String),
+}
+
+pub struct LdkNode {
+    node: Arc<Node>,
+}
+
+impl LdkNode {
+    pub async fn new(
+        data_dir: String,
+        listen: String,
+        alias: String,
+        chain: LdkChainSource,
+    ) -> Result<Self> {
+        let mut builder = Builder::new();
+        builder
+            .set_network(Network::Signet)
+            .set_storage_dir_path(data_dir)
+            .set_gossip_source_p2p()
+            .set_node_alias(alias)?
+            .set_listening_addresses(vec![SocketAddress::from_str(&listen)
+                .map_err(|_| anyhow::anyhow!("invalid listening address {listen}"))?])?;
+
+        match chain {
+            LdkChainSource::Bitcoind {
+                host,
+                port,
+                user,
+                password,
+            } => builder.set_chain_source_bitcoind_rpc(host, port, user, password),
+            LdkChainSource::Esplora(url) => builder.set_chain_source_esplora(url, None),
+        };
+
+        let node = actix_web::web::block(move || -> Result<Arc<Node>> {
+            let node = Arc::new(builder.build()?);
+            node.start()?;
+            Ok(node)
+        })
+        .await??;
+
+        info!("started the embedded ldk node {}", node.node_id());
+        info!(
+            "the ldk node's on-chain address is {}",
+            node.onchain_payment().new_address()?
+        );
+
+        // ldk-node won't make progress if nobody consumes its events
+        let events = node.clone();
+        std::thread::spawn(move || loop {
+            let event = events.wait_next_event();
+            debug!("ldk event: {event:?}");
+            if let Err(e) = events.event_handled() {
+                warn!("couldn't mark an ldk event as handled: {e}");
+            }
+        });
+
+        Ok(Self { node })
+    }
+
+    /// Runs `f` with our node on actix's blocking pool
+    async fn with_node<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Node) -> Result<T, Error> + Send + 'static,
+    ) -> Result<T, Error> {
+        let node = self.node.clone();
+
+        actix_web::web::block(move || f(&node))
+            .await
+            .map_err(|e| Error::LDKError(e.to_string()))?
+    }
+}
+
+#[async_trait(?Send)]
+impl LightningBackend for LdkNode {
+    /// We can only connect when we're given an address, otherwise opening the channel will look
+    /// for one in the network graph
+    async fn connect(
+        &self,
+        id: PublicKey,
+        address: Option<&str>,
+        _required_features: &[usize],
+    ) -> Result<(), Error> {
+        let Some(address) = address else {
+            return Ok(());
+        };
+        let address = SocketAddress::from_str(address)
+            .map_err(|_| Error::PeerUnreachable(format!("invalid address {address}")))?;
+
+        self.with_node(move |node| {
+            // ldk-node uses a newer version of secp256k1
+            let id = LdkPublicKey::from_slice(&id.serialize())
+                .expect("a valid key is valid for any secp256k1 version");
+
+            node.connect(id, address, false)
+                .map_err(|e| Error::PeerUnreachable(e.to_string()))
+        })
+        .await
+    }
+
+    /// Opens a channel to `id`, which must have announced an address we can reach it at, and
+    /// returns the channel's user id. ldk-node can't open zero-conf channels
+    async fn open_channel(&self, id: PublicKey, channel: ChannelRequest) -> Result<String, Error> {
+        if channel.zero_conf {
+            return Err(Error::NotSupported);
+        }
+
+        let funding = channel.capacity.to_sat();
+        let push = channel.push.to_sat() * 1_000;
+
+        self.with_node(move |node| {
+            // ldk-node uses a newer version of secp256k1
+            let id = LdkPublicKey::from_slice(&id.serialize())
+                .expect("a valid key is valid for any secp256k1 version");
+
+            // prefer the address we're already connected at, if any
+            let address = node
+                .list_peers()
+                .into_iter()
+                .find(|peer| peer.node_id == id)
+                .map(|peer| peer.address)
+                .or_else(|| {
+                    node.network_graph()
+                        .node(&NodeId::from_pubkey(&id))
+                        .and_then(|info| info.announcement_info)
+                        .and_then(|announcement| announcement.addresses().first().cloned())
+                })
+                .ok_or(Error::LDKError(
+                    "we don't know any address for this node".into(),
+                ))?;
+
+            let channel = node
+                .open_announced_channel(id, address, funding, Some(push), None)
+                .map_err(|e| Error::LDKError(e.to_string()))?;
+
+            Ok(channel.0.to_string())
+        })
+        .await
+    }
+
+    /// ldk-node doesn't tell us the closing transaction. We accept both the channel id and the
+    /// user channel id we returned when opening the channel
+    async fn close_channel(&self, channel_id: &str, force: bool) -> Result<String, Error> {
+        let channel_id = channel_id.to_string();
+
+        self.with_node(move |node| {
+            let channel = node
+                .list_channels()
+                .into_iter()
+                .find(|channel| {
+                    channel.channel_id.to_string() == channel_id
+                        || channel.user_channel_id.0.to_string() == channel_id
+                })
+                .ok_or(Error::LDKError("we don't have this channel".into()))?;
+
+            let (id, peer) = (&channel.user_channel_id, channel.counterparty_node_id);
+            match node.close_channel(id, peer) {
+                Err(_) if force => node.force_close_channel(id, peer, None),
+                closed => closed,
+            }
+            .map_err(|e| Error::LDKError(e.to_string()))?;
+
+            Ok(String::new())
+        })
+        .await
+    }
+
+    async fn create_invoice(&self, amount_msat: u64, description: &str) -> Result<String, Error> {
+        let description =
+            Bolt11InvoiceDescription::Hash(Sha256(sha256::Hash::hash(description.as_bytes())));
+
+        self.with_node(move |node| {
+            node.bolt11_payment()
+                .receive(amount_msat, &description, INVOICE_EXPIRY)
+                .map(|invoice| invoice.to_string())
+                .map_err(|e| Error::LDKError(e.to_string()))
+        })
+        .await
+    }
+
+    async fn pay_invoice(&self, invoice: &str) -> Result<String, Error> {
+        let invoice = Bolt11Invoice::from_str(invoice)
+            .map_err(|e| Error::LDKError(format!("invalid invoice: {e}")))?;
+
+        self.with_node(move |node| {
+            let id = node
+                .bolt11_payment()
+                .send(&invoice, None)
+                .map_err(|e| Error::LDKError(e.to_string()))?;
+
+            wait_for_payment(node, id)
+        })
+        .await
+    }
+
+    async fn keysend(&self, id: PublicKey, amount_msat: u64) -> Result<String, Error> {
+        self.with_node(move |node| {
+            // ldk-node uses a newer version of secp256k1
+            let id = LdkPublicKey::from_slice(&id.serialize())
+                .expect("a valid key is valid for any secp256k1 version");
+
+            let payment = node
+                .spontaneous_payment()
+                .send(amount_msat, id, None)
+                .map_err(|e| Error::LDKError(e.to_string()))?;
+
+            wait_for_payment(node, payment)
+        })
+        .await
+    }
+
+    async fn create_offer(&self, description: &str) -> Result<String, Error> {
+        let description = description.to_string();
+
+        self.with_node(move |node| {
+            node.bolt12_payment()
+                .receive_variable_amount(&description, None)
+                .map(|offer| offer.to_string())
+                .map_err(|e| Error::LDKError(e.to_string()))
+        })
+        .await
+    }
+
+    async fn pay_offer(&self, offer: &Offer, amount_msat: u64) -> Result<String, Error> {
+        let offer = offer.clone();
+
+        self.with_node(move |node| {
+            let payment = node.bolt12_payment();
+            let id = match offer.amount() {
+                Some(_) => payment.send(&offer, None, None),
+                None => payment.send_using_amount(&offer, amount_msat, None, None),
+            }
+            .map_err(|e| Error::LDKError(e.to_string()))?;
+
+            wait_for_payment(node, id)
+        })
+        .await
+    }
+
+    async fn node_info(&self) -> Result<NodeInfo, Error> {
+        self.with_node(|node| {
+            Ok(NodeInfo {
+                id: from_ldk_key(node.node_id()),
+                alias: node
+                    .node_alias()
+                    .map(|alias| alias.to_string())
+                    .unwrap_or_default(),
+                block_height: node.status().current_best_block.height,
+            })
+        })
+        .await
+    }
+
+    async fn list_channels(&self) -> Result<Vec<ChannelInfo>, Error> {
+        self.with_node(|node| {
+            Ok(node
+                .list_channels()
+                .into_iter()
+                .map(|channel| ChannelInfo {
+                    peer: from_ldk_key(channel.counterparty_node_id),
+                    channel_id: channel.channel_id.to_string(),
+                    capacity: Amount::from_sat(channel.channel_value_sats),
+                    local_balance: Amount::from_sat(channel.outbound_capacity_msat / 1_000),
+                    state: match (channel.is_channel_ready, channel.is_usable) {
+                        (false, _) => "pending",
+                        (true, false) => "ready",
+                        (true, true) => "usable",
+                    }
+                    .to_string(),
+                    funding_outpoint: channel.funding_txo.map(|outpoint| outpoint.to_string()),
+                    active: channel.is_usable,
+                })
+                .collect())
+        })
+        .await
+    }
+
+    /// Stops the node, so it's done with its chain sync and peers before we exit
+    async fn shutdown(&self) {
+        let stopped = self
+            .with_node(|node| node.stop().map_err(|e| Error::LDKError(e.to_string())))
+            .await;
+        if let Err(e) = stopped {
+            warn!("couldn't stop the ldk node: {e}");
+        }
+    }
+}
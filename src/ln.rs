@@ -0,0 +1,288 @@
+//SPDX-License-Identifier: MIT
+
+//! The Lightning node behind our LN routes. Every implementation we support implements
+//! [LightningBackend], and which one we talk to is picked at startup with `LN_BACKEND`, among
+//! the ones compiled in.
+
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::Amount;
+use lightning::offers::offer::Amount as OfferAmount;
+use lightning::offers::offer::Offer;
+use lightning_invoice::Bolt11Invoice;
+use lightning_invoice::Currency;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::api::Error;
+
+/// Some information about our node
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
+pub struct NodeInfo {
+    pub id: PublicKey,
+    pub alias: String,
+    pub block_height: u32,
+}
+
+/// One of our channels, open or still waiting for its funding transaction to confirm
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct ChannelInfo {
+    #[schema(value_type = String)]
+    pub peer: PublicKey,
+    pub channel_id: String,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    #[schema(value_type = u64)]
+    pub capacity: Amount,
+    #[serde(with = "bitcoin::amount::serde::as_sat")]
+    #[schema(value_type = u64)]
+    pub local_balance: Amount,
+    /// The channel's state, as our node calls it
+    pub state: String,
+    /// The funding transaction's outpoint, as `txid:vout`, if we know it already
+    pub funding_outpoint: Option<String>,
+    /// Whether this channel can be used for payments right now
+    pub active: bool,
+}
+
+/// The channel someone asked us to open
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelRequest {
+    pub capacity: Amount,
+    /// How much of `capacity` goes to the other side
+    pub push: Amount,
+    /// Whether the channel can be used before its funding transaction confirms
+    pub zero_conf: bool,
+}
+
+/// Feature bits peers need for some channels, as the even bit of each pair (BOLT 9)
+pub const STATIC_REMOTE_KEY: usize = 12;
+pub const DUAL_FUND: usize = 28;
+pub const ZERO_CONF: usize = 50;
+
+/// Where a hold invoice is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(any(feature = "ln", feature = "lnd")), allow(dead_code))]
+pub enum HoldInvoiceState {
+    /// Nobody paid it yet
+    Open,
+    /// It was paid, and the payment waits for us to settle or cancel it
+    Accepted,
+    Settled,
+    Canceled,
+}
+
+/// A dual-funded channel we opened, and how its funding transaction came to be
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DualFundedChannel {
+    pub channel_id: String,
+    pub txid: String,
+    /// Which output of the funding transaction is the channel
+    pub funding_outnum: u64,
+    /// The funding transaction as negotiated with the peer, with our signatures
+    pub psbt: String,
+}
+
+/// The channels users may ask for, from `MIN_CHANNEL_VALUE`, `MAX_CHANNEL_VALUE` and
+/// `MAX_PUSH_VALUE`. Requests that don't say how big their channel should be get
+/// `CHANNEL_VALUE` and `PUSH_VALUE`
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelLimits {
+    pub default_capacity: Amount,
+    pub default_push: Amount,
+    pub min_capacity: Amount,
+    pub max_capacity: Amount,
+    pub max_push: Amount,
+    /// How much capacity all our channels with a single node may have together
+    pub max_peer_capacity: Option<Amount>,
+}
+
+impl ChannelLimits {
+    pub fn check(&self, capacity: Amount, push: Amount) -> Result<(), Error> {
+        if capacity < self.min_capacity || capacity > self.max_capacity {
+            return Err(Error::InvalidChannel(format!(
+                "capacity should be between {} and {} sats",
+                self.min_capacity.to_sat(),
+                self.max_capacity.to_sat()
+            )));
+        }
+
+        if push > self.max_push {
+            return Err(Error::InvalidChannel(format!(
+                "we can push at most {} sats",
+                self.max_push.to_sat()
+            )));
+        }
+
+        if push >= capacity {
+            return Err(Error::InvalidChannel(
+                "the push amount should be smaller than the capacity".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that giving a node `capacity` more is fine, when it has `existing` with us already
+    pub fn check_peer(&self, existing: Amount, capacity: Amount) -> Result<(), Error> {
+        match self.max_peer_capacity {
+            Some(max) if existing + capacity > max => Err(Error::InvalidChannel(format!(
+                "you already have {} sats in channels with us, and can't have more than {}",
+                existing.to_sat(),
+                max.to_sat()
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A Lightning node we can use to hand out sats
+///
+/// Some implementations hold a lock across `.await`, so the futures here aren't `Send`. That's
+/// fine for actix, which runs each handler on a single thread.
+#[async_trait(?Send)]
+pub trait LightningBackend: Send + Sync + 'static {
+    /// Connects to `id`, at `address` if we're given one, so we can tell people we couldn't
+    /// reach their node before trying to open a channel. Implementations that can see the peer's
+    /// features also check it supports `required_features`
+    async fn connect(
+        &self,
+        id: PublicKey,
+        address: Option<&str>,
+        required_features: &[usize],
+    ) -> Result<(), Error>;
+    /// Opens a channel to `id` and returns something that identifies it
+    async fn open_channel(&self, id: PublicKey, channel: ChannelRequest) -> Result<String, Error>;
+    /// Opens a dual-funded channel to `id`, where we put in `amount` and the peer may add
+    /// funds of its own
+    async fn open_dual_funded_channel(
+        &self,
+        _id: PublicKey,
+        _amount: Amount,
+    ) -> Result<DualFundedChannel, Error> {
+        Err(Error::NotSupported)
+    }
+    /// Closes the channel with `channel_id`, as [list_channels](Self::list_channels) calls it.
+    /// We try a cooperative close first, and if that fails and `force` is set, we close it
+    /// unilaterally. Returns the closing transaction's id, if our node tells us
+    async fn close_channel(&self, channel_id: &str, force: bool) -> Result<String, Error>;
+    /// Adds `amount` of our funds to the channel with `channel_id`, and returns the splice's
+    /// txid
+    async fn splice_in(&self, _channel_id: &str, _amount: Amount) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+    /// Creates a hold invoice for `amount_msat`, whose payments we'll only take once we reveal
+    /// `preimage` with [settle_hold_invoice](Self::settle_hold_invoice)
+    async fn create_hold_invoice(
+        &self,
+        _preimage: [u8; 32],
+        _amount_msat: u64,
+        _description: &str,
+        _expiry: u32,
+    ) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+    async fn hold_invoice_state(&self, _payment_hash: [u8; 32]) -> Result<HoldInvoiceState, Error> {
+        Err(Error::NotSupported)
+    }
+    /// Takes the payment held by the invoice for `preimage`
+    async fn settle_hold_invoice(&self, _preimage: [u8; 32]) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+    /// Gives the payment held by the invoice for `payment_hash` back to its sender
+    async fn cancel_hold_invoice(&self, _payment_hash: [u8; 32]) -> Result<(), Error> {
+        Err(Error::NotSupported)
+    }
+    /// Creates an invoice for `amount_msat` that commits to the hash of `description`, as
+    /// LNURL-pay requires
+    async fn create_invoice(&self, amount_msat: u64, description: &str) -> Result<String, Error>;
+    /// Pays a BOLT11 invoice and returns the payment preimage, hex encoded
+    async fn pay_invoice(&self, invoice: &str) -> Result<String, Error>;
+    /// Pushes `amount_msat` to `id` with keysend and returns the payment preimage, hex encoded
+    async fn keysend(&self, id: PublicKey, amount_msat: u64) -> Result<String, Error>;
+    /// Creates a BOLT12 offer for any amount, so people can pay us
+    async fn create_offer(&self, _description: &str) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+    /// Fetches an invoice for `amount_msat` from `offer` and pays it, returning the preimage
+    /// hex encoded. If the offer has an amount, `amount_msat` is that amount
+    async fn pay_offer(&self, _offer: &Offer, _amount_msat: u64) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+    #[allow(dead_code)]
+    async fn node_info(&self) -> Result<NodeInfo, Error>;
+    /// Lists our channels, including the ones that aren't open yet
+    async fn list_channels(&self) -> Result<Vec<ChannelInfo>, Error>;
+    /// Lets go of our node before we exit. Nothing is called after this
+    async fn shutdown(&self) {}
+}
+
+/// Checks that `invoice` is a signet invoice that hasn't expired, asking for `min` to `max`
+pub fn check_invoice(invoice: &str, min: Amount, max: Amount) -> Result<Bolt11Invoice, Error> {
+    let invoice = Bolt11Invoice::from_str(invoice)
+        .map_err(|e| Error::InvalidInvoice(format!("we couldn't parse it: {e}")))?;
+
+    if invoice.currency() != Currency::Signet {
+        return Err(Error::InvalidInvoice("it isn't for signet".into()));
+    }
+
+    if invoice.is_expired() {
+        return Err(Error::InvalidInvoice("it has expired".into()));
+    }
+
+    let (min, max) = (min.to_sat() * 1_000, max.to_sat() * 1_000);
+    match invoice.amount_milli_satoshis() {
+        Some(amount) if (min..=max).contains(&amount) => Ok(invoice),
+        Some(_) => Err(Error::InvalidInvoice(format!(
+            "it should be for {min} to {max} msats"
+        ))),
+        None => Err(Error::InvalidInvoice("it doesn't have an amount".into())),
+    }
+}
+
+/// Checks that `offer` is a signet offer that hasn't expired, for `min` to `max`. If the offer
+/// doesn't set an amount, we pay `amount`. Returns the offer and the amount we should pay, in msats
+pub fn check_offer(
+    offer: &str,
+    amount: Option<Amount>,
+    min: Amount,
+    max: Amount,
+) -> Result<(Offer, u64), Error> {
+    let offer = Offer::from_str(offer)
+        .map_err(|e| Error::InvalidInvoice(format!("we couldn't parse the offer: {e:?}")))?;
+
+    let signet = lightning::bitcoin::constants::ChainHash::using_genesis_block(
+        lightning::bitcoin::Network::Signet,
+    );
+    if !offer.supports_chain(signet) {
+        return Err(Error::InvalidInvoice("it isn't for signet".into()));
+    }
+
+    if offer.is_expired() {
+        return Err(Error::InvalidInvoice("it has expired".into()));
+    }
+
+    let amount = match (offer.amount(), amount) {
+        (Some(OfferAmount::Bitcoin { amount_msats }), _) => amount_msats,
+        (Some(OfferAmount::Currency { .. }), _) => {
+            return Err(Error::InvalidInvoice("we only pay in bitcoin".into()))
+        }
+        (None, Some(amount)) => amount.to_sat() * 1_000,
+        (None, None) => {
+            return Err(Error::InvalidInvoice(
+                "this offer doesn't have an amount, tell us how much you want".into(),
+            ))
+        }
+    };
+
+    let (min, max) = (min.to_sat() * 1_000, max.to_sat() * 1_000);
+    if !(min..=max).contains(&amount) {
+        return Err(Error::InvalidInvoice(format!(
+            "it should be for {min} to {max} msats"
+        )));
+    }
+
+    Ok((offer, amount))
+}
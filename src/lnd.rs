@@ -0,0 +1,675 @@
+//SPDX-License-Identifier: MIT
+
+//! A client for LND's gRPC interface. We only need a couple of calls, so instead of compiling
+//! all of LND's protos, we declare the messages we use here, with the same field tags as
+//! `lightning.proto`. Fields we don't declare are simply skipped when decoding.
+//!
+//! LND uses a self-signed certificate that webpki refuses as an end-entity cert, so rather than
+//! trusting it as a CA we pin it: the node must present exactly the certificate we were given.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::DisplayHex;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::Amount;
+use bitcoin::Txid;
+use rustls::client::ServerCertVerified;
+use rustls::client::ServerCertVerifier;
+use rustls::Certificate;
+use rustls::ServerName;
+use tonic::client::Grpc;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::metadata::MetadataValue;
+use tonic::transport::Channel;
+use tonic::Code;
+use tracing::info;
+
+use crate::api::Error;
+use crate::ln::ChannelInfo;
+use crate::ln::ChannelRequest;
+use crate::ln::HoldInvoiceState;
+use crate::ln::LightningBackend;
+use crate::ln::NodeInfo;
+use crate::retry;
+use crate::retry::Failure;
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct GetInfoRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct GetInfoResponse {
+    #[prost(string, tag = "1")]
+    identity_pubkey: String,
+    #[prost(string, tag = "2")]
+    alias: String,
+    #[prost(uint32, tag = "6")]
+    block_height: u32,
+    #[prost(bool, tag = "9")]
+    synced_to_chain: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Invoice {
+    #[prost(bytes = "vec", tag = "10")]
+    description_hash: Vec<u8>,
+    #[prost(int32, tag = "21")]
+    state: i32,
+    #[prost(int64, tag = "23")]
+    value_msat: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct AddHoldInvoiceRequest {
+    #[prost(string, tag = "1")]
+    memo: String,
+    #[prost(bytes = "vec", tag = "2")]
+    hash: Vec<u8>,
+    #[prost(int64, tag = "6")]
+    expiry: i64,
+    #[prost(int64, tag = "10")]
+    value_msat: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct AddHoldInvoiceResp {
+    #[prost(string, tag = "1")]
+    payment_request: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct LookupInvoiceMsg {
+    #[prost(bytes = "vec", tag = "1")]
+    payment_hash: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SettleInvoiceMsg {
+    #[prost(bytes = "vec", tag = "1")]
+    preimage: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SettleInvoiceResp {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CancelInvoiceMsg {
+    #[prost(bytes = "vec", tag = "1")]
+    payment_hash: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CancelInvoiceResp {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct AddInvoiceResponse {
+    #[prost(string, tag = "2")]
+    payment_request: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SendRequest {
+    #[prost(bytes = "vec", tag = "1")]
+    dest: Vec<u8>,
+    #[prost(bytes = "vec", tag = "4")]
+    payment_hash: Vec<u8>,
+    #[prost(string, tag = "6")]
+    payment_request: String,
+    #[prost(map = "uint64, bytes", tag = "11")]
+    dest_custom_records: HashMap<u64, Vec<u8>>,
+    #[prost(int64, tag = "12")]
+    amt_msat: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SendResponse {
+    #[prost(string, tag = "1")]
+    payment_error: String,
+    #[prost(bytes = "vec", tag = "2")]
+    payment_preimage: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ListChannelsRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ListChannelsResponse {
+    #[prost(message, repeated, tag = "11")]
+    channels: Vec<LndChannel>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct LndChannel {
+    #[prost(bool, tag = "1")]
+    active: bool,
+    #[prost(string, tag = "2")]
+    remote_pubkey: String,
+    #[prost(string, tag = "3")]
+    channel_point: String,
+    #[prost(int64, tag = "5")]
+    capacity: i64,
+    #[prost(int64, tag = "6")]
+    local_balance: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct LightningAddress {
+    #[prost(string, tag = "1")]
+    pubkey: String,
+    #[prost(string, tag = "2")]
+    host: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ConnectPeerRequest {
+    #[prost(message, optional, tag = "1")]
+    addr: Option<LightningAddress>,
+    #[prost(bool, tag = "2")]
+    perm: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ConnectPeerResponse {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CloseChannelRequest {
+    #[prost(message, optional, tag = "1")]
+    channel_point: Option<ChannelPoint>,
+    #[prost(bool, tag = "2")]
+    force: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct CloseStatusUpdate {
+    #[prost(message, optional, tag = "1")]
+    close_pending: Option<PendingUpdate>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PendingUpdate {
+    #[prost(bytes = "vec", tag = "1")]
+    txid: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PendingChannelsRequest {}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PendingChannelsResponse {
+    #[prost(message, repeated, tag = "2")]
+    pending_open_channels: Vec<PendingOpenChannel>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PendingOpenChannel {
+    #[prost(message, optional, tag = "1")]
+    channel: Option<PendingChannel>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct PendingChannel {
+    #[prost(string, tag = "1")]
+    remote_node_pub: String,
+    #[prost(string, tag = "2")]
+    channel_point: String,
+    #[prost(int64, tag = "3")]
+    capacity: i64,
+    #[prost(int64, tag = "4")]
+    local_balance: i64,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct OpenChannelRequest {
+    #[prost(bytes = "vec", tag = "2")]
+    node_pubkey: Vec<u8>,
+    #[prost(int64, tag = "4")]
+    local_funding_amount: i64,
+    #[prost(int64, tag = "5")]
+    push_sat: i64,
+    #[prost(bool, tag = "8")]
+    private: bool,
+    #[prost(bool, tag = "12")]
+    spend_unconfirmed: bool,
+    #[prost(int32, tag = "18")]
+    commitment_type: i32,
+    #[prost(bool, tag = "19")]
+    zero_conf: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct ChannelPoint {
+    #[prost(bytes = "vec", tag = "1")]
+    funding_txid_bytes: Vec<u8>,
+    #[prost(string, tag = "2")]
+    funding_txid_str: String,
+    #[prost(uint32, tag = "3")]
+    output_index: u32,
+}
+
+/// `CommitmentType::ANCHORS`, which lnd requires for zero-conf channels
+const ANCHORS: i32 = 3;
+
+/// The custom record keysend senders put the preimage in
+const KEYSEND_RECORD: u64 = 5_482_373_484;
+
+/// Only accepts the exact certificate LND wrote to its `tls.cert`
+struct PinnedCertificate(Vec<u8>);
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.0 != self.0 {
+            return Err(rustls::Error::General(
+                "lnd presented an unexpected certificate".into(),
+            ));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The calls lnd may act on without us hearing back, which we don't make twice
+const NOT_IDEMPOTENT: &[&str] = &[
+    "/lnrpc.Lightning/SendPaymentSync",
+    "/lnrpc.Lightning/OpenChannelSync",
+    "/invoicesrpc.Invoices/AddHoldInvoice",
+    "/invoicesrpc.Invoices/SettleInvoice",
+];
+
+pub struct LndDaemon {
+    channel: Channel,
+    macaroon: MetadataValue<tonic::metadata::Ascii>,
+}
+
+impl LndDaemon {
+    pub async fn new(url: String, cert_file: &Path, macaroon_file: &Path) -> Result<Self> {
+        let pem = std::fs::read(cert_file)?;
+        let Some(cert) = rustls_pemfile::certs(&mut pem.as_slice())?.pop() else {
+            anyhow::bail!("no certificate found in {}", cert_file.display());
+        };
+
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(PinnedCertificate(cert)))
+            .with_no_client_auth();
+
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
+            .https_only()
+            .enable_http2()
+            .build();
+
+        let channel = Channel::from_shared(url)?
+            .connect_with_connector(connector)
+            .await?;
+
+        let macaroon = std::fs::read(macaroon_file)?
+            .to_lower_hex_string()
+            .parse()?;
+
+        let lnd = Self { channel, macaroon };
+
+        let info: GetInfoResponse = lnd
+            .call("/lnrpc.Lightning/GetInfo", GetInfoRequest {})
+            .await?;
+        info!(
+            "connected to lnd node {} ({}), synced to chain: {}",
+            info.alias, info.identity_pubkey, info.synced_to_chain
+        );
+
+        Ok(lnd)
+    }
+
+    /// Sends a payment and waits for it to complete, returning its preimage
+    async fn send_payment(&self, request: SendRequest) -> Result<String, Error> {
+        let response: SendResponse = self
+            .call("/lnrpc.Lightning/SendPaymentSync", request)
+            .await?;
+        if !response.payment_error.is_empty() {
+            return Err(Error::LNDError(response.payment_error));
+        }
+
+        Ok(response.payment_preimage.to_lower_hex_string())
+    }
+
+    /// Asks lnd to close the channel at `channel_point`, and waits until it broadcasts the
+    /// closing transaction
+    async fn close(&self, channel_point: ChannelPoint, force: bool) -> Result<String, Error> {
+        let request = CloseChannelRequest {
+            channel_point: Some(channel_point),
+            force,
+        };
+
+        let mut grpc = Grpc::new(self.channel.clone());
+        grpc.ready()
+            .await
+            .map_err(|e| Error::LNDError(e.to_string()))?;
+
+        let mut request = tonic::Request::new(request);
+        request
+            .metadata_mut()
+            .insert("macaroon", self.macaroon.clone());
+
+        let mut updates = grpc
+            .server_streaming(
+                request,
+                PathAndQuery::from_static("/lnrpc.Lightning/CloseChannel"),
+                ProstCodec::<CloseChannelRequest, CloseStatusUpdate>::default(),
+            )
+            .await
+            .map_err(|status| Error::LNDError(status.message().to_string()))?
+            .into_inner();
+
+        // the first update comes once the closing transaction is broadcast
+        while let Some(update) = updates
+            .message()
+            .await
+            .map_err(|status| Error::LNDError(status.message().to_string()))?
+        {
+            if let Some(pending) = update.close_pending {
+                return Ok(Txid::from_slice(&pending.txid)
+                    .map(|txid| txid.to_string())
+                    .unwrap_or_default());
+            }
+        }
+
+        Err(Error::LNDError("lnd stopped closing the channel".into()))
+    }
+
+    /// Makes an unary call to `path`, authenticated with our macaroon, trying again a few times
+    /// if lnd didn't get it. Those lnd may have acted on without us hearing back we only try once
+    async fn call<Req, Res>(&self, path: &'static str, request: Req) -> Result<Res, Error>
+    where
+        Req: prost::Message + Clone + Send + Sync + 'static,
+        Res: prost::Message + Default + Send + Sync + 'static,
+    {
+        retry::with_backoff("lnd", !NOT_IDEMPOTENT.contains(&path), || {
+            let request = request.clone();
+            async move {
+                let mut grpc = Grpc::new(self.channel.clone());
+                grpc.ready()
+                    .await
+                    .map_err(|e| Failure::Unanswered(Error::LNDError(e.to_string())))?;
+
+                let mut request = tonic::Request::new(request);
+                request
+                    .metadata_mut()
+                    .insert("macaroon", self.macaroon.clone());
+
+                let response = grpc
+                    .unary(
+                        request,
+                        PathAndQuery::from_static(path),
+                        ProstCodec::default(),
+                    )
+                    .await
+                    .map_err(|status| {
+                        let error = Error::LNDError(status.message().to_string());
+                        match status.code() {
+                            Code::Unavailable | Code::DeadlineExceeded => {
+                                Failure::Unanswered(error)
+                            }
+                            _ => Failure::Answered(error),
+                        }
+                    })?;
+
+                Ok(response.into_inner())
+            }
+        })
+        .await
+    }
+}
+
+#[async_trait(?Send)]
+impl LightningBackend for LndDaemon {
+    /// lnd needs an address to connect to, without one we leave it for when we open the channel
+    async fn connect(
+        &self,
+        id: PublicKey,
+        address: Option<&str>,
+        _required_features: &[usize],
+    ) -> Result<(), Error> {
+        let Some(address) = address else {
+            return Ok(());
+        };
+
+        let request = ConnectPeerRequest {
+            addr: Some(LightningAddress {
+                pubkey: id.to_string(),
+                host: address.to_string(),
+            }),
+            perm: false,
+        };
+
+        match self
+            .call::<_, ConnectPeerResponse>("/lnrpc.Lightning/ConnectPeer", request)
+            .await
+        {
+            Err(Error::LNDError(e)) if !e.contains("already connected") => {
+                Err(Error::PeerUnreachable(e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Opens a channel to `id` and returns its funding outpoint
+    async fn open_channel(&self, id: PublicKey, channel: ChannelRequest) -> Result<String, Error> {
+        let request = OpenChannelRequest {
+            node_pubkey: id.serialize().to_vec(),
+            local_funding_amount: channel.capacity.to_sat() as i64,
+            push_sat: channel.push.to_sat() as i64,
+            private: false,
+            spend_unconfirmed: true,
+            commitment_type: if channel.zero_conf { ANCHORS } else { 0 },
+            zero_conf: channel.zero_conf,
+        };
+
+        let point: ChannelPoint = self
+            .call("/lnrpc.Lightning/OpenChannelSync", request)
+            .await?;
+
+        // lnd may answer with either the raw txid bytes or its string form
+        let txid = match Txid::from_slice(&point.funding_txid_bytes) {
+            Ok(txid) => txid.to_string(),
+            Err(_) => point.funding_txid_str,
+        };
+
+        Ok(format!("{txid}:{}", point.output_index))
+    }
+
+    /// Our channel ids are the funding outpoints, which is how lnd refers to channels
+    async fn close_channel(&self, channel_id: &str, force: bool) -> Result<String, Error> {
+        let Some((txid, vout)) = channel_id
+            .split_once(':')
+            .and_then(|(txid, vout)| Some((txid, vout.parse().ok()?)))
+        else {
+            return Err(Error::LNDError(format!(
+                "{channel_id} isn't a channel point"
+            )));
+        };
+
+        let channel_point = ChannelPoint {
+            funding_txid_bytes: vec![],
+            funding_txid_str: txid.to_string(),
+            output_index: vout,
+        };
+
+        match self.close(channel_point.clone(), false).await {
+            Err(_) if force => self.close(channel_point, true).await,
+            closed => closed,
+        }
+    }
+
+    async fn create_invoice(&self, amount_msat: u64, description: &str) -> Result<String, Error> {
+        let request = Invoice {
+            description_hash: sha256::Hash::hash(description.as_bytes())
+                .to_byte_array()
+                .to_vec(),
+            value_msat: amount_msat as i64,
+            ..Default::default()
+        };
+
+        let response: AddInvoiceResponse =
+            self.call("/lnrpc.Lightning/AddInvoice", request).await?;
+
+        Ok(response.payment_request)
+    }
+
+    async fn create_hold_invoice(
+        &self,
+        preimage: [u8; 32],
+        amount_msat: u64,
+        description: &str,
+        expiry: u32,
+    ) -> Result<String, Error> {
+        let request = AddHoldInvoiceRequest {
+            memo: description.to_string(),
+            hash: sha256::Hash::hash(&preimage).to_byte_array().to_vec(),
+            expiry: expiry as i64,
+            value_msat: amount_msat as i64,
+        };
+
+        let response: AddHoldInvoiceResp = self
+            .call("/invoicesrpc.Invoices/AddHoldInvoice", request)
+            .await?;
+
+        Ok(response.payment_request)
+    }
+
+    async fn hold_invoice_state(&self, payment_hash: [u8; 32]) -> Result<HoldInvoiceState, Error> {
+        let request = LookupInvoiceMsg {
+            payment_hash: payment_hash.to_vec(),
+        };
+
+        let invoice: Invoice = self
+            .call("/invoicesrpc.Invoices/LookupInvoiceV2", request)
+            .await?;
+
+        match invoice.state {
+            0 => Ok(HoldInvoiceState::Open),
+            1 => Ok(HoldInvoiceState::Settled),
+            2 => Ok(HoldInvoiceState::Canceled),
+            3 => Ok(HoldInvoiceState::Accepted),
+            state => Err(Error::LNDError(format!("unknown invoice state {state}"))),
+        }
+    }
+
+    async fn settle_hold_invoice(&self, preimage: [u8; 32]) -> Result<(), Error> {
+        let request = SettleInvoiceMsg {
+            preimage: preimage.to_vec(),
+        };
+
+        self.call::<_, SettleInvoiceResp>("/invoicesrpc.Invoices/SettleInvoice", request)
+            .await?;
+        Ok(())
+    }
+
+    async fn cancel_hold_invoice(&self, payment_hash: [u8; 32]) -> Result<(), Error> {
+        let request = CancelInvoiceMsg {
+            payment_hash: payment_hash.to_vec(),
+        };
+
+        self.call::<_, CancelInvoiceResp>("/invoicesrpc.Invoices/CancelInvoice", request)
+            .await?;
+        Ok(())
+    }
+
+    async fn pay_invoice(&self, invoice: &str) -> Result<String, Error> {
+        let request = SendRequest {
+            payment_request: invoice.to_string(),
+            ..Default::default()
+        };
+
+        self.send_payment(request).await
+    }
+
+    async fn keysend(&self, id: PublicKey, amount_msat: u64) -> Result<String, Error> {
+        let preimage = bitcoin::secp256k1::rand::random::<[u8; 32]>();
+        let request = SendRequest {
+            dest: id.serialize().to_vec(),
+            payment_hash: sha256::Hash::hash(&preimage).to_byte_array().to_vec(),
+            dest_custom_records: HashMap::from([(KEYSEND_RECORD, preimage.to_vec())]),
+            amt_msat: amount_msat as i64,
+            ..Default::default()
+        };
+
+        self.send_payment(request).await
+    }
+
+    async fn node_info(&self) -> Result<NodeInfo, Error> {
+        let info: GetInfoResponse = self
+            .call("/lnrpc.Lightning/GetInfo", GetInfoRequest {})
+            .await?;
+
+        Ok(NodeInfo {
+            id: info
+                .identity_pubkey
+                .parse()
+                .map_err(|_| Error::LNDError("invalid node id".into()))?,
+            alias: info.alias,
+            block_height: info.block_height,
+        })
+    }
+
+    async fn list_channels(&self) -> Result<Vec<ChannelInfo>, Error> {
+        let response: ListChannelsResponse = self
+            .call("/lnrpc.Lightning/ListChannels", ListChannelsRequest {})
+            .await?;
+        let pending: PendingChannelsResponse = self
+            .call(
+                "/lnrpc.Lightning/PendingChannels",
+                PendingChannelsRequest {},
+            )
+            .await?;
+
+        let open = response.channels.into_iter().map(|channel| {
+            Ok(ChannelInfo {
+                peer: channel
+                    .remote_pubkey
+                    .parse()
+                    .map_err(|_| Error::LNDError("invalid peer id".into()))?,
+                channel_id: channel.channel_point.clone(),
+                capacity: Amount::from_sat(channel.capacity as u64),
+                local_balance: Amount::from_sat(channel.local_balance as u64),
+                state: if channel.active { "active" } else { "inactive" }.to_string(),
+                funding_outpoint: Some(channel.channel_point),
+                active: channel.active,
+            })
+        });
+
+        let pending = pending
+            .pending_open_channels
+            .into_iter()
+            .filter_map(|pending| pending.channel)
+            .map(|channel| {
+                Ok(ChannelInfo {
+                    peer: channel
+                        .remote_node_pub
+                        .parse()
+                        .map_err(|_| Error::LNDError("invalid peer id".into()))?,
+                    channel_id: channel.channel_point.clone(),
+                    capacity: Amount::from_sat(channel.capacity as u64),
+                    local_balance: Amount::from_sat(channel.local_balance as u64),
+                    state: "pending_open".to_string(),
+                    funding_outpoint: Some(channel.channel_point),
+                    active: false,
+                })
+            });
+
+        open.chain(pending).collect()
+    }
+}
@@ -0,0 +1,388 @@
+//SPDX-License-Identifier: MIT
+
+//! LNURL routes, letting any LNURL capable wallet talk to the faucet. We derive our public url
+//! from the request itself, so these work behind whatever domain the faucet is served from.
+//!
+//! LNURL-pay (LUD-06 and LUD-16) lets people top the faucet up with a lightning address:
+//! `faucet@<our domain>` resolves to `/.well-known/lnurlp/faucet`, which points wallets to a
+//! callback that returns an invoice committing to our metadata.
+//!
+//! LNURL-withdraw (LUD-03) hands out sats without an on-chain address: `/lnurlw` gives a
+//! single-use link that, once scanned, lets the wallet send us an invoice, which we then pay.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use bitcoin::bech32;
+use bitcoin::hex::DisplayHex;
+use bitcoin::Amount;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::api::check_lightning_payment;
+use crate::api::record_lightning_payment;
+use crate::api::AppState;
+use crate::backend::ChainBackend;
+use crate::ln::check_invoice;
+use crate::ratelimit;
+
+/// The smallest donation we accept, in msats
+const MIN_DONATION: u64 = 1_000;
+/// The largest donation we accept, in msats
+const MAX_DONATION: u64 = 100_000_000_000;
+
+/// How long a withdraw link can be used for
+const WITHDRAW_EXPIRY: Duration = Duration::from_secs(3_600);
+
+/// The withdraw links we gave out and haven't been used yet, by their k1
+#[derive(Default)]
+pub struct Withdrawals {
+    pending: Mutex<HashMap<String, Instant>>,
+}
+
+impl Withdrawals {
+    /// Creates a new single-use k1
+    fn create(&self) -> String {
+        let k1 = bitcoin::secp256k1::rand::random::<[u8; 32]>().to_lower_hex_string();
+
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|_, created| created.elapsed() < WITHDRAW_EXPIRY);
+        pending.insert(k1.clone(), Instant::now());
+
+        k1
+    }
+
+    fn is_pending(&self, k1: &str) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .get(k1)
+            .is_some_and(|created| created.elapsed() < WITHDRAW_EXPIRY)
+    }
+
+    /// Marks `k1` as used, returning whether it could still be used
+    fn claim(&self, k1: &str) -> bool {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(k1)
+            .is_some_and(|created| created.elapsed() < WITHDRAW_EXPIRY)
+    }
+}
+
+/// How LNURL services tell wallets something went wrong
+#[derive(Serialize)]
+struct LnurlError {
+    status: &'static str,
+    reason: String,
+}
+
+impl LnurlError {
+    fn response(reason: impl ToString) -> HttpResponse {
+        HttpResponse::Ok().json(LnurlError {
+            status: "ERROR",
+            reason: reason.to_string(),
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PayRequest {
+    callback: String,
+    max_sendable: u64,
+    min_sendable: u64,
+    metadata: String,
+    tag: &'static str,
+}
+
+#[derive(Deserialize)]
+pub struct PayCallback {
+    amount: u64,
+}
+
+#[derive(Serialize)]
+struct PayCallbackResponse {
+    pr: String,
+    routes: Vec<()>,
+}
+
+#[derive(Serialize)]
+struct LnurlOk {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WithdrawRequest {
+    tag: &'static str,
+    callback: String,
+    k1: String,
+    default_description: &'static str,
+    min_withdrawable: u64,
+    max_withdrawable: u64,
+}
+
+#[derive(Deserialize)]
+pub struct WithdrawQuery {
+    k1: String,
+}
+
+#[derive(Deserialize)]
+pub struct WithdrawCallback {
+    k1: String,
+    pr: String,
+}
+
+/// The url we're being reached at, like `https://faucet.example.com`
+fn base_url(req: &HttpRequest) -> String {
+    let info = req.connection_info();
+    format!("{}://{}", info.scheme(), info.host())
+}
+
+/// The LNURL-pay metadata. The invoices we hand out commit to its hash, so it must be the same
+/// in both steps of the protocol
+fn pay_metadata(req: &HttpRequest) -> String {
+    let info = req.connection_info();
+    serde_json::json!([
+        ["text/plain", "Donate to the faucet"],
+        ["text/identifier", format!("faucet@{}", info.host())],
+    ])
+    .to_string()
+}
+
+pub async fn pay_request(req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(PayRequest {
+        callback: format!("{}/lnurlp/callback", base_url(&req)),
+        max_sendable: MAX_DONATION,
+        min_sendable: MIN_DONATION,
+        metadata: pay_metadata(&req),
+        tag: "payRequest",
+    })
+}
+
+pub async fn pay_callback<B: ChainBackend>(
+    req: HttpRequest,
+    params: web::Query<PayCallback>,
+    data: web::Data<AppState<B>>,
+) -> HttpResponse {
+    let amount = params.amount;
+    if !(MIN_DONATION..=MAX_DONATION).contains(&amount) {
+        return LnurlError::response(format!(
+            "amount should be between {MIN_DONATION} and {MAX_DONATION} msats"
+        ));
+    }
+
+    match data
+        .lightning
+        .create_invoice(amount, &pay_metadata(&req))
+        .await
+    {
+        Ok(pr) => HttpResponse::Ok().json(PayCallbackResponse { pr, routes: vec![] }),
+        Err(e) => LnurlError::response(e),
+    }
+}
+
+/// Creates a new withdraw link, encoded as LNURL-withdraw expects
+pub async fn withdraw_link<B: ChainBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> HttpResponse {
+    let k1 = data.lnurl_withdrawals.create();
+    let url = format!("{}/lnurlw/request?k1={k1}", base_url(&req));
+
+    let lnurl = bech32::encode_upper::<bech32::Bech32>(
+        bech32::Hrp::parse_unchecked("lnurl"),
+        url.as_bytes(),
+    )
+    .expect("writing to a string doesn't fail");
+
+    HttpResponse::Ok().body(format!("lightning:{lnurl}\n"))
+}
+
+pub async fn withdraw_request<B: ChainBackend>(
+    req: HttpRequest,
+    params: web::Query<WithdrawQuery>,
+    data: web::Data<AppState<B>>,
+) -> HttpResponse {
+    if !data.lnurl_withdrawals.is_pending(&params.k1) {
+        return LnurlError::response("this link was already used or has expired");
+    }
+
+    let limits = data.controls.limits();
+    HttpResponse::Ok().json(WithdrawRequest {
+        tag: "withdrawRequest",
+        callback: format!("{}/lnurlw/callback", base_url(&req)),
+        k1: params.into_inner().k1,
+        default_description: "sats from the faucet",
+        min_withdrawable: limits.min_sendable.to_sat() * 1_000,
+        max_withdrawable: limits.max_sendable.to_sat() * 1_000,
+    })
+}
+
+pub async fn withdraw_callback<B: ChainBackend>(
+    req: HttpRequest,
+    params: web::Query<WithdrawCallback>,
+    data: web::Data<AppState<B>>,
+) -> HttpResponse {
+    let WithdrawCallback { k1, pr } = params.into_inner();
+
+    // whoever claims the link gets the sats, so that's who we check
+    let limits = data.controls.limits();
+    let amount = match data
+        .controls
+        .check_running()
+        .and_then(|_| check_invoice(&pr, limits.min_sendable, limits.max_sendable))
+        .and_then(|invoice| {
+            let amount_msat = invoice.amount_milli_satoshis().unwrap_or_default();
+            let amount = Amount::from_sat(amount_msat.div_ceil(1_000));
+            check_lightning_payment(&req, &data, amount).map(|_| amount)
+        }) {
+        Ok(amount) => amount,
+        Err(e) => return LnurlError::response(e),
+    };
+
+    // only claim the link once we know the invoice is fine, so the user can try again
+    if !data.lnurl_withdrawals.claim(&k1) {
+        return LnurlError::response("this link was already used or has expired");
+    }
+
+    // the wallet expects an answer right away, and we pay afterwards
+    actix_web::rt::spawn(async move {
+        match data.lightning.pay_invoice(&pr).await {
+            Ok(_) => record_lightning_payment(&req, &data, &pr, amount),
+            Err(e) => {
+                warn!("couldn't pay an LNURL-withdraw invoice: {e}");
+                ratelimit::give_sats_back(&req, &data, amount.to_sat());
+            }
+        }
+    });
+
+    HttpResponse::Ok().json(LnurlOk { status: "OK" })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::testing::app_state;
+    use crate::testing::MockNode;
+
+    fn request() -> HttpRequest {
+        TestRequest::default()
+            .insert_header(("host", "faucet.example.com"))
+            .to_http_request()
+    }
+
+    async fn json(response: HttpResponse) -> serde_json::Value {
+        let body = to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn withdraw_links_work_once() {
+        let withdrawals = Withdrawals::default();
+        let k1 = withdrawals.create();
+
+        assert!(withdrawals.is_pending(&k1));
+        assert!(!withdrawals.is_pending("nope"));
+        assert!(withdrawals.claim(&k1));
+        assert!(!withdrawals.is_pending(&k1));
+        assert!(!withdrawals.claim(&k1));
+    }
+
+    #[actix_web::test]
+    async fn points_wallets_to_the_callback() {
+        let pay_request = json(pay_request(request()).await).await;
+
+        assert_eq!(pay_request["tag"], "payRequest");
+        assert_eq!(
+            pay_request["callback"],
+            "http://faucet.example.com/lnurlp/callback"
+        );
+        assert_eq!(pay_request["minSendable"], MIN_DONATION);
+        assert_eq!(pay_request["maxSendable"], MAX_DONATION);
+        let metadata: serde_json::Value =
+            serde_json::from_str(pay_request["metadata"].as_str().unwrap()).unwrap();
+        assert_eq!(
+            metadata[1],
+            serde_json::json!(["text/identifier", "faucet@faucet.example.com"])
+        );
+    }
+
+    #[actix_web::test]
+    async fn refuses_donations_out_of_bounds() {
+        let data = web::Data::new(app_state(MockNode::default()));
+
+        for amount in [MIN_DONATION - 1, MAX_DONATION + 1] {
+            let response =
+                pay_callback(request(), web::Query(PayCallback { amount }), data.clone()).await;
+            assert_eq!(json(response).await["status"], "ERROR");
+        }
+    }
+
+    #[actix_web::test]
+    async fn hands_out_withdraw_links() {
+        let data = web::Data::new(app_state(MockNode::default()));
+
+        let response = withdraw_link(request(), data.clone()).await;
+        let body = to_bytes(response.into_body()).await.unwrap();
+        let lnurl = std::str::from_utf8(&body)
+            .unwrap()
+            .trim()
+            .strip_prefix("lightning:")
+            .unwrap()
+            .to_string();
+        let (hrp, url) = bech32::decode(&lnurl).unwrap();
+        assert_eq!(hrp.to_lowercase(), "lnurl");
+        let url = String::from_utf8(url).unwrap();
+        let k1 = url
+            .strip_prefix("http://faucet.example.com/lnurlw/request?k1=")
+            .unwrap()
+            .to_string();
+
+        let response = withdraw_request(
+            request(),
+            web::Query(WithdrawQuery { k1: k1.clone() }),
+            data.clone(),
+        )
+        .await;
+        let withdraw_request = json(response).await;
+        assert_eq!(withdraw_request["tag"], "withdrawRequest");
+        assert_eq!(withdraw_request["k1"], k1);
+        assert_eq!(withdraw_request["minWithdrawable"], 1_000_000);
+        assert_eq!(withdraw_request["maxWithdrawable"], 100_000_000);
+    }
+
+    #[actix_web::test]
+    async fn refuses_links_it_didnt_hand_out() {
+        let data = web::Data::new(app_state(MockNode::default()));
+
+        let response = withdraw_request(
+            request(),
+            web::Query(WithdrawQuery { k1: "nope".into() }),
+            data.clone(),
+        )
+        .await;
+        assert_eq!(json(response).await["status"], "ERROR");
+
+        let response = withdraw_callback(
+            request(),
+            web::Query(WithdrawCallback {
+                k1: "nope".into(),
+                pr: "lntbs1".into(),
+            }),
+            data,
+        )
+        .await;
+        assert_eq!(json(response).await["status"], "ERROR");
+    }
+}
@@ -1,85 +1,1560 @@
 extern crate bitcoincore_rpc;
+mod abuse;
+mod access;
+mod accesslog;
+mod admin;
+#[cfg(feature = "alerts")]
+mod alerts;
 mod api;
+mod apikeys;
+mod assets;
+mod backend;
+mod bip322;
+mod bump;
+#[cfg(feature = "captcha")]
+mod captcha;
+mod coinselect;
+mod consolidate;
+mod cooldown;
+mod cors;
+mod cpfp;
+mod db;
+mod events;
+mod export;
+mod fees;
+#[cfg(feature = "github")]
+mod github;
+mod idempotency;
+#[cfg(feature = "nostr")]
+mod nostr;
+#[cfg(feature = "onion")]
+mod onion;
+mod openapi;
+mod payjoin;
+mod pow;
+mod proxy;
+mod qr;
+mod queue;
+mod ratelimit;
+#[cfg(feature = "sentry")]
+mod reporting;
+mod response;
+mod retry;
+mod scheduler;
+mod silentpayments;
+mod stats;
+#[cfg(feature = "systemd")]
+mod systemd;
+#[cfg(test)]
+mod testing;
+mod tiers;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tor")]
+mod tor;
+#[cfg(feature = "zmq")]
+mod tracker;
+#[cfg(feature = "webhooks")]
+mod webhook;
+#[cfg(feature = "nostr")]
+mod websocket;
+mod ws;
+#[cfg(feature = "zmq")]
+mod zmq;
 
+#[cfg(feature = "eclair")]
+mod eclair;
+#[cfg(feature = "lightning")]
+mod hold;
+#[cfg(feature = "ldk")]
+mod ldk;
+#[cfg(feature = "lightning")]
+mod ln;
+#[cfg(feature = "lnd")]
+mod lnd;
+#[cfg(feature = "lightning")]
+mod lnurl;
 #[cfg(feature = "ln")]
 mod open_channel;
+#[cfg(feature = "lightning")]
+mod reclaim;
+#[cfg(feature = "redis")]
+mod shared;
 
-use std::{env, process::exit, str::FromStr};
+use std::{env, process::exit, str::FromStr, time::Duration};
 
-use bitcoin::{Address, Amount};
-use bitcoincore_rpc::{Auth, Client};
+use backend::bitcoind;
+use backend::bitcoind::BitcoinCore;
+use backend::ChainBackend;
+use bitcoin::{Address, AddressType, Amount, FeeRate};
+use tracing::error;
+use tracing::info;
+use tracing::warn;
+#[cfg(feature = "sentry")]
+use tracing_subscriber::layer::SubscriberExt;
+#[cfg(feature = "sentry")]
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
 
-#[cfg(feature = "ln")]
-use cln_rpc::ClnRpc;
+#[cfg(feature = "lightning")]
+use ln::LightningBackend;
+
+/// Sets up our logs. LOG_LEVEL filters them, like `info` or `faucet=debug,actix_web=warn`, and
+/// LOG_FORMAT=json writes them as one json object per line, for log shippers
+fn init_logging() {
+    let level = env::var("LOG_LEVEL").unwrap_or("info".into());
+    let (filter, invalid) = match EnvFilter::try_new(&level) {
+        Ok(filter) => (filter, None),
+        Err(e) => (EnvFilter::new("info"), Some(e)),
+    };
+
+    let logs = tracing_subscriber::fmt().with_env_filter(filter);
+
+    // errors go to Sentry too, with what we logged before them as breadcrumbs
+    #[cfg(feature = "sentry")]
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => logs.json().finish().with(sentry_tracing::layer()).init(),
+        _ => logs.finish().with(sentry_tracing::layer()).init(),
+    }
+    #[cfg(not(feature = "sentry"))]
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => logs.json().init(),
+        _ => logs.init(),
+    }
+
+    if let Some(e) = invalid {
+        warn!("error parsing LOG_LEVEL {e}, logging at info");
+    }
+}
+
+fn bitcoind_backend() -> anyhow::Result<Box<dyn ChainBackend>> {
+    let Ok(cookie_files) = env::var("BITCOIND_COOKIE_FILE") else {
+        error!("cookie file not set");
+        exit(1);
+    };
+
+    // both may be comma separated lists, to fail over between several nodes
+    let urls = env::var("BITCOIND_URL").unwrap_or("http://localhost:38332".into());
+    let urls = urls.split(',').map(str::trim).collect::<Vec<_>>();
+    let cookie_files = cookie_files.split(',').map(str::trim).collect::<Vec<_>>();
+
+    if cookie_files.len() != 1 && cookie_files.len() != urls.len() {
+        error!("You have to provide either one cookie file or one for each BITCOIND_URL");
+        exit(1);
+    }
+
+    let timeout = env::var("BITCOIND_TIMEOUT")
+        .map(|secs| secs.parse().unwrap_or(15))
+        .unwrap_or(15);
+
+    let nodes = urls
+        .iter()
+        .enumerate()
+        .map(|(index, url)| {
+            let cookie_file = cookie_files.get(index).unwrap_or(&cookie_files[0]);
+            bitcoind::connect(url, cookie_file.into(), Duration::from_secs(timeout))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let backend = BitcoinCore::new(nodes);
+    if urls.len() > 1 {
+        let interval = env::var("BITCOIND_HEALTH_CHECK_INTERVAL")
+            .map(|secs| secs.parse().unwrap_or(30))
+            .unwrap_or(30);
+
+        backend.spawn_health_check(Duration::from_secs(interval));
+    }
+
+    Ok(Box::new(backend))
+}
+
+#[cfg(any(feature = "esplora", feature = "electrum", feature = "utreexod"))]
+fn local_wallet() -> anyhow::Result<backend::wallet::LocalWallet> {
+    let Ok(key) = env::var("FAUCET_PRIVATE_KEY") else {
+        error!("You have to provide a WIF private key in FAUCET_PRIVATE_KEY for this backend");
+        exit(1);
+    };
+
+    let wallet = backend::wallet::LocalWallet::from_wif(&key, bitcoin::Network::Signet)?;
+    info!("faucet wallet address is {}", wallet.address());
+
+    Ok(wallet)
+}
+
+#[cfg(feature = "esplora")]
+fn esplora_backend() -> anyhow::Result<Box<dyn ChainBackend>> {
+    let url = env::var("ESPLORA_URL").unwrap_or("http://localhost:3002".into());
+
+    Ok(Box::new(backend::esplora::Esplora::new(
+        url,
+        local_wallet()?,
+    )))
+}
+
+#[cfg(feature = "electrum")]
+fn electrum_backend() -> anyhow::Result<Box<dyn ChainBackend>> {
+    let url = env::var("ELECTRUM_URL").unwrap_or("tcp://localhost:50001".into());
+
+    Ok(Box::new(backend::electrum::Electrum::new(
+        &url,
+        local_wallet()?,
+    )?))
+}
+
+#[cfg(feature = "bdk")]
+fn bdk_backend() -> anyhow::Result<Box<dyn ChainBackend>> {
+    use backend::bdk::BdkWallet;
+    use backend::bdk::ChainSource;
+
+    // descriptors can be passed directly or in a file, one per line
+    let (descriptor, change_descriptor) = match env::var("BDK_DESCRIPTOR_FILE") {
+        Ok(path) => {
+            let contents = std::fs::read_to_string(path)?;
+            let mut lines = contents.lines().map(str::trim).filter(|l| !l.is_empty());
+            let (Some(descriptor), Some(change)) = (lines.next(), lines.next()) else {
+                error!("BDK_DESCRIPTOR_FILE should have a descriptor and a change descriptor");
+                exit(1);
+            };
+            (descriptor.to_string(), change.to_string())
+        }
+        Err(_) => {
+            let (Ok(descriptor), Ok(change)) = (
+                env::var("BDK_DESCRIPTOR"),
+                env::var("BDK_CHANGE_DESCRIPTOR"),
+            ) else {
+                error!("You have to set BDK_DESCRIPTOR and BDK_CHANGE_DESCRIPTOR or BDK_DESCRIPTOR_FILE");
+                exit(1);
+            };
+            (descriptor, change)
+        }
+    };
+
+    let chain = match env::var("BDK_CHAIN_SOURCE").as_deref() {
+        Ok("esplora") => {
+            let url = env::var("ESPLORA_URL").unwrap_or("http://localhost:3002".into());
+            ChainSource::Esplora(bdk_esplora::esplora_client::Builder::new(&url).build_blocking())
+        }
+        _ => {
+            let Ok(cookie_file) = env::var("BITCOIND_COOKIE_FILE") else {
+                error!("cookie file not set");
+                exit(1);
+            };
+            let url = env::var("BITCOIND_URL").unwrap_or("http://localhost:38332".into());
+            let start_height = env::var("BDK_START_HEIGHT")
+                .map(|height| height.parse().unwrap_or_default())
+                .unwrap_or(0);
+
+            ChainSource::Bitcoind {
+                client: bdk_bitcoind_rpc::bitcoincore_rpc::Client::new(
+                    &url,
+                    bdk_bitcoind_rpc::bitcoincore_rpc::Auth::CookieFile(cookie_file.into()),
+                )?,
+                start_height,
+            }
+        }
+    };
+
+    info!("syncing the bdk wallet, this may take a while");
+    Ok(Box::new(BdkWallet::new(
+        descriptor,
+        change_descriptor,
+        bitcoin::Network::Signet,
+        chain,
+    )?))
+}
+
+#[cfg(feature = "utreexod")]
+fn utreexod_backend() -> anyhow::Result<backend::utreexod::Utreexod> {
+    let url = env::var("UTREEXOD_URL").unwrap_or("http://localhost:38332".into());
+    let (Ok(user), Ok(pass)) = (env::var("UTREEXOD_USER"), env::var("UTREEXOD_PASS")) else {
+        error!("You have to provide UTREEXOD_USER and UTREEXOD_PASS");
+        exit(1);
+    };
+
+    let rpc = bitcoincore_rpc::Client::new(&url, bitcoincore_rpc::Auth::UserPass(user, pass))?;
+    Ok(backend::utreexod::Utreexod::new(rpc, local_wallet()?))
+}
+
+/// Cooldowns lasting `hours`, saved to `file`, or kept in the Redis server we share with other
+/// faucets, if any
+fn load_cooldowns(
+    hours: u64,
+    file: String,
+    name: &'static str,
+    #[cfg(feature = "redis")] shared: &Option<std::sync::Arc<shared::SharedStore>>,
+) -> anyhow::Result<cooldown::Cooldowns> {
+    let window = Duration::from_secs(hours * 3_600);
+
+    #[cfg(feature = "redis")]
+    if let Some(store) = shared {
+        return Ok(cooldown::Cooldowns::shared(window, store.clone(), name));
+    }
+    #[cfg(not(feature = "redis"))]
+    let _ = name;
+
+    cooldown::Cooldowns::load(window, Some(file.into()))
+}
+
+/// Reads an amount of sats from `var`, or uses `default` if it isn't set
+fn sats_from_env(var: &str, default: u64) -> Amount {
+    match env::var(var).map(|value| value.parse()) {
+        Ok(Ok(value)) => {
+            info!("{var} set to {value} sats");
+            Amount::from_sat(value)
+        }
+        Ok(Err(e)) => {
+            warn!("error parsing {var} {e}, using default of {default}");
+            Amount::from_sat(default)
+        }
+        Err(_) => {
+            info!("{var} not set, using default of {default}");
+            Amount::from_sat(default)
+        }
+    }
+}
 
 #[cfg(feature = "ln")]
-use open_channel::CLNDaemon;
+async fn cln_node() -> anyhow::Result<Box<dyn LightningBackend>> {
+    let Ok(cln_rpc) = env::var("CLN_RPC_DIR") else {
+        error!("You have to provide the CLN_RPC_DIR");
+        exit(1);
+    };
+
+    Ok(Box::new(
+        open_channel::CLNDaemon::new(cln_rpc.into()).await?,
+    ))
+}
+
+#[cfg(feature = "lnd")]
+async fn lnd_node() -> anyhow::Result<Box<dyn LightningBackend>> {
+    let url = env::var("LND_URL").unwrap_or("https://localhost:10009".into());
+    let (Ok(cert_file), Ok(macaroon_file)) =
+        (env::var("LND_CERT_FILE"), env::var("LND_MACAROON_FILE"))
+    else {
+        error!("You have to provide LND_CERT_FILE and LND_MACAROON_FILE");
+        exit(1);
+    };
+
+    let lnd = lnd::LndDaemon::new(url, cert_file.as_ref(), macaroon_file.as_ref()).await?;
+    Ok(Box::new(lnd))
+}
+
+#[cfg(feature = "eclair")]
+fn eclair_node() -> anyhow::Result<Box<dyn LightningBackend>> {
+    let url = env::var("ECLAIR_URL").unwrap_or("http://localhost:8080".into());
+    let Ok(password) = env::var("ECLAIR_PASSWORD") else {
+        error!("You have to provide ECLAIR_PASSWORD");
+        exit(1);
+    };
+
+    Ok(Box::new(eclair::EclairDaemon::new(url, &password)?))
+}
+
+#[cfg(feature = "ldk")]
+async fn ldk_node() -> anyhow::Result<Box<dyn LightningBackend>> {
+    use ldk::LdkChainSource;
+
+    let data_dir = env::var("LDK_DATA_DIR").unwrap_or("ldk".into());
+    let listen = env::var("LDK_LISTEN_ADDRESS").unwrap_or("0.0.0.0:9735".into());
+    let alias = env::var("LDK_ALIAS").unwrap_or("yet-another-faucet".into());
+
+    let chain = match env::var("LDK_CHAIN_SOURCE").as_deref() {
+        Ok("esplora") => LdkChainSource::Esplora(
+            env::var("ESPLORA_URL").unwrap_or("http://localhost:3002".into()),
+        ),
+        _ => {
+            let Ok(cookie_file) = env::var("BITCOIND_COOKIE_FILE") else {
+                error!("cookie file not set");
+                exit(1);
+            };
+            let cookie = std::fs::read_to_string(cookie_file)?;
+            let Some((user, password)) = cookie.trim().split_once(':') else {
+                error!("invalid cookie file");
+                exit(1);
+            };
+
+            let url = env::var("BITCOIND_URL").unwrap_or("http://localhost:38332".into());
+            let url = url.trim_start_matches("http://");
+            let Some((host, Ok(port))) = url
+                .trim_end_matches('/')
+                .split_once(':')
+                .map(|(host, port)| (host, port.parse()))
+            else {
+                error!("BITCOIND_URL should look like http://host:port");
+                exit(1);
+            };
+
+            LdkChainSource::Bitcoind {
+                host: host.to_string(),
+                port,
+                user: user.to_string(),
+                password: password.to_string(),
+            }
+        }
+    };
+
+    let ldk = ldk::LdkNode::new(data_dir, listen, alias, chain).await?;
+    Ok(Box::new(ldk))
+}
 
 #[actix::main]
 async fn main() -> anyhow::Result<()> {
-    let Ok(cookie_file) = env::var("BITCOIND_COOKIE_FILE") else {
-        println!("cookie file not set");
-        exit(1);
+    init_logging();
+
+    // reports are sent for as long as this is around
+    #[cfg(feature = "sentry")]
+    let _sentry = match env::var("SENTRY_DSN") {
+        Ok(dsn) => {
+            let environment = env::var("SENTRY_ENVIRONMENT").ok();
+            match reporting::init(&dsn, environment) {
+                Ok(guard) => {
+                    info!("SENTRY_DSN set, reporting panics and failures to Sentry");
+                    Some(guard)
+                }
+                Err(e) => {
+                    error!("invalid SENTRY_DSN: {e}");
+                    exit(1);
+                }
+            }
+        }
+        Err(_) => {
+            info!("SENTRY_DSN not set, we won't report failures");
+            None
+        }
     };
 
-    let url = env::var("BITCOIND_URL").unwrap_or("http://localhost:38332".into());
+    #[cfg(feature = "utreexod")]
+    let mut utreexo = None;
 
-    let rpc = Client::new(&url, Auth::CookieFile(cookie_file.into()))?;
+    let rpc = match env::var("CHAIN_BACKEND").as_deref() {
+        Ok("bitcoind") | Err(_) => bitcoind_backend()?,
+        #[cfg(feature = "esplora")]
+        Ok("esplora") => esplora_backend()?,
+        #[cfg(feature = "electrum")]
+        Ok("electrum") => electrum_backend()?,
+        #[cfg(feature = "bdk")]
+        Ok("bdk") => bdk_backend()?,
+        #[cfg(feature = "utreexod")]
+        Ok("utreexod") => {
+            let backend = utreexod_backend()?;
+            utreexo = Some(backend.info());
+            Box::new(backend)
+        }
+        Ok(other) => {
+            error!("unknown CHAIN_BACKEND {other}");
+            exit(1);
+        }
+    };
 
-    let Ok(Ok(change)) = env::var("CHANGE_ADDRESS").map(|address| {
-        Address::from_str(&address).and_then(|address| Ok(address.assume_checked()))
-    }) else {
-        println!(
+    // with a watch-only wallet, signatures come from somewhere else
+    #[cfg(feature = "external-signer")]
+    let rpc: Box<dyn ChainBackend> = match env::var("SIGNER_ENDPOINT") {
+        Ok(endpoint) => {
+            info!("using the external signer at {endpoint}");
+            let endpoint = endpoint.parse().expect("parsing an endpoint is infallible");
+            Box::new(backend::signer::ExternalSigner::new(rpc, endpoint))
+        }
+        Err(_) => rpc,
+    };
+
+    let Ok(Ok(change)) = env::var("CHANGE_ADDRESS")
+        .map(|address| Address::from_str(&address).map(|address| address.assume_checked()))
+    else {
+        error!(
             "You have to provide a valid change address. \n Please set the CHANGE_ADDRESS env var"
         );
         exit(1);
     };
 
-    #[cfg(feature = "ln")]
-    let Ok(cln_rpc) = env::var("CLN_RPC_DIR") else {
-        println!("You have to provide the CLN_RPC_DIR");
-        exit(1);
+    #[cfg(feature = "lightning")]
+    let lightning = {
+        // if LN_BACKEND isn't set, we use the first one compiled in
+        let default = if cfg!(feature = "ln") {
+            "cln"
+        } else if cfg!(feature = "lnd") {
+            "lnd"
+        } else if cfg!(feature = "eclair") {
+            "eclair"
+        } else {
+            "ldk"
+        };
+
+        match env::var("LN_BACKEND").as_deref().unwrap_or(default) {
+            #[cfg(feature = "ln")]
+            "cln" => cln_node().await?,
+            #[cfg(feature = "lnd")]
+            "lnd" => lnd_node().await?,
+            #[cfg(feature = "eclair")]
+            "eclair" => eclair_node()?,
+            #[cfg(feature = "ldk")]
+            "ldk" => ldk_node().await?,
+            other => {
+                error!("unknown LN_BACKEND {other}");
+                exit(1);
+            }
+        }
+    };
+
+    #[cfg(feature = "lightning")]
+    let allow_zero_conf = match env::var("ZERO_CONF_CHANNELS").as_deref() {
+        Ok("true") | Ok("1") => {
+            info!("ZERO_CONF_CHANNELS set, we'll open zero-conf channels on request");
+            true
+        }
+        _ => {
+            info!("ZERO_CONF_CHANNELS not set, we won't open zero-conf channels");
+            false
+        }
+    };
+
+    let admin_token = match env::var("ADMIN_TOKEN") {
+        Ok(token) if !token.is_empty() => Some(token),
+        _ => {
+            info!("ADMIN_TOKEN not set, admin routes are disabled");
+            None
+        }
+    };
+
+    #[cfg(feature = "lightning")]
+    let hold_payouts = match env::var("HOLD_INVOICE_PAYOUTS").as_deref() {
+        Ok("true") | Ok("1") => {
+            warn!("HOLD_INVOICE_PAYOUTS set, users must pay a hold invoice to get coins");
+            Some(Default::default())
+        }
+        _ => None,
+    };
+
+    #[cfg(feature = "redis")]
+    let shared = match env::var("REDIS_URL") {
+        Ok(url) => {
+            info!("REDIS_URL set, sharing rate limits, cooldowns and the daily budget through it");
+            Some(std::sync::Arc::new(shared::SharedStore::new(&url)?))
+        }
+        Err(_) => {
+            info!("REDIS_URL not set, rate limits, cooldowns and the daily budget are only known to us");
+            None
+        }
+    };
+
+    #[cfg(feature = "lightning")]
+    let channel_cooldowns = match env::var("CHANNEL_COOLDOWN_HOURS")
+        .map(|hours| hours.parse::<u64>())
+    {
+        Ok(Ok(hours)) => {
+            let file = env::var("CHANNEL_COOLDOWN_FILE").unwrap_or("channel_cooldowns.json".into());
+            info!("CHANNEL_COOLDOWN_HOURS set, nodes get one channel every {hours} hours");
+
+            Some(load_cooldowns(
+                hours,
+                file,
+                "channel",
+                #[cfg(feature = "redis")]
+                &shared,
+            )?)
+        }
+        Ok(Err(e)) => {
+            warn!("error parsing CHANNEL_COOLDOWN_HOURS {e}, nodes can get as many channels as they want");
+            None
+        }
+        Err(_) => {
+            info!("CHANNEL_COOLDOWN_HOURS not set, nodes can get as many channels as they want");
+            None
+        }
+    };
+
+    #[cfg(feature = "lightning")]
+    let reclaim_after = match env::var("RECLAIM_INACTIVE_DAYS").map(|days| days.parse::<u64>()) {
+        Ok(Ok(days)) => {
+            info!("RECLAIM_INACTIVE_DAYS set, closing channels inactive for {days} days");
+            Some(std::time::Duration::from_secs(days * 24 * 3_600))
+        }
+        Ok(Err(e)) => {
+            warn!("error parsing RECLAIM_INACTIVE_DAYS {e}, we won't close inactive channels");
+            None
+        }
+        Err(_) => {
+            info!("RECLAIM_INACTIVE_DAYS not set, we won't close inactive channels");
+            None
+        }
+    };
+
+    #[cfg(feature = "lightning")]
+    let channel_limits = {
+        let default_capacity = sats_from_env("CHANNEL_VALUE", 1_000_000);
+        let default_push = sats_from_env("PUSH_VALUE", 1_000_000);
+        let max_peer_capacity = match env::var("MAX_PEER_CAPACITY").map(|max| max.parse()) {
+            Ok(Ok(max)) => {
+                info!("MAX_PEER_CAPACITY set to {max} sats");
+                Some(Amount::from_sat(max))
+            }
+            Ok(Err(e)) => {
+                warn!("error parsing MAX_PEER_CAPACITY {e}, nodes can have as much as they want");
+                None
+            }
+            Err(_) => None,
+        };
+
+        ln::ChannelLimits {
+            default_capacity,
+            default_push,
+            min_capacity: sats_from_env("MIN_CHANNEL_VALUE", 20_000),
+            max_capacity: sats_from_env("MAX_CHANNEL_VALUE", default_capacity.to_sat()),
+            max_push: sats_from_env("MAX_PUSH_VALUE", default_push.to_sat()),
+            max_peer_capacity,
+        }
     };
 
     let max_sendable: Amount = match env::var("MAX_SENDABLE_AMOUNT").map(|amount| amount.parse()) {
         Ok(Ok(value)) => {
-            println!("MAX_SENDABLE_AMOUNT set to {value}");
+            info!("MAX_SENDABLE_AMOUNT set to {value}");
             value
         }
         Ok(Err(e)) => {
-            println!("error parsing the MAX_SENDABLE_AMOUNT {e}, using default of 1_000_000");
+            warn!("error parsing the MAX_SENDABLE_AMOUNT {e}, using default of 1_000_000");
             Amount::from_sat(1_000_000)
         }
         Err(_) => {
-            println!("MAX_SENDABLE_AMOUNTA not set, using default of 1_000_000");
+            info!("MAX_SENDABLE_AMOUNTA not set, using default of 1_000_000");
             Amount::from_sat(1_000_000)
         }
     };
 
     let min_sendable: Amount = match env::var("MIN_SENDABLE_AMOUNT").map(|amount| amount.parse()) {
         Ok(Ok(value)) => {
-            println!("MIN_SENDABLE_AMOUNT set to {value}");
+            info!("MIN_SENDABLE_AMOUNT set to {value}");
             value
         }
         Ok(Err(e)) => {
-            println!("error parsing the MIN_SENDABLE_AMOUNT {e}, using default of 420");
+            warn!("error parsing the MIN_SENDABLE_AMOUNT {e}, using default of 420");
             Amount::from_sat(420)
         }
         Err(_) => {
-            println!("MIN_SENDABLE_AMOUNT not set, uing default of 420");
+            info!("MIN_SENDABLE_AMOUNT not set, uing default of 420");
             Amount::from_sat(420)
         }
     };
 
-    #[cfg(feature = "ln")]
+    let daily_budget = match env::var("DAILY_BUDGET").map(|sats| sats.parse::<u64>()) {
+        Ok(Ok(sats)) => {
+            info!("DAILY_BUDGET set, we give out at most {sats} sats a day");
+            Some(Amount::from_sat(sats))
+        }
+        Ok(Err(e)) => {
+            warn!("error parsing DAILY_BUDGET {e}, we won't limit what we give out a day");
+            None
+        }
+        Err(_) => {
+            info!("DAILY_BUDGET not set, we won't limit what we give out a day");
+            None
+        }
+    };
+
+    let captcha_max_sendable_amount = match env::var("CAPTCHA_MAX_SENDABLE_AMOUNT")
+        .map(|amount| amount.parse::<Amount>())
+    {
+        Ok(Ok(value)) => {
+            info!("CAPTCHA_MAX_SENDABLE_AMOUNT set, users solving a captcha or proof of work may get {value}, and others don't have to");
+            Some(value)
+        }
+        Ok(Err(e)) => {
+            warn!("error parsing CAPTCHA_MAX_SENDABLE_AMOUNT {e}, solving a captcha gets nothing more");
+            None
+        }
+        Err(_) => {
+            info!("CAPTCHA_MAX_SENDABLE_AMOUNT not set, solving a captcha gets nothing more");
+            None
+        }
+    };
+
+    let verified_max_sendable_amount = match env::var("VERIFIED_MAX_SENDABLE_AMOUNT")
+        .map(|amount| amount.parse::<Amount>())
     {
-        let cln_rpc = ClnRpc::new(cln_rpc).await?;
-        let cln = CLNDaemon::new(cln_rpc).await?;
-        api::create_api(rpc, cln, max_sendable, min_sendable, change).await?;
+        Ok(Ok(value)) => {
+            info!("VERIFIED_MAX_SENDABLE_AMOUNT set, users proving they own the address may get {value}");
+            Some(value)
+        }
+        Ok(Err(e)) => {
+            warn!("error parsing VERIFIED_MAX_SENDABLE_AMOUNT {e}, everyone gets the same");
+            None
+        }
+        Err(_) => {
+            info!("VERIFIED_MAX_SENDABLE_AMOUNT not set, everyone gets the same");
+            None
+        }
+    };
+
+    #[cfg(feature = "nostr")]
+    let nostr = match env::var("NOSTR_RELAYS") {
+        Ok(relays) => {
+            let relays = relays
+                .split(',')
+                .map(|relay| relay.trim().to_string())
+                .filter(|relay| !relay.is_empty())
+                .collect::<Vec<_>>();
+            let days = match env::var("NOSTR_MIN_ACCOUNT_AGE_DAYS").map(|days| days.parse::<u64>())
+            {
+                Ok(Ok(days)) => days,
+                _ => {
+                    warn!("NOSTR_MIN_ACCOUNT_AGE_DAYS not set or invalid, using default of 30");
+                    30
+                }
+            };
+            let max_sendable = match env::var("NOSTR_MAX_SENDABLE_AMOUNT")
+                .map(|amount| amount.parse::<Amount>())
+            {
+                Ok(Ok(amount)) => amount,
+                _ => {
+                    warn!("NOSTR_MAX_SENDABLE_AMOUNT not set or invalid, Nostr users may get as much as others");
+                    max_sendable
+                }
+            };
+            let cooldown = match env::var("NOSTR_ADDRESS_COOLDOWN_HOURS")
+                .map(|hours| hours.parse::<u64>())
+            {
+                Ok(Ok(hours)) => Some(Duration::from_secs(hours * 3_600)),
+                _ => {
+                    warn!("NOSTR_ADDRESS_COOLDOWN_HOURS not set or invalid, Nostr users wait as long as others");
+                    None
+                }
+            };
+
+            info!("NOSTR_RELAYS set, Nostr users older than {days} days may get {max_sendable}");
+            Some(nostr::NostrTier::new(
+                relays,
+                Duration::from_secs(days * 24 * 3_600),
+                max_sendable,
+                cooldown,
+            ))
+        }
+        Err(_) => {
+            info!("NOSTR_RELAYS not set, Nostr users get the same as everyone");
+            None
+        }
+    };
+
+    #[cfg(feature = "github")]
+    let github = match (
+        env::var("GITHUB_CLIENT_ID"),
+        env::var("GITHUB_CLIENT_SECRET"),
+        env::var("GITHUB_REDIRECT_URL"),
+    ) {
+        (Ok(client_id), Ok(client_secret), Ok(redirect_url)) => {
+            let days = match env::var("GITHUB_MIN_ACCOUNT_AGE_DAYS").map(|days| days.parse::<u64>())
+            {
+                Ok(Ok(days)) => days,
+                _ => {
+                    warn!("GITHUB_MIN_ACCOUNT_AGE_DAYS not set or invalid, using default of 30");
+                    30
+                }
+            };
+            let max_sendable = match env::var("GITHUB_MAX_SENDABLE_AMOUNT")
+                .map(|amount| amount.parse::<Amount>())
+            {
+                Ok(Ok(amount)) => amount,
+                _ => {
+                    warn!("GITHUB_MAX_SENDABLE_AMOUNT not set or invalid, GitHub users may get as much as others");
+                    max_sendable
+                }
+            };
+
+            info!("GITHUB_CLIENT_ID set, GitHub users older than {days} days may get {max_sendable} a day");
+            Some(github::GithubTier::new(
+                client_id,
+                client_secret,
+                redirect_url,
+                Duration::from_secs(days * 24 * 3_600),
+                max_sendable,
+            ))
+        }
+        (Err(_), Err(_), Err(_)) => {
+            info!("GITHUB_CLIENT_ID not set, GitHub users get the same as everyone");
+            None
+        }
+        _ => {
+            error!("GITHUB_CLIENT_ID, GITHUB_CLIENT_SECRET and GITHUB_REDIRECT_URL must be set together");
+            exit(1);
+        }
+    };
+
+    #[cfg(feature = "zmq")]
+    let tracker = {
+        let tracker = std::sync::Arc::new(tracker::PayoutTracker::default());
+
+        match env::var("BITCOIND_ZMQ_RAWBLOCK") {
+            Ok(rawblock) => {
+                let hashtx = env::var("BITCOIND_ZMQ_HASHTX").ok();
+                zmq::spawn_listener(rawblock, hashtx, tracker.clone());
+            }
+            Err(_) => info!("BITCOIND_ZMQ_RAWBLOCK not set, we won't track confirmations"),
+        }
+
+        tracker
+    };
+
+    let per_hour = |var: &str| match env::var(var).map(|limit| limit.parse::<u64>()) {
+        Ok(Ok(limit)) => {
+            info!("{var} set to {limit}");
+            Some(limit)
+        }
+        Ok(Err(e)) => {
+            warn!("error parsing {var} {e}, there will be no limit");
+            None
+        }
+        Err(_) => None,
+    };
+    let rate_limiter = match (
+        per_hour("RATE_LIMIT_REQUESTS_PER_HOUR"),
+        per_hour("RATE_LIMIT_SATS_PER_HOUR"),
+    ) {
+        (None, None) => {
+            info!("RATE_LIMIT_REQUESTS_PER_HOUR and RATE_LIMIT_SATS_PER_HOUR not set, we won't rate limit clients");
+            None
+        }
+        #[cfg(feature = "redis")]
+        (requests, sats) if shared.is_some() => Some(ratelimit::RateLimiter::shared(
+            requests,
+            sats,
+            shared.clone().unwrap(),
+        )),
+        (requests, sats) => Some(ratelimit::RateLimiter::new(requests, sats)),
+    };
+
+    let key_limiter = |requests, sats| {
+        #[cfg(feature = "redis")]
+        if let Some(store) = &shared {
+            return ratelimit::RateLimiter::shared(requests, sats, store.clone());
+        }
+        ratelimit::RateLimiter::new(requests, sats)
+    };
+    let api_keys =
+        match env::var("API_KEYS").map(|keys| apikeys::ApiKeys::parse(&keys, key_limiter)) {
+            Ok(Ok(keys)) => {
+                info!("API_KEYS set, we know {} keys", keys.len());
+                keys
+            }
+            Ok(Err(e)) => {
+                error!("error parsing API_KEYS: {e}");
+                exit(1);
+            }
+            Err(_) => {
+                info!("API_KEYS not set, clients can't use API keys");
+                Default::default()
+            }
+        };
+
+    let address_cooldowns = match env::var("ADDRESS_COOLDOWN_HOURS")
+        .map(|hours| hours.parse::<u64>())
+    {
+        Ok(Ok(hours)) => {
+            let file = env::var("ADDRESS_COOLDOWN_FILE").unwrap_or("address_cooldowns.json".into());
+            info!("ADDRESS_COOLDOWN_HOURS set, addresses get paid once every {hours} hours");
+
+            Some(load_cooldowns(
+                hours,
+                file,
+                "address",
+                #[cfg(feature = "redis")]
+                &shared,
+            )?)
+        }
+        Ok(Err(e)) => {
+            warn!(
+                "error parsing ADDRESS_COOLDOWN_HOURS {e}, addresses can be paid as often as asked"
+            );
+            None
+        }
+        Err(_) => {
+            info!("ADDRESS_COOLDOWN_HOURS not set, addresses can be paid as often as asked");
+            None
+        }
+    };
+
+    let db_file = env::var("DATABASE_FILE").unwrap_or_else(|_| {
+        info!("DATABASE_FILE not set, using faucet.db");
+        "faucet.db".into()
+    });
+    let db = db::Database::open(db_file.as_ref())?;
+    let access = access::AccessLists::load(&db)?;
+
+    let payout_queue = match env::var("PAYOUT_QUEUE").as_deref() {
+        Ok("true") | Ok("1") => {
+            let setting = |var: &str, default: u64| match env::var(var).map(|value| value.parse()) {
+                Ok(Ok(value)) => value,
+                _ => {
+                    warn!("{var} not set or invalid, using default of {default}");
+                    default
+                }
+            };
+            let capacity = setting("PAYOUT_QUEUE_CAPACITY", 1_000);
+            let batch_size = setting("PAYOUT_QUEUE_BATCH_SIZE", 20);
+            let interval = setting("PAYOUT_QUEUE_INTERVAL_SECONDS", 30);
+
+            info!("PAYOUT_QUEUE set, we pay up to {batch_size} requests every {interval} seconds");
+            let queue = queue::PayoutQueue::new(
+                capacity as usize,
+                batch_size.max(1) as usize,
+                Duration::from_secs(interval),
+            );
+            queue.restore(&db)?;
+            Some(queue)
+        }
+        _ => {
+            info!("PAYOUT_QUEUE not set, /send/ pays right away");
+            None
+        }
+    };
+
+    let coin_selection = coinselect::CoinSelection {
+        strategy: match env::var("COIN_SELECTION").map(|strategy| strategy.parse()) {
+            Ok(Ok(strategy)) => {
+                info!("COIN_SELECTION set, selecting coins with {strategy:?}");
+                strategy
+            }
+            Ok(Err(e)) => {
+                error!("invalid COIN_SELECTION: {e}");
+                exit(1);
+            }
+            Err(_) => {
+                info!("COIN_SELECTION not set, using default of branch and bound");
+                coinselect::Strategy::BranchAndBound
+            }
+        },
+        // enough for another of the biggest payouts
+        change_target: sats_from_env("CHANGE_TARGET_SATS", max_sendable.to_sat()),
+        // change under the change address's dust threshold wouldn't relay
+        min_change: coinselect::MIN_CHANGE.max(change.script_pubkey().dust_value()),
+        min_confirmations: match env::var("MIN_CONFIRMATIONS").map(|n| n.parse::<u32>()) {
+            Ok(Ok(n)) => {
+                info!("MIN_CONFIRMATIONS set, spending coins with {n} confirmations or more");
+                n
+            }
+            Ok(Err(e)) => {
+                error!("invalid MIN_CONFIRMATIONS: {e}");
+                exit(1);
+            }
+            Err(_) => {
+                info!("MIN_CONFIRMATIONS not set, spending unconfirmed coins too");
+                0
+            }
+        },
+    };
+
+    let feerate_from_env = |var: &str, default: u64| {
+        match env::var(var).map(|rate| rate.parse::<u64>()) {
+            Ok(Ok(rate)) => {
+                info!("{var} set to {rate} sat/vB");
+                FeeRate::from_sat_per_vb(rate)
+            }
+            Ok(Err(e)) => {
+                warn!("error parsing {var} {e}, using default of {default} sat/vB");
+                FeeRate::from_sat_per_vb(default)
+            }
+            Err(_) => {
+                info!("{var} not set, using default of {default} sat/vB");
+                FeeRate::from_sat_per_vb(default)
+            }
+        }
+        .unwrap_or_else(|| {
+            error!("{var} is too high");
+            exit(1);
+        })
+    };
+    let target = env::var("PAYOUT_FEE_TARGET")
+        .map(|target| target.parse().unwrap_or(6))
+        .unwrap_or(6)
+        .clamp(1, fees::MAX_TARGET);
+    let fees = fees::FeePolicy {
+        target,
+        // requests only get to pay more if we let them
+        min_target: env::var("MIN_PAYOUT_FEE_TARGET")
+            .map(|min| min.parse().unwrap_or(target))
+            .unwrap_or(target)
+            .clamp(1, target),
+        floor: feerate_from_env("FEE_RATE_FLOOR", 1),
+        ceiling: feerate_from_env("FEE_RATE_CEILING", 100),
+    };
+    if fees.floor > fees.ceiling {
+        error!("FEE_RATE_FLOOR is over FEE_RATE_CEILING");
+        exit(1);
     }
 
-    #[cfg(not(feature = "ln"))]
-    api::create_api(rpc, max_sendable, min_sendable, change).await?;
+    let bumper = match env::var("BUMP_AFTER_BLOCKS").map(|blocks| blocks.parse::<u32>()) {
+        Ok(Ok(blocks)) if blocks > 0 => {
+            info!("BUMP_AFTER_BLOCKS set, we bump payouts that waited {blocks} blocks");
+            bump::Bumper::new(Some(blocks))
+        }
+        Ok(_) => {
+            warn!("BUMP_AFTER_BLOCKS isn't a positive number, we only bump payouts when asked to");
+            bump::Bumper::new(None)
+        }
+        Err(_) => {
+            info!("BUMP_AFTER_BLOCKS not set, we only bump payouts when asked to");
+            bump::Bumper::new(None)
+        }
+    };
+
+    let accelerator = match env::var("CPFP_AFTER_BLOCKS").map(|blocks| blocks.parse::<u32>()) {
+        Ok(Ok(blocks)) if blocks > 0 => {
+            info!("CPFP_AFTER_BLOCKS set, we speed up donations that waited {blocks} blocks");
+            cpfp::Accelerator::new(Some(blocks))
+        }
+        Ok(_) => {
+            warn!("CPFP_AFTER_BLOCKS isn't a positive number, we only speed up donations when asked to");
+            cpfp::Accelerator::new(None)
+        }
+        Err(_) => {
+            info!("CPFP_AFTER_BLOCKS not set, we only speed up donations when asked to");
+            cpfp::Accelerator::new(None)
+        }
+    };
+
+    let payjoins = match env::var("PAYJOIN").as_deref() {
+        Ok("true") | Ok("1") => {
+            info!("PAYJOIN set, donors may refill us with payjoins");
+            Some(payjoin::Payjoins::default())
+        }
+        _ => {
+            info!("PAYJOIN not set, we don't take payjoins");
+            None
+        }
+    };
+
+    let scheduler = match env::var("JOB_SCHEDULES").map(|s| scheduler::parse_schedules(&s)) {
+        Ok(Ok(schedules)) => {
+            info!("JOB_SCHEDULES set, some jobs run on schedules of their own");
+            scheduler::Scheduler::new(schedules)
+        }
+        Ok(Err(e)) => {
+            error!("JOB_SCHEDULES is invalid: {e}");
+            exit(1);
+        }
+        Err(_) => {
+            info!("JOB_SCHEDULES not set, our jobs run on their usual schedules");
+            scheduler::Scheduler::default()
+        }
+    };
+
+    let assets = match env::var("STATIC_DIR") {
+        Ok(dir) if std::path::Path::new(&dir).is_dir() => {
+            info!("STATIC_DIR set, files in {dir} take the place of our own");
+            assets::Assets::new(Some(dir.into()))
+        }
+        Ok(dir) => {
+            error!("STATIC_DIR {dir} isn't a directory");
+            exit(1);
+        }
+        Err(_) => {
+            info!("STATIC_DIR not set, serving the pages we're built with");
+            assets::Assets::default()
+        }
+    };
+
+    let consolidation = match env::var("CONSOLIDATE_BELOW_SATS").map(|sats| sats.parse::<u64>()) {
+        Ok(Ok(sats)) if sats > 0 => {
+            info!("CONSOLIDATE_BELOW_SATS set, we merge coins under {sats} sats when it's quiet");
+            let count_from_env = |var: &str, default: usize| match env::var(var)
+                .map(|count| count.parse::<usize>())
+            {
+                Ok(Ok(count)) if count > 0 => count,
+                Ok(_) => {
+                    error!("{var} isn't a positive number");
+                    exit(1);
+                }
+                Err(_) => default,
+            };
+            let consolidation = consolidate::Consolidation {
+                threshold: Amount::from_sat(sats),
+                min_inputs: count_from_env("CONSOLIDATE_MIN_INPUTS", 10).max(2),
+                max_inputs: count_from_env("CONSOLIDATE_MAX_INPUTS", 100),
+                max_feerate: feerate_from_env("CONSOLIDATE_MAX_FEE_RATE", 2),
+                quiet_for: Duration::from_secs(
+                    60 * count_from_env("CONSOLIDATE_QUIET_MINUTES", 30) as u64,
+                ),
+            };
+            if consolidation.min_inputs > consolidation.max_inputs {
+                error!("CONSOLIDATE_MIN_INPUTS is over CONSOLIDATE_MAX_INPUTS");
+                exit(1);
+            }
+            Some(consolidation)
+        }
+        Ok(_) => {
+            warn!("CONSOLIDATE_BELOW_SATS isn't a positive number, we don't consolidate coins");
+            None
+        }
+        Err(_) => {
+            info!("CONSOLIDATE_BELOW_SATS not set, we don't consolidate coins");
+            None
+        }
+    };
+
+    let max_batch_outputs = match env::var("MAX_BATCH_OUTPUTS").map(|max| max.parse::<usize>()) {
+        Ok(Ok(max)) if max > 0 => {
+            info!("MAX_BATCH_OUTPUTS set, /send/batch pays up to {max} addresses at once");
+            Some(max)
+        }
+        Ok(_) => {
+            warn!("MAX_BATCH_OUTPUTS isn't a positive number, /send/batch is disabled");
+            None
+        }
+        Err(_) => {
+            info!("MAX_BATCH_OUTPUTS not set, /send/batch is disabled");
+            None
+        }
+    };
+
+    let max_recipients = match env::var("MAX_RECIPIENTS").map(|max| max.parse::<usize>()) {
+        Ok(Ok(max)) if max > 0 => {
+            info!("MAX_RECIPIENTS set, /send/ requests may pay up to {max} addresses");
+            max
+        }
+        Ok(_) => {
+            error!("MAX_RECIPIENTS isn't a positive number");
+            exit(1);
+        }
+        Err(_) => 1,
+    };
+
+    let address_types = match env::var("ADDRESS_TYPES") {
+        Ok(types) => {
+            let types = types
+                .split(',')
+                .map(|kind| kind.trim().parse::<AddressType>())
+                .collect::<Result<Vec<_>, _>>();
+            match types {
+                Ok(types) if !types.is_empty() => {
+                    info!("ADDRESS_TYPES set, only paying {types:?} addresses");
+                    Some(types)
+                }
+                Ok(_) => {
+                    error!("ADDRESS_TYPES has no address types");
+                    exit(1);
+                }
+                Err(e) => {
+                    error!("invalid ADDRESS_TYPES: {e}, use p2pkh, p2sh, p2wpkh, p2wsh or p2tr");
+                    exit(1);
+                }
+            }
+        }
+        Err(_) => None,
+    };
+
+    #[cfg(feature = "webhooks")]
+    let webhooks = match env::var("WEBHOOK_SECRET") {
+        Ok(secret) if !secret.is_empty() => {
+            let confirmations = match env::var("WEBHOOK_CONFIRMATIONS").map(|n| n.parse::<u32>()) {
+                Ok(Ok(n)) if n > 0 => n,
+                Ok(_) => {
+                    error!("WEBHOOK_CONFIRMATIONS isn't a positive number");
+                    exit(1);
+                }
+                Err(_) => 1,
+            };
+            info!("WEBHOOK_SECRET set, /send/ users may get a callback after {confirmations} confirmations");
+            Some(webhook::Webhooks::new(secret, confirmations))
+        }
+        _ => {
+            info!("WEBHOOK_SECRET not set, /send/ users can't ask for callbacks");
+            None
+        }
+    };
+
+    let challenges = match env::var("POW_DIFFICULTY").map(|bits| bits.parse::<u32>()) {
+        Ok(Ok(bits)) if bits <= 256 => {
+            info!("POW_DIFFICULTY set, /send/ users have to find hashes with {bits} zero bits");
+            Some(pow::Challenges::new(bits))
+        }
+        Ok(Ok(bits)) => {
+            error!("POW_DIFFICULTY is {bits}, but hashes only have 256 bits");
+            exit(1);
+        }
+        Ok(Err(e)) => {
+            warn!("error parsing POW_DIFFICULTY {e}, we won't ask for proofs of work");
+            None
+        }
+        Err(_) => {
+            info!("POW_DIFFICULTY not set, we won't ask for proofs of work");
+            None
+        }
+    };
+
+    #[cfg(feature = "captcha")]
+    let captcha = match env::var("CAPTCHA_PROVIDER") {
+        Ok(name) => {
+            let Some(provider) = captcha::Provider::from_name(&name) else {
+                error!("CAPTCHA_PROVIDER must be hcaptcha or turnstile");
+                exit(1);
+            };
+            let (Ok(site_key), Ok(secret_key)) =
+                (env::var("CAPTCHA_SITE_KEY"), env::var("CAPTCHA_SECRET_KEY"))
+            else {
+                error!("CAPTCHA_PROVIDER set but CAPTCHA_SITE_KEY or CAPTCHA_SECRET_KEY isn't");
+                exit(1);
+            };
+
+            info!("CAPTCHA_PROVIDER set, /send/ users have to solve a {name} captcha");
+            Some(captcha::Captcha::new(provider, site_key, secret_key))
+        }
+        Err(_) => {
+            info!("CAPTCHA_PROVIDER not set, /send/ users won't solve a captcha");
+            None
+        }
+    };
+
+    let abuse = {
+        let threshold = |var: &str| match env::var(var).map(|score| score.parse::<f64>()) {
+            Ok(Ok(score)) => Some(score),
+            Ok(Err(e)) => {
+                warn!("error parsing {var} {e}, ignoring it");
+                None
+            }
+            Err(_) => None,
+        };
+        let challenge_at = threshold("ABUSE_CHALLENGE_SCORE");
+        let downgrade_at = threshold("ABUSE_DOWNGRADE_SCORE");
+        let weights = match env::var("ABUSE_WEIGHTS").map(|weights| abuse::Weights::parse(&weights))
+        {
+            Ok(Ok(weights)) => weights,
+            Ok(Err(e)) => {
+                error!("error parsing ABUSE_WEIGHTS: {e}");
+                exit(1);
+            }
+            Err(_) => abuse::Weights::default(),
+        };
+
+        if challenge_at.is_some() || downgrade_at.is_some() {
+            info!("ABUSE_CHALLENGE_SCORE or ABUSE_DOWNGRADE_SCORE set, scoring /send/ requests with {weights:?}");
+            if challenge_at.is_some() && challenges.is_none() {
+                #[cfg(feature = "captcha")]
+                let has_captcha = captcha.is_some();
+                #[cfg(not(feature = "captcha"))]
+                let has_captcha = false;
+                if !has_captcha {
+                    warn!("ABUSE_CHALLENGE_SCORE set, but there's no captcha or proof of work to ask for");
+                }
+            }
+            Some(abuse::AbuseScorer::new(weights, challenge_at, downgrade_at))
+        } else {
+            info!(
+                "ABUSE_CHALLENGE_SCORE and ABUSE_DOWNGRADE_SCORE not set, we won't score requests"
+            );
+            None
+        }
+    };
+
+    #[cfg(feature = "tor")]
+    let tor = {
+        let policy = match env::var("TOR_EXIT_POLICY").as_deref() {
+            Ok("allow") | Err(_) => None,
+            Ok("challenge") => {
+                #[cfg(feature = "captcha")]
+                let has_captcha = captcha.is_some();
+                #[cfg(not(feature = "captcha"))]
+                let has_captcha = false;
+                if challenges.is_none() && !has_captcha {
+                    warn!("TOR_EXIT_POLICY is challenge, but there's no captcha or proof of work to ask for");
+                }
+                Some(tor::TorPolicy::Challenge)
+            }
+            Ok("limit") => {
+                match env::var("TOR_MAX_SENDABLE_AMOUNT").map(|amount| amount.parse::<Amount>()) {
+                    Ok(Ok(amount)) => Some(tor::TorPolicy::Limit(amount)),
+                    _ => {
+                        error!("TOR_EXIT_POLICY is limit, but TOR_MAX_SENDABLE_AMOUNT isn't set or is invalid");
+                        exit(1);
+                    }
+                }
+            }
+            Ok(policy) => {
+                error!("TOR_EXIT_POLICY must be allow, challenge or limit, not {policy}");
+                exit(1);
+            }
+        };
+
+        match policy {
+            Some(policy) => {
+                let list_url =
+                    env::var("TOR_EXIT_LIST_URL").unwrap_or(tor::DEFAULT_EXIT_LIST_URL.into());
+                let refresh = env::var("TOR_EXIT_LIST_REFRESH_SECONDS")
+                    .map(|secs| secs.parse().unwrap_or(3_600))
+                    .unwrap_or(3_600);
+
+                info!("TOR_EXIT_POLICY set, requests from Tor exits get {policy:?}");
+                Some(tor::TorExits::new(
+                    policy,
+                    list_url,
+                    Duration::from_secs(refresh),
+                ))
+            }
+            None => {
+                info!("TOR_EXIT_POLICY not set, Tor users are treated like everyone else");
+                None
+            }
+        }
+    };
+
+    let cors = match env::var("CORS_ALLOWED_ORIGINS") {
+        Ok(origins) if !origins.is_empty() => {
+            let cors = cors::CorsPolicy::new(
+                &origins,
+                env::var("CORS_ALLOWED_METHODS")
+                    .ok()
+                    .filter(|methods| !methods.is_empty())
+                    .as_deref(),
+                env::var("CORS_ALLOWED_HEADERS")
+                    .ok()
+                    .filter(|headers| !headers.is_empty())
+                    .as_deref(),
+            )
+            .unwrap_or_else(|e| {
+                error!("invalid CORS_ALLOWED_ORIGINS, CORS_ALLOWED_METHODS or CORS_ALLOWED_HEADERS: {e}");
+                exit(1);
+            });
+
+            match &cors {
+                cors::CorsPolicy::Permissive => {
+                    info!("CORS_ALLOWED_ORIGINS is *, any website may call us")
+                }
+                cors::CorsPolicy::Restricted { origins, .. } => {
+                    info!("only {} may call us from a browser", origins.join(", "))
+                }
+            }
+            cors
+        }
+        _ => {
+            warn!("CORS_ALLOWED_ORIGINS not set, any website may call us. Set it to * if that's what you want");
+            cors::CorsPolicy::Permissive
+        }
+    };
+
+    let trusted_proxies = match env::var("TRUSTED_PROXIES") {
+        Ok(proxies) => {
+            let proxies = proxies
+                .parse::<proxy::TrustedProxies>()
+                .unwrap_or_else(|e| {
+                    error!("invalid TRUSTED_PROXIES: {e}");
+                    exit(1);
+                });
+
+            info!(
+                "TRUSTED_PROXIES set, we believe who {} networks forward requests for",
+                proxies.len()
+            );
+            proxies
+        }
+        Err(_) => {
+            info!("TRUSTED_PROXIES not set, clients are who connects to us");
+            Default::default()
+        }
+    };
+
+    #[cfg(feature = "tls")]
+    let tls = match (env::var("TLS_CERT_FILE"), env::var("TLS_KEY_FILE")) {
+        (Ok(cert_file), Ok(key_file)) => {
+            let reload = env::var("TLS_RELOAD_SECONDS")
+                .map(|secs| secs.parse().unwrap_or(60))
+                .unwrap_or(60);
+            let certificates = tls::Certificates::load(
+                cert_file.into(),
+                key_file.into(),
+                Duration::from_secs(reload),
+            )
+            .unwrap_or_else(|e| {
+                error!("couldn't load the TLS certificate: {e}");
+                exit(1);
+            });
+
+            info!("TLS_CERT_FILE set, serving HTTPS and looking for a new certificate every {reload} seconds");
+            Some(std::sync::Arc::new(certificates))
+        }
+        _ => {
+            info!("TLS_CERT_FILE or TLS_KEY_FILE not set, serving plain HTTP");
+            None
+        }
+    };
+
+    #[cfg(feature = "alerts")]
+    let alerts = {
+        let mut channels = vec![];
+        if let Ok(url) = env::var("ALERT_WEBHOOK_URL") {
+            channels.push(alerts::Channel::Webhook(url));
+        }
+        match (
+            env::var("ALERT_TELEGRAM_BOT_TOKEN"),
+            env::var("ALERT_TELEGRAM_CHAT_ID"),
+        ) {
+            (Ok(token), Ok(chat_id)) => channels.push(alerts::Channel::Telegram { token, chat_id }),
+            (Err(_), Err(_)) => {}
+            _ => {
+                error!("ALERT_TELEGRAM_BOT_TOKEN and ALERT_TELEGRAM_CHAT_ID must be set together");
+                exit(1);
+            }
+        }
+        match (
+            env::var("ALERT_SMTP_URL"),
+            env::var("ALERT_EMAIL_FROM"),
+            env::var("ALERT_EMAIL_TO"),
+        ) {
+            (Ok(url), Ok(from), Ok(to)) => match alerts::Channel::email(&url, &from, &to) {
+                Ok(channel) => channels.push(channel),
+                Err(e) => {
+                    error!("invalid ALERT_SMTP_URL, ALERT_EMAIL_FROM or ALERT_EMAIL_TO: {e}");
+                    exit(1);
+                }
+            },
+            (Err(_), Err(_), Err(_)) => {}
+            _ => {
+                error!("ALERT_SMTP_URL, ALERT_EMAIL_FROM and ALERT_EMAIL_TO must be set together");
+                exit(1);
+            }
+        }
+
+        let threshold = |var: &str| match env::var(var).map(|sats| sats.parse::<u64>()) {
+            Ok(Ok(sats)) => Some(Amount::from_sat(sats)),
+            Ok(Err(e)) => {
+                warn!("error parsing {var} {e}, we won't alert about it");
+                None
+            }
+            Err(_) => None,
+        };
+        let min_balance = threshold("LOW_BALANCE_ALERT_SATS");
+        #[cfg(feature = "lightning")]
+        let min_liquidity = threshold("LOW_LIQUIDITY_ALERT_SATS");
+        #[cfg(feature = "lightning")]
+        let thresholds = min_balance.is_some() || min_liquidity.is_some();
+        #[cfg(not(feature = "lightning"))]
+        let thresholds = min_balance.is_some();
+
+        if channels.is_empty() {
+            info!("no ALERT_WEBHOOK_URL, ALERT_TELEGRAM_BOT_TOKEN or ALERT_SMTP_URL set, we won't send alerts");
+            None
+        } else {
+            if !thresholds {
+                warn!("alerts are set up, but neither LOW_BALANCE_ALERT_SATS nor LOW_LIQUIDITY_ALERT_SATS is");
+            }
+            let repeat = env::var("ALERT_REPEAT_HOURS")
+                .map(|hours| hours.parse().unwrap_or(6))
+                .unwrap_or(6);
+
+            info!(
+                "sending alerts through {} channels, repeated every {repeat} hours",
+                channels.len()
+            );
+            Some(alerts::Alerts::new(
+                channels,
+                min_balance,
+                #[cfg(feature = "lightning")]
+                min_liquidity,
+                Duration::from_secs(repeat * 3_600),
+            ))
+        }
+    };
+
+    #[cfg(feature = "onion")]
+    {
+        let state_dir = env::var("ONION_STATE_DIR").unwrap_or_else(|_| {
+            warn!("ONION_STATE_DIR not set, using default of onion");
+            "onion".into()
+        });
+
+        #[cfg(feature = "tls")]
+        let (scheme, port) = match tls {
+            Some(_) => ("https", 443),
+            None => ("http", 80),
+        };
+        #[cfg(not(feature = "tls"))]
+        let (scheme, port) = ("http", 80);
+
+        match onion::publish(state_dir.as_ref(), port).await {
+            Ok(address) => info!("serving as an onion service at {scheme}://{address}"),
+            Err(e) => {
+                error!("couldn't publish the onion service: {e}");
+                exit(1);
+            }
+        }
+    }
+
+    let app_state = api::AppState {
+        backend: rpc,
+        change_address: change,
+        controls: admin::Controls::new(admin::Limits {
+            max_sendable,
+            min_sendable,
+            daily_budget,
+        }),
+        tiers: tiers::TierLimits {
+            captcha: captcha_max_sendable_amount,
+            signed_message: verified_max_sendable_amount,
+        },
+        #[cfg(feature = "nostr")]
+        nostr,
+        #[cfg(feature = "github")]
+        github,
+        rate_limiter,
+        address_cooldowns,
+        db,
+        access,
+        idempotency: Default::default(),
+        activity: Default::default(),
+        abuse,
+        #[cfg(feature = "tor")]
+        tor,
+        trusted_proxies: actix_web::web::Data::new(trusted_proxies),
+        cors,
+        coin_selection,
+        fees,
+        accelerator,
+        payjoins,
+        consolidation,
+        bumper,
+        scheduler,
+        assets,
+        #[cfg(feature = "tls")]
+        tls,
+        #[cfg(feature = "alerts")]
+        alerts,
+        max_batch_outputs,
+        max_recipients,
+        address_types,
+        payout_queue,
+        #[cfg(feature = "webhooks")]
+        webhooks,
+        challenges,
+        #[cfg(feature = "captcha")]
+        captcha,
+        #[cfg(feature = "lightning")]
+        lightning,
+        #[cfg(feature = "lightning")]
+        lnurl_withdrawals: Default::default(),
+        #[cfg(feature = "lightning")]
+        bolt12_offer: Default::default(),
+        #[cfg(feature = "lightning")]
+        allow_zero_conf,
+        #[cfg(feature = "lightning")]
+        channel_limits,
+        admin_token,
+        api_keys,
+        #[cfg(feature = "lightning")]
+        reclaim_after,
+        #[cfg(feature = "lightning")]
+        hold_payouts,
+        #[cfg(feature = "lightning")]
+        channel_cooldowns,
+        #[cfg(feature = "redis")]
+        shared,
+        #[cfg(feature = "zmq")]
+        tracker,
+        #[cfg(feature = "utreexod")]
+        utreexo,
+    };
+
+    let shutdown_timeout = env::var("SHUTDOWN_TIMEOUT_SECONDS")
+        .map(|secs| secs.parse().unwrap_or(30))
+        .unwrap_or(30);
+    info!(
+        "on SIGINT or SIGTERM, we take up to {shutdown_timeout} seconds to finish what we're doing"
+    );
+
+    api::create_api(app_state, Duration::from_secs(shutdown_timeout)).await?;
 
     Ok(())
 }
@@ -0,0 +1,276 @@
+//SPDX-License-Identifier: MIT
+
+//! A payout tier for Nostr users. Requests may carry a NIP-98 `Authorization: Nostr <event>`
+//! header, a signed event saying which route they're for. If the pubkey that signed it has an
+//! established profile, that is a profile and a note older than some days on our relays, the
+//! user may ask for more and waits less before asking again. Fresh keys are free to make, old
+//! ones aren't.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use actix_web::http::header;
+use actix_web::HttpRequest;
+use bitcoin::base64::engine::general_purpose::STANDARD;
+use bitcoin::base64::Engine;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::schnorr::Signature;
+use bitcoin::secp256k1::Message;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::secp256k1::XOnlyPublicKey;
+use bitcoin::Amount;
+use serde::Deserialize;
+use serde_json::json;
+use serde_json::Value;
+use tracing::warn;
+
+use crate::api::Error;
+use crate::websocket::WebSocket;
+
+/// The kind of NIP-98 auth events
+const HTTP_AUTH: u64 = 27235;
+
+/// How far an auth event's timestamp may be from ours, in seconds
+const AUTH_WINDOW: u64 = 60;
+
+/// How long we remember whether a pubkey is established
+const LOOKUP_TTL: Duration = Duration::from_secs(3_600);
+
+/// A Nostr event, as NIP-01 describes it
+#[derive(Debug, Deserialize)]
+struct Event {
+    id: String,
+    pubkey: String,
+    created_at: u64,
+    kind: u64,
+    tags: Vec<Vec<String>>,
+    content: String,
+    sig: String,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("we're past 1970")
+        .as_secs()
+}
+
+impl Event {
+    /// Checks that the id commits to the event and that its author signed it, returning the
+    /// author
+    fn verify(&self) -> Option<XOnlyPublicKey> {
+        let serialized = json!([
+            0,
+            self.pubkey,
+            self.created_at,
+            self.kind,
+            self.tags,
+            self.content
+        ])
+        .to_string();
+        let id = sha256::Hash::hash(serialized.as_bytes());
+        if id.to_string() != self.id {
+            return None;
+        }
+
+        let pubkey = XOnlyPublicKey::from_str(&self.pubkey).ok()?;
+        let signature = Signature::from_str(&self.sig).ok()?;
+        Secp256k1::verification_only()
+            .verify_schnorr(
+                &signature,
+                &Message::from_digest(id.to_byte_array()),
+                &pubkey,
+            )
+            .ok()?;
+
+        Some(pubkey)
+    }
+
+    /// The value of the first tag called `name`
+    fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().map(String::as_str) == Some(name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+}
+
+/// The path of an absolute `url`, without its query
+fn url_path(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    let path = rest.find('/').map_or("/", |slash| &rest[slash..]);
+
+    Some(path.split(['?', '#']).next().unwrap_or(path))
+}
+
+/// Asks `relay` whether `pubkey` has a profile and a note from before `until`
+fn lookup(relay: &str, pubkey: &XOnlyPublicKey, until: u64) -> std::io::Result<bool> {
+    let mut socket = WebSocket::connect(relay)?;
+    let subscription = "faucet";
+    let author = pubkey.to_string();
+
+    socket.send(
+        &json!([
+            "REQ",
+            subscription,
+            { "kinds": [0], "authors": [author], "limit": 1 },
+            { "kinds": [1], "authors": [author], "until": until, "limit": 1 }
+        ])
+        .to_string(),
+    )?;
+
+    let mut has_profile = false;
+    let mut has_old_note = false;
+    while !(has_profile && has_old_note) {
+        let message: Vec<Value> = serde_json::from_str(&socket.receive()?)?;
+        match message.first().and_then(Value::as_str) {
+            Some("EVENT") => {
+                let Some(Ok(event)) = message.get(2).cloned().map(serde_json::from_value::<Event>)
+                else {
+                    continue;
+                };
+                // relays may send anything, so we check what they send
+                if event.verify().as_ref() != Some(pubkey) {
+                    continue;
+                }
+
+                match event.kind {
+                    0 => has_profile = true,
+                    1 if event.created_at <= until => has_old_note = true,
+                    _ => {}
+                }
+            }
+            Some("EOSE") | Some("CLOSED") => break,
+            _ => {}
+        }
+    }
+
+    let _ = socket.send(&json!(["CLOSE", subscription]).to_string());
+    socket.close();
+
+    Ok(has_profile && has_old_note)
+}
+
+pub struct NostrTier {
+    relays: Vec<String>,
+    /// How old a pubkey's notes have to be for it to count as established
+    min_age: Duration,
+    /// How much established users may get
+    pub max_sendable: Amount,
+    /// How long established users wait before getting paid again, if not as long as others
+    pub cooldown: Option<Duration>,
+    /// The auth events we accepted, so they can't be used again, with when they were made
+    used: Mutex<HashMap<String, u64>>,
+    /// Whether pubkeys are established, and when we found out
+    established: Mutex<HashMap<XOnlyPublicKey, (bool, Instant)>>,
+}
+
+impl NostrTier {
+    pub fn new(
+        relays: Vec<String>,
+        min_age: Duration,
+        max_sendable: Amount,
+        cooldown: Option<Duration>,
+    ) -> Self {
+        Self {
+            relays,
+            min_age,
+            max_sendable,
+            cooldown,
+            used: Mutex::new(HashMap::new()),
+            established: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Checks `req`'s NIP-98 auth header, if it has one, returning who signed it
+    pub fn authenticate(&self, req: &HttpRequest) -> Result<Option<XOnlyPublicKey>, Error> {
+        let Some(event) = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Nostr "))
+        else {
+            return Ok(None);
+        };
+
+        let invalid = |reason: &str| Error::InvalidNostrAuth(reason.to_string());
+        let event = STANDARD
+            .decode(event.trim())
+            .map_err(|_| invalid("the event isn't base64"))?;
+        let event: Event =
+            serde_json::from_slice(&event).map_err(|_| invalid("the event isn't valid json"))?;
+
+        if event.kind != HTTP_AUTH {
+            return Err(invalid("the event isn't an http auth event"));
+        }
+        if now().abs_diff(event.created_at) > AUTH_WINDOW {
+            return Err(invalid("the event is too old, or from the future"));
+        }
+        if event.tag("u").and_then(url_path) != Some(req.path()) {
+            return Err(invalid("the event is for another url"));
+        }
+        if !event
+            .tag("method")
+            .is_some_and(|method| method.eq_ignore_ascii_case(req.method().as_str()))
+        {
+            return Err(invalid("the event is for another method"));
+        }
+        let pubkey = event.verify().ok_or(invalid("the event isn't signed"))?;
+
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, created_at| now().abs_diff(*created_at) <= AUTH_WINDOW);
+        if used.insert(event.id, event.created_at).is_some() {
+            return Err(invalid("the event was used already"));
+        }
+
+        Ok(Some(pubkey))
+    }
+
+    /// Whether `pubkey` has a profile and old enough notes on any of our relays. If we can't
+    /// reach any of them, it doesn't
+    pub async fn is_established(&self, pubkey: XOnlyPublicKey) -> bool {
+        {
+            let mut established = self.established.lock().unwrap();
+            established.retain(|_, (_, at)| at.elapsed() < LOOKUP_TTL);
+            if let Some((established, _)) = established.get(&pubkey) {
+                return *established;
+            }
+        }
+
+        let relays = self.relays.clone();
+        let until = now().saturating_sub(self.min_age.as_secs());
+        let found = actix_web::web::block(move || {
+            let mut reached = false;
+            for relay in relays {
+                match lookup(&relay, &pubkey, until) {
+                    Ok(true) => return Some(true),
+                    Ok(false) => reached = true,
+                    Err(e) => warn!("couldn't ask {relay} about {pubkey}: {e}"),
+                }
+            }
+
+            reached.then_some(false)
+        })
+        .await
+        .ok()
+        .flatten();
+
+        // we'll ask again next time if no relay answered
+        let Some(found) = found else {
+            return false;
+        };
+        self.established
+            .lock()
+            .unwrap()
+            .insert(pubkey, (found, Instant::now()));
+
+        found
+    }
+}
@@ -0,0 +1,101 @@
+//SPDX-License-Identifier: MIT
+
+//! Publishing the faucet as a Tor onion service, so people can use it without leaving Tor and
+//! operators don't need a public address. We run Tor ourselves, with arti, and hand each stream
+//! someone opens to the onion over to the API. Arti keeps the service's key in its state
+//! directory, so the address stays the same across restarts, and the service goes away when we
+//! exit.
+
+use std::path::Path;
+
+use anyhow::anyhow;
+use arti_client::config::onion_service::OnionServiceConfigBuilder;
+use arti_client::config::TorClientConfigBuilder;
+use arti_client::TorClient;
+use futures::StreamExt;
+use tokio::net::TcpStream;
+use tor_cell::relaycell::msg::Connected;
+use tor_hsservice::handle_rend_requests;
+use tor_hsservice::StreamRequest;
+use tor_proto::stream::IncomingStreamRequest;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+/// Where we forward onion streams to, the API on the port we serve it on
+const TARGET: &str = "127.0.0.1:8080";
+
+/// What arti calls our service, it names the key in the state directory
+const NICKNAME: &str = "faucet";
+
+/// Connects to Tor, keeping what arti needs in `state_dir`, and publishes the API as an onion
+/// service on the onion's `port`. Returns the onion address once it's up, and keeps
+/// forwarding streams to the API in the background
+pub async fn publish(state_dir: &Path, port: u16) -> anyhow::Result<String> {
+    let config =
+        TorClientConfigBuilder::from_directories(state_dir.join("state"), state_dir.join("cache"))
+            .build()?;
+
+    info!("connecting to Tor, this may take a minute");
+    let client = TorClient::create_bootstrapped(config).await?;
+
+    let service = OnionServiceConfigBuilder::default()
+        .nickname(NICKNAME.parse()?)
+        .build()?;
+    let (service, requests) = client.launch_onion_service(service)?;
+    let address = service
+        .onion_name()
+        .ok_or_else(|| anyhow!("arti didn't tell us the onion address"))?
+        .to_string();
+
+    actix::spawn(async move {
+        // the service is up for as long as these are around
+        let _client = client;
+        let _service = service;
+
+        let mut streams = handle_rend_requests(requests);
+        while let Some(stream) = streams.next().await {
+            actix::spawn(forward(stream, port));
+        }
+        warn!("arti stopped handing us onion streams, the onion service is down");
+    });
+
+    Ok(address)
+}
+
+/// Accepts a stream to our port and copies it to and from the API, turning away anything else
+async fn forward(request: StreamRequest, port: u16) {
+    let ours = matches!(
+        request.request(),
+        IncomingStreamRequest::Begin(begin) if begin.port() == port
+    );
+    if !ours {
+        if let Err(e) = request.shutdown_circuit() {
+            debug!("couldn't close a circuit asking for something we don't serve: {e}");
+        }
+        return;
+    }
+
+    let mut api = match TcpStream::connect(TARGET).await {
+        Ok(api) => api,
+        Err(e) => {
+            warn!("couldn't reach the API for an onion stream: {e}");
+            if let Err(e) = request.shutdown_circuit() {
+                debug!("couldn't close the onion circuit: {e}");
+            }
+            return;
+        }
+    };
+
+    let mut onion = match request.accept(Connected::new_empty()).await {
+        Ok(onion) => onion,
+        Err(e) => {
+            debug!("couldn't accept an onion stream: {e}");
+            return;
+        }
+    };
+
+    if let Err(e) = tokio::io::copy_bidirectional(&mut onion, &mut api).await {
+        debug!("onion stream closed: {e}");
+    }
+}
@@ -1,69 +1,610 @@
-use std::{env, sync::Mutex};
+use std::{
+    io::Write,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Result;
+use async_trait::async_trait;
+use bitcoin::hashes::Hash;
+use bitcoin::hex::DisplayHex;
+use bitcoin::hex::FromHex;
 use cln_rpc::{
-    model::requests::GetinfoRequest,
-    primitives::{Amount, AmountOrAll, PublicKey},
-    Response,
+    model::requests::{
+        ConnectRequest, FetchinvoiceRequest, GetinfoRequest, InvoiceRequest, KeysendRequest,
+        ListfundsRequest, PayRequest,
+    },
+    primitives::{Amount, AmountOrAll, AmountOrAny, ChannelState, PublicKey},
+    Request, Response,
 };
+use lightning::offers::offer::Offer;
+use serde_json::json;
+use serde_json::Value;
 
 use crate::api::Error;
+use crate::ln::ChannelInfo;
+use crate::ln::ChannelRequest;
+use crate::ln::DualFundedChannel;
+use crate::ln::HoldInvoiceState;
+use crate::ln::LightningBackend;
+use crate::ln::NodeInfo;
+use crate::retry;
+use crate::retry::Failure;
+use crate::retry::Reconnecting;
+
+/// How long we wait for the peer to agree on a cooperative close before force closing
+const UNILATERAL_TIMEOUT: u32 = 30;
+
+/// How long we wait for cln to answer a call. Longer than the minute cln tries paying for
+const CALL_TIMEOUT: Duration = Duration::from_secs(120);
+
 pub struct CLNDaemon {
-    rpc: Mutex<cln_rpc::ClnRpc>,
-    channel_lease_value: Amount,
-    channel_lease_push: Amount,
+    /// Our connection to cln, opened again after it breaks
+    rpc: Reconnecting<cln_rpc::ClnRpc, Error>,
+    /// cln-rpc doesn't know about every method, we use this to call them directly, and to
+    /// connect again when our connection drops
+    rpc_path: PathBuf,
+}
+
+/// cln-rpc uses its own version of secp256k1
+fn to_cln_key(key: bitcoin::secp256k1::PublicKey) -> PublicKey {
+    PublicKey::from_slice(&key.serialize()).expect("a valid key is valid for any secp256k1 version")
+}
+
+fn from_cln_key(key: PublicKey) -> bitcoin::secp256k1::PublicKey {
+    bitcoin::secp256k1::PublicKey::from_slice(&key.serialize())
+        .expect("a valid key is valid for any secp256k1 version")
+}
+
+/// Whether the big-endian feature bitmap `features` has either bit of the pair starting at `bit`
+fn supports_feature(features: &[u8], bit: usize) -> bool {
+    let is_set = |bit: usize| {
+        let byte = bit / 8;
+        byte < features.len() && features[features.len() - 1 - byte] & (1 << (bit % 8)) != 0
+    };
+
+    is_set(bit) || is_set(bit + 1)
 }
 
 impl CLNDaemon {
-    pub async fn new(mut rpc: cln_rpc::ClnRpc) -> Result<Self> {
-        let Response::Getinfo(res) = rpc
+    pub async fn new(rpc_path: PathBuf) -> Result<Self> {
+        let mut rpc = cln_rpc::ClnRpc::new(&rpc_path).await?;
+        let Response::Getinfo(_) = rpc
             .call(cln_rpc::Request::Getinfo(GetinfoRequest {}))
             .await?
         else {
             panic!("what?");
         };
 
-        let channel_lease_value = env::var("CHANNEL_VALUE")
-            .map(|value| value.parse().unwrap_or_default())
-            .unwrap_or(1_000_000);
-        let channel_lease_push = env::var("PUSH_VALUE")
-            .map(|value| value.parse().unwrap_or_default())
-            .unwrap_or(1_000_000);
-
         Ok(Self {
-            rpc: Mutex::new(rpc),
-            channel_lease_push: Amount::from_sat(channel_lease_push),
-            channel_lease_value: Amount::from_sat(channel_lease_value),
+            rpc: Reconnecting::new(rpc, || {
+                Error::CLNError("our connection to cln is closed".into())
+            }),
+            rpc_path,
         })
     }
 
-    #[cfg(feature = "ln")]
-    pub async fn open_channel(&self, id: PublicKey) -> Result<String, crate::api::Error> {
+    /// Calls cln through cln-rpc, trying again a few times if cln didn't answer. Those we can't
+    /// safely send twice, as cln may have done what they ask without us hearing back, we only try
+    /// once
+    async fn call(&self, request: Request) -> Result<Response, Error> {
+        let idempotent = !matches!(request, Request::KeySend(_) | Request::FundChannel(_));
+        let request = &request;
+
+        retry::with_backoff("cln", idempotent, || {
+            self.rpc.call(
+                || async {
+                    cln_rpc::ClnRpc::new(&self.rpc_path)
+                        .await
+                        .map_err(|e| Error::CLNError(e.to_string()))
+                },
+                |rpc| {
+                    let request = request.clone();
+                    Box::pin(async move {
+                        match actix::clock::timeout(CALL_TIMEOUT, rpc.call(request)).await {
+                            // errors without a code are ours, cln never answered, and our
+                            // connection may be broken
+                            Ok(Err(e)) if e.code.is_none() => {
+                                Err(Failure::Unanswered(Error::CLNError(e.message)))
+                            }
+                            Ok(result) => result
+                                .map_err(|e| Failure::Answered(Error::CLNError(e.to_string()))),
+                            // its answer may still come, and be taken for the next call's
+                            Err(_) => Err(Failure::Unanswered(Error::CLNError(format!(
+                                "no answer within {CALL_TIMEOUT:?}"
+                            )))),
+                        }
+                    })
+                },
+            )
+        })
+        .await
+    }
+
+    /// Walks through the interactive protocol cln uses to build a funding transaction with a
+    /// peer, both for dual-funded opens and splices. We call `update` until both sides have
+    /// signed the commitments, then sign our inputs and hand them to `signed`. Returns the last
+    /// update, our signed psbt and what `signed` answered
+    async fn negotiate_funding(
+        &self,
+        update: &'static str,
+        signed: &'static str,
+        channel_id: &str,
+        mut psbt: Value,
+    ) -> Result<(Value, Value, Value), Error> {
+        let last_update = loop {
+            let update = self
+                .call_raw(update, json!({ "channel_id": channel_id, "psbt": psbt }))
+                .await?;
+            psbt = update["psbt"].clone();
+
+            if update["commitments_secured"].as_bool() == Some(true) {
+                break update;
+            }
+        };
+
+        let signpsbt = self.call_raw("signpsbt", json!({ "psbt": psbt })).await?;
+        let signed_psbt = signpsbt["signed_psbt"].clone();
+
+        let result = self
+            .call_raw(
+                signed,
+                json!({ "channel_id": channel_id, "signed_psbt": signed_psbt }),
+            )
+            .await?;
+
+        Ok((last_update, signed_psbt, result))
+    }
+
+    /// Selects our coins to put `amount` into a channel. `startweight` is the weight of what cln
+    /// will add to the transaction on its own
+    async fn fund_psbt(&self, amount: bitcoin::Amount, startweight: u64) -> Result<Value, Error> {
+        let funding = self
+            .call_raw(
+                "fundpsbt",
+                json!({
+                    "satoshi": amount.to_sat(),
+                    "feerate": "opening",
+                    "startweight": startweight,
+                    "excess_as_change": true,
+                }),
+            )
+            .await?;
+
+        Ok(funding["psbt"].clone())
+    }
+
+    /// Calls `method` with a fresh connection to cln, for the methods cln-rpc doesn't have or
+    /// that may take too long to hold our connection for
+    async fn call_raw(&self, method: &'static str, params: Value) -> Result<Value, Error> {
+        // cln may be restarting, we wait a bit for its socket to be back
+        let mut stream = retry::with_backoff("cln", true, || async move {
+            let rpc_path = self.rpc_path.clone();
+            actix_web::web::block(move || UnixStream::connect(rpc_path))
+                .await
+                .map_err(|e| Failure::Answered(Error::CLNError(e.to_string())))?
+                .map_err(|e| Failure::Unanswered(Error::CLNError(e.to_string())))
+        })
+        .await?;
+
+        let mut response = actix_web::web::block(move || -> std::io::Result<Value> {
+            let request = json!({
+                "jsonrpc": "2.0",
+                "id": 0,
+                "method": method,
+                "params": params,
+            });
+            stream.write_all(request.to_string().as_bytes())?;
+
+            let response = serde_json::Deserializer::from_reader(stream)
+                .into_iter()
+                .next()
+                .ok_or(std::io::ErrorKind::UnexpectedEof)??;
+            Ok(response)
+        })
+        .await
+        .map_err(|e| Error::CLNError(e.to_string()))?
+        .map_err(|e| Error::CLNError(e.to_string()))?;
+
+        match response.get("error") {
+            Some(error) => Err(Error::CLNError(error.to_string())),
+            None => Ok(response["result"].take()),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl LightningBackend for CLNDaemon {
+    async fn connect(
+        &self,
+        id: bitcoin::secp256k1::PublicKey,
+        address: Option<&str>,
+        required_features: &[usize],
+    ) -> Result<(), Error> {
+        let res = self
+            .call(Request::Connect(ConnectRequest {
+                // cln takes the address as part of the id
+                id: match address {
+                    Some(address) => format!("{id}@{address}"),
+                    None => id.to_string(),
+                },
+                host: None,
+                port: None,
+            }))
+            .await
+            .map_err(|e| Error::PeerUnreachable(e.to_string()))?;
+        let Response::Connect(peer) = res else {
+            panic!("what?")
+        };
+
+        let features = Vec::<u8>::from_hex(&peer.features)
+            .map_err(|_| Error::CLNError("cln gave us invalid features".into()))?;
+        match required_features
+            .iter()
+            .find(|&&bit| !supports_feature(&features, bit))
+        {
+            Some(&bit) => Err(Error::PeerMissingFeature(bit)),
+            None => Ok(()),
+        }
+    }
+
+    /// With `mindepth` set to 0, cln negotiates the zeroconf channel type with our peer
+    async fn open_channel(
+        &self,
+        id: bitcoin::secp256k1::PublicKey,
+        channel: ChannelRequest,
+    ) -> Result<String, Error> {
         let res = self
-            .rpc
-            .lock()
-            .unwrap()
             .call(cln_rpc::Request::FundChannel(
                 cln_rpc::model::requests::FundchannelRequest {
-                    id,
-                    amount: AmountOrAll::Amount(self.channel_lease_value),
+                    id: to_cln_key(id),
+                    amount: AmountOrAll::Amount(Amount::from_sat(channel.capacity.to_sat())),
                     feerate: None,
                     announce: Some(true),
                     minconf: Some(0),
-                    push_msat: Some(self.channel_lease_push),
+                    push_msat: Some(Amount::from_sat(channel.push.to_sat())),
                     close_to: None,
                     request_amt: None,
                     compact_lease: None,
                     utxos: None,
-                    mindepth: None,
+                    mindepth: channel.zero_conf.then_some(0),
                     reserve: None,
                 },
             ))
-            .await
-            .map_err(|e| Error::CLNError(e.to_string()))?;
+            .await?;
         let Response::FundChannel(channel_result) = res else {
             panic!("what?")
         };
         Ok(channel_result.channel_id)
     }
+
+    /// Needs `experimental-dual-fund` on both nodes. The peer decides how much it puts in, usually
+    /// with the funder plugin
+    async fn open_dual_funded_channel(
+        &self,
+        id: bitcoin::secp256k1::PublicKey,
+        amount: bitcoin::Amount,
+    ) -> Result<DualFundedChannel, Error> {
+        // our inputs and change, cln adds the channel output itself. It weighs 172 wu
+        let initial_psbt = self.fund_psbt(amount, 172).await?;
+
+        let init = self
+            .call_raw(
+                "openchannel_init",
+                json!({
+                    "id": id.to_string(),
+                    "amount": amount.to_sat(),
+                    "initialpsbt": initial_psbt,
+                }),
+            )
+            .await;
+
+        let init = match init {
+            Ok(init) => init,
+            Err(e) => {
+                // don't leave our coins reserved for a channel that won't happen
+                let _ = self
+                    .call_raw("unreserveinputs", json!({ "psbt": initial_psbt }))
+                    .await;
+                return Err(e);
+            }
+        };
+
+        let channel_id = init["channel_id"].as_str().unwrap_or_default().to_string();
+        match self
+            .negotiate_funding(
+                "openchannel_update",
+                "openchannel_signed",
+                &channel_id,
+                init["psbt"].clone(),
+            )
+            .await
+        {
+            Ok((update, signed_psbt, funded)) => Ok(DualFundedChannel {
+                channel_id,
+                txid: funded["txid"].as_str().unwrap_or_default().to_string(),
+                funding_outnum: update["funding_outnum"].as_u64().unwrap_or_default(),
+                psbt: signed_psbt.as_str().unwrap_or_default().to_string(),
+            }),
+            Err(e) => {
+                let _ = self
+                    .call_raw("openchannel_abort", json!({ "channel_id": channel_id }))
+                    .await;
+                let _ = self
+                    .call_raw("unreserveinputs", json!({ "psbt": initial_psbt }))
+                    .await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Without `force`, cln waits for the peer to come back to close cooperatively
+    async fn close_channel(&self, channel_id: &str, force: bool) -> Result<String, Error> {
+        let params = json!({
+            "id": channel_id,
+            // 0 means never close unilaterally
+            "unilateraltimeout": if force { UNILATERAL_TIMEOUT } else { 0 },
+        });
+
+        let closed = self.call_raw("close", params).await?;
+        Ok(closed["txid"].as_str().unwrap_or_default().to_string())
+    }
+
+    /// Needs `experimental-splicing` on both nodes
+    async fn splice_in(&self, channel_id: &str, amount: bitcoin::Amount) -> Result<String, Error> {
+        // cln adds the new channel output and spends the old one, and they're about 800 wu
+        let initial_psbt = self.fund_psbt(amount, 800).await?;
+
+        let init = self
+            .call_raw(
+                "splice_init",
+                json!({
+                    "channel_id": channel_id,
+                    "relative_amount": amount.to_sat(),
+                    "initialpsbt": initial_psbt,
+                }),
+            )
+            .await;
+
+        let spliced = match init {
+            Ok(init) => {
+                self.negotiate_funding(
+                    "splice_update",
+                    "splice_signed",
+                    channel_id,
+                    init["psbt"].clone(),
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+
+        match spliced {
+            Ok((_, _, spliced)) => Ok(spliced["txid"].as_str().unwrap_or_default().to_string()),
+            Err(e) => {
+                // don't leave our coins reserved for a splice that won't happen
+                let _ = self
+                    .call_raw("unreserveinputs", json!({ "psbt": initial_psbt }))
+                    .await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn create_invoice(&self, amount_msat: u64, description: &str) -> Result<String, Error> {
+        // cln wants an unique label for each invoice
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("we're past 1970")
+            .as_nanos();
+
+        let res = self
+            .call(Request::Invoice(InvoiceRequest {
+                amount_msat: AmountOrAny::Amount(Amount::from_msat(amount_msat)),
+                description: description.to_string(),
+                label: format!("faucet-{now}"),
+                expiry: None,
+                fallbacks: None,
+                preimage: None,
+                cltv: None,
+                deschashonly: Some(true),
+            }))
+            .await?;
+        let Response::Invoice(invoice) = res else {
+            panic!("what?")
+        };
+        Ok(invoice.bolt11)
+    }
+
+    /// Needs the holdinvoice plugin, cln can't hold invoices on its own
+    async fn create_hold_invoice(
+        &self,
+        preimage: [u8; 32],
+        amount_msat: u64,
+        description: &str,
+        expiry: u32,
+    ) -> Result<String, Error> {
+        let hash = bitcoin::hashes::sha256::Hash::hash(&preimage);
+        let params = json!({
+            "amount_msat": amount_msat,
+            "label": format!("faucet-hold-{hash}"),
+            "description": description,
+            "expiry": expiry,
+            "preimage": preimage.to_lower_hex_string(),
+        });
+
+        let invoice = self.call_raw("holdinvoice", params).await?;
+        match invoice["bolt11"].as_str() {
+            Some(invoice) => Ok(invoice.to_string()),
+            None => Err(Error::CLNError(invoice.to_string())),
+        }
+    }
+
+    async fn hold_invoice_state(&self, payment_hash: [u8; 32]) -> Result<HoldInvoiceState, Error> {
+        let params = json!({ "payment_hash": payment_hash.to_lower_hex_string() });
+
+        let invoice = self.call_raw("holdinvoicelookup", params).await?;
+        match invoice["state"].as_str() {
+            Some("OPEN") => Ok(HoldInvoiceState::Open),
+            Some("ACCEPTED") => Ok(HoldInvoiceState::Accepted),
+            Some("SETTLED") => Ok(HoldInvoiceState::Settled),
+            Some("CANCELED") => Ok(HoldInvoiceState::Canceled),
+            _ => Err(Error::CLNError(invoice.to_string())),
+        }
+    }
+
+    /// The plugin knows the preimage already, we only tell it which invoice to settle
+    async fn settle_hold_invoice(&self, preimage: [u8; 32]) -> Result<(), Error> {
+        let hash = bitcoin::hashes::sha256::Hash::hash(&preimage);
+
+        self.call_raw(
+            "holdinvoicesettle",
+            json!({ "payment_hash": hash.to_string() }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn cancel_hold_invoice(&self, payment_hash: [u8; 32]) -> Result<(), Error> {
+        let params = json!({ "payment_hash": payment_hash.to_lower_hex_string() });
+
+        self.call_raw("holdinvoicecancel", params).await?;
+        Ok(())
+    }
+
+    async fn pay_invoice(&self, invoice: &str) -> Result<String, Error> {
+        let res = self
+            .call(Request::Pay(PayRequest {
+                bolt11: invoice.to_string(),
+                amount_msat: None,
+                label: None,
+                riskfactor: None,
+                maxfeepercent: None,
+                retry_for: None,
+                maxdelay: None,
+                exemptfee: None,
+                localinvreqid: None,
+                exclude: None,
+                maxfee: None,
+                description: None,
+            }))
+            .await?;
+        let Response::Pay(payment) = res else {
+            panic!("what?")
+        };
+        Ok(payment.payment_preimage.to_vec().to_lower_hex_string())
+    }
+
+    async fn keysend(
+        &self,
+        id: bitcoin::secp256k1::PublicKey,
+        amount_msat: u64,
+    ) -> Result<String, Error> {
+        let res = self
+            .call(Request::KeySend(KeysendRequest {
+                destination: to_cln_key(id),
+                amount_msat: Amount::from_msat(amount_msat),
+                label: None,
+                maxfeepercent: None,
+                retry_for: None,
+                maxdelay: None,
+                exemptfee: None,
+                routehints: None,
+                extratlvs: None,
+            }))
+            .await?;
+        let Response::KeySend(payment) = res else {
+            panic!("what?")
+        };
+        Ok(payment.payment_preimage.to_vec().to_lower_hex_string())
+    }
+
+    async fn create_offer(&self, description: &str) -> Result<String, Error> {
+        let params = json!({
+            "amount": "any",
+            "description": description,
+        });
+
+        // an existing offer with the same parameters is returned as is
+        let offer = self.call_raw("offer", params).await?;
+        match offer["bolt12"].as_str() {
+            Some(offer) => Ok(offer.to_string()),
+            None => Err(Error::CLNError(offer.to_string())),
+        }
+    }
+
+    async fn pay_offer(&self, offer: &Offer, amount_msat: u64) -> Result<String, Error> {
+        let res = self
+            .call(Request::FetchInvoice(FetchinvoiceRequest {
+                offer: offer.to_string(),
+                // cln refuses an amount for offers that already have one
+                amount_msat: offer
+                    .amount()
+                    .is_none()
+                    .then_some(Amount::from_msat(amount_msat)),
+                quantity: None,
+                recurrence_counter: None,
+                recurrence_start: None,
+                recurrence_label: None,
+                timeout: None,
+                payer_note: None,
+            }))
+            .await?;
+        let Response::FetchInvoice(invoice) = res else {
+            panic!("what?")
+        };
+
+        // pay takes bolt12 invoices as well
+        self.pay_invoice(&invoice.invoice).await
+    }
+
+    async fn node_info(&self) -> Result<NodeInfo, Error> {
+        let Response::Getinfo(info) = self.call(Request::Getinfo(GetinfoRequest {})).await? else {
+            panic!("what?")
+        };
+
+        Ok(NodeInfo {
+            id: from_cln_key(info.id),
+            alias: info.alias.unwrap_or_default(),
+            block_height: info.blockheight,
+        })
+    }
+
+    async fn list_channels(&self) -> Result<Vec<ChannelInfo>, Error> {
+        let Response::ListFunds(funds) = self
+            .call(Request::ListFunds(ListfundsRequest { spent: None }))
+            .await?
+        else {
+            panic!("what?")
+        };
+
+        Ok(funds
+            .channels
+            .into_iter()
+            .map(|channel| ChannelInfo {
+                peer: from_cln_key(channel.peer_id),
+                channel_id: channel
+                    .channel_id
+                    .map(|id| id.to_string())
+                    .unwrap_or(format!(
+                        "{}:{}",
+                        channel.funding_txid, channel.funding_output
+                    )),
+                capacity: bitcoin::Amount::from_sat(channel.amount_msat.msat() / 1_000),
+                local_balance: bitcoin::Amount::from_sat(channel.our_amount_msat.msat() / 1_000),
+                state: format!("{:?}", channel.state),
+                funding_outpoint: Some(format!(
+                    "{}:{}",
+                    channel.funding_txid, channel.funding_output
+                )),
+                active: channel.connected && channel.state == ChannelState::CHANNELD_NORMAL,
+            })
+            .collect())
+    }
+
+    /// Closes our connection to cln, once the call using it is done
+    async fn shutdown(&self) {
+        self.rpc.close().await;
+    }
 }
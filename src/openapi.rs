@@ -0,0 +1,227 @@
+//SPDX-License-Identifier: MIT
+
+//! A machine readable description of our API, for client generators and other frontends. We
+//! serve it as OpenAPI at /openapi.json, and a Swagger UI to try it out at /docs.
+//!
+//! It only describes the routes this build serves. The LNURL routes, which follow their own
+//! spec, and the GitHub login redirects are left out.
+
+use std::sync::OnceLock;
+
+use actix_web::http::header::ContentType;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use utoipa::openapi::security::ApiKey;
+use utoipa::openapi::security::ApiKeyValue;
+use utoipa::openapi::security::HttpAuthScheme;
+use utoipa::openapi::security::HttpBuilder;
+use utoipa::openapi::security::SecurityScheme;
+use utoipa::Modify;
+use utoipa::OpenApi;
+
+use crate::abuse;
+use crate::access;
+use crate::admin;
+use crate::api;
+use crate::api::AppState;
+use crate::assets;
+use crate::backend::ChainBackend;
+use crate::bump;
+use crate::cpfp;
+use crate::events;
+use crate::export;
+use crate::pow;
+use crate::qr;
+use crate::response;
+use crate::scheduler;
+use crate::stats;
+use crate::tiers;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "yet-another-faucet",
+        description = "A signet faucet, paying on-chain and, depending on the build, over Lightning",
+        license(name = "MIT")
+    ),
+    paths(
+        api::send_to_address,
+        api::send_batch_request,
+        api::challenge,
+        api::info,
+        api::balance,
+        api::fee,
+        api::donate,
+        api::health,
+        api::ready,
+        api::queued_payout_status,
+        api::tx_status,
+        api::history,
+        stats::stats,
+        events::events,
+        qr::qr,
+        admin::get_status,
+        admin::pause,
+        admin::drain,
+        admin::resume,
+        admin::set_limits,
+        admin::sweep,
+        admin::export_psbt,
+        bump::bump_payout,
+        cpfp::accelerate_transaction,
+        scheduler::list_jobs,
+        export::export,
+        access::list_rules,
+        access::add_rule,
+        access::remove_rule,
+        abuse::list_decisions,
+    ),
+    components(schemas(
+        api::SendMoney,
+        api::Recipients,
+        api::Proof,
+        api::SendBatch,
+        api::BatchOutput,
+        response::ErrorBody,
+        response::ErrorDetail,
+        response::SendResponse,
+        response::PayoutProgress,
+        response::TxStatus,
+        response::Info,
+        response::RateLimits,
+        response::CooldownInfo,
+        response::QueueInfo,
+        response::Health,
+        response::Readiness,
+        response::ReadyStatus,
+        response::Dependency,
+        response::Fees,
+        response::Donation,
+        response::History,
+        response::PastPayout,
+        stats::Stats,
+        stats::Totals,
+        stats::DayStats,
+        response::Balance,
+        response::Removed,
+        pow::Challenge,
+        qr::Format,
+        tiers::Tier,
+        tiers::TierInfo,
+        admin::Mode,
+        admin::Status,
+        admin::NewLimits,
+        admin::Sweep,
+        admin::Swept,
+        admin::Export,
+        admin::Exported,
+        bump::Bumped,
+        cpfp::Accelerated,
+        scheduler::JobStats,
+        export::Record,
+        access::Rule,
+        access::NewRule,
+        access::List,
+        access::Kind,
+        abuse::Decision,
+        abuse::Signals,
+        abuse::Verdict,
+    )),
+    modifiers(&AdminToken),
+    tags(
+        (name = "faucet", description = "Getting coins, and what the faucet can give"),
+        (name = "admin", description = "Managing the faucet, with the admin token"),
+    )
+)]
+struct FaucetApi;
+
+#[cfg(feature = "lightning")]
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        api::hold_payout_status,
+        api::open_channel,
+        api::open_dual_funded_channel,
+        api::open_inbound_channel,
+        api::list_channels,
+        api::close_channel,
+        api::pay_invoice,
+        api::offer,
+        api::keysend,
+    ),
+    components(schemas(
+        api::GetChannel,
+        api::GetDualFundedChannel,
+        api::GetInboundChannel,
+        api::CloseChannel,
+        api::PayInvoice,
+        api::Keysend,
+        response::LightningBalance,
+        response::ChannelPolicy,
+        response::Channel,
+        response::ClosedChannel,
+        response::Payment,
+        response::Offer,
+        crate::ln::ChannelInfo,
+        crate::ln::DualFundedChannel,
+    )),
+    tags((name = "lightning", description = "Channels and Lightning payments"))
+)]
+struct LightningApi;
+
+#[cfg(feature = "utreexod")]
+#[derive(OpenApi)]
+#[openapi(
+    paths(api::utreexo_roots),
+    tags((name = "utreexo", description = "Our utreexo accumulator"))
+)]
+struct UtreexoApi;
+
+/// Admin routes take the token as `Authorization: Bearer <token>`, or an admin API key as
+/// `X-Api-Key: <key>`
+struct AdminToken;
+
+impl Modify for AdminToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "admin_token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+            components.add_security_scheme(
+                "api_key",
+                SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))),
+            );
+        }
+    }
+}
+
+/// The description of every route this build serves
+pub fn spec() -> utoipa::openapi::OpenApi {
+    #[allow(unused_mut)]
+    let mut spec = FaucetApi::openapi();
+
+    #[cfg(feature = "lightning")]
+    spec.merge(LightningApi::openapi());
+    #[cfg(feature = "utreexod")]
+    spec.merge(UtreexoApi::openapi());
+
+    spec
+}
+
+pub async fn openapi_json() -> HttpResponse {
+    static SPEC: OnceLock<String> = OnceLock::new();
+    let spec = SPEC.get_or_init(|| spec().to_json().expect("the spec serializes"));
+
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(spec.as_str())
+}
+
+pub async fn docs<B: ChainBackend>(req: HttpRequest, data: web::Data<AppState<B>>) -> HttpResponse {
+    match data.assets.get("docs.html") {
+        Some(page) => assets::respond(&req, "docs.html", page),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
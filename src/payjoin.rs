@@ -0,0 +1,590 @@
+//SPDX-License-Identifier: MIT
+
+//! Receiving refills as [BIP78](https://github.com/bitcoin/bips/blob/master/bip-0078.mediawiki)
+//! payjoins. With `PAYJOIN` set, the BIP21 URIs /donate hands out carry a `pj` parameter
+//! pointing here. Donor wallets supporting it post the transaction they'd broadcast, and we add
+//! one of our coins to it, paying ourselves its value on top of the donation. Onlookers can't
+//! tell which inputs are the donor's anymore, and we merge one of our coins for free: we take
+//! our smallest, as faucets tend to have lots of those.
+//!
+//! Our input's fee comes out of the donor's change if they let us, and out of what we get if
+//! they don't. We only join transactions paying an address /donate gave out since we started,
+//! and only once, and we don't broadcast the original if the donor never broadcasts the payjoin.
+//! Neither do we show our coin for originals the mempool wouldn't take, if the backend can tell.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use actix_web::http::header::ContentType;
+use actix_web::web;
+use actix_web::HttpResponse;
+use bitcoin::secp256k1::rand;
+use bitcoin::secp256k1::rand::Rng;
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::OutPoint;
+use bitcoin::Psbt;
+use bitcoin::ScriptBuf;
+use bitcoin::TxIn;
+use bitcoin::Witness;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+use crate::fees;
+
+/// How long after we hand out an address we payjoin donations to it
+const FORGET_AFTER: Duration = Duration::from_secs(24 * 3_600);
+
+/// The addresses we handed out for payjoins
+#[derive(Default)]
+pub struct Payjoins {
+    scripts: Mutex<HashMap<ScriptBuf, Instant>>,
+}
+
+impl Payjoins {
+    /// Takes payjoins to `script` for a while
+    pub fn expect(&self, script: ScriptBuf) {
+        let mut scripts = self.scripts.lock().unwrap();
+        scripts.retain(|_, at| at.elapsed() < FORGET_AFTER);
+        scripts.insert(script, Instant::now());
+    }
+
+    /// The first of `scripts` we take payjoins to, if any
+    fn find<'a>(&self, mut scripts: impl Iterator<Item = (usize, &'a ScriptBuf)>) -> Option<usize> {
+        let expected = self.scripts.lock().unwrap();
+        scripts
+            .find(|(_, script)| {
+                expected
+                    .get(*script)
+                    .is_some_and(|at| at.elapsed() < FORGET_AFTER)
+            })
+            .map(|(index, _)| index)
+    }
+}
+
+/// The query string BIP78 senders use
+#[derive(Deserialize)]
+pub struct PayjoinParams {
+    v: Option<u32>,
+    additionalfeeoutputindex: Option<usize>,
+    maxadditionalfeecontribution: Option<u64>,
+    /// In sat/vB
+    minfeerate: Option<f64>,
+}
+
+/// Why we didn't join a transaction, as BIP78 puts it
+#[derive(Debug)]
+enum PayjoinError {
+    /// Something failed on our side
+    Unavailable,
+    /// We have no coin to add
+    NotEnoughMoney,
+    VersionUnsupported,
+    OriginalPsbtRejected(String),
+}
+
+impl PayjoinError {
+    fn code(&self) -> &'static str {
+        match self {
+            PayjoinError::Unavailable => "unavailable",
+            PayjoinError::NotEnoughMoney => "not-enough-money",
+            PayjoinError::VersionUnsupported => "version-unsupported",
+            PayjoinError::OriginalPsbtRejected(_) => "original-psbt-rejected",
+        }
+    }
+}
+
+impl Display for PayjoinError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PayjoinError::Unavailable => write!(f, "we can't payjoin right now"),
+            PayjoinError::NotEnoughMoney => write!(f, "we have no coins to add"),
+            PayjoinError::VersionUnsupported => write!(f, "we only speak version 1"),
+            PayjoinError::OriginalPsbtRejected(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl From<Error> for PayjoinError {
+    fn from(e: Error) -> Self {
+        warn!("couldn't payjoin: {e}");
+        PayjoinError::Unavailable
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PayjoinErrorBody {
+    error_code: &'static str,
+    message: String,
+}
+
+/// Answers a BIP78 sender with our proposal, or why there isn't one. Senders broadcast their
+/// original transaction when we fail, so donations go through either way
+pub async fn receive<B: ChainBackend>(
+    params: web::Query<PayjoinParams>,
+    body: String,
+    data: web::Data<AppState<B>>,
+) -> HttpResponse {
+    if data.payjoins.is_none() {
+        return HttpResponse::NotFound().finish();
+    }
+
+    // proposing asks our node for coins and signatures, which we don't wait for here
+    let proposal = web::block(move || {
+        let payjoins = data.payjoins.as_ref().expect("we take payjoins");
+        propose(&data, payjoins, &params, body.trim())
+    })
+    .await
+    .unwrap_or(Err(PayjoinError::Unavailable));
+
+    match proposal {
+        Ok(proposal) => HttpResponse::Ok()
+            .content_type(ContentType::plaintext())
+            .body(proposal.to_string()),
+        Err(e) => HttpResponse::BadRequest().json(PayjoinErrorBody {
+            error_code: e.code(),
+            message: e.to_string(),
+        }),
+    }
+}
+
+/// Adds one of our coins to the donation in `original`, a base64 PSBT, and signs it
+fn propose<B: ChainBackend>(
+    data: &AppState<B>,
+    payjoins: &Payjoins,
+    params: &PayjoinParams,
+    original: &str,
+) -> Result<Psbt, PayjoinError> {
+    let rejected = |reason: &str| PayjoinError::OriginalPsbtRejected(reason.into());
+
+    if params.v.is_some_and(|version| version != 1) {
+        return Err(PayjoinError::VersionUnsupported);
+    }
+
+    let original: Psbt = original
+        .parse()
+        .map_err(|_| rejected("that's not a base64 psbt"))?;
+    let finalized = original
+        .inputs
+        .iter()
+        .all(|input| input.final_script_sig.is_some() || input.final_script_witness.is_some());
+    if !finalized {
+        return Err(rejected("the original transaction isn't signed"));
+    }
+    let spent = original
+        .inputs
+        .iter()
+        .zip(&original.unsigned_tx.input)
+        .map(|(input, txin)| {
+            input
+                .witness_utxo
+                .as_ref()
+                .or_else(|| {
+                    let tx = input.non_witness_utxo.as_ref()?;
+                    tx.output.get(txin.previous_output.vout as usize)
+                })
+                .map(|prevout| prevout.value)
+        })
+        .sum::<Option<Amount>>()
+        .ok_or_else(|| rejected("the original transaction doesn't tell what it spends"))?;
+
+    let tx = original
+        .clone()
+        .extract_tx()
+        .map_err(|_| rejected("the original transaction pays an absurd fee"))?;
+    let sequence = tx
+        .input
+        .first()
+        .ok_or_else(|| rejected("the original transaction spends nothing"))?
+        .sequence;
+    let fee = spent
+        .checked_sub(tx.output.iter().map(|output| output.value).sum())
+        .ok_or_else(|| rejected("the original transaction spends more than it has"))?;
+    let feerate = FeeRate::from_sat_per_kwu(fee.to_sat() * 1_000 / tx.weight().to_wu().max(1));
+
+    let ours = payjoins
+        .find(
+            tx.output
+                .iter()
+                .map(|output| &output.script_pubkey)
+                .enumerate(),
+        )
+        .ok_or_else(|| rejected("it doesn't pay an address we gave out for payjoins"))?;
+
+    // senders mustn't learn about our coins by sending us our own
+    let utxos = data.backend.list_unspent()?;
+    let outpoints = utxos
+        .iter()
+        .map(|utxo| OutPoint::new(utxo.txid, utxo.vout))
+        .collect::<HashSet<_>>();
+    if tx
+        .input
+        .iter()
+        .any(|txin| outpoints.contains(&txin.previous_output))
+    {
+        return Err(rejected("it spends our coins"));
+    }
+
+    // otherwise we'd hand our coin out for a transaction that can't go through
+    if let Some(reason) = data.backend.mempool_rejects(&tx)? {
+        return Err(PayjoinError::OriginalPsbtRejected(format!(
+            "the original transaction can't be broadcast: {reason}"
+        )));
+    }
+
+    let input_fee = fees::fee(feerate, fees::INPUT_VSIZE);
+    let utxo = utxos
+        .into_iter()
+        .filter(|utxo| utxo.amount > input_fee && data.coin_selection.spendable(utxo))
+        .min_by_key(|utxo| utxo.amount)
+        .ok_or(PayjoinError::NotEnoughMoney)?;
+
+    // our input pays for itself out of the donor's change, as much as they let us
+    let mut proposal = tx.clone();
+    let from_donor = match (
+        params.additionalfeeoutputindex,
+        params.maxadditionalfeecontribution,
+    ) {
+        (Some(index), Some(max)) if index != ours && index < proposal.output.len() => {
+            let output = &mut proposal.output[index];
+            let dust = output.script_pubkey.dust_value();
+            let contribution = input_fee
+                .min(Amount::from_sat(max))
+                .min(output.value.checked_sub(dust).unwrap_or(Amount::ZERO));
+            output.value -= contribution;
+            contribution
+        }
+        _ => Amount::ZERO,
+    };
+    proposal.output[ours].value = (proposal.output[ours].value + utxo.amount)
+        .checked_sub(input_fee - from_donor)
+        .ok_or(PayjoinError::NotEnoughMoney)?;
+
+    let new_feerate = FeeRate::from_sat_per_kwu(
+        (fee + input_fee).to_sat() * 1_000 / (tx.weight().to_wu() + fees::INPUT_VSIZE * 4).max(1),
+    );
+    if params
+        .minfeerate
+        .is_some_and(|min| (new_feerate.to_sat_per_kwu() as f64) < min * 250.0)
+    {
+        return Err(rejected("we can't keep the feerate you asked for"));
+    }
+
+    for txin in &mut proposal.input {
+        txin.script_sig = ScriptBuf::new();
+        txin.witness = Witness::new();
+    }
+    let index = rand::thread_rng().gen_range(0..=proposal.input.len());
+    proposal.input.insert(
+        index,
+        TxIn {
+            previous_output: OutPoint::new(utxo.txid, utxo.vout),
+            script_sig: ScriptBuf::new(),
+            // BIP78 wants every input to look alike
+            sequence,
+            witness: Witness::new(),
+        },
+    );
+
+    // the donor's inputs tell what they spend while we sign, but they sign them again
+    let mut unsigned =
+        Psbt::from_unsigned_tx(proposal.clone()).map_err(|_| PayjoinError::Unavailable)?;
+    let mut donor_inputs = original.inputs.into_iter();
+    for (i, input) in unsigned.inputs.iter_mut().enumerate() {
+        if i != index {
+            let donor = donor_inputs
+                .next()
+                .ok_or_else(|| rejected("the original psbt is missing inputs"))?;
+            input.witness_utxo = donor.witness_utxo;
+            input.non_witness_utxo = donor.non_witness_utxo;
+        }
+    }
+    let signed = data.backend.sign_psbt(unsigned)?;
+    let ours_signed = signed.inputs.get(index).ok_or(PayjoinError::Unavailable)?;
+    if ours_signed.final_script_witness.is_none() && ours_signed.final_script_sig.is_none() {
+        warn!("couldn't payjoin: we didn't sign our input");
+        return Err(PayjoinError::Unavailable);
+    }
+
+    let mut response = Psbt::from_unsigned_tx(proposal).map_err(|_| PayjoinError::Unavailable)?;
+    response.inputs[index] = bitcoin::psbt::Input {
+        witness_utxo: ours_signed.witness_utxo.clone(),
+        non_witness_utxo: ours_signed.non_witness_utxo.clone(),
+        final_script_sig: ours_signed.final_script_sig.clone(),
+        final_script_witness: ours_signed.final_script_witness.clone(),
+        ..Default::default()
+    };
+
+    payjoins
+        .scripts
+        .lock()
+        .unwrap()
+        .remove(&tx.output[ours].script_pubkey);
+    info!(
+        "joined a donation of {} with our {}:{}",
+        tx.output[ours].value, utxo.txid, utxo.vout
+    );
+
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::absolute::LockTime;
+    use bitcoin::hashes::Hash;
+    use bitcoin::transaction::Version;
+    use bitcoin::Sequence;
+    use bitcoin::Transaction;
+    use bitcoin::TxOut;
+    use bitcoin::Txid;
+
+    use super::*;
+    use crate::testing::address;
+    use crate::testing::app_state;
+    use crate::testing::coin;
+    use crate::testing::MockNode;
+
+    /// Where the donation goes, an address /donate gave out
+    const DONATION_ADDRESS: &str = "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7";
+    const DONOR_ADDRESS: &str = "tb1pqqqqp399et2xygdj5xreqhjjvcmzhxw4aywxecjdzew6hylgvsesf3hn0c";
+    const DONATION: Amount = Amount::from_sat(50_000);
+    const FEE: Amount = Amount::from_sat(2_000);
+
+    /// A signed donation paying us `DONATION`, and the donor `change`, spending `spent`
+    fn original(spent: OutPoint, change: Amount) -> Psbt {
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: spent,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![
+                TxOut {
+                    value: DONATION,
+                    script_pubkey: address(DONATION_ADDRESS).script_pubkey(),
+                },
+                TxOut {
+                    value: change,
+                    script_pubkey: address(DONOR_ADDRESS).script_pubkey(),
+                },
+            ],
+        };
+
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: DONATION + change + FEE,
+            script_pubkey: address(DONOR_ADDRESS).script_pubkey(),
+        });
+        psbt.inputs[0].final_script_witness = Some(Witness::from_slice(&[[3; 64]]));
+        psbt
+    }
+
+    /// A coin that isn't ours
+    fn donor_coin() -> OutPoint {
+        OutPoint::new(Txid::from_byte_array([1; 32]), 0)
+    }
+
+    /// What adding our input costs at the original's feerate
+    fn input_fee(original: &Psbt) -> Amount {
+        let weight = original.clone().extract_tx().unwrap().weight().to_wu();
+        let feerate = FeeRate::from_sat_per_kwu(FEE.to_sat() * 1_000 / weight);
+        fees::fee(feerate, fees::INPUT_VSIZE)
+    }
+
+    fn params(fee_output: Option<usize>, max_contribution: Option<u64>) -> PayjoinParams {
+        PayjoinParams {
+            v: Some(1),
+            additionalfeeoutputindex: fee_output,
+            maxadditionalfeecontribution: max_contribution,
+            minfeerate: None,
+        }
+    }
+
+    /// Proposes a payjoin for `original` from a faucet with `node`
+    fn propose_with(
+        node: MockNode,
+        params: &PayjoinParams,
+        original: &Psbt,
+    ) -> Result<Psbt, PayjoinError> {
+        let data = app_state(node);
+        let payjoins = Payjoins::default();
+        payjoins.expect(address(DONATION_ADDRESS).script_pubkey());
+
+        propose(&data, &payjoins, params, &original.to_string())
+    }
+
+    #[test]
+    fn adds_our_smallest_coin() {
+        let node = MockNode {
+            coins: vec![
+                coin(0, Amount::from_sat(900_000)),
+                coin(1, Amount::from_sat(100)),
+                coin(2, Amount::from_sat(300_000)),
+            ],
+            ..Default::default()
+        };
+        let original = original(donor_coin(), Amount::from_sat(30_000));
+
+        let proposal = propose_with(node, &params(None, None), &original).unwrap();
+        let tx = &proposal.unsigned_tx;
+        assert_eq!(tx.input.len(), 2);
+        let ours = tx
+            .input
+            .iter()
+            .position(|txin| txin.previous_output != donor_coin())
+            .unwrap();
+        assert_eq!(tx.input[ours].previous_output.vout, 2);
+        assert!(proposal.inputs[ours].final_script_witness.is_some());
+        assert_eq!(tx.input[ours].sequence, tx.input[1 - ours].sequence);
+
+        // the donor signs theirs again
+        assert!(proposal.inputs[1 - ours].final_script_witness.is_none());
+        assert_eq!(
+            tx.output[0].value,
+            DONATION + Amount::from_sat(300_000) - input_fee(&original)
+        );
+        assert_eq!(tx.output[1].value, Amount::from_sat(30_000));
+    }
+
+    #[test]
+    fn takes_no_more_than_dust_from_the_donor() {
+        let change = address(DONOR_ADDRESS).script_pubkey().dust_value() + Amount::from_sat(100);
+        let original = original(donor_coin(), change);
+        let input_fee = input_fee(&original);
+        assert!(input_fee > Amount::from_sat(100));
+
+        let proposal = propose_with(
+            MockNode::default(),
+            &params(Some(1), Some(10_000)),
+            &original,
+        )
+        .unwrap();
+        let tx = &proposal.unsigned_tx;
+        assert_eq!(tx.output[1].value, change - Amount::from_sat(100));
+        assert_eq!(
+            tx.output[0].value,
+            DONATION + Amount::ONE_BTC - (input_fee - Amount::from_sat(100))
+        );
+    }
+
+    #[test]
+    fn takes_no_more_than_the_donor_allows() {
+        let change = Amount::from_sat(30_000);
+        let original = original(donor_coin(), change);
+
+        let proposal =
+            propose_with(MockNode::default(), &params(Some(1), Some(10)), &original).unwrap();
+        let tx = &proposal.unsigned_tx;
+        assert_eq!(tx.output[1].value, change - Amount::from_sat(10));
+        assert_eq!(
+            tx.output[0].value,
+            DONATION + Amount::ONE_BTC - (input_fee(&original) - Amount::from_sat(10))
+        );
+    }
+
+    #[test]
+    fn pays_for_our_input_when_the_donor_points_at_our_output() {
+        let change = Amount::from_sat(30_000);
+        let original = original(donor_coin(), change);
+
+        let proposal = propose_with(
+            MockNode::default(),
+            &params(Some(0), Some(10_000)),
+            &original,
+        )
+        .unwrap();
+        let tx = &proposal.unsigned_tx;
+        assert_eq!(tx.output[1].value, change);
+        assert_eq!(
+            tx.output[0].value,
+            DONATION + Amount::ONE_BTC - input_fee(&original)
+        );
+    }
+
+    #[test]
+    fn keeps_the_feerate_the_donor_asks_for() {
+        let original = original(donor_coin(), Amount::from_sat(30_000));
+
+        let mut params = params(None, None);
+        params.minfeerate = Some(1.0);
+        assert!(propose_with(MockNode::default(), &params, &original).is_ok());
+
+        params.minfeerate = Some(1_000.0);
+        assert!(matches!(
+            propose_with(MockNode::default(), &params, &original),
+            Err(PayjoinError::OriginalPsbtRejected(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_originals_spending_our_coins() {
+        let ours = OutPoint::new(Txid::all_zeros(), 0);
+        let original = original(ours, Amount::from_sat(30_000));
+
+        assert!(matches!(
+            propose_with(MockNode::default(), &params(None, None), &original),
+            Err(PayjoinError::OriginalPsbtRejected(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_originals_the_mempool_wouldnt_take() {
+        let node = MockNode {
+            rejects: Some("bad-txns-inputs-missingorspent".into()),
+            ..Default::default()
+        };
+        let original = original(donor_coin(), Amount::from_sat(30_000));
+
+        assert!(matches!(
+            propose_with(node, &params(None, None), &original),
+            Err(PayjoinError::OriginalPsbtRejected(_))
+        ));
+    }
+
+    #[test]
+    fn refuses_what_we_cant_join() {
+        let node = MockNode::default;
+        let original = original(donor_coin(), Amount::from_sat(30_000));
+
+        // unsigned
+        let mut unsigned = original.clone();
+        unsigned.inputs[0].final_script_witness = None;
+        assert!(propose_with(node(), &params(None, None), &unsigned).is_err());
+
+        // not paying us
+        let mut elsewhere = original.clone();
+        elsewhere.unsigned_tx.output[0].script_pubkey = address(DONOR_ADDRESS).script_pubkey();
+        assert!(propose_with(node(), &params(None, None), &elsewhere).is_err());
+
+        // another version
+        let mut v2 = params(None, None);
+        v2.v = Some(2);
+        assert!(matches!(
+            propose_with(node(), &v2, &original),
+            Err(PayjoinError::VersionUnsupported)
+        ));
+
+        // without any coin to add
+        let broke = MockNode {
+            coins: Vec::new(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            propose_with(broke, &params(None, None), &original),
+            Err(PayjoinError::NotEnoughMoney)
+        ));
+    }
+}
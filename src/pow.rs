@@ -0,0 +1,269 @@
+//SPDX-License-Identifier: MIT
+
+//! Hashcash-style proofs of work, to slow bots down without a CAPTCHA, which CLI users and Tor
+//! visitors can't always solve.
+//!
+//! Clients GET /challenge and look for a nonce such that `sha256(challenge || nonce)`, with both
+//! as text, starts with `difficulty` zero bits. They then send the challenge and the nonce along
+//! with their request. Each challenge can only be used once, and only for a few minutes.
+//!
+//! We don't keep the challenges we hand out, so asking for them costs us nothing: each carries
+//! when we made it and a MAC of that with a key only we know. We only remember the solved ones,
+//! until they expire, so they can't be used twice. Challenges from before a restart don't verify.
+
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use bitcoin::hashes::hmac::Hmac;
+use bitcoin::hashes::hmac::HmacEngine;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::HashEngine;
+use bitcoin::hex::DisplayHex;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::api::Error;
+
+/// For how long a challenge can be solved
+const CHALLENGE_TTL: Duration = Duration::from_secs(300);
+
+/// What GET /challenge returns
+#[derive(Serialize, ToSchema)]
+pub struct Challenge {
+    challenge: String,
+    difficulty: u32,
+    /// In seconds
+    expires_in: u64,
+}
+
+pub struct Challenges {
+    difficulty: u32,
+    /// What we MAC our challenges with
+    key: [u8; 32],
+    /// The challenges solved already, by when we made them, so the expired ones go first
+    spent: Mutex<BTreeSet<(u64, String)>>,
+}
+
+/// How many zero bits `hash` starts with
+fn leading_zeros(hash: &[u8]) -> u32 {
+    let mut zeros = 0;
+    for byte in hash {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    zeros
+}
+
+/// The seconds since the epoch
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Challenges {
+    pub fn new(difficulty: u32) -> Self {
+        Self {
+            difficulty,
+            key: bitcoin::secp256k1::rand::random(),
+            spent: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// The MAC proving we made the challenge starting with `issued`
+    fn mac(&self, issued: &str) -> String {
+        let mut engine = HmacEngine::<sha256::Hash>::new(&self.key);
+        engine.input(issued.as_bytes());
+        Hmac::<sha256::Hash>::from_engine(engine).as_byte_array()[..16].to_lower_hex_string()
+    }
+
+    /// Makes a new challenge
+    pub fn issue(&self) -> Challenge {
+        self.issue_at(now())
+    }
+
+    fn issue_at(&self, now: u64) -> Challenge {
+        let random = bitcoin::secp256k1::rand::random::<[u8; 16]>().to_lower_hex_string();
+        let issued = format!("{now}.{random}");
+        let challenge = format!("{issued}.{}", self.mac(&issued));
+
+        Challenge {
+            challenge,
+            difficulty: self.difficulty,
+            expires_in: CHALLENGE_TTL.as_secs(),
+        }
+    }
+
+    /// Checks that `nonce` solves `challenge`, which we must have made lately and not seen
+    /// solved before
+    pub fn verify(&self, challenge: &str, nonce: &str) -> Result<(), Error> {
+        self.verify_at(challenge, nonce, now())
+    }
+
+    fn verify_at(&self, challenge: &str, nonce: &str, now: u64) -> Result<(), Error> {
+        let (issued, mac) = challenge
+            .rsplit_once('.')
+            .ok_or(Error::InvalidProofOfWork)?;
+        if mac != self.mac(issued) {
+            return Err(Error::InvalidProofOfWork);
+        }
+        let issued_at = issued
+            .split_once('.')
+            .and_then(|(at, _)| at.parse::<u64>().ok())
+            .ok_or(Error::InvalidProofOfWork)?;
+        if now >= issued_at + CHALLENGE_TTL.as_secs() {
+            return Err(Error::InvalidProofOfWork);
+        }
+
+        let hash = sha256::Hash::hash(format!("{challenge}{nonce}").as_bytes());
+        if leading_zeros(hash.as_byte_array()) < self.difficulty {
+            return Err(Error::InvalidProofOfWork);
+        }
+
+        let mut spent = self.spent.lock().unwrap();
+        while let Some((at, _)) = spent.first() {
+            if now < at + CHALLENGE_TTL.as_secs() {
+                break;
+            }
+            spent.pop_first();
+        }
+        if !spent.insert((issued_at, challenge.to_string())) {
+            return Err(Error::InvalidProofOfWork);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFFICULTY: u32 = 8;
+    const NOW: u64 = 1_700_000_000;
+
+    /// Finds a nonce solving `challenge`
+    fn solve(challenge: &str) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| {
+                let hash = sha256::Hash::hash(format!("{challenge}{nonce}").as_bytes());
+                leading_zeros(hash.as_byte_array()) >= DIFFICULTY
+            })
+            .unwrap()
+    }
+
+    /// A nonce that doesn't solve `challenge`
+    fn wrong_nonce(challenge: &str) -> String {
+        (0u64..)
+            .map(|nonce| nonce.to_string())
+            .find(|nonce| {
+                let hash = sha256::Hash::hash(format!("{challenge}{nonce}").as_bytes());
+                leading_zeros(hash.as_byte_array()) < DIFFICULTY
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zeros(&[]), 0);
+        assert_eq!(leading_zeros(&[0xff, 0x00]), 0);
+        assert_eq!(leading_zeros(&[0x01, 0xff]), 7);
+        assert_eq!(leading_zeros(&[0x00, 0x00, 0x10]), 19);
+        assert_eq!(leading_zeros(&[0x00, 0x80]), 8);
+        assert_eq!(leading_zeros(&[0x00; 4]), 32);
+    }
+
+    #[test]
+    fn takes_a_solved_challenge() {
+        let challenges = Challenges::new(DIFFICULTY);
+        let challenge = challenges.issue_at(NOW).challenge;
+
+        let nonce = solve(&challenge);
+        assert!(challenges.verify_at(&challenge, &nonce, NOW + 10).is_ok());
+    }
+
+    #[test]
+    fn refuses_wrong_nonces() {
+        let challenges = Challenges::new(DIFFICULTY);
+        let challenge = challenges.issue_at(NOW).challenge;
+
+        let nonce = wrong_nonce(&challenge);
+        assert!(matches!(
+            challenges.verify_at(&challenge, &nonce, NOW),
+            Err(Error::InvalidProofOfWork)
+        ));
+        // failing doesn't spend it
+        let nonce = solve(&challenge);
+        assert!(challenges.verify_at(&challenge, &nonce, NOW).is_ok());
+    }
+
+    #[test]
+    fn refuses_expired_challenges() {
+        let challenges = Challenges::new(DIFFICULTY);
+        let challenge = challenges.issue_at(NOW).challenge;
+
+        let nonce = solve(&challenge);
+        let expired = NOW + CHALLENGE_TTL.as_secs();
+        assert!(matches!(
+            challenges.verify_at(&challenge, &nonce, expired),
+            Err(Error::InvalidProofOfWork)
+        ));
+    }
+
+    #[test]
+    fn refuses_reused_challenges() {
+        let challenges = Challenges::new(DIFFICULTY);
+        let challenge = challenges.issue_at(NOW).challenge;
+
+        let nonce = solve(&challenge);
+        assert!(challenges.verify_at(&challenge, &nonce, NOW).is_ok());
+        assert!(matches!(
+            challenges.verify_at(&challenge, &nonce, NOW + 1),
+            Err(Error::InvalidProofOfWork)
+        ));
+    }
+
+    #[test]
+    fn refuses_challenges_we_didnt_make() {
+        let challenges = Challenges::new(DIFFICULTY);
+        let other = Challenges::new(DIFFICULTY);
+
+        // made by another faucet, or before a restart
+        let challenge = other.issue_at(NOW).challenge;
+        let nonce = solve(&challenge);
+        assert!(challenges.verify_at(&challenge, &nonce, NOW).is_err());
+
+        // or made up, with a later time so they'd last longer
+        let (_, rest) = challenge.split_once('.').unwrap();
+        let forged = format!("{}.{rest}", NOW + 1_000);
+        let nonce = solve(&forged);
+        assert!(other.verify_at(&forged, &nonce, NOW).is_err());
+
+        for garbage in ["", "abc", "1.2", "..", "x.y.z"] {
+            assert!(challenges.verify_at(garbage, "0", NOW).is_err());
+        }
+    }
+
+    #[test]
+    fn forgets_spent_challenges_once_they_expire() {
+        let challenges = Challenges::new(DIFFICULTY);
+        let old = challenges.issue_at(NOW).challenge;
+        challenges.verify_at(&old, &solve(&old), NOW).unwrap();
+
+        let later = NOW + CHALLENGE_TTL.as_secs();
+        let new = challenges.issue_at(later).challenge;
+        challenges.verify_at(&new, &solve(&new), later).unwrap();
+
+        let spent = challenges.spent.lock().unwrap();
+        assert_eq!(spent.len(), 1);
+        assert_eq!(spent.first().unwrap().0, later);
+    }
+}
@@ -0,0 +1,225 @@
+//SPDX-License-Identifier: MIT
+
+//! Finding out who a request is from when we're behind a reverse proxy. The peer we see is then
+//! the proxy, and the client is in the `Forwarded` or `X-Forwarded-For` headers it adds. Anyone
+//! can send those headers, so we only read them from peers in the trusted list, and only as far
+//! back as the proxies we trust: the client is the last hop that isn't one of them.
+
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use actix_web::http::header::HeaderMap;
+use actix_web::http::header::FORWARDED;
+use actix_web::http::header::X_FORWARDED_FOR;
+use ipnet::IpNet;
+
+/// The proxies we believe about who they're forwarding requests for
+#[derive(Debug, Default, Clone)]
+pub struct TrustedProxies(Vec<IpNet>);
+
+impl FromStr for TrustedProxies {
+    type Err = String;
+
+    /// Takes comma separated networks, like `10.0.0.0/8`, or single addresses
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|network| !network.is_empty())
+            .map(|network| {
+                IpNet::from_str(network)
+                    .or_else(|_| IpAddr::from_str(network).map(IpNet::from))
+                    .map_err(|_| format!("{network} isn't an address or network"))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl TrustedProxies {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn trusts(&self, ip: &IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(ip))
+    }
+
+    /// Who a request from `peer` is for. Going from the hop closest to us, we skip the proxies
+    /// we trust, and stop at the first hop that isn't one, or that we can't read
+    pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client = peer;
+        if !self.trusts(&client) {
+            return client;
+        }
+
+        for hop in forwarded_for(headers).into_iter().rev() {
+            // an obfuscated or garbled hop, the proxy that added it is all we know
+            let Some(hop) = hop else {
+                break;
+            };
+            client = hop;
+            if !self.trusts(&client) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+/// The hops a request went through, oldest first, from `Forwarded` or, if it isn't there,
+/// `X-Forwarded-For`
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded = headers
+        .get_all(FORWARDED)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (name, node) = pair.trim().split_once('=')?;
+                name.eq_ignore_ascii_case("for").then(|| parse_node(node))
+            })
+        })
+        .collect::<Vec<_>>();
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Reads a hop like `192.0.2.43`, `"192.0.2.43:4711"` or `"[2001:db8::17]:4711"`. Obfuscated
+/// ones like `unknown` or `_hidden` are `None`
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+
+    IpAddr::from_str(node)
+        .or_else(|_| SocketAddr::from_str(node).map(|addr| addr.ip()))
+        .or_else(|_| IpAddr::from_str(node.trim_start_matches('[').trim_end_matches(']')))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::HeaderName;
+    use actix_web::http::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(headers: &[(HeaderName, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn proxies() -> TrustedProxies {
+        "10.0.0.0/8, 192.0.2.1".parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks_and_addresses() {
+        assert_eq!(proxies().len(), 2);
+        assert_eq!("".parse::<TrustedProxies>().unwrap().len(), 0);
+        assert!("10.0.0.0/8,nope".parse::<TrustedProxies>().is_err());
+        assert!("10.0.0.0/33".parse::<TrustedProxies>().is_err());
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let headers = headers(&[
+            (X_FORWARDED_FOR, "198.51.100.7"),
+            (FORWARDED, "for=198.51.100.8"),
+        ]);
+
+        assert_eq!(
+            proxies().client_ip(ip("203.0.113.5"), &headers),
+            ip("203.0.113.5")
+        );
+        // nor does anyone get believed when we trust no proxies
+        assert_eq!(
+            TrustedProxies::default().client_ip(ip("10.0.0.1"), &headers),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn ignores_spoofed_hops_before_the_client() {
+        // the client sent its own X-Forwarded-For, and our proxy appended who it really is
+        let headers = headers(&[(X_FORWARDED_FOR, "1.2.3.4, 198.51.100.7")]);
+
+        assert_eq!(
+            proxies().client_ip(ip("10.0.0.1"), &headers),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn follows_a_chain_of_trusted_proxies() {
+        let headers = headers(&[
+            (X_FORWARDED_FOR, "1.2.3.4, 198.51.100.7, 192.0.2.1"),
+            (X_FORWARDED_FOR, "10.1.2.3"),
+        ]);
+
+        assert_eq!(
+            proxies().client_ip(ip("10.0.0.1"), &headers),
+            ip("198.51.100.7")
+        );
+    }
+
+    #[test]
+    fn reads_quoted_ipv6_hops_with_ports() {
+        let headers = headers(&[(
+            FORWARDED,
+            "for=192.0.2.60;proto=http, For=\"[2001:db8:cafe::17]:4711\";by=10.0.0.1",
+        )]);
+
+        assert_eq!(
+            proxies().client_ip(ip("10.0.0.1"), &headers),
+            ip("2001:db8:cafe::17")
+        );
+    }
+
+    #[test]
+    fn prefers_forwarded_to_x_forwarded_for() {
+        let headers = headers(&[
+            (X_FORWARDED_FOR, "198.51.100.7"),
+            (FORWARDED, "for=\"198.51.100.8:1234\""),
+        ]);
+
+        assert_eq!(
+            proxies().client_ip(ip("10.0.0.1"), &headers),
+            ip("198.51.100.8")
+        );
+    }
+
+    #[test]
+    fn stops_at_hops_it_cant_read() {
+        // the proxy doesn't tell who it forwards for, so it's all we know
+        let headers = headers(&[(FORWARDED, "for=unknown, for=10.0.0.2")]);
+
+        assert_eq!(
+            proxies().client_ip(ip("10.0.0.1"), &headers),
+            ip("10.0.0.2")
+        );
+    }
+
+    #[test]
+    fn takes_the_last_trusted_proxy_without_headers() {
+        assert_eq!(
+            proxies().client_ip(ip("10.0.0.1"), &HeaderMap::new()),
+            ip("10.0.0.1")
+        );
+    }
+}
@@ -0,0 +1,118 @@
+//SPDX-License-Identifier: MIT
+
+//! Renders QR codes, so the index page and bots can show the invoices and URIs we give out
+//! without a QR library of their own. GET /qr?data=... answers with `data` as an SVG, or as a
+//! PNG with `format=png`, and responses holding an invoice or offer link to its QR code.
+
+use std::io::Cursor;
+
+use actix_web::http::header;
+use actix_web::web;
+use actix_web::HttpResponse;
+use image::ImageFormat;
+use image::Luma;
+use qrcode::render::svg;
+use qrcode::QrCode;
+use serde::Deserialize;
+use utoipa::IntoParams;
+use utoipa::ToSchema;
+
+use crate::api::Error;
+
+/// How wide our codes are, in pixels, unless asked for a `size`
+const DEFAULT_SIZE: u32 = 256;
+
+/// The smallest and largest `size` we render
+const MIN_SIZE: u32 = 64;
+const MAX_SIZE: u32 = 1_024;
+
+/// The most a code may hold, in bytes. BOLT11 invoices are well within this
+const MAX_DATA_LENGTH: usize = 2_048;
+
+/// How long clients may keep a code, in seconds. The same data always makes the same code
+const CACHE_MAX_AGE: u32 = 86_400;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Svg,
+    Png,
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct QrQuery {
+    /// What to encode, like a BIP21 URI or a `lightning:` invoice
+    data: String,
+    format: Option<Format>,
+    /// The code's width and height, in pixels, from 64 to 1024
+    size: Option<u32>,
+}
+
+/// The link to `data`'s QR code
+pub fn link(data: &str) -> String {
+    let mut link = String::from("/v1/qr?data=");
+    for byte in data.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                link.push(byte as char)
+            }
+            _ => link.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    link
+}
+
+/// The link to the QR code for paying `invoice`. It's in upper case, which QR codes hold more
+/// compactly, and wallets take either way
+#[cfg(feature = "lightning")]
+pub fn invoice_link(invoice: &str) -> String {
+    link(&format!("lightning:{invoice}").to_uppercase())
+}
+
+/// Renders `data` as a QR code
+#[utoipa::path(
+    get,
+    path = "/v1/qr",
+    tag = "faucet",
+    params(QrQuery),
+    responses(
+        (status = 200, description = "The QR code", content_type = ["image/svg+xml", "image/png"], body = String),
+        (status = "4XX", body = ErrorBody),
+    )
+)]
+pub async fn qr(query: web::Query<QrQuery>) -> Result<HttpResponse, Error> {
+    if query.data.len() > MAX_DATA_LENGTH {
+        return Err(Error::InvalidRequest(format!(
+            "data may have up to {MAX_DATA_LENGTH} bytes"
+        )));
+    }
+    let size = query.size.unwrap_or(DEFAULT_SIZE).clamp(MIN_SIZE, MAX_SIZE);
+    let code = QrCode::new(query.data.as_bytes())
+        .map_err(|e| Error::InvalidRequest(format!("can't make a QR code: {e}")))?;
+
+    let mut response = HttpResponse::Ok();
+    response.insert_header((
+        header::CACHE_CONTROL,
+        format!("public, max-age={CACHE_MAX_AGE}"),
+    ));
+
+    match query.format.unwrap_or_default() {
+        Format::Svg => {
+            let image = code
+                .render::<svg::Color>()
+                .min_dimensions(size, size)
+                .build();
+            Ok(response.content_type("image/svg+xml").body(image))
+        }
+        Format::Png => {
+            let image = code.render::<Luma<u8>>().min_dimensions(size, size).build();
+            let mut png = Cursor::new(vec![]);
+            image
+                .write_to(&mut png, ImageFormat::Png)
+                .expect("encoding to memory doesn't fail");
+            Ok(response.content_type("image/png").body(png.into_inner()))
+        }
+    }
+}
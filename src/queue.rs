@@ -0,0 +1,626 @@
+//SPDX-License-Identifier: MIT
+
+//! A queue for /send/. When it's enabled, /send/ doesn't build a transaction itself: it checks
+//! the request, queues it and answers with an id right away. A background worker then pays the
+//! queue in batches, one transaction every interval, so bursts of requests turn into a steady
+//! trickle of transactions instead of a pile of them spending each other's change.
+//!
+//! Batches take requests oldest first, but at most one per client, so a client queueing a lot
+//! doesn't hold everyone else back. Clients poll GET /queue/{id} to know how theirs is doing.
+//!
+//! Requests are kept in the database too, as they go from queued to sending to paid or failed,
+//! so a restart picks up where we left off. Those we were sending when we stopped get paid again
+//! unless we find their payout, and silent payments, whose payouts we can't tell, fail instead.
+//! Clients' IPs are only kept until their request is done.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use actix_web::web;
+use bitcoin::hex::DisplayHex;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::Txid;
+use bitcoincore_rpc::jsonrpc::serde_json;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::blocking;
+use crate::api::check_address_cooldown;
+use crate::api::check_budget;
+use crate::api::send_batch;
+use crate::api::AppState;
+use crate::api::Error;
+use crate::api::Payout;
+use crate::backend::ChainBackend;
+use crate::db::now;
+use crate::db::Database;
+use crate::db::StoredRequest;
+use crate::silentpayments::SilentPaymentAddress;
+
+/// For how long we remember what happened to a request once it's done
+const FORGET_AFTER: Duration = Duration::from_secs(3_600);
+
+/// How long a batch may take, on top of the interval, before we think the worker is stuck
+#[cfg(feature = "systemd")]
+const STUCK_AFTER: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone)]
+pub enum QueueStatus {
+    /// Still waiting, behind `ahead` other requests
+    Queued {
+        ahead: usize,
+    },
+    /// Paid by output `vout` of `txid`
+    Paid {
+        txid: Txid,
+        vout: u32,
+    },
+    Failed(String),
+}
+
+struct Queued {
+    id: String,
+    payout: Payout,
+}
+
+/// A queued payout, as we keep it in the database
+#[derive(Serialize, Deserialize)]
+struct StoredPayout {
+    address: String,
+    amount: u64,
+    silent_payment: Option<SilentPaymentAddress>,
+    client: Option<IpAddr>,
+    cooldown: Option<Duration>,
+    account: Option<String>,
+    memo: Option<Vec<u8>>,
+    fee_target: Option<u16>,
+    callback: Option<String>,
+}
+
+impl From<&Payout> for StoredPayout {
+    fn from(payout: &Payout) -> Self {
+        Self {
+            address: payout.address.to_string(),
+            amount: payout.amount.to_sat(),
+            silent_payment: payout.silent_payment,
+            client: payout.client,
+            cooldown: payout.cooldown,
+            account: payout.account.clone(),
+            memo: payout.memo.clone(),
+            fee_target: payout.fee_target,
+            #[cfg(feature = "webhooks")]
+            callback: payout.callback.clone(),
+            #[cfg(not(feature = "webhooks"))]
+            callback: None,
+        }
+    }
+}
+
+impl StoredPayout {
+    fn into_payout(self) -> Option<Payout> {
+        Some(Payout {
+            // we checked it before queueing it
+            address: self.address.parse::<Address<_>>().ok()?.assume_checked(),
+            amount: Amount::from_sat(self.amount),
+            silent_payment: self.silent_payment,
+            client: self.client,
+            cooldown: self.cooldown,
+            account: self.account,
+            memo: self.memo,
+            fee_target: self.fee_target,
+            #[cfg(feature = "webhooks")]
+            callback: self.callback,
+        })
+    }
+}
+
+pub struct PayoutQueue {
+    /// How many requests may wait at once
+    capacity: usize,
+    /// How many requests we pay in a transaction, at most
+    batch_size: usize,
+    /// How long we wait between transactions
+    interval: Duration,
+    pending: Mutex<VecDeque<Queued>>,
+    /// What happened to the requests we're done with, and when
+    done: Mutex<HashMap<String, (QueueStatus, Instant)>>,
+    /// When the worker last started waiting for the next batch
+    #[cfg(feature = "systemd")]
+    heartbeat: Mutex<Instant>,
+}
+
+impl PayoutQueue {
+    pub fn new(capacity: usize, batch_size: usize, interval: Duration) -> Self {
+        Self {
+            capacity,
+            batch_size,
+            interval,
+            pending: Mutex::new(VecDeque::new()),
+            done: Mutex::new(HashMap::new()),
+            #[cfg(feature = "systemd")]
+            heartbeat: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Queues `payout`, returning the id to ask about it with
+    pub fn push(&self, db: &Database, payout: Payout) -> Result<String, Error> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= self.capacity {
+            return Err(Error::QueueFull);
+        }
+
+        let id = bitcoin::secp256k1::rand::random::<[u8; 16]>().to_lower_hex_string();
+        let stored =
+            serde_json::to_string(&StoredPayout::from(&payout)).expect("payouts serialize");
+        db.queue_request(&id, &stored)?;
+        pending.push_back(Queued {
+            id: id.clone(),
+            payout,
+        });
+
+        Ok(id)
+    }
+
+    /// How many requests are waiting, and how many may
+    pub fn depth(&self) -> (usize, usize) {
+        (self.pending.lock().unwrap().len(), self.capacity)
+    }
+
+    /// Whether the worker came back for another batch lately, rather than hanging on the last
+    #[cfg(feature = "systemd")]
+    pub fn worker_alive(&self) -> bool {
+        self.heartbeat.lock().unwrap().elapsed() < self.interval + STUCK_AFTER
+    }
+
+    pub fn status(&self, id: &str) -> Option<QueueStatus> {
+        if let Some(ahead) = self
+            .pending
+            .lock()
+            .unwrap()
+            .iter()
+            .position(|queued| queued.id == id)
+        {
+            return Some(QueueStatus::Queued { ahead });
+        }
+
+        self.done
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|(status, _)| status.clone())
+    }
+
+    /// Takes the next batch out of the queue: the oldest requests, one per client and per
+    /// script, since a transaction can't pay the same script twice, and with one memo at most,
+    /// since it only has room for one
+    fn next_batch(&self) -> Vec<Queued> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut clients = HashSet::new();
+        let mut scripts = HashSet::new();
+        let mut memo = false;
+        let mut batch = vec![];
+        let mut rest = VecDeque::new();
+
+        while let Some(queued) = pending.pop_front() {
+            let fits = batch.len() < self.batch_size
+                && !queued.payout.client.is_some_and(|ip| clients.contains(&ip))
+                && !scripts.contains(&queued.payout.address.script_pubkey())
+                && (!memo || queued.payout.memo.is_none());
+            if fits {
+                clients.extend(queued.payout.client);
+                scripts.insert(queued.payout.address.script_pubkey());
+                memo |= queued.payout.memo.is_some();
+                batch.push(queued);
+            } else {
+                rest.push_back(queued);
+            }
+        }
+        *pending = rest;
+
+        // they're still waiting, for their transaction now
+        let mut done = self.done.lock().unwrap();
+        for queued in &batch {
+            done.insert(
+                queued.id.clone(),
+                (QueueStatus::Queued { ahead: 0 }, Instant::now()),
+            );
+        }
+
+        batch
+    }
+
+    fn finish(&self, db: &Database, id: String, status: QueueStatus) {
+        let (state, paid, error) = match &status {
+            QueueStatus::Paid { txid, vout } => ("paid", Some((*txid, *vout)), None),
+            QueueStatus::Failed(reason) => ("failed", None, Some(reason.as_str())),
+            QueueStatus::Queued { .. } => unreachable!("finished requests aren't queued"),
+        };
+        let forget_before = now().saturating_sub(FORGET_AFTER.as_secs());
+        if let Err(e) = db.finish_request(&id, state, paid, error, forget_before) {
+            warn!("couldn't record that queued request {id} {state}: {e}");
+        }
+
+        let mut done = self.done.lock().unwrap();
+        done.retain(|_, (_, at)| at.elapsed() < FORGET_AFTER);
+        done.insert(id, (status, Instant::now()));
+    }
+
+    /// Brings back the requests we had when we stopped. Those we were sending may have gone out
+    /// already, we look for their payout before queueing them again
+    pub fn restore(&self, db: &Database) -> Result<(), Error> {
+        let mut restored = 0;
+
+        for stored in db.queued_requests()? {
+            let StoredRequest {
+                id,
+                state,
+                payout,
+                txid,
+                vout,
+                error,
+                updated_at,
+            } = stored;
+
+            // those that ended before we stopped are only there to tell clients how they did
+            let ended = match (state.as_str(), txid, vout) {
+                ("paid", Some(txid), Some(vout)) => Some(QueueStatus::Paid { txid, vout }),
+                ("failed", _, _) => Some(QueueStatus::Failed(error.unwrap_or_default())),
+                _ => None,
+            };
+            if let Some(status) = ended {
+                let ago = Duration::from_secs(now().saturating_sub(updated_at));
+                if let Some(at) = Instant::now().checked_sub(ago) {
+                    self.done.lock().unwrap().insert(id, (status, at));
+                }
+                continue;
+            }
+
+            let payout = payout
+                .and_then(|payout| serde_json::from_str::<StoredPayout>(&payout).ok())
+                .and_then(StoredPayout::into_payout);
+            let status = match (state.as_str(), payout) {
+                ("queued", Some(payout)) => {
+                    self.pending
+                        .lock()
+                        .unwrap()
+                        .push_back(Queued { id, payout });
+                    restored += 1;
+                    continue;
+                }
+                ("sending", Some(payout)) if payout.silent_payment.is_some() => {
+                    QueueStatus::Failed("we stopped while paying it, it may have gone out".into())
+                }
+                ("sending", Some(payout)) => {
+                    match db.find_payout(&payout.address, payout.amount, updated_at)? {
+                        Some((txid, Some(vout))) => QueueStatus::Paid { txid, vout },
+                        Some((_, None)) => QueueStatus::Failed(
+                            "we stopped while paying it, it may have gone out".into(),
+                        ),
+                        None => {
+                            db.update_request(&id, "queued")?;
+                            self.pending
+                                .lock()
+                                .unwrap()
+                                .push_back(Queued { id, payout });
+                            restored += 1;
+                            continue;
+                        }
+                    }
+                }
+                (state, _) => {
+                    warn!("couldn't bring back queued request {id}, {state} when we stopped");
+                    QueueStatus::Failed("we lost it while restarting".into())
+                }
+            };
+            self.finish(db, id, status);
+        }
+
+        if restored > 0 {
+            info!("restored {restored} queued requests");
+        }
+        Ok(())
+    }
+}
+
+/// Pays a batch of queued requests every interval
+pub fn spawn_worker<B: ChainBackend>(data: web::Data<AppState<B>>) {
+    actix::spawn(async move {
+        loop {
+            let Some(queue) = &data.payout_queue else {
+                return;
+            };
+            #[cfg(feature = "systemd")]
+            {
+                *queue.heartbeat.lock().unwrap() = Instant::now();
+            }
+            actix::clock::sleep(queue.interval).await;
+
+            // requests stay queued while an admin has paused payouts
+            if data.controls.may_pay() {
+                let paid = blocking(&data, |data| {
+                    if let Some(queue) = &data.payout_queue {
+                        pay_batch(data, queue);
+                    }
+                    Ok(())
+                });
+                if let Err(e) = paid.await {
+                    warn!("couldn't pay the next batch: {e}");
+                }
+            }
+        }
+    });
+}
+
+/// Pays what's left in the queue before we stop, batch after batch without waiting between
+/// them, until it's empty or `timeout` has passed. Whatever is still queued then waits for us
+/// to start again
+pub fn drain<B: ChainBackend>(data: &AppState<B>, timeout: Duration) {
+    let Some(queue) = &data.payout_queue else {
+        return;
+    };
+    let started = Instant::now();
+
+    while data.controls.may_pay() && started.elapsed() < timeout {
+        if !pay_batch(data, queue) {
+            break;
+        }
+    }
+
+    let (left, _) = queue.depth();
+    if left > 0 {
+        warn!("stopping with {left} requests still queued");
+    }
+}
+
+/// Pays the next batch in one transaction. Returns false if there was nothing left to pay
+fn pay_batch<B: ChainBackend>(data: &AppState<B>, queue: &PayoutQueue) -> bool {
+    let next = queue.next_batch();
+    if next.is_empty() {
+        return false;
+    }
+
+    // things may have changed since the requests were queued
+    let mut total = Amount::ZERO;
+    let mut batch = vec![];
+    for queued in next {
+        let payout = &queued.payout;
+        let checked = check_budget(data, total + payout.amount)
+            .and_then(|_| check_address_cooldown(data, &payout.address, payout.cooldown));
+        match checked {
+            Ok(()) => {
+                total += payout.amount;
+                batch.push(queued);
+            }
+            Err(e) => queue.finish(&data.db, queued.id, QueueStatus::Failed(e.to_string())),
+        }
+    }
+    if batch.is_empty() {
+        return true;
+    }
+
+    // should we stop while sending them, we look for their payout when we start again
+    for queued in &batch {
+        if let Err(e) = data.db.update_request(&queued.id, "sending") {
+            warn!(
+                "couldn't record that we're sending queued request {}: {e}",
+                queued.id
+            );
+        }
+    }
+    let payouts = batch
+        .iter()
+        .map(|queued| queued.payout.clone())
+        .collect::<Vec<_>>();
+    match send_batch(data, &payouts) {
+        Ok(sent) => {
+            for (queued, vout) in batch.into_iter().zip(sent.vouts) {
+                let status = QueueStatus::Paid {
+                    txid: sent.txid,
+                    vout,
+                };
+                queue.finish(&data.db, queued.id, status);
+            }
+        }
+        Err(e) => {
+            warn!("couldn't pay a batch of {} requests: {e}", payouts.len());
+            for queued in batch {
+                queue.finish(&data.db, queued.id, QueueStatus::Failed(e.to_string()));
+            }
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use bitcoin::Network;
+    use bitcoin::ScriptBuf;
+
+    use super::*;
+    use crate::testing::app_state;
+    use crate::testing::MockNode;
+
+    /// A different address for each `n`
+    fn address(n: u8) -> Address {
+        Address::p2wsh(&ScriptBuf::from(vec![n]), Network::Signet)
+    }
+
+    fn payout(n: u8, client: Option<&str>) -> Payout {
+        Payout {
+            address: address(n),
+            amount: Amount::from_sat(10_000),
+            silent_payment: None,
+            client: client.map(|ip| ip.parse().unwrap()),
+            cooldown: None,
+            account: None,
+            memo: None,
+            fee_target: None,
+            #[cfg(feature = "webhooks")]
+            callback: None,
+        }
+    }
+
+    fn queue(batch_size: usize) -> (PayoutQueue, Database) {
+        (
+            PayoutQueue::new(10, batch_size, Duration::from_secs(60)),
+            Database::open(":memory:".as_ref()).unwrap(),
+        )
+    }
+
+    /// The ids of the next batch, by the order they were queued in
+    fn next_batch(queue: &PayoutQueue, ids: &[String]) -> Vec<usize> {
+        queue
+            .next_batch()
+            .iter()
+            .map(|queued| ids.iter().position(|id| *id == queued.id).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn batches_one_request_per_client() {
+        let (queue, db) = queue(10);
+        let ids = [
+            payout(0, Some("192.0.2.1")),
+            payout(1, Some("192.0.2.1")),
+            payout(2, Some("192.0.2.2")),
+            payout(3, None),
+            payout(4, None),
+            payout(5, Some("192.0.2.1")),
+        ]
+        .into_iter()
+        .map(|payout| queue.push(&db, payout).unwrap())
+        .collect::<Vec<_>>();
+
+        // clients we don't know don't hold each other back
+        assert_eq!(next_batch(&queue, &ids), [0, 2, 3, 4]);
+        assert!(matches!(
+            queue.status(&ids[5]),
+            Some(QueueStatus::Queued { ahead: 1 })
+        ));
+        assert_eq!(next_batch(&queue, &ids), [1]);
+        assert_eq!(next_batch(&queue, &ids), [5]);
+        assert!(queue.next_batch().is_empty());
+    }
+
+    #[test]
+    fn batches_the_oldest_first() {
+        let (queue, db) = queue(2);
+        let ids = (0..5)
+            .map(|n| queue.push(&db, payout(n, None)).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(next_batch(&queue, &ids), [0, 1]);
+        assert_eq!(next_batch(&queue, &ids), [2, 3]);
+        assert_eq!(next_batch(&queue, &ids), [4]);
+    }
+
+    #[test]
+    fn batches_one_request_per_script_and_memo() {
+        let (queue, db) = queue(10);
+        let with_memo = |n| Payout {
+            memo: Some(b"ci".to_vec()),
+            ..payout(n, None)
+        };
+        let ids = [payout(0, None), payout(0, None), with_memo(1), with_memo(2)]
+            .into_iter()
+            .map(|payout| queue.push(&db, payout).unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(next_batch(&queue, &ids), [0, 2]);
+        assert_eq!(next_batch(&queue, &ids), [1, 3]);
+    }
+
+    #[test]
+    fn holds_no_more_than_its_capacity() {
+        let db = Database::open(":memory:".as_ref()).unwrap();
+        let queue = PayoutQueue::new(2, 10, Duration::from_secs(60));
+
+        queue.push(&db, payout(0, None)).unwrap();
+        queue.push(&db, payout(1, None)).unwrap();
+        assert!(matches!(
+            queue.push(&db, payout(2, None)),
+            Err(Error::QueueFull)
+        ));
+        assert_eq!(queue.depth(), (2, 2));
+    }
+
+    #[test]
+    fn pays_batches() {
+        let mut data = app_state(MockNode::default());
+        data.payout_queue = Some(PayoutQueue::new(10, 10, Duration::from_secs(60)));
+        let queue = data.payout_queue.as_ref().unwrap();
+        let first = queue.push(&data.db, payout(0, None)).unwrap();
+        let second = queue.push(&data.db, payout(1, None)).unwrap();
+
+        assert!(pay_batch(&data, queue));
+        let (Some(QueueStatus::Paid { txid, vout }), Some(QueueStatus::Paid { txid: other, .. })) =
+            (queue.status(&first), queue.status(&second))
+        else {
+            panic!("both are paid");
+        };
+        assert_eq!(txid, other);
+        assert!(vout < 3);
+        assert!(!pay_batch(&data, queue));
+    }
+
+    #[test]
+    fn restores_what_it_had() {
+        let (queue, db) = queue(10);
+        let ids = (0..4)
+            .map(|n| queue.push(&db, payout(n, None)).unwrap())
+            .collect::<Vec<_>>();
+        let txid = Txid::from_byte_array([1; 32]);
+
+        // we stopped while sending the second, and with the last two done
+        db.update_request(&ids[1], "sending").unwrap();
+        queue.finish(&db, ids[2].clone(), QueueStatus::Paid { txid, vout: 1 });
+        queue.finish(&db, ids[3].clone(), QueueStatus::Failed("no".into()));
+
+        let restored = PayoutQueue::new(10, 10, Duration::from_secs(60));
+        restored.restore(&db).unwrap();
+
+        assert_eq!(restored.depth().0, 2);
+        assert_eq!(next_batch(&restored, &ids), [0, 1]);
+        assert!(matches!(
+            restored.status(&ids[2]),
+            Some(QueueStatus::Paid { vout: 1, .. })
+        ));
+        assert!(matches!(
+            restored.status(&ids[3]),
+            Some(QueueStatus::Failed(reason)) if reason == "no"
+        ));
+    }
+
+    #[test]
+    fn fails_silent_payments_it_was_sending() {
+        let (queue, db) = queue(10);
+        let key = "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        let silent_payment =
+            serde_json::from_value(serde_json::json!({ "scan": key, "spend": key })).unwrap();
+        let id = queue
+            .push(
+                &db,
+                Payout {
+                    silent_payment,
+                    ..payout(0, None)
+                },
+            )
+            .unwrap();
+        db.update_request(&id, "sending").unwrap();
+
+        let restored = PayoutQueue::new(10, 10, Duration::from_secs(60));
+        restored.restore(&db).unwrap();
+        assert_eq!(restored.depth().0, 0);
+        assert!(matches!(restored.status(&id), Some(QueueStatus::Failed(_))));
+    }
+}
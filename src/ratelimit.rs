@@ -0,0 +1,491 @@
+//SPDX-License-Identifier: MIT
+
+//! Per-IP rate limiting, so a script can't drain the faucet in seconds. Each client gets two
+//! token buckets: one for requests and one for sats, both refilling continuously up to their
+//! hourly allowance. Clients with a partner or admin API key are limited by their key instead.
+//!
+//! Requests are counted by a middleware wrapping the routes that give money away, while sats
+//! are taken by the handlers themselves, once they know how much the request is for.
+//!
+//! The buckets are kept in memory, or in a Redis server if several faucets share the limits.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::IpAddr;
+#[cfg(feature = "redis")]
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use actix_web::dev::Service;
+use actix_web::dev::ServiceRequest;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::header::HeaderMap;
+use actix_web::http::header::HeaderName;
+use actix_web::http::header::HeaderValue;
+use actix_web::web;
+use actix_web::HttpRequest;
+use futures::future::Either;
+use tracing::warn;
+
+use crate::api::AppState;
+use crate::api::Error;
+use crate::apikeys::Scope;
+use crate::backend::ChainBackend;
+use crate::proxy::TrustedProxies;
+#[cfg(feature = "redis")]
+use crate::shared::SharedStore;
+
+/// A client that didn't make any requests for this long has full buckets, so we forget it
+const FORGET_AFTER: Duration = Duration::from_secs(3_600);
+
+struct Buckets {
+    requests: f64,
+    sats: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    requests_per_hour: Option<u64>,
+    sats_per_hour: Option<u64>,
+    /// Keyed by the client's IP, or by whatever else tells clients apart
+    clients: Mutex<HashMap<String, Buckets>>,
+    /// If set, the buckets live there instead of in `clients`
+    #[cfg(feature = "redis")]
+    shared: Option<Arc<SharedStore>>,
+}
+
+/// What's left of a client's hourly requests, for the `RateLimit-*` headers
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    pub limit: u64,
+    pub remaining: u64,
+    /// How long until the client has all of `limit` again
+    pub reset: Duration,
+}
+
+impl Quota {
+    /// The quota of a bucket refilling to `per_hour`, with `left` tokens in it
+    fn new(per_hour: u64, left: f64) -> Self {
+        let per_second = per_hour as f64 / 3_600.0;
+        Self {
+            limit: per_hour,
+            remaining: left as u64,
+            reset: Duration::from_secs_f64((per_hour as f64 - left).max(0.0) / per_second),
+        }
+    }
+}
+
+/// Takes `amount` tokens from a bucket refilling at `per_hour`, or tells how long until it has
+/// enough
+fn take(tokens: &mut f64, amount: f64, per_hour: Option<u64>) -> Result<(), Duration> {
+    let Some(per_hour) = per_hour else {
+        return Ok(());
+    };
+
+    if *tokens < amount {
+        let per_second = per_hour as f64 / 3_600.0;
+        return Err(Duration::from_secs_f64((amount - *tokens) / per_second));
+    }
+
+    *tokens -= amount;
+    Ok(())
+}
+
+impl RateLimiter {
+    /// How many requests each client may make, and how many sats it may get, per hour
+    pub fn limits(&self) -> (Option<u64>, Option<u64>) {
+        (self.requests_per_hour, self.sats_per_hour)
+    }
+
+    pub fn new(requests_per_hour: Option<u64>, sats_per_hour: Option<u64>) -> Self {
+        Self {
+            requests_per_hour,
+            sats_per_hour,
+            clients: Mutex::new(HashMap::new()),
+            #[cfg(feature = "redis")]
+            shared: None,
+        }
+    }
+
+    /// Creates a rate limiter keeping its buckets in `store`
+    #[cfg(feature = "redis")]
+    pub fn shared(
+        requests_per_hour: Option<u64>,
+        sats_per_hour: Option<u64>,
+        store: Arc<SharedStore>,
+    ) -> Self {
+        Self {
+            shared: Some(store),
+            ..Self::new(requests_per_hour, sats_per_hour)
+        }
+    }
+
+    /// Takes `amount` from `client`'s bucket called `bucket` in the shared store, if we have one,
+    /// returning how much is left in it
+    #[cfg(feature = "redis")]
+    fn take_shared(
+        &self,
+        client: &str,
+        bucket: &str,
+        amount: u64,
+        per_hour: Option<u64>,
+    ) -> Option<Result<Option<u64>, Error>> {
+        let store = self.shared.as_ref()?;
+        let Some(per_hour) = per_hour else {
+            return Some(Ok(None));
+        };
+
+        Some(
+            match store.take(&format!("{bucket}:{client}"), amount, per_hour) {
+                Ok(Ok(left)) => Ok(Some(left)),
+                Ok(Err(retry_after)) => Err(Error::RateLimited { retry_after }),
+                Err(e) => Err(e),
+            },
+        )
+    }
+
+    /// Runs `f` with `client`'s buckets, refilled for the time since we last looked at them
+    fn with_buckets<T>(&self, client: &str, f: impl FnOnce(&mut Buckets) -> T) -> T {
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|_, buckets| buckets.updated.elapsed() < FORGET_AFTER);
+
+        let max_requests = self.requests_per_hour.unwrap_or_default() as f64;
+        let max_sats = self.sats_per_hour.unwrap_or_default() as f64;
+        let buckets = clients.entry(client.to_string()).or_insert(Buckets {
+            requests: max_requests,
+            sats: max_sats,
+            updated: Instant::now(),
+        });
+
+        let hours = buckets.updated.elapsed().as_secs_f64() / 3_600.0;
+        buckets.requests = (buckets.requests + hours * max_requests).min(max_requests);
+        buckets.sats = (buckets.sats + hours * max_sats).min(max_sats);
+        buckets.updated = Instant::now();
+
+        f(buckets)
+    }
+
+    /// Counts one request from `client`, returning what's left of its quota if requests are
+    /// limited
+    pub fn take_request(&self, client: &str) -> Result<Option<Quota>, Error> {
+        #[cfg(feature = "redis")]
+        if let Some(taken) = self.take_shared(client, "requests", 1, self.requests_per_hour) {
+            return taken.map(|left| {
+                left.zip(self.requests_per_hour)
+                    .map(|(left, per_hour)| Quota::new(per_hour, left as f64))
+            });
+        }
+
+        self.with_buckets(client, |buckets| {
+            take(&mut buckets.requests, 1.0, self.requests_per_hour)?;
+            Ok(self
+                .requests_per_hour
+                .map(|per_hour| Quota::new(per_hour, buckets.requests)))
+        })
+        .map_err(|retry_after| Error::RateLimited { retry_after })
+    }
+
+    /// Takes `sats` from what `client` may get this hour
+    pub fn take_sats(&self, client: &str, sats: u64) -> Result<(), Error> {
+        #[cfg(feature = "redis")]
+        if let Some(taken) = self.take_shared(client, "sats", sats, self.sats_per_hour) {
+            return taken.map(|_| ());
+        }
+
+        self.with_buckets(client, |buckets| {
+            take(&mut buckets.sats, sats as f64, self.sats_per_hour)
+        })
+        .map_err(|retry_after| Error::RateLimited { retry_after })
+    }
+
+    /// Gives `sats` we took from `client` back, for payments that didn't go through
+    #[cfg_attr(not(feature = "lightning"), allow(dead_code))]
+    pub fn give_sats(&self, client: &str, sats: u64) -> Result<(), Error> {
+        let Some(per_hour) = self.sats_per_hour else {
+            return Ok(());
+        };
+
+        #[cfg(feature = "redis")]
+        if let Some(store) = &self.shared {
+            return store.give(&format!("sats:{client}"), sats, per_hour);
+        }
+
+        self.with_buckets(client, |buckets| {
+            buckets.sats = (buckets.sats + sats as f64).min(per_hour as f64);
+        });
+        Ok(())
+    }
+}
+
+/// The address of whoever made `req`. Behind a reverse proxy we trust, that's who it forwarded
+/// the request for, not the proxy itself
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr()?.ip();
+
+    Some(match req.app_data::<web::Data<TrustedProxies>>() {
+        Some(proxies) => proxies.client_ip(peer, req.headers()),
+        None => peer,
+    })
+}
+
+/// Takes `sats` from the client's allowance, if we're rate limiting
+pub fn take_sats<B: ChainBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    sats: u64,
+) -> Result<(), Error> {
+    let key = data.api_keys.authenticate(req)?;
+    if let Some(key) = key {
+        key.take_sats(sats)?;
+        if key.scope >= Scope::Partner {
+            return Ok(());
+        }
+    }
+
+    match (&data.rate_limiter, client_ip(req)) {
+        (Some(limiter), Some(ip)) if !data.access.is_allowed(ip) => {
+            limiter.take_sats(&ip.to_string(), sats)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Gives `sats` that [take_sats] took back to the client's allowance, once paying them failed
+#[cfg(feature = "lightning")]
+pub fn give_sats_back<B: ChainBackend>(req: &HttpRequest, data: &AppState<B>, sats: u64) {
+    let key = data.api_keys.authenticate(req).ok().flatten();
+    if let Some(key) = key {
+        if let Err(e) = key.give_sats(sats) {
+            warn!("couldn't give {sats} sats back to key {}: {e}", key.name);
+        }
+        if key.scope >= Scope::Partner {
+            return;
+        }
+    }
+
+    match (&data.rate_limiter, client_ip(req)) {
+        (Some(limiter), Some(ip)) if !data.access.is_allowed(ip) => {
+            if let Err(e) = limiter.give_sats(&ip.to_string(), sats) {
+                warn!("couldn't give {sats} sats back to {ip}: {e}");
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Counts a request against the client's allowance, if we're rate limiting. Returns what's left
+/// of it, or of its key's if that's tighter
+pub fn take_request<B: ChainBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+) -> Result<Option<Quota>, Error> {
+    let key = data.api_keys.authenticate(req)?;
+    let mut quota = None;
+    if let Some(key) = key {
+        quota = key.take_request()?;
+        if key.scope >= Scope::Partner {
+            return Ok(quota);
+        }
+    }
+
+    let ip_quota = match (&data.rate_limiter, client_ip(req)) {
+        (Some(limiter), Some(ip)) if !data.access.is_allowed(ip) => {
+            limiter.take_request(&ip.to_string())?
+        }
+        _ => None,
+    };
+
+    Ok(match (quota, ip_quota) {
+        (Some(quota), Some(ip_quota)) if quota.remaining < ip_quota.remaining => Some(quota),
+        (quota, ip_quota) => ip_quota.or(quota),
+    })
+}
+
+/// The most requests per hour the limits for `req`'s client let it make, if there's a limit
+fn request_limit<B: ChainBackend>(req: &HttpRequest, data: &AppState<B>) -> Option<u64> {
+    let key = data.api_keys.authenticate(req).ok().flatten();
+    let key_limit = key.and_then(|key| key.requests_per_hour());
+    if key.is_some_and(|key| key.scope >= Scope::Partner) {
+        return key_limit;
+    }
+
+    let ip_limit = data
+        .rate_limiter
+        .as_ref()
+        .and_then(|limiter| limiter.requests_per_hour)
+        .filter(|_| client_ip(req).is_some_and(|ip| !data.access.is_allowed(ip)));
+    match (key_limit, ip_limit) {
+        (Some(key_limit), Some(ip_limit)) => Some(key_limit.min(ip_limit)),
+        (key_limit, ip_limit) => key_limit.or(ip_limit),
+    }
+}
+
+/// Tells the client its quota with the `RateLimit-Limit`, `RateLimit-Remaining` and
+/// `RateLimit-Reset` headers from the IETF draft, so it can slow down before it gets a 429
+fn set_quota_headers(headers: &mut HeaderMap, quota: Quota) {
+    let reset = quota.reset.as_secs_f64().ceil() as u64;
+    for (name, value) in [
+        ("ratelimit-limit", quota.limit),
+        ("ratelimit-remaining", quota.remaining),
+        ("ratelimit-reset", reset),
+    ] {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+/// Counts a request we turned down towards the daily report
+fn record_rejection<B: ChainBackend>(data: &AppState<B>, reason: &str) {
+    if let Err(e) = data.db.record_rejection(reason) {
+        warn!("couldn't record a rejection: {e}");
+    }
+}
+
+/// A middleware counting requests, for `wrap_fn`. Clients that made too many get a 429, and
+/// every answer tells what's left of the client's quota
+pub fn limit_requests<B: ChainBackend, S>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse, Error = actix_web::Error>,
+{
+    let Some(data) = req.app_data::<web::Data<AppState<B>>>().cloned() else {
+        return Either::Left(srv.call(req));
+    };
+
+    match take_request(req.request(), &data) {
+        Ok(quota) => {
+            let response = srv.call(req);
+            Either::Right(Either::Left(async move {
+                let mut response = response.await?;
+                if let Some(error) = response.response().error() {
+                    record_rejection(
+                        &data,
+                        error.as_error::<Error>().map_or("other", Error::code),
+                    );
+                }
+                if let Some(quota) = quota {
+                    set_quota_headers(response.headers_mut(), quota);
+                }
+                Ok(response)
+            }))
+        }
+        Err(e) => {
+            record_rejection(&data, e.code());
+            // they may try again once there's a request in the bucket
+            let quota = match &e {
+                Error::RateLimited { retry_after } => {
+                    request_limit(req.request(), &data).map(|limit| Quota {
+                        limit,
+                        remaining: 0,
+                        reset: *retry_after,
+                    })
+                }
+                _ => None,
+            };
+            let mut response = req.error_response(e);
+            if let Some(quota) = quota {
+                set_quota_headers(response.headers_mut(), quota);
+            }
+            Either::Right(Either::Right(std::future::ready(Ok(response))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+    use crate::testing::app_state;
+    use crate::testing::MockNode;
+
+    #[test]
+    fn buckets_run_out() {
+        let mut tokens = 2.0;
+        assert!(take(&mut tokens, 1.0, Some(3_600)).is_ok());
+        assert!(take(&mut tokens, 1.0, Some(3_600)).is_ok());
+
+        // a token a second
+        let retry_after = take(&mut tokens, 1.0, Some(3_600)).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(1));
+        assert_eq!(tokens, 0.0);
+
+        // without a limit, there's always enough
+        assert!(take(&mut tokens, 1_000.0, None).is_ok());
+    }
+
+    #[test]
+    fn tells_when_the_quota_is_full_again() {
+        let quota = Quota::new(60, 30.0);
+        assert_eq!(quota.limit, 60);
+        assert_eq!(quota.remaining, 30);
+        assert_eq!(quota.reset, Duration::from_secs(1_800));
+
+        assert_eq!(Quota::new(60, 60.0).reset, Duration::ZERO);
+    }
+
+    #[test]
+    fn limits_requests_per_client() {
+        let limiter = RateLimiter::new(Some(2), None);
+
+        let quota = limiter.take_request("alice").unwrap().unwrap();
+        assert_eq!((quota.limit, quota.remaining), (2, 1));
+        limiter.take_request("alice").unwrap();
+        assert!(matches!(
+            limiter.take_request("alice"),
+            Err(Error::RateLimited { .. })
+        ));
+
+        // bob's bucket is full
+        assert!(limiter.take_request("bob").is_ok());
+        // and sats aren't limited
+        assert!(limiter.take_sats("alice", u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn limits_sats_per_client() {
+        let limiter = RateLimiter::new(None, Some(10_000));
+
+        limiter.take_sats("alice", 6_000).unwrap();
+        assert!(matches!(
+            limiter.take_sats("alice", 6_000),
+            Err(Error::RateLimited { .. })
+        ));
+        limiter.take_sats("alice", 4_000).unwrap();
+        assert!(limiter.take_request("alice").unwrap().is_none());
+    }
+
+    #[test]
+    fn gives_sats_back() {
+        let limiter = RateLimiter::new(None, Some(10_000));
+
+        limiter.take_sats("alice", 10_000).unwrap();
+        limiter.give_sats("alice", 10_000).unwrap();
+        limiter.take_sats("alice", 10_000).unwrap();
+
+        // but never more than the allowance
+        limiter.give_sats("alice", 50_000).unwrap();
+        assert!(limiter.take_sats("alice", 10_001).is_err());
+    }
+
+    #[test]
+    fn limits_clients_by_ip() {
+        let mut data = app_state(MockNode::default());
+        data.rate_limiter = Some(RateLimiter::new(Some(1), None));
+        let req = |ip: &str| {
+            TestRequest::default()
+                .peer_addr(format!("{ip}:1234").parse().unwrap())
+                .to_http_request()
+        };
+
+        assert!(take_request(&req("192.0.2.1"), &data).is_ok());
+        assert!(matches!(
+            take_request(&req("192.0.2.1"), &data),
+            Err(Error::RateLimited { .. })
+        ));
+        assert!(take_request(&req("192.0.2.2"), &data).is_ok());
+    }
+}
@@ -0,0 +1,78 @@
+//SPDX-License-Identifier: MIT
+
+//! Closes channels whose peer has been gone for too long. People often ask for a channel, play
+//! with it for a while and then throw their node away, leaving our sats stuck in the channel.
+//! We only know for how long a channel has been inactive since we started, so a restart resets
+//! the count.
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::time::Duration;
+use std::time::Instant;
+
+use actix_web::web;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::AppState;
+use crate::backend::ChainBackend;
+
+/// How often we look for inactive channels
+const CHECK_INTERVAL: Duration = Duration::from_secs(3_600);
+
+/// Periodically force closes channels that have been inactive for longer than `after`
+pub fn spawn_reclaimer<B: ChainBackend>(data: web::Data<AppState<B>>, after: Duration) {
+    actix::spawn(async move {
+        // when we first saw each channel inactive
+        let mut inactive_since = HashMap::<String, Instant>::new();
+        // the channels we already closed, which may stick around while they're closing
+        let mut closed = HashSet::<String>::new();
+
+        loop {
+            match data.lightning.list_channels().await {
+                Ok(channels) => {
+                    let listed = channels
+                        .iter()
+                        .map(|channel| channel.channel_id.clone())
+                        .collect::<HashSet<_>>();
+                    inactive_since.retain(|id, _| listed.contains(id));
+                    closed.retain(|id| listed.contains(id));
+
+                    for channel in channels {
+                        if channel.active {
+                            inactive_since.remove(&channel.channel_id);
+                            continue;
+                        }
+
+                        let since = *inactive_since
+                            .entry(channel.channel_id.clone())
+                            .or_insert_with(Instant::now);
+                        if since.elapsed() < after || closed.contains(&channel.channel_id) {
+                            continue;
+                        }
+
+                        match data
+                            .lightning
+                            .close_channel(&channel.channel_id, true)
+                            .await
+                        {
+                            Ok(_) => {
+                                info!(
+                                    "closed channel {} with {}, it was inactive for too long",
+                                    channel.channel_id, channel.peer
+                                );
+                                closed.insert(channel.channel_id);
+                            }
+                            Err(e) => {
+                                warn!("couldn't close channel {}: {e}", channel.channel_id)
+                            }
+                        }
+                    }
+                }
+                Err(e) => warn!("couldn't list our channels to reclaim them: {e}"),
+            }
+
+            actix::clock::sleep(CHECK_INTERVAL).await;
+        }
+    });
+}
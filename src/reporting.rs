@@ -0,0 +1,84 @@
+//SPDX-License-Identifier: MIT
+
+//! Reports what breaks to Sentry, so operators hear about it before users complain. Panics are
+//! reported as they happen, and so are our own failures answering a request, like bitcoind not
+//! answering: anything the access log sees us answer with a 500, tagged with the request's id,
+//! route and error code, and with its method and url. What we log at `error` is reported too,
+//! and what we logged before it comes along as breadcrumbs.
+//!
+//! Client IPs and headers aren't sent, so user reports stay in our own logs.
+
+use std::borrow::Cow;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::http::StatusCode;
+use sentry::protocol::Event;
+use sentry::protocol::Request;
+use sentry::ClientInitGuard;
+
+use crate::api::Error;
+
+/// Starts reporting to `dsn`, until the guard is dropped. Reports are tagged with
+/// `environment`, if set, to tell faucets apart
+pub fn init(dsn: &str, environment: Option<String>) -> anyhow::Result<ClientInitGuard> {
+    Ok(sentry::init(sentry::ClientOptions {
+        dsn: Some(dsn.parse()?),
+        release: sentry::release_name!(),
+        environment: environment.map(Cow::Owned),
+        ..Default::default()
+    }))
+}
+
+/// What we tell Sentry about the request a failure happened in, taken before answering it
+pub struct RequestInfo {
+    id: String,
+    route: String,
+    request: Request,
+}
+
+impl RequestInfo {
+    pub fn new(req: &ServiceRequest, id: String) -> Self {
+        let connection = req.connection_info();
+        let url = format!(
+            "{}://{}{}",
+            connection.scheme(),
+            connection.host(),
+            req.path()
+        );
+
+        Self {
+            id,
+            route: req
+                .match_pattern()
+                .unwrap_or_else(|| req.path().to_string()),
+            request: Request {
+                url: url.parse().ok(),
+                method: Some(req.method().to_string()),
+                query_string: Some(req.query_string().to_string())
+                    .filter(|query| !query.is_empty()),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Reports `error`, if it's one of our own failures, which we answer with a 500
+pub fn report_failure(info: RequestInfo, error: &actix_web::Error) {
+    if error.as_response_error().status_code() != StatusCode::INTERNAL_SERVER_ERROR {
+        return;
+    }
+
+    let mut event: Event = match error.as_error::<Error>() {
+        Some(error) => {
+            let mut event = sentry::event_from_error(error);
+            event.tags.insert("code".into(), error.code().into());
+            event
+        }
+        None => sentry::event_from_error(error),
+    };
+
+    event.tags.insert("request_id".into(), info.id);
+    event.transaction = Some(info.route);
+    event.request = Some(info.request);
+    sentry::capture_event(event);
+}
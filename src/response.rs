@@ -0,0 +1,349 @@
+//SPDX-License-Identifier: MIT
+
+//! What our routes answer with. Everything but the index page and the LNURL routes, which
+//! follow their own spec, answers with JSON: the models here on success, and an [ErrorBody] on
+//! failure, whose `code` scripts can match on and whose `message` is meant for people.
+//!
+//! Amounts are in sats.
+
+use bitcoin::Txid;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::accesslog;
+use crate::admin::Mode;
+use crate::tiers::TierInfo;
+
+/// What failed routes answer with
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+    /// The id we logged the request with, as in the `X-Request-Id` header, to tell us when
+    /// reporting a problem
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorDetail {
+    /// What went wrong, like `amount_too_large`
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ErrorBody {
+    pub fn new(code: &'static str, message: String) -> Self {
+        Self {
+            error: ErrorDetail { code, message },
+            request_id: accesslog::request_id(),
+        }
+    }
+}
+
+/// What /send/ answers with, depending on how we pay it
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(untagged)]
+pub enum SendResponse {
+    /// We paid it right away
+    Sent {
+        #[schema(value_type = String)]
+        txid: Txid,
+        /// The output paying the address, unless we paid a batch
+        #[serde(skip_serializing_if = "Option::is_none")]
+        vout: Option<u32>,
+        /// The outputs paying each address, in the order they were given
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        vouts: Vec<u32>,
+        amount: u64,
+        fee: u64,
+    },
+    /// It's waiting in the payout queue, GET /queue/{request_id} tells how it's doing
+    Queued { request_id: String },
+    /// It's paid once the user pays this hold invoice, GET /send/{payment_hash} tells how it's
+    /// doing
+    #[cfg(feature = "lightning")]
+    HoldInvoice {
+        invoice: String,
+        #[schema(value_type = String)]
+        payment_hash: bitcoin::hashes::sha256::Hash,
+        /// A link to the invoice's QR code
+        qr: String,
+    },
+}
+
+/// How a queued or hold-invoice gated payout is doing
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PayoutProgress {
+    /// The hold invoice isn't paid yet
+    #[cfg(feature = "lightning")]
+    WaitingForPayment,
+    /// Behind `ahead` other requests
+    Queued {
+        ahead: usize,
+    },
+    Paid {
+        #[schema(value_type = String)]
+        txid: Txid,
+        /// The output paying the address, in the transaction it shares with other queued
+        /// requests
+        #[serde(skip_serializing_if = "Option::is_none")]
+        vout: Option<u32>,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+/// How one of our payouts is doing
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TxStatus {
+    InMempool,
+    Confirmed {
+        #[schema(value_type = String)]
+        block_hash: bitcoin::BlockHash,
+        height: u32,
+        confirmations: u32,
+    },
+    /// Something spending the same coins took its place
+    Replaced {
+        #[schema(value_type = Option<String>)]
+        replaced_by: Option<Txid>,
+    },
+    /// It's gone from the mempool without confirming
+    Evicted,
+}
+
+/// What users may ask for and what the faucet can do, for clients to set themselves up with
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Info {
+    /// Like `signet`
+    #[schema(value_type = String)]
+    pub network: bitcoin::Network,
+    /// Whether we take requests right now
+    pub mode: Mode,
+    pub min_sendable: u64,
+    /// The most anonymous users may get, others may get more depending on their tier
+    pub max_sendable: u64,
+    /// The tiers users may get into, lowest first
+    pub tiers: Vec<TierInfo>,
+    /// Whether /send/ takes proofs of work, from /challenge
+    pub proof_of_work: bool,
+    /// Whether /send/ takes captchas
+    pub captcha: bool,
+    /// What we give out in a rolling day at most, if there's a limit
+    pub daily_budget: Option<u64>,
+    pub rate_limits: RateLimits,
+    pub cooldowns: CooldownInfo,
+    /// How many addresses /send/batch pays at once, if it's enabled
+    pub max_batch_outputs: Option<usize>,
+    /// How many confirmations a payout gets before we call back about it, if /send/ takes a
+    /// `callback_url`
+    pub callback_confirmations: Option<u32>,
+    /// Set if /send/ queues requests instead of paying them right away
+    pub queue: Option<QueueInfo>,
+    /// Whether we're built with Lightning, and so have the Lightning routes
+    pub lightning: bool,
+    #[cfg(feature = "lightning")]
+    pub channels: ChannelPolicy,
+    /// Whether /send/ answers with a hold invoice to pay first
+    #[cfg(feature = "lightning")]
+    pub hold_invoices: bool,
+}
+
+/// How much each client may ask for per hour, if there's a limit
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RateLimits {
+    pub requests_per_hour: Option<u64>,
+    pub sats_per_hour: Option<u64>,
+}
+
+/// How long, in seconds, users wait before getting paid again, if at all
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CooldownInfo {
+    /// Before the same address gets paid again
+    pub address: Option<u64>,
+    /// Before the same node gets another channel
+    #[cfg(feature = "lightning")]
+    pub channel: Option<u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct QueueInfo {
+    /// How many requests are waiting
+    pub depth: usize,
+    /// How many requests may wait
+    pub capacity: usize,
+}
+
+/// What channels /channel/ opens
+#[cfg(feature = "lightning")]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChannelPolicy {
+    pub min_capacity: u64,
+    pub max_capacity: u64,
+    pub default_capacity: u64,
+    pub default_push: u64,
+    pub max_push: u64,
+    pub zero_conf: bool,
+}
+
+/// What /health answers with, as long as we're up
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Health {
+    pub status: &'static str,
+}
+
+/// Whether we can serve requests, and how each of the services we depend on is doing
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    pub status: ReadyStatus,
+    pub chain: Dependency,
+    pub database: Dependency,
+    #[cfg(feature = "lightning")]
+    pub lightning: Dependency,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReadyStatus {
+    /// Everything works
+    Ready,
+    /// We can make on-chain payouts, but Lightning is down
+    #[cfg(feature = "lightning")]
+    Degraded,
+    /// We can't make payouts
+    Unavailable,
+}
+
+/// How a service we depend on is doing
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Dependency {
+    pub ok: bool,
+    /// The tip's height, as the service sees it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_height: Option<u32>,
+    /// Why it isn't ok
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Dependency {
+    pub fn new<T: Into<Option<u32>>, E: ToString>(probe: Result<T, E>) -> Self {
+        match probe {
+            Ok(block_height) => Self {
+                ok: true,
+                block_height: block_height.into(),
+                error: None,
+            },
+            Err(e) => Self {
+                ok: false,
+                block_height: None,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+/// What fees look like, for integrators to tell when payouts may confirm
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Fees {
+    /// How many blocks `feerate` is for
+    pub target: u16,
+    /// The feerate to confirm within `target` blocks, in sat/vB, if the backend knows it
+    pub feerate: Option<u64>,
+    /// What a payout to one address pays in fees right now, in sats
+    pub payout_fee: u64,
+}
+
+/// Where to send coins to refill the faucet
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Donation {
+    /// A fresh address, unless our backend only has the one
+    pub address: String,
+    /// An invoice for the `amount` asked for, if it was and we have Lightning
+    pub invoice: Option<String>,
+    /// Our BOLT12 offer for any amount, if our Lightning node makes those
+    pub offer: Option<String>,
+    /// A BIP21 URI with all of the above, for wallets to pay however they can
+    pub uri: String,
+    /// A link to the URI's QR code
+    pub qr: String,
+}
+
+/// A page of our past payouts, newest first
+#[derive(Debug, Serialize, ToSchema)]
+pub struct History {
+    pub page: u32,
+    pub limit: u32,
+    /// How many payouts there are on all pages
+    pub total: u64,
+    pub payouts: Vec<PastPayout>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PastPayout {
+    /// Cut short, like `tb1qxy2k…0wlh`
+    pub address: String,
+    pub amount: u64,
+    #[schema(value_type = String)]
+    pub txid: Txid,
+    /// Unix time
+    pub created_at: u64,
+}
+
+/// What we have to give out
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Balance {
+    /// What our wallet can spend
+    pub onchain: u64,
+    #[cfg(feature = "lightning")]
+    pub lightning: LightningBalance,
+}
+
+/// What our active channels can send and receive, not counting their reserves
+#[cfg(feature = "lightning")]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct LightningBalance {
+    pub spendable: u64,
+    pub receivable: u64,
+}
+
+/// A channel we opened or grew, as our node identifies it
+#[cfg(feature = "lightning")]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Channel {
+    pub channel: String,
+}
+
+/// A channel we closed
+#[cfg(feature = "lightning")]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClosedChannel {
+    /// The closing transaction, if our node tells us
+    pub txid: String,
+}
+
+/// A Lightning payment we made
+#[cfg(feature = "lightning")]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Payment {
+    /// Hex encoded
+    pub preimage: String,
+}
+
+/// Our BOLT12 offer, for refills
+#[cfg(feature = "lightning")]
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Offer {
+    pub offer: String,
+    /// A link to the offer's QR code
+    pub qr: String,
+}
+
+/// An access rule we removed
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Removed {
+    pub removed: i64,
+}
@@ -0,0 +1,320 @@
+//SPDX-License-Identifier: MIT
+
+//! Retrying calls to our nodes. A node that's restarting, still loading its block index or just
+//! slow to answer shouldn't fail a payout, so we try again a few times before giving up, waiting
+//! twice as long every time. The waits are a bit random, so faucets sharing a node don't all
+//! come back at once.
+//!
+//! We only try again when the node didn't answer. An error it answered with would be the same
+//! the next time, and a call it never answered may still have gone through, so those that
+//! can't be made twice, like payments, get a single try.
+//!
+//! Lightning nodes we keep a connection to are behind a [Reconnecting], which connects again
+//! on the next call once a call goes unanswered, as the connection may be what's broken.
+
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use bitcoin::secp256k1::rand;
+use bitcoin::secp256k1::rand::Rng;
+use futures::future::LocalBoxFuture;
+use futures::lock::Mutex;
+use tracing::warn;
+
+/// How many times we try a call before giving up on it
+pub const ATTEMPTS: u32 = 4;
+
+/// How long we wait after the first failure, before the jitter
+const FIRST_BACKOFF: Duration = Duration::from_millis(250);
+
+/// How long to wait before trying again, after `attempt` failed, counting from zero. Somewhere
+/// between the backoff and twice that
+pub fn backoff(attempt: u32) -> Duration {
+    let backoff = FIRST_BACKOFF * 2u32.pow(attempt);
+    backoff + backoff.mul_f64(rand::thread_rng().gen_range(0.0..1.0))
+}
+
+/// How a call to a node failed
+#[derive(Debug)]
+#[cfg_attr(
+    not(any(feature = "ln", feature = "lnd", feature = "eclair")),
+    allow(dead_code)
+)]
+pub enum Failure<E> {
+    /// The node answered with an error, asking again won't change its mind
+    Answered(E),
+    /// We didn't hear back, because we couldn't reach the node or it took too long
+    Unanswered(E),
+}
+
+#[cfg_attr(
+    not(any(feature = "ln", feature = "lnd", feature = "eclair")),
+    allow(dead_code)
+)]
+impl<E> Failure<E> {
+    pub fn into_inner(self) -> E {
+        match self {
+            Failure::Answered(e) | Failure::Unanswered(e) => e,
+        }
+    }
+}
+
+/// Makes `call` to `node` until it's answered, waiting [backoff] in between, up to [ATTEMPTS]
+/// times. Calls that aren't `idempotent` are only made once
+#[cfg_attr(
+    not(any(feature = "ln", feature = "lnd", feature = "eclair")),
+    allow(dead_code)
+)]
+pub async fn with_backoff<T, E, F, Fut>(node: &str, idempotent: bool, mut call: F) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Failure<E>>>,
+{
+    let attempts = if idempotent { ATTEMPTS } else { 1 };
+    let mut attempt = 0;
+    loop {
+        match call().await {
+            Ok(result) => return Ok(result),
+            Err(Failure::Unanswered(e)) if attempt + 1 < attempts => {
+                warn!("{node} didn't answer, trying again: {e}");
+                actix::clock::sleep(backoff(attempt)).await;
+                attempt += 1;
+            }
+            Err(failure) => return Err(failure.into_inner()),
+        }
+    }
+}
+
+/// A connection to a node, opened again on the next call after one went unanswered. Calls take
+/// turns using it, without holding up the thread they run on
+#[cfg_attr(not(feature = "ln"), allow(dead_code))]
+pub struct Reconnecting<C, E> {
+    connection: Mutex<Option<C>>,
+    /// Whether we shut down, and shouldn't connect again
+    closed: AtomicBool,
+    /// What calls fail with once we shut down
+    closed_error: fn() -> E,
+}
+
+#[cfg_attr(not(feature = "ln"), allow(dead_code))]
+impl<C, E> Reconnecting<C, E> {
+    pub fn new(connection: C, closed_error: fn() -> E) -> Self {
+        Self {
+            connection: Mutex::new(Some(connection)),
+            closed: AtomicBool::new(false),
+            closed_error,
+        }
+    }
+
+    /// Makes `call` with our connection, opening one with `connect` if we don't have it. We drop
+    /// the connection if the call goes unanswered
+    pub async fn call<T, Fut>(
+        &self,
+        connect: impl FnOnce() -> Fut,
+        call: impl for<'c> FnOnce(&'c mut C) -> LocalBoxFuture<'c, Result<T, Failure<E>>>,
+    ) -> Result<T, Failure<E>>
+    where
+        Fut: Future<Output = Result<C, E>>,
+    {
+        let mut connection = self.connection.lock().await;
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(Failure::Answered((self.closed_error)()));
+        }
+        // the node may have restarted since our connection dropped
+        let conn = match connection.as_mut() {
+            Some(conn) => conn,
+            None => connection.insert(connect().await.map_err(Failure::Unanswered)?),
+        };
+
+        let result = call(conn).await;
+        if let Err(Failure::Unanswered(_)) = result {
+            connection.take();
+        }
+
+        result
+    }
+
+    /// Drops our connection once the call using it is done, and doesn't open another
+    pub async fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        self.connection.lock().await.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// Makes a call failing with `failures`, in order, and then answering, counting the attempts
+    async fn call(
+        idempotent: bool,
+        failures: Vec<Failure<&'static str>>,
+        attempts: &Cell<u32>,
+    ) -> Result<u32, &'static str> {
+        let mut failures = failures.into_iter();
+        with_backoff("node", idempotent, || {
+            attempts.set(attempts.get() + 1);
+            let result = failures.next().map_or(Ok(attempts.get()), Err);
+            async move { result }
+        })
+        .await
+    }
+
+    #[actix_web::test]
+    async fn retries_until_answered() {
+        let attempts = Cell::new(0);
+        let failures = vec![
+            Failure::Unanswered("refused"),
+            Failure::Unanswered("timeout"),
+        ];
+
+        assert_eq!(call(true, failures, &attempts).await, Ok(3));
+    }
+
+    #[actix_web::test]
+    async fn gives_up_after_every_attempt() {
+        let attempts = Cell::new(0);
+        let failures = (0..ATTEMPTS)
+            .map(|_| Failure::Unanswered("refused"))
+            .collect();
+
+        assert_eq!(call(true, failures, &attempts).await, Err("refused"));
+        assert_eq!(attempts.get(), ATTEMPTS);
+    }
+
+    #[actix_web::test]
+    async fn doesnt_retry_answered_errors() {
+        let attempts = Cell::new(0);
+        let failures = vec![Failure::Answered("no route")];
+
+        assert_eq!(call(true, failures, &attempts).await, Err("no route"));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[actix_web::test]
+    async fn makes_calls_that_arent_idempotent_once() {
+        let attempts = Cell::new(0);
+        let failures = vec![Failure::Unanswered("timeout")];
+
+        assert_eq!(call(false, failures, &attempts).await, Err("timeout"));
+        assert_eq!(attempts.get(), 1);
+    }
+
+    /// A connection failing with `failures`, and then answering with its id
+    struct Connection {
+        id: u32,
+        failures: Vec<Failure<&'static str>>,
+    }
+
+    fn node(failures: Vec<Failure<&'static str>>) -> Reconnecting<Connection, &'static str> {
+        Reconnecting::new(Connection { id: 0, failures }, || "closed")
+    }
+
+    /// Calls `node`, counting how many times we connected, each connection getting the next id,
+    /// and how many times we made the call
+    async fn call_node(
+        node: &Reconnecting<Connection, &'static str>,
+        idempotent: bool,
+        connects: &Cell<u32>,
+        calls: &Cell<u32>,
+    ) -> Result<u32, &'static str> {
+        with_backoff("node", idempotent, || {
+            node.call(
+                || async {
+                    connects.set(connects.get() + 1);
+                    Ok(Connection {
+                        id: connects.get(),
+                        failures: vec![],
+                    })
+                },
+                |conn| {
+                    calls.set(calls.get() + 1);
+                    Box::pin(async move { conn.failures.pop().map_or(Ok(conn.id), Err) })
+                },
+            )
+        })
+        .await
+    }
+
+    #[actix_web::test]
+    async fn reconnects_after_an_unanswered_call() {
+        let node = node(vec![Failure::Unanswered("timeout")]);
+        let (connects, calls) = (Cell::new(0), Cell::new(0));
+
+        assert_eq!(call_node(&node, true, &connects, &calls).await, Ok(1));
+        assert_eq!((connects.get(), calls.get()), (1, 2));
+
+        // the new connection is kept
+        assert_eq!(call_node(&node, true, &connects, &calls).await, Ok(1));
+        assert_eq!(connects.get(), 1);
+    }
+
+    #[actix_web::test]
+    async fn keeps_the_connection_after_an_answered_error() {
+        let node = node(vec![Failure::Answered("no route")]);
+        let (connects, calls) = (Cell::new(0), Cell::new(0));
+
+        assert_eq!(
+            call_node(&node, true, &connects, &calls).await,
+            Err("no route")
+        );
+        assert_eq!(call_node(&node, true, &connects, &calls).await, Ok(0));
+        assert_eq!((connects.get(), calls.get()), (0, 2));
+    }
+
+    #[actix_web::test]
+    async fn reconnects_after_a_call_made_once() {
+        let node = node(vec![Failure::Unanswered("timeout")]);
+        let (connects, calls) = (Cell::new(0), Cell::new(0));
+
+        assert_eq!(
+            call_node(&node, false, &connects, &calls).await,
+            Err("timeout")
+        );
+        assert_eq!((connects.get(), calls.get()), (0, 1));
+
+        assert_eq!(call_node(&node, false, &connects, &calls).await, Ok(1));
+    }
+
+    #[actix_web::test]
+    async fn retries_connecting() {
+        let node = node(vec![Failure::Unanswered("broken pipe")]);
+        let connects = RefCell::new(vec![Err("refused"), Ok(7)]);
+
+        let result = with_backoff("node", true, || {
+            node.call(
+                || async {
+                    connects.borrow_mut().remove(0).map(|id| Connection {
+                        id,
+                        failures: vec![],
+                    })
+                },
+                |conn| Box::pin(async move { conn.failures.pop().map_or(Ok(conn.id), Err) }),
+            )
+        })
+        .await;
+
+        assert_eq!(result, Ok(7));
+        assert!(connects.borrow().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn doesnt_connect_once_closed() {
+        let node = node(vec![Failure::Unanswered("broken pipe")]);
+        let (connects, calls) = (Cell::new(0), Cell::new(0));
+        node.close().await;
+
+        assert_eq!(
+            call_node(&node, true, &connects, &calls).await,
+            Err("closed")
+        );
+        assert_eq!((connects.get(), calls.get()), (0, 0));
+    }
+}
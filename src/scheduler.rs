@@ -0,0 +1,361 @@
+//SPDX-License-Identifier: MIT
+
+//! Running the jobs that keep the faucet in shape: bumping and speeding up stuck transactions,
+//! consolidating our coins, noting when payouts confirm and reporting on the day. Each job runs
+//! on a schedule, either every so often, like `10m`, or on a cron expression, like `0 3 * * *`,
+//! in UTC. Operators may change them with `JOB_SCHEDULES`, `job=schedule` pairs separated by `;`.
+//!
+//! Jobs talk to our node, so they run on actix's blocking pool, and never overlap with
+//! themselves. We keep count of how they went, for admins to see at /admin/jobs. Once we're
+//! asked to stop, we don't start any more, and wait for those running to be done.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use actix_web::web;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::api::blocking;
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+use crate::db::now;
+use crate::stats::civil;
+use crate::stats::DAY;
+
+/// How far ahead we look for when a cron expression matches, a few leap years' worth
+const MAX_LOOKAHEAD: u64 = 4 * 366 * DAY;
+
+/// When a job runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Schedule {
+    Every(Duration),
+    Cron(Cron),
+}
+
+/// A cron expression: minute, hour, day of the month, month and day of the week, each a set of
+/// the values it matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+}
+
+/// Parses a cron field, `*`, a value, a range like `1-5` or a list of those, each maybe with a
+/// step like `*/15`, into the set of values from `min` to `max` it matches
+fn parse_field(field: &str, min: u64, max: u64) -> Option<u64> {
+    let mut set = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok().filter(|step| *step > 0)?),
+            None => (part, 1),
+        };
+        let (first, last) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((first, last)) => (first.parse().ok()?, last.parse().ok()?),
+                None => {
+                    let value = range.parse().ok()?;
+                    // `5/10` means from 5 on, every 10
+                    (value, if step > 1 { max } else { value })
+                }
+            },
+        };
+        if first < min || last > max || first > last {
+            return None;
+        }
+        for value in (first..=last).step_by(step as usize) {
+            set |= 1 << value;
+        }
+    }
+
+    Some(set)
+}
+
+impl FromStr for Cron {
+    type Err = String;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields = expression.split_whitespace().collect::<Vec<_>>();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("{expression} doesn't have 5 fields"));
+        };
+        let invalid = |field: &str| format!("invalid field {field} in {expression}");
+
+        Ok(Cron {
+            expression: expression.to_string(),
+            minutes: parse_field(minutes, 0, 59).ok_or_else(|| invalid(minutes))?,
+            hours: parse_field(hours, 0, 23).ok_or_else(|| invalid(hours))?,
+            days: parse_field(days, 1, 31).ok_or_else(|| invalid(days))?,
+            months: parse_field(months, 1, 12).ok_or_else(|| invalid(months))?,
+            // both 0 and 7 are sunday
+            weekdays: parse_field(weekdays, 0, 7)
+                .map(|set| (set | set >> 7) & 0x7f)
+                .ok_or_else(|| invalid(weekdays))?,
+        })
+    }
+}
+
+impl Cron {
+    /// Whether the day `time` falls on matches. Like cron, if both the day of the month and of
+    /// the week are restricted, either will do
+    fn matches_day(&self, time: u64) -> bool {
+        let (_, month, day) = civil(time);
+        // the epoch was a thursday
+        let weekday = (time / DAY + 4) % 7;
+
+        let day_matches = self.days & 1 << day != 0;
+        let weekday_matches = self.weekdays & 1 << weekday != 0;
+        let day_matches = match (self.days.count_ones(), self.weekdays.count_ones()) {
+            (31, _) => weekday_matches,
+            (_, 7) => day_matches,
+            _ => day_matches || weekday_matches,
+        };
+
+        day_matches && self.months & 1 << month != 0
+    }
+
+    /// The first minute after `time`, in unix time, the expression matches, if any soon
+    fn next_after(&self, time: u64) -> Option<u64> {
+        let mut next = (time / 60 + 1) * 60;
+        while next < time + MAX_LOOKAHEAD {
+            if !self.matches_day(next) {
+                next = (next / DAY + 1) * DAY;
+                continue;
+            }
+            if self.hours & 1 << (next % DAY / 3_600) == 0 {
+                next = (next / 3_600 + 1) * 3_600;
+                continue;
+            }
+            if self.minutes & 1 << (next % 3_600 / 60) == 0 {
+                next += 60;
+                continue;
+            }
+            return Some(next);
+        }
+
+        None
+    }
+}
+
+impl Schedule {
+    /// Runs right after midnight, UTC
+    pub fn daily() -> Self {
+        "0 0 * * *".parse().expect("a valid expression")
+    }
+
+    /// When a job on this schedule runs next, after `time`
+    fn next_after(&self, time: u64) -> Option<u64> {
+        match self {
+            Schedule::Every(interval) => Some(time + interval.as_secs()),
+            Schedule::Cron(cron) => cron.next_after(time),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    /// An interval in seconds, minutes, hours or days, like `30s` or `6h`, or a cron expression
+    fn from_str(schedule: &str) -> Result<Self, Self::Err> {
+        let schedule = schedule.trim();
+        let unit = match schedule.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 3_600,
+            Some('d') => DAY,
+            _ => return Ok(Schedule::Cron(schedule.parse()?)),
+        };
+        match schedule[..schedule.len() - 1].parse::<u64>() {
+            Ok(count) if count > 0 => Ok(Schedule::Every(Duration::from_secs(count * unit))),
+            _ => Err(format!("invalid interval {schedule}")),
+        }
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::Every(interval) => write!(f, "every {}s", interval.as_secs()),
+            Schedule::Cron(cron) => write!(f, "{}", cron.expression),
+        }
+    }
+}
+
+/// Parses `JOB_SCHEDULES`, `job=schedule` pairs separated by `;`
+pub fn parse_schedules(schedules: &str) -> Result<HashMap<String, Schedule>, String> {
+    schedules
+        .split(';')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (job, schedule) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("{pair} isn't a job=schedule pair"))?;
+            Ok((job.trim().to_string(), schedule.parse()?))
+        })
+        .collect()
+}
+
+/// How a job went so far
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct JobStats {
+    pub name: &'static str,
+    pub schedule: String,
+    pub runs: u64,
+    pub failures: u64,
+    /// Whether it's running right now
+    pub running: bool,
+    /// When it last started, in unix time
+    pub last_run: Option<u64>,
+    /// How long its last run took, in milliseconds
+    pub last_duration_ms: Option<u64>,
+    /// Why its last run failed, if it did
+    pub last_error: Option<String>,
+    /// When it runs next, in unix time
+    pub next_run: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    /// The schedules operators picked, by job
+    schedules: HashMap<String, Schedule>,
+    jobs: Mutex<BTreeMap<&'static str, JobStats>>,
+    stopping: AtomicBool,
+}
+
+impl Scheduler {
+    pub fn new(schedules: HashMap<String, Schedule>) -> Self {
+        Self {
+            schedules,
+            ..Default::default()
+        }
+    }
+
+    /// Updates the stats of the job `name`
+    fn update(&self, name: &'static str, update: impl FnOnce(&mut JobStats)) {
+        if let Some(stats) = self.jobs.lock().unwrap().get_mut(name) {
+            update(stats);
+        }
+    }
+
+    /// How every job went so far
+    pub fn jobs(&self) -> Vec<JobStats> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Starts no more jobs, and waits up to `timeout` for those running to be done
+    pub async fn stop(&self, timeout: Duration) {
+        self.stopping.store(true, Ordering::SeqCst);
+
+        let started = Instant::now();
+        while started.elapsed() < timeout {
+            let running = self
+                .jobs
+                .lock()
+                .unwrap()
+                .values()
+                .filter(|job| job.running)
+                .count();
+            if running == 0 {
+                return;
+            }
+            actix::clock::sleep(Duration::from_millis(100)).await;
+        }
+        warn!("stopping with jobs still running");
+    }
+}
+
+/// Runs `job` on `schedule`, or on the schedule operators picked for `name`
+pub fn register<B: ChainBackend>(
+    data: &web::Data<AppState<B>>,
+    name: &'static str,
+    schedule: Schedule,
+    job: impl Fn(&AppState<B>) -> Result<(), Error> + Send + Sync + 'static,
+) {
+    let scheduler = &data.scheduler;
+    let schedule = scheduler.schedules.get(name).cloned().unwrap_or(schedule);
+    info!("scheduled {name}: {schedule}");
+    scheduler.jobs.lock().unwrap().insert(
+        name,
+        JobStats {
+            name,
+            schedule: schedule.to_string(),
+            runs: 0,
+            failures: 0,
+            running: false,
+            last_run: None,
+            last_duration_ms: None,
+            last_error: None,
+            next_run: None,
+        },
+    );
+
+    let data = data.clone();
+    let job = Arc::new(job);
+    actix::spawn(async move {
+        loop {
+            let Some(next) = schedule.next_after(now()) else {
+                warn!("{name} won't run again");
+                return;
+            };
+            data.scheduler
+                .update(name, |stats| stats.next_run = Some(next));
+            actix::clock::sleep(Duration::from_secs(next.saturating_sub(now()))).await;
+
+            if data.scheduler.stopping.load(Ordering::SeqCst) {
+                return;
+            }
+            data.scheduler.update(name, |stats| {
+                stats.running = true;
+                stats.last_run = Some(now());
+            });
+
+            let started = Instant::now();
+            let job = job.clone();
+            let result = blocking(&data, move |data| job(data)).await;
+            if let Err(e) = &result {
+                warn!("{name} failed: {e}");
+            }
+
+            data.scheduler.update(name, |stats| {
+                stats.running = false;
+                stats.runs += 1;
+                stats.last_duration_ms = Some(started.elapsed().as_millis() as u64);
+                stats.last_error = result.err().map(|e| e.to_string());
+                if stats.last_error.is_some() {
+                    stats.failures += 1;
+                }
+            });
+        }
+    });
+}
+
+/// Tells how the background jobs went so far, and when they run next
+#[utoipa::path(
+    get,
+    path = "/v1/admin/jobs",
+    tag = "admin",
+    security(("admin_token" = []), ("api_key" = [])),
+    responses(
+        (status = 200, body = [JobStats]),
+        (status = "4XX", body = ErrorBody),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn list_jobs<B: ChainBackend>(data: web::Data<AppState<B>>) -> web::Json<Vec<JobStats>> {
+    web::Json(data.scheduler.jobs())
+}
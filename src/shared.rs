@@ -0,0 +1,241 @@
+//SPDX-License-Identifier: MIT
+
+//! A Redis server holding the rate limits, cooldowns and daily budget, for operators running
+//! several faucets behind a load balancer. Otherwise each faucet would only know about the
+//! requests it served, and clients could get paid once by each of them, while every faucet
+//! would give out a whole budget of its own.
+//!
+//! Every key is prefixed by `faucet:`, so the server may be used for other things too.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bitcoin::Amount;
+use bitcoincore_rpc::jsonrpc::serde_json;
+use redis::Commands;
+use redis::Connection;
+use redis::Script;
+
+use crate::api::Error;
+use crate::cooldown::Grant;
+
+/// Refills a token bucket and takes from it, atomically. Buckets are hashes with the tokens left
+/// and when we last refilled them, in milliseconds. Returns how many milliseconds until the
+/// bucket has enough tokens, or 0 if we took them, and how many whole tokens are left
+const TAKE_SCRIPT: &str = r"
+local max = tonumber(ARGV[1])
+local amount = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local tokens = tonumber(redis.call('HGET', KEYS[1], 'tokens')) or max
+local updated = tonumber(redis.call('HGET', KEYS[1], 'updated')) or now
+tokens = math.min(max, tokens + (now - updated) * max / 3600000)
+
+local wait = 0
+if tokens < amount then
+    wait = math.ceil((amount - tokens) * 3600000 / max)
+else
+    tokens = tokens - amount
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], 3600000)
+return {wait, math.floor(tokens)}
+";
+
+/// Puts `amount` tokens back in a bucket, atomically, refilling it like TAKE_SCRIPT does
+const GIVE_SCRIPT: &str = r"
+local max = tonumber(ARGV[1])
+local amount = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local tokens = tonumber(redis.call('HGET', KEYS[1], 'tokens')) or max
+local updated = tonumber(redis.call('HGET', KEYS[1], 'updated')) or now
+tokens = math.min(max, tokens + (now - updated) * max / 3600000 + amount)
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], 3600000)
+return 0
+";
+
+/// Records a payment in the budget, a sorted set of `<id>:<amount>` members scored by when we
+/// made them, in milliseconds, dropping those that left the window
+const SPEND_SCRIPT: &str = r"
+local window = tonumber(ARGV[1])
+local amount = ARGV[2]
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+local id = redis.call('INCR', KEYS[2])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - window)
+redis.call('ZADD', KEYS[1], now, id .. ':' .. amount)
+redis.call('PEXPIRE', KEYS[1], window)
+redis.call('PEXPIRE', KEYS[2], window)
+return 0
+";
+
+/// Returns the payments in the budget made in the window, oldest first, as members and their
+/// scores
+const SPENT_SCRIPT: &str = r"
+local window = tonumber(ARGV[1])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - window)
+return redis.call('ZRANGE', KEYS[1], 0, -1, 'WITHSCORES')
+";
+
+pub struct SharedStore {
+    client: redis::Client,
+    /// We connect on first use, and again after something goes wrong
+    connection: Mutex<Option<Connection>>,
+    take: Script,
+    give: Script,
+    spend: Script,
+    spent: Script,
+}
+
+impl From<redis::RedisError> for Error {
+    fn from(value: redis::RedisError) -> Self {
+        Error::SharedStoreError(value.to_string())
+    }
+}
+
+impl SharedStore {
+    pub fn new(url: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url)?;
+        // fail early if the server isn't there
+        let connection = client.get_connection()?;
+
+        Ok(Self {
+            client,
+            connection: Mutex::new(Some(connection)),
+            take: Script::new(TAKE_SCRIPT),
+            give: Script::new(GIVE_SCRIPT),
+            spend: Script::new(SPEND_SCRIPT),
+            spent: Script::new(SPENT_SCRIPT),
+        })
+    }
+
+    /// Runs `f` with our connection, dropping it if it fails, so the next call reconnects
+    fn with_connection<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> redis::RedisResult<T>,
+    ) -> Result<T, Error> {
+        let mut connection = self.connection.lock().unwrap();
+        let conn = match connection.as_mut() {
+            Some(conn) => conn,
+            None => connection.insert(self.client.get_connection()?),
+        };
+
+        let result = f(conn);
+        if result.is_err() {
+            *connection = None;
+        }
+
+        Ok(result?)
+    }
+
+    /// Takes `amount` tokens from `bucket`, which refills at `per_hour`, returning how many are
+    /// left, or tells how long until it has enough
+    pub fn take(
+        &self,
+        bucket: &str,
+        amount: u64,
+        per_hour: u64,
+    ) -> Result<Result<u64, Duration>, Error> {
+        let (wait, left): (u64, u64) = self.with_connection(|conn| {
+            self.take
+                .key(format!("faucet:bucket:{bucket}"))
+                .arg(per_hour)
+                .arg(amount)
+                .invoke(conn)
+        })?;
+
+        Ok(match wait {
+            0 => Ok(left),
+            wait => Err(Duration::from_millis(wait)),
+        })
+    }
+
+    /// Puts `amount` tokens we took back in `bucket`, which refills at `per_hour`
+    pub fn give(&self, bucket: &str, amount: u64, per_hour: u64) -> Result<(), Error> {
+        self.with_connection(|conn| {
+            self.give
+                .key(format!("faucet:bucket:{bucket}"))
+                .arg(per_hour)
+                .arg(amount)
+                .invoke::<()>(conn)
+        })
+    }
+
+    /// If `key` is still cooling down, returns what it got and how long until it's over
+    pub fn check_cooldown(&self, key: &str) -> Result<Option<(Grant, Duration)>, Error> {
+        let key = format!("faucet:cooldown:{key}");
+        let (grant, ttl): (Option<String>, i64) =
+            self.with_connection(|conn| redis::pipe().get(&key).pttl(&key).query(conn))?;
+
+        let Some(grant) = grant else {
+            return Ok(None);
+        };
+        let grant =
+            serde_json::from_str(&grant).map_err(|e| Error::SharedStoreError(e.to_string()))?;
+
+        // a negative ttl means the key just expired
+        Ok(u64::try_from(ttl)
+            .ok()
+            .map(|ttl| (grant, Duration::from_millis(ttl))))
+    }
+
+    /// Starts `key`'s cooldown, lasting `window`, now that it got `grant`
+    pub fn record_cooldown(&self, key: &str, grant: &Grant, window: Duration) -> Result<(), Error> {
+        let grant =
+            serde_json::to_string(grant).map_err(|e| Error::SharedStoreError(e.to_string()))?;
+
+        self.with_connection(|conn| {
+            conn.pset_ex(
+                format!("faucet:cooldown:{key}"),
+                grant,
+                window.as_millis() as u64,
+            )
+        })
+    }
+
+    /// Counts `amount` we just paid towards the daily budget every faucet shares, for `window`
+    pub fn record_spent(&self, amount: Amount, window: Duration) -> Result<(), Error> {
+        self.with_connection(|conn| {
+            self.spend
+                .key("faucet:budget")
+                .key("faucet:budget:id")
+                .arg(window.as_millis() as u64)
+                .arg(amount.to_sat())
+                .invoke::<()>(conn)
+        })
+    }
+
+    /// When every faucet paid anyone in the last `window`, as unix time, and how much, oldest
+    /// first
+    pub fn spent_since(&self, window: Duration) -> Result<Vec<(u64, Amount)>, Error> {
+        let spent: Vec<(String, u64)> = self.with_connection(|conn| {
+            self.spent
+                .key("faucet:budget")
+                .arg(window.as_millis() as u64)
+                .invoke(conn)
+        })?;
+
+        spent
+            .into_iter()
+            .map(|(member, paid_at)| {
+                let amount = member
+                    .split_once(':')
+                    .and_then(|(_, amount)| amount.parse().ok())
+                    .ok_or_else(|| {
+                        Error::SharedStoreError(format!("invalid budget entry {member}"))
+                    })?;
+                Ok((paid_at / 1_000, Amount::from_sat(amount)))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,324 @@
+//SPDX-License-Identifier: MIT
+
+//! Paying [BIP352](https://github.com/bitcoin/bips/blob/master/bip-0352.mediawiki) silent
+//! payment addresses. They aren't scripts we can pay to, but a scan key and a spend key: the
+//! output paying them is a taproot key made out of the spend key, tweaked by a secret we share
+//! with the scan key's owner through the keys of the inputs we spend. Only after selecting coins
+//! do we know the output, so payouts to them carry a stand-in address until then.
+//!
+//! Their owner finds the payment by doing the same with the inputs' public keys, so we need the
+//! private keys of every coin we spend, which only some backends can give us.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use bech32::primitives::decode::CheckedHrpstring;
+use bech32::Bech32m;
+use bitcoin::consensus::serialize;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::HashEngine;
+use bitcoin::key::TweakedPublicKey;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::secp256k1::Scalar;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::Address;
+use bitcoin::Network;
+use bitcoin::OutPoint;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::api::Error;
+
+/// What signet and testnet silent payment addresses start with
+const HRP: &str = "tsp";
+
+/// How long the keys in an address are
+const KEYS_LENGTH: usize = 66;
+
+/// A silent payment address, as the keys it's made of
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SilentPaymentAddress {
+    scan: PublicKey,
+    spend: PublicKey,
+}
+
+impl SilentPaymentAddress {
+    /// The address standing for this one in access lists, cooldowns and abuse scores, before we
+    /// know what we pay it to: a taproot address with the spend key as its output key. Nobody
+    /// should ever pay to it
+    pub fn stand_in(&self) -> Address {
+        let key = TweakedPublicKey::dangerous_assume_tweaked(self.spend.x_only_public_key().0);
+        Address::p2tr_tweaked(key, Network::Signet)
+    }
+}
+
+impl FromStr for SilentPaymentAddress {
+    type Err = Error;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        let mut checked =
+            CheckedHrpstring::new::<Bech32m>(address).map_err(|_| Error::InvalidAddress)?;
+        if checked.hrp().to_lowercase() != HRP {
+            return Err(Error::InvalidAddress);
+        }
+
+        // a version, then the keys
+        let version = checked
+            .remove_witness_version()
+            .ok_or(Error::InvalidAddress)?
+            .to_u8();
+        checked
+            .validate_segwit_padding()
+            .map_err(|_| Error::InvalidAddress)?;
+        let bytes = checked.byte_iter().collect::<Vec<_>>();
+
+        // later versions may add things after the keys, which we can leave out
+        match version {
+            0 if bytes.len() == KEYS_LENGTH => {}
+            1..=30 if bytes.len() >= KEYS_LENGTH => {}
+            _ => return Err(Error::InvalidAddress),
+        }
+
+        Ok(Self {
+            scan: PublicKey::from_slice(&bytes[..33]).map_err(|_| Error::InvalidAddress)?,
+            spend: PublicKey::from_slice(&bytes[33..KEYS_LENGTH])
+                .map_err(|_| Error::InvalidAddress)?,
+        })
+    }
+}
+
+/// The BIP340 tagged hash of `parts`, one after the other
+fn tagged_hash(tag: &str, parts: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_byte_array());
+    engine.input(tag.as_byte_array());
+    for part in parts {
+        engine.input(part);
+    }
+
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+/// The addresses paying each of `recipients`, in a transaction spending `inputs`, whose private
+/// keys add up to `secret`
+pub fn derive_outputs(
+    secret: SecretKey,
+    inputs: &[OutPoint],
+    recipients: &[SilentPaymentAddress],
+) -> Result<Vec<Address>, Error> {
+    fn invalid<E>(_: E) -> Error {
+        Error::InvalidRequest("can't derive a silent payment output".into())
+    }
+    let secp = Secp256k1::new();
+
+    // commits to the inputs, so every transaction pays different outputs
+    let smallest = inputs
+        .iter()
+        .map(serialize)
+        .min()
+        .ok_or(Error::OutOfMoney)?;
+    let input_hash = tagged_hash(
+        "BIP0352/Inputs",
+        &[&smallest, &secret.public_key(&secp).serialize()],
+    );
+    let tweak = secret
+        .mul_tweak(&Scalar::from_be_bytes(input_hash).map_err(invalid)?)
+        .map_err(invalid)?;
+
+    // outputs to the same scan key are told apart by a counter
+    let mut counters = HashMap::new();
+    recipients
+        .iter()
+        .map(|recipient| {
+            let k: &mut u32 = counters.entry(recipient.scan).or_default();
+            let shared_secret = recipient
+                .scan
+                .mul_tweak(&secp, &Scalar::from(tweak))
+                .map_err(invalid)?;
+            let t = tagged_hash(
+                "BIP0352/SharedSecret",
+                &[&shared_secret.serialize(), &k.to_be_bytes()],
+            );
+            *k += 1;
+
+            let output = recipient
+                .spend
+                .add_exp_tweak(&secp, &Scalar::from_be_bytes(t).map_err(invalid)?)
+                .map_err(invalid)?;
+            let key = TweakedPublicKey::dangerous_assume_tweaked(output.x_only_public_key().0);
+            Ok(Address::p2tr_tweaked(key, Network::Signet))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bech32::primitives::iter::ByteIterExt;
+    use bech32::primitives::iter::Fe32IterExt;
+    use bech32::Fe32;
+    use bech32::Hrp;
+    use bitcoin::hex::DisplayHex;
+    use bitcoin::Txid;
+
+    use super::*;
+
+    /// The address of BIP352's first test vectors, on mainnet, and the keys it's made of
+    const ADDRESS: &str = "sp1qqgste7k9hx0qftg6qmwlkqtwuy6cycyavzmzj85c6qdfhjdpdjtdgqjuexzk6murw56suy3e0rd2cgqvycxttddwsvgxe2usfpxumr70xc9pkqwv";
+    const SCAN_KEY: &str = "0f694e068028a717f8af6b9411f9a133dd3565258714cc226594b34db90c1f2c";
+    const SPEND_KEY: &str = "9d6ad855ce3417ef84e836892e5a56392bfba05fa5d97ccea30e266f540e08b3";
+    /// The same, with label 2
+    const LABELLED_ADDRESS: &str = "sp1qqgste7k9hx0qftg6qmwlkqtwuy6cycyavzmzj85c6qdfhjdpdjtdgqjex54dmqmmv6rw353tsuqhs99ydvadxzrsy9nuvk74epvee55drs734pqq";
+
+    /// The coins the vectors spend, as their txid and key
+    const INPUTS: [(&str, &str); 2] = [
+        (
+            "f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16",
+            "eadc78165ff1f8ea94ad7cfdc54990738a4c53f6e0507b42154201b8e5dff3b1",
+        ),
+        (
+            "a1075db55d416d3ca199f55b6084e2115b9345e16c5cf302fc80e9d5fbf5d48d",
+            "93f5ed907ad5b2bdbbdcb5d9116ebc0a4e1f92f910d5260237fa45a9408aad16",
+        ),
+    ];
+
+    /// The output key paying `ADDRESS` from `INPUTS`, from the vectors. The others are as
+    /// rust-silentpayments derives them
+    const OUTPUT: &str = "3e9fce73d4e77a4809908e3c3a2e54ee147b9312dc5044a193d1fc85de46e3c1";
+    const SECOND_OUTPUT: &str = "0ffe0b3d72d66b785e1a7ad416edcc22b951293b1507aa04850e890b002c60f1";
+    const LABELLED_OUTPUT: &str =
+        "f371bc2e01413c9eca6903a80be883467972b0c40b929be0a6be708cb5442d57";
+
+    /// A silent payment address for signet, of `version`, with `data`
+    fn encode(version: Fe32, data: &[u8]) -> String {
+        data.iter()
+            .copied()
+            .bytes_to_fes()
+            .with_checksum::<Bech32m>(&Hrp::parse(HRP).unwrap())
+            .with_witness_version(version)
+            .chars()
+            .collect()
+    }
+
+    /// The signet address with the same keys as `address`, from the vectors
+    fn signet(address: &str) -> SilentPaymentAddress {
+        let mut checked = CheckedHrpstring::new::<Bech32m>(address).unwrap();
+        let version = checked.remove_witness_version().unwrap();
+        let data = checked.byte_iter().collect::<Vec<_>>();
+
+        encode(version, &data).parse().unwrap()
+    }
+
+    fn outpoints() -> Vec<OutPoint> {
+        INPUTS
+            .iter()
+            .map(|(txid, _)| OutPoint::new(txid.parse::<Txid>().unwrap(), 0))
+            .collect()
+    }
+
+    /// What our backends would give us for `INPUTS`: the sum of their keys
+    fn secret() -> SecretKey {
+        let [first, second] = INPUTS.map(|(_, key)| key.parse::<SecretKey>().unwrap());
+        first.add_tweak(&Scalar::from(second)).unwrap()
+    }
+
+    /// The output keys `addresses` pay to, in hex
+    fn keys(addresses: Vec<Address>) -> Vec<String> {
+        addresses
+            .iter()
+            .map(|address| address.script_pubkey().as_bytes()[2..].to_lower_hex_string())
+            .collect()
+    }
+
+    #[test]
+    fn decodes_addresses() {
+        let secp = Secp256k1::new();
+        let address = signet(ADDRESS);
+        assert_eq!(
+            address.scan,
+            SCAN_KEY.parse::<SecretKey>().unwrap().public_key(&secp)
+        );
+        assert_eq!(
+            address.spend,
+            SPEND_KEY.parse::<SecretKey>().unwrap().public_key(&secp)
+        );
+
+        // labels change the spend key only
+        let labelled = signet(LABELLED_ADDRESS);
+        assert_eq!(labelled.scan, address.scan);
+        assert_ne!(labelled.spend, address.spend);
+
+        // later versions may add to the keys
+        let mut data = [address.scan.serialize(), address.spend.serialize()].concat();
+        data.extend([1, 2, 3]);
+        assert_eq!(
+            encode(Fe32::P, &data)
+                .parse::<SilentPaymentAddress>()
+                .unwrap(),
+            address
+        );
+    }
+
+    #[test]
+    fn refuses_what_isnt_a_signet_address() {
+        let address = signet(ADDRESS);
+        let keys = [address.scan.serialize(), address.spend.serialize()].concat();
+        let mut longer = keys.clone();
+        longer.push(0);
+
+        for invalid in [
+            ADDRESS.to_string(),
+            encode(Fe32::Q, &keys[..65]),
+            encode(Fe32::Q, &longer),
+            encode(Fe32::L, &keys),
+            // a typo
+            encode(Fe32::Q, &keys).replace("tsp1qq", "tsp1qp"),
+            "tsp1".to_string(),
+            String::new(),
+        ] {
+            assert!(
+                matches!(
+                    SilentPaymentAddress::from_str(&invalid),
+                    Err(Error::InvalidAddress)
+                ),
+                "{invalid}"
+            );
+        }
+    }
+
+    #[test]
+    fn pays_a_single_recipient() {
+        let outputs = derive_outputs(secret(), &outpoints(), &[signet(ADDRESS)]).unwrap();
+        assert_eq!(keys(outputs), [OUTPUT]);
+
+        // the order we spend the coins in doesn't matter
+        let mut reversed = outpoints();
+        reversed.reverse();
+        let outputs = derive_outputs(secret(), &reversed, &[signet(ADDRESS)]).unwrap();
+        assert_eq!(keys(outputs), [OUTPUT]);
+    }
+
+    #[test]
+    fn pays_the_same_scan_key_more_than_once() {
+        let outputs =
+            derive_outputs(secret(), &outpoints(), &[signet(ADDRESS), signet(ADDRESS)]).unwrap();
+        assert_eq!(keys(outputs), [OUTPUT, SECOND_OUTPUT]);
+    }
+
+    #[test]
+    fn pays_labelled_addresses() {
+        let outputs = derive_outputs(secret(), &outpoints(), &[signet(LABELLED_ADDRESS)]).unwrap();
+        assert_eq!(keys(outputs), [LABELLED_OUTPUT]);
+
+        // with the same scan key, they count towards the same outputs
+        let outputs = derive_outputs(
+            secret(),
+            &outpoints(),
+            &[signet(ADDRESS), signet(LABELLED_ADDRESS)],
+        )
+        .unwrap();
+        assert_eq!(keys(outputs)[0], OUTPUT);
+    }
+}
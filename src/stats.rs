@@ -0,0 +1,245 @@
+//SPDX-License-Identifier: MIT
+
+//! A daily report of what the faucet did, so operators know how it's being used without digging
+//! through the database. Right after midnight, UTC, we add up the day that ended: how many
+//! payouts we made and how many sats they sent, to how many addresses, how many channels we
+//! opened, and how many requests for money we turned down, by why. Each report is kept in the
+//! `daily_stats` table and, if alerts are set up, sent through their channels too.
+//!
+//! Anyone can see the same numbers at /stats, for the whole time we've been running and day by
+//! day, along with how long payouts took to confirm, for dashboards showing how the faucet is
+//! used. They're added up from the database every time, so we let clients cache them for a bit.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix_web::http::header;
+use actix_web::web;
+use actix_web::HttpResponse;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+use utoipa::IntoParams;
+use utoipa::ToSchema;
+
+use crate::api::blocking;
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+use crate::backend::TxState;
+use crate::db::now;
+use crate::scheduler;
+use crate::scheduler::Schedule;
+
+/// How long a day is, in seconds
+pub const DAY: u64 = 86_400;
+
+/// How many days /stats goes through one by one, unless asked for `days`
+const STATS_DAYS: u32 = 30;
+
+/// The most days /stats goes through one by one
+const MAX_STATS_DAYS: u32 = 365;
+
+/// How often we look for payouts that confirmed
+const CONFIRMATIONS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long we wait for a payout to confirm before we stop looking
+const CONFIRMATIONS_WINDOW: Duration = Duration::from_secs(14 * DAY);
+
+/// How long clients may keep our stats, in seconds
+const STATS_MAX_AGE: u32 = 60;
+
+/// What we did in a day
+#[derive(Debug, Serialize)]
+pub struct DailyReport {
+    /// When the day started, in unix time
+    pub day: u64,
+    pub payouts: u64,
+    pub sats_out: u64,
+    pub unique_addresses: u64,
+    pub channels_opened: u64,
+    /// How many requests we turned down, by error code
+    pub rejections: BTreeMap<String, u64>,
+}
+
+impl DailyReport {
+    /// The report as the operator reads it
+    #[cfg(feature = "alerts")]
+    fn message(&self) -> String {
+        let mut message = format!(
+            "{} payouts sent {} sats to {} addresses",
+            self.payouts, self.sats_out, self.unique_addresses
+        );
+        #[cfg(feature = "lightning")]
+        message.push_str(&format!(
+            ", and we opened {} channels",
+            self.channels_opened
+        ));
+
+        if self.rejections.is_empty() {
+            message.push_str(". We didn't turn down any requests.");
+        } else {
+            let total: u64 = self.rejections.values().sum();
+            message.push_str(&format!(". We turned down {total} requests:"));
+            for (reason, count) in &self.rejections {
+                message.push_str(&format!("\n  {reason}: {count}"));
+            }
+        }
+
+        message
+    }
+}
+
+/// What we did in some time
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct Totals {
+    pub payouts: u64,
+    pub sats_out: u64,
+    pub unique_addresses: u64,
+    pub channels_opened: u64,
+    /// How many requests we turned down, by error code
+    pub rejections: BTreeMap<String, u64>,
+    /// How long payouts took to confirm, on average, in seconds. We only know for those we saw
+    /// confirm since we started keeping track, and to within how often we look
+    pub average_confirmation_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DayStats {
+    /// When the day started, in unix time
+    pub day: u64,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+/// What we did since we started, and lately, day by day
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Stats {
+    pub total: Totals,
+    /// Oldest first, ending with today so far
+    pub days: Vec<DayStats>,
+}
+
+/// `day`, in unix time, as `YYYY-MM-DD`
+fn date(day: u64) -> String {
+    let (year, month, day_of_month) = civil(day);
+    format!("{year:04}-{month:02}-{day_of_month:02}")
+}
+
+/// The year, month and day of the month `time`, in unix time, falls on
+pub fn civil(time: u64) -> (i64, u32, u32) {
+    // from Howard Hinnant's civil_from_days
+    let days = (time / DAY) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month as u32, day_of_month as u32)
+}
+
+/// Reports on the day starting at `day`, storing it and sending it to the operator
+fn report<B: ChainBackend>(data: &AppState<B>, day: u64) -> Result<(), Error> {
+    let report = data.db.daily_report(day)?;
+    data.db.record_daily_report(&report)?;
+    info!(
+        "{}: {} payouts, {} sats out",
+        date(day),
+        report.payouts,
+        report.sats_out
+    );
+
+    #[cfg(feature = "alerts")]
+    if let Some(alerts) = &data.alerts {
+        alerts.send(
+            &format!("Faucet report for {}", date(day)),
+            &report.message(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Reports on the day that ended last, unless we did already
+fn report_yesterday<B: ChainBackend>(data: &AppState<B>) -> Result<(), Error> {
+    let yesterday = now() / DAY * DAY - DAY;
+    match data.db.last_daily_report()? {
+        Some(last) if last >= yesterday => Ok(()),
+        _ => report(data, yesterday),
+    }
+}
+
+/// Records when the payouts of the last [CONFIRMATIONS_WINDOW] confirmed, if they did, so we
+/// know how long they took. Replaced payouts are recorded under their replacement, which is
+/// what we look for
+fn record_confirmations<B: ChainBackend>(data: &AppState<B>) -> Result<(), Error> {
+    for txid in data.db.unconfirmed_payouts(CONFIRMATIONS_WINDOW)? {
+        if let TxState::Confirmed { .. } = data.backend.transaction_status(&txid)? {
+            data.db.confirm_payout(&txid)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reports on each day once it's over. If we were down when one ended, we report on it when we
+/// come back, but only on the last one. Also keeps track of when payouts confirm, for /stats
+pub fn spawn_reporter<B: ChainBackend>(data: &web::Data<AppState<B>>) {
+    let catch_up = data.clone();
+    actix::spawn(async move {
+        if let Err(e) = blocking(&catch_up, report_yesterday).await {
+            warn!("couldn't report on yesterday: {e}");
+        }
+    });
+
+    scheduler::register(data, "daily_report", Schedule::daily(), report_yesterday);
+    scheduler::register(
+        data,
+        "confirmations",
+        Schedule::Every(CONFIRMATIONS_INTERVAL),
+        record_confirmations,
+    );
+}
+
+#[derive(Deserialize, IntoParams)]
+pub struct StatsQuery {
+    /// How many days, up to today, to go through one by one
+    days: Option<u32>,
+}
+
+/// Tells what the faucet did since it started, and in each of the last 30 days, or `days`
+#[utoipa::path(
+    get,
+    path = "/v1/stats",
+    tag = "faucet",
+    params(StatsQuery),
+    responses(
+        (status = 200, body = Stats),
+        (status = "5XX", body = ErrorBody),
+    )
+)]
+pub async fn stats<B: ChainBackend>(
+    query: web::Query<StatsQuery>,
+    data: web::Data<AppState<B>>,
+) -> Result<HttpResponse, Error> {
+    let days = query.days.unwrap_or(STATS_DAYS).clamp(1, MAX_STATS_DAYS) as u64;
+    let since = (now() / DAY + 1 - days) * DAY;
+    let stats = blocking(&data, move |data| Ok(data.db.stats(since)?)).await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            header::CACHE_CONTROL,
+            format!("public, max-age={STATS_MAX_AGE}"),
+        ))
+        .json(stats))
+}
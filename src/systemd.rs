@@ -0,0 +1,80 @@
+//SPDX-License-Identifier: MIT
+
+//! Tells systemd how we're doing, for `Type=notify` units. We say we're ready once we listen
+//! for requests, and that we're stopping when we get asked to. If the unit sets `WatchdogSec`,
+//! we also ping systemd's watchdog, but only while our chain backend answers and the payout
+//! queue worker keeps going, so a faucet that's up but wedged gets restarted.
+
+use std::time::Duration;
+
+use actix_web::web;
+use sd_notify::NotifyState;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::AppState;
+use crate::backend::ChainBackend;
+
+/// Tells systemd we're serving requests
+pub fn notify_ready() {
+    notify(&[NotifyState::Ready, NotifyState::Status("serving requests")]);
+}
+
+/// Tells systemd we're shutting down, so it doesn't think we hang while we drain
+pub fn notify_stopping() {
+    notify(&[
+        NotifyState::Stopping,
+        NotifyState::Status("finishing requests and the payout queue"),
+    ]);
+}
+
+/// Without a NOTIFY_SOCKET, we aren't running under systemd and this does nothing
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!("couldn't notify systemd: {e}");
+    }
+}
+
+/// Whether we can still pay people: the chain backend answers, and the queue worker, if we
+/// have one, isn't stuck
+fn is_healthy<B: ChainBackend>(data: &AppState<B>) -> Result<(), String> {
+    data.backend
+        .block_height()
+        .map_err(|e| format!("the chain backend isn't answering: {e}"))?;
+
+    match &data.payout_queue {
+        Some(queue) if !queue.worker_alive() => Err("the payout queue worker is stuck".into()),
+        _ => Ok(()),
+    }
+}
+
+/// Pings the watchdog twice per `WatchdogSec`, as long as we're healthy. If we aren't, we stop
+/// pinging and systemd restarts us once the watchdog runs out
+pub fn spawn_watchdog<B: ChainBackend>(data: web::Data<AppState<B>>) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    let interval = Duration::from_micros(usec) / 2;
+    info!(
+        "systemd's watchdog is on, we check our health every {} seconds",
+        interval.as_secs_f32()
+    );
+
+    actix::spawn(async move {
+        loop {
+            match is_healthy(&data) {
+                Ok(()) => notify(&[
+                    NotifyState::Watchdog,
+                    NotifyState::Status("serving requests"),
+                ]),
+                Err(e) => {
+                    warn!("not pinging systemd's watchdog: {e}");
+                    notify(&[NotifyState::Status(&e)]);
+                }
+            }
+
+            actix::clock::sleep(interval).await;
+        }
+    });
+}
@@ -0,0 +1,367 @@
+//SPDX-License-Identifier: MIT
+
+//! What the tests share: a node that lives in memory and a faucet paying from it, with none of
+//! the optional checks, so each test turns on what it looks at.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use actix_web::web;
+use bitcoin::absolute::LockTime;
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::SecretKey;
+use bitcoin::transaction::Version;
+use bitcoin::Address;
+use bitcoin::Amount;
+use bitcoin::FeeRate;
+use bitcoin::Network;
+use bitcoin::OutPoint;
+use bitcoin::Psbt;
+use bitcoin::ScriptBuf;
+use bitcoin::Sequence;
+use bitcoin::Transaction;
+use bitcoin::TxIn;
+use bitcoin::TxOut;
+use bitcoin::Txid;
+use bitcoin::Witness;
+
+use crate::access::AccessLists;
+use crate::admin;
+use crate::admin::Controls;
+use crate::api::AppState;
+use crate::api::Error;
+use crate::backend::ChainBackend;
+use crate::backend::MempoolEntry;
+use crate::backend::TxState;
+use crate::backend::Utxo;
+use crate::bump::Bumper;
+use crate::coinselect::CoinSelection;
+use crate::coinselect::Strategy;
+use crate::coinselect::MIN_CHANGE;
+use crate::cors::CorsPolicy;
+use crate::cpfp::Accelerator;
+use crate::db::Database;
+use crate::fees::FeePolicy;
+use crate::tiers::TierLimits;
+
+/// Where our node sends change
+pub const CHANGE_ADDRESS: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+pub fn address(address: &str) -> Address {
+    Address::from_str(address)
+        .unwrap()
+        .require_network(Network::Signet)
+        .unwrap()
+}
+
+/// A confirmed coin of ours, the `vout` of a made-up transaction
+pub fn coin(vout: u32, amount: Amount) -> Utxo {
+    Utxo {
+        txid: Txid::all_zeros(),
+        vout,
+        amount,
+        confirmations: 6,
+        coinbase: false,
+    }
+}
+
+/// A node holding `coins`, which signs anything spending them and takes `broadcast_delay` to
+/// answer broadcasts
+pub struct MockNode {
+    pub coins: Vec<Utxo>,
+    pub broadcast_delay: Duration,
+    /// What it estimates transactions should pay
+    pub estimate: Option<FeeRate>,
+    /// What's waiting in the mempool
+    pub mempool: HashMap<Txid, MempoolEntry>,
+    /// Why the mempool turns transactions down, if it does
+    pub rejects: Option<String>,
+    pub broadcasts: AtomicU64,
+    /// How many broadcasts are being answered right now
+    pub in_flight: AtomicUsize,
+    /// The most broadcasts we answered at once
+    pub peak_in_flight: AtomicUsize,
+}
+
+impl Default for MockNode {
+    /// A node with a single big coin, estimating 1 sat/vB, which answers right away
+    fn default() -> Self {
+        Self {
+            coins: vec![coin(0, Amount::ONE_BTC)],
+            broadcast_delay: Duration::ZERO,
+            estimate: FeeRate::from_sat_per_vb(1),
+            mempool: HashMap::new(),
+            rejects: None,
+            broadcasts: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            peak_in_flight: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl MockNode {
+    fn coin(&self, outpoint: OutPoint) -> Option<&Utxo> {
+        self.coins
+            .iter()
+            .find(|coin| OutPoint::new(coin.txid, coin.vout) == outpoint)
+    }
+}
+
+impl ChainBackend for MockNode {
+    fn list_unspent(&self) -> Result<Vec<Utxo>, Error> {
+        Ok(self.coins.clone())
+    }
+
+    fn create_transaction(
+        &self,
+        inputs: &[Utxo],
+        outputs: &[(Address, Amount)],
+    ) -> Result<Transaction, Error> {
+        Ok(Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|utxo| TxIn {
+                    previous_output: OutPoint::new(utxo.txid, utxo.vout),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|(address, amount)| TxOut {
+                    value: *amount,
+                    script_pubkey: address.script_pubkey(),
+                })
+                .collect(),
+        })
+    }
+
+    fn sign_transaction(&self, tx: &Transaction) -> Result<Transaction, Error> {
+        Ok(tx.clone())
+    }
+
+    fn create_psbt(&self, tx: &Transaction) -> Result<Psbt, Error> {
+        Psbt::from_unsigned_tx(tx.clone()).map_err(|_| Error::SigningFailed)
+    }
+
+    fn finalize_psbt(&self, psbt: Psbt) -> Result<Transaction, Error> {
+        psbt.extract_tx().map_err(|_| Error::SigningFailed)
+    }
+
+    fn sign_psbt(&self, mut psbt: Psbt) -> Result<Psbt, Error> {
+        for (txin, input) in psbt.unsigned_tx.input.iter().zip(psbt.inputs.iter_mut()) {
+            let Some(coin) = self.coin(txin.previous_output) else {
+                continue;
+            };
+            input.witness_utxo = Some(TxOut {
+                value: coin.amount,
+                script_pubkey: address(CHANGE_ADDRESS).script_pubkey(),
+            });
+            input.final_script_witness = Some(Witness::from_slice(&[[1; 72], [2; 72]]));
+        }
+
+        Ok(psbt)
+    }
+
+    fn broadcast_transaction(&self, _tx: &Transaction) -> Result<Txid, Error> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+        std::thread::sleep(self.broadcast_delay);
+        self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+        // every payout spends the same coin, so we make their txids up
+        let broadcasts = self.broadcasts.fetch_add(1, Ordering::SeqCst) + 1;
+        let mut txid = [0; 32];
+        txid[..8].copy_from_slice(&broadcasts.to_le_bytes());
+        Ok(Txid::from_byte_array(txid))
+    }
+
+    fn get_balance(&self) -> Result<Amount, Error> {
+        Ok(self.coins.iter().map(|coin| coin.amount).sum())
+    }
+
+    fn block_height(&self) -> Result<u32, Error> {
+        Ok(200_000)
+    }
+
+    fn transaction_status(&self, _txid: &Txid) -> Result<TxState, Error> {
+        Ok(TxState::Unconfirmed)
+    }
+
+    fn estimate_fee(&self, _target: u16) -> Result<Option<FeeRate>, Error> {
+        Ok(self.estimate)
+    }
+
+    fn mempool_entry(&self, txid: &Txid) -> Result<Option<MempoolEntry>, Error> {
+        Ok(self.mempool.get(txid).copied())
+    }
+
+    fn mempool_rejects(&self, _tx: &Transaction) -> Result<Option<String>, Error> {
+        Ok(self.rejects.clone())
+    }
+
+    fn silent_payment_key(&self, _inputs: &[Utxo]) -> Result<SecretKey, Error> {
+        Err(Error::SilentPaymentsUnsupported)
+    }
+
+    fn receive_address(&self) -> Result<Address, Error> {
+        Ok(address(CHANGE_ADDRESS))
+    }
+}
+
+/// A Lightning node without channels, so every payment fails
+#[cfg(feature = "lightning")]
+pub struct MockLightning;
+
+#[cfg(feature = "lightning")]
+#[async_trait::async_trait(?Send)]
+impl crate::ln::LightningBackend for MockLightning {
+    async fn connect(
+        &self,
+        _id: bitcoin::secp256k1::PublicKey,
+        _address: Option<&str>,
+        _required_features: &[usize],
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    async fn open_channel(
+        &self,
+        _id: bitcoin::secp256k1::PublicKey,
+        _channel: crate::ln::ChannelRequest,
+    ) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+
+    async fn close_channel(&self, _channel_id: &str, _force: bool) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+
+    async fn create_invoice(&self, _amount_msat: u64, _description: &str) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+
+    async fn pay_invoice(&self, _invoice: &str) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+
+    async fn keysend(
+        &self,
+        _id: bitcoin::secp256k1::PublicKey,
+        _amount_msat: u64,
+    ) -> Result<String, Error> {
+        Err(Error::NotSupported)
+    }
+
+    async fn node_info(&self) -> Result<crate::ln::NodeInfo, Error> {
+        Err(Error::NotSupported)
+    }
+
+    async fn list_channels(&self) -> Result<Vec<crate::ln::ChannelInfo>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+/// A faucet paying from `backend`, with none of the optional checks
+pub fn app_state<B: ChainBackend>(backend: B) -> AppState<B> {
+    let db = Database::open(":memory:".as_ref()).unwrap();
+    let access = AccessLists::load(&db).unwrap();
+
+    AppState {
+        backend,
+        change_address: address(CHANGE_ADDRESS),
+        controls: Controls::new(admin::Limits {
+            max_sendable: Amount::from_sat(100_000),
+            min_sendable: Amount::from_sat(1_000),
+            daily_budget: None,
+        }),
+        tiers: TierLimits {
+            captcha: None,
+            signed_message: None,
+        },
+        #[cfg(feature = "nostr")]
+        nostr: None,
+        #[cfg(feature = "github")]
+        github: None,
+        rate_limiter: None,
+        address_cooldowns: None,
+        db,
+        access,
+        idempotency: Default::default(),
+        activity: Default::default(),
+        abuse: None,
+        #[cfg(feature = "tor")]
+        tor: None,
+        trusted_proxies: web::Data::new(Default::default()),
+        cors: CorsPolicy::Permissive,
+        coin_selection: CoinSelection {
+            strategy: Strategy::BranchAndBound,
+            change_target: Amount::ZERO,
+            min_change: MIN_CHANGE,
+            min_confirmations: 0,
+        },
+        fees: FeePolicy {
+            target: 6,
+            min_target: 6,
+            floor: FeeRate::from_sat_per_vb_unchecked(1),
+            ceiling: FeeRate::from_sat_per_vb_unchecked(100),
+        },
+        accelerator: Accelerator::new(None),
+        payjoins: None,
+        consolidation: None,
+        bumper: Bumper::new(None),
+        scheduler: Default::default(),
+        assets: Default::default(),
+        #[cfg(feature = "tls")]
+        tls: None,
+        #[cfg(feature = "alerts")]
+        alerts: None,
+        max_batch_outputs: None,
+        max_recipients: 1,
+        address_types: None,
+        payout_queue: None,
+        #[cfg(feature = "webhooks")]
+        webhooks: None,
+        challenges: None,
+        #[cfg(feature = "captcha")]
+        captcha: None,
+        #[cfg(feature = "lightning")]
+        lightning: Box::new(MockLightning),
+        #[cfg(feature = "lightning")]
+        lnurl_withdrawals: Default::default(),
+        #[cfg(feature = "lightning")]
+        bolt12_offer: Default::default(),
+        #[cfg(feature = "lightning")]
+        allow_zero_conf: false,
+        #[cfg(feature = "lightning")]
+        channel_limits: crate::ln::ChannelLimits {
+            default_capacity: Amount::from_sat(1_000_000),
+            default_push: Amount::ZERO,
+            min_capacity: Amount::from_sat(20_000),
+            max_capacity: Amount::from_sat(1_000_000),
+            max_push: Amount::ZERO,
+            max_peer_capacity: None,
+        },
+        admin_token: None,
+        api_keys: Default::default(),
+        #[cfg(feature = "lightning")]
+        reclaim_after: None,
+        #[cfg(feature = "lightning")]
+        hold_payouts: None,
+        #[cfg(feature = "lightning")]
+        channel_cooldowns: None,
+        #[cfg(feature = "redis")]
+        shared: None,
+        #[cfg(feature = "zmq")]
+        tracker: Default::default(),
+        #[cfg(feature = "utreexod")]
+        utreexo: None,
+    }
+}
@@ -0,0 +1,58 @@
+//SPDX-License-Identifier: MIT
+
+//! Payout tiers. How much /send/ gives out depends on what the request proved: anyone gets the
+//! anonymous tier, up to MAX_SENDABLE_AMOUNT, and each check passed may take them to a higher
+//! one. Requests get the highest tier they qualify for.
+//!
+//!  - captcha: the request solved a CAPTCHA, or a proof of work
+//!  - signed-message: the request carries a signature from the address' key
+//!  - authenticated: the user is logged in, with Nostr or GitHub. Each of those sets its own
+//!    limit
+
+use bitcoin::Amount;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum Tier {
+    Anonymous,
+    Captcha,
+    SignedMessage,
+    #[cfg(any(feature = "nostr", feature = "github"))]
+    Authenticated,
+}
+
+/// How much the tiers above the anonymous one may get, if they're enabled
+#[derive(Debug, Default)]
+pub struct TierLimits {
+    pub captcha: Option<Amount>,
+    pub signed_message: Option<Amount>,
+}
+
+/// A tier, as /info shows it
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TierInfo {
+    pub tier: Tier,
+    /// In sats
+    pub max_sendable: u64,
+    /// How to get into the tier, if there's more than one way
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub via: Option<&'static str>,
+}
+
+impl TierInfo {
+    pub fn new(tier: Tier, max_sendable: Amount) -> Self {
+        Self {
+            tier,
+            max_sendable: max_sendable.to_sat(),
+            via: None,
+        }
+    }
+
+    #[cfg(any(feature = "nostr", feature = "github"))]
+    pub fn via(mut self, via: &'static str) -> Self {
+        self.via = Some(via);
+        self
+    }
+}
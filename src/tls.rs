@@ -0,0 +1,128 @@
+//SPDX-License-Identifier: MIT
+
+//! Serves HTTPS ourselves, for operators who'd rather not run a reverse proxy only for TLS. We
+//! take a PEM certificate chain and private key, like the ones certbot writes, and check every
+//! so often whether they changed, so renewed certificates are used without a restart.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use actix_web::web;
+use rustls::server::ClientHello;
+use rustls::server::ResolvesServerCert;
+use rustls::sign::CertifiedKey;
+use rustls::Certificate;
+use rustls::PrivateKey;
+use rustls::ServerConfig;
+use rustls_pemfile::Item;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::AppState;
+use crate::backend::ChainBackend;
+
+pub struct Certificates {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    /// How often we look for new certificates
+    reload: Duration,
+    current: RwLock<Arc<CertifiedKey>>,
+    /// When the files we're using were last modified
+    modified: Mutex<(SystemTime, SystemTime)>,
+}
+
+impl Certificates {
+    pub fn load(cert_file: PathBuf, key_file: PathBuf, reload: Duration) -> anyhow::Result<Self> {
+        let modified = (modified(&cert_file)?, modified(&key_file)?);
+        let current = read_key(&cert_file, &key_file)?;
+
+        Ok(Self {
+            cert_file,
+            key_file,
+            reload,
+            current: RwLock::new(Arc::new(current)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// What we give rustls to serve with. It asks us for the certificate on every handshake,
+    /// so reloading it doesn't need a new config
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+
+    /// Loads the certificate again if either file changed since we last did
+    fn reload(&self) -> anyhow::Result<bool> {
+        let modified = (modified(&self.cert_file)?, modified(&self.key_file)?);
+        if *self.modified.lock().unwrap() == modified {
+            return Ok(false);
+        }
+
+        let key = read_key(&self.cert_file, &self.key_file)?;
+        *self.current.write().unwrap() = Arc::new(key);
+        *self.modified.lock().unwrap() = modified;
+
+        Ok(true)
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+fn modified(file: &PathBuf) -> anyhow::Result<SystemTime> {
+    Ok(std::fs::metadata(file)?.modified()?)
+}
+
+/// Reads a certificate chain, leaf first, and the first private key in `key_file`, which may
+/// be PKCS#8, PKCS#1 or SEC1
+fn read_key(cert_file: &PathBuf, key_file: &PathBuf) -> anyhow::Result<CertifiedKey> {
+    let chain = rustls_pemfile::certs(&mut std::fs::read(cert_file)?.as_slice())?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<_>>();
+    if chain.is_empty() {
+        anyhow::bail!("no certificate in {}", cert_file.display());
+    }
+
+    let key = rustls_pemfile::read_all(&mut std::fs::read(key_file)?.as_slice())?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::anyhow!("no private key in {}", key_file.display()))?;
+    let key = rustls::sign::any_supported_type(&key)?;
+
+    Ok(CertifiedKey::new(chain, key))
+}
+
+/// Picks up renewed certificates. If they can't be loaded, we keep serving the ones we had
+pub fn spawn_reloader<B: ChainBackend>(data: web::Data<AppState<B>>) {
+    actix::spawn(async move {
+        loop {
+            let Some(certificates) = &data.tls else {
+                return;
+            };
+            actix::clock::sleep(certificates.reload).await;
+
+            match certificates.reload() {
+                Ok(true) => info!(
+                    "loaded the new certificate in {}",
+                    certificates.cert_file.display()
+                ),
+                Ok(false) => {}
+                Err(e) => warn!("couldn't load the new certificate: {e}"),
+            }
+        }
+    });
+}
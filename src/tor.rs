@@ -0,0 +1,104 @@
+//SPDX-License-Identifier: MIT
+
+//! A policy for Tor exits. Tor users are as welcome as anyone, but a faucet is also easy to
+//! drain through it, with a fresh circuit for every request, so operators may want to make them
+//! prove more or get less. We know the exits from the list the Tor Project publishes, which we
+//! download again every so often.
+//!
+//! This only catches Tor, VPNs don't publish their exits.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::web;
+use bitcoin::Amount;
+use tracing::info;
+use tracing::warn;
+
+use crate::api::AppState;
+use crate::backend::ChainBackend;
+
+/// Where the Tor Project lists the exits, one IP a line
+pub const DEFAULT_EXIT_LIST_URL: &str = "https://check.torproject.org/torbulkexitlist";
+
+/// What we do with requests coming from Tor exits, unless we treat them like everyone else
+#[derive(Debug, Clone, Copy)]
+pub enum TorPolicy {
+    /// They have to solve a captcha or proof of work, even if those are optional
+    Challenge,
+    /// They may get this much at most, whatever their tier
+    Limit(Amount),
+}
+
+pub struct TorExits {
+    pub policy: TorPolicy,
+    list_url: String,
+    /// How often we download the list again
+    refresh: Duration,
+    exits: RwLock<HashSet<IpAddr>>,
+    agent: ureq::Agent,
+}
+
+impl TorExits {
+    pub fn new(policy: TorPolicy, list_url: String, refresh: Duration) -> Self {
+        Self {
+            policy,
+            list_url,
+            refresh,
+            exits: RwLock::new(HashSet::new()),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// What applies to `client`, if it's a Tor exit
+    pub fn policy_for(&self, client: Option<IpAddr>) -> Option<TorPolicy> {
+        let client = client?;
+        // the list only has IPv4 exits written as such
+        let client = match client {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(client, IpAddr::V4),
+            client => client,
+        };
+
+        self.exits
+            .read()
+            .unwrap()
+            .contains(&client)
+            .then_some(self.policy)
+    }
+
+    /// Downloads the exit list, skipping the lines that aren't IPs
+    async fn download(&self) -> anyhow::Result<HashSet<IpAddr>> {
+        let request = self.agent.get(&self.list_url);
+        let list =
+            web::block(move || -> anyhow::Result<String> { Ok(request.call()?.into_string()?) })
+                .await??;
+
+        Ok(list
+            .lines()
+            .filter_map(|line| line.trim().parse().ok())
+            .collect())
+    }
+}
+
+/// Keeps the exit list fresh. If a download fails, we keep the list we had
+pub fn spawn_refresher<B: ChainBackend>(data: web::Data<AppState<B>>) {
+    actix::spawn(async move {
+        loop {
+            let Some(tor) = &data.tor else {
+                return;
+            };
+
+            match tor.download().await {
+                Ok(exits) => {
+                    info!("got {} Tor exits from {}", exits.len(), tor.list_url);
+                    *tor.exits.write().unwrap() = exits;
+                }
+                Err(e) => warn!("couldn't download the Tor exits: {e}"),
+            }
+
+            actix::clock::sleep(tor.refresh).await;
+        }
+    });
+}
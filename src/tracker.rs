@@ -0,0 +1,95 @@
+//SPDX-License-Identifier: MIT
+
+//! Keeps track of the transactions we broadcast, so we know when they confirm. The tracker
+//! doesn't talk to the network itself, someone (like our zmq listener) has to tell it about
+//! new blocks and transactions.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use bitcoin::Block;
+use bitcoin::BlockHash;
+use bitcoin::Txid;
+
+/// Where one of our payouts is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayoutStatus {
+    /// We broadcast it, but haven't seen it since
+    Broadcast,
+    /// Our node has it in its mempool
+    InMempool,
+    /// It's in a block
+    Confirmed { block_hash: BlockHash, height: u32 },
+}
+
+#[derive(Default)]
+struct TrackerState {
+    payouts: HashMap<Txid, PayoutStatus>,
+    /// The height of the last block we've seen
+    tip: u32,
+}
+
+#[derive(Default)]
+pub struct PayoutTracker {
+    state: Mutex<TrackerState>,
+}
+
+impl PayoutTracker {
+    /// Starts tracking a transaction we've just broadcast
+    pub fn track(&self, txid: Txid) {
+        self.state
+            .lock()
+            .unwrap()
+            .payouts
+            .insert(txid, PayoutStatus::Broadcast);
+    }
+
+    /// Returns the status of `txid` and how many confirmations it has, if we know about it
+    pub fn status(&self, txid: &Txid) -> Option<(PayoutStatus, u32)> {
+        let state = self.state.lock().unwrap();
+        let status = *state.payouts.get(txid)?;
+
+        let confirmations = match status {
+            PayoutStatus::Confirmed { height, .. } => state.tip.saturating_sub(height) + 1,
+            _ => 0,
+        };
+
+        Some((status, confirmations))
+    }
+
+    /// A transaction made into our node's mempool
+    pub fn transaction_seen(&self, txid: &Txid) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(status @ PayoutStatus::Broadcast) = state.payouts.get_mut(txid) {
+            *status = PayoutStatus::InMempool;
+        }
+    }
+
+    /// A new block was connected to our node's best chain
+    pub fn block_connected(&self, block: &Block) {
+        // only blocks older than BIP34 don't have the height in the coinbase
+        let Ok(height) = block.bip34_block_height() else {
+            return;
+        };
+        let height = height as u32;
+        let block_hash = block.block_hash();
+
+        let mut state = self.state.lock().unwrap();
+
+        // if this block isn't higher than our tip, there was a reorg, and anything confirmed
+        // in the blocks we lost is back to the mempool
+        for status in state.payouts.values_mut() {
+            if matches!(status, PayoutStatus::Confirmed { height: h, .. } if *h >= height) {
+                *status = PayoutStatus::InMempool;
+            }
+        }
+
+        for tx in &block.txdata {
+            if let Some(status) = state.payouts.get_mut(&tx.txid()) {
+                *status = PayoutStatus::Confirmed { block_hash, height };
+            }
+        }
+
+        state.tip = height;
+    }
+}
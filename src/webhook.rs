@@ -0,0 +1,379 @@
+//SPDX-License-Identifier: MIT
+
+//! Callbacks for payouts. /send/ users may give us a `callback_url`, and once their payout has
+//! enough confirmations, or got replaced, we post what happened to it there. CI pipelines funding
+//! test wallets wait for that instead of polling us.
+//!
+//! The body is signed with our webhook secret, as `X-Faucet-Signature: sha256=<hex>` holding the
+//! HMAC-SHA256 of the body, so receivers know it came from us. We keep the callbacks in memory,
+//! so a restart forgets them.
+//!
+//! Callbacks only go to public hosts, or anyone could have us post to bitcoind or to something
+//! else on our network. Hosts are looked up again when we post, so one that pointed somewhere
+//! public when we took the url can't point to us by then.
+
+use std::io;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+use actix_web::web;
+use bitcoin::hashes::hmac::Hmac;
+use bitcoin::hashes::hmac::HmacEngine;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::HashEngine;
+use bitcoin::BlockHash;
+use bitcoin::Txid;
+use bitcoincore_rpc::jsonrpc::serde_json;
+use serde::Serialize;
+use tracing::warn;
+use url::Url;
+
+use crate::api::AppState;
+use crate::api::Error;
+use crate::api::Payout;
+use crate::backend::ChainBackend;
+use crate::backend::TxState;
+
+/// How often we look at the payouts we have to call back about
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// For how long we wait for a payout to confirm before forgetting its callback
+const GIVE_UP_AFTER: Duration = Duration::from_secs(24 * 3_600);
+
+/// How many times we try to post a callback before giving up on it
+const MAX_ATTEMPTS: u32 = 5;
+
+/// The longest callback url we take
+const MAX_URL_LENGTH: usize = 2_048;
+
+/// What happened to a payout, as we post it
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event {
+    Confirmed {
+        block_hash: BlockHash,
+        height: u32,
+        confirmations: u32,
+    },
+    Replaced {
+        replaced_by: Option<Txid>,
+    },
+}
+
+/// The body of a callback
+#[derive(Debug, Serialize)]
+struct Callback {
+    #[serde(flatten)]
+    event: Event,
+    txid: Txid,
+    address: String,
+    /// In sats
+    amount: u64,
+    /// When we sent this, in seconds since the epoch, so receivers can drop old ones
+    sent_at: u64,
+}
+
+struct Watched {
+    url: String,
+    txid: Txid,
+    payout: Payout,
+    since: Instant,
+    attempts: u32,
+}
+
+pub struct Webhooks {
+    /// The key we sign callbacks with
+    secret: String,
+    /// How many confirmations a payout needs before we call back
+    confirmations: u32,
+    agent: ureq::Agent,
+    watched: Mutex<Vec<Watched>>,
+}
+
+impl Webhooks {
+    pub fn new(secret: String, confirmations: u32) -> Self {
+        Self {
+            secret,
+            confirmations,
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(10))
+                .redirects(0)
+                .resolver(resolve_public)
+                .build(),
+            watched: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn confirmations(&self) -> u32 {
+        self.confirmations
+    }
+
+    /// Calls back `payout.callback`, if set, once `txid` has enough confirmations
+    pub fn watch(&self, txid: Txid, payout: &Payout) {
+        let Some(url) = &payout.callback else {
+            return;
+        };
+
+        self.watched.lock().unwrap().push(Watched {
+            url: url.clone(),
+            txid,
+            payout: payout.clone(),
+            since: Instant::now(),
+            attempts: 0,
+        });
+    }
+
+    /// Follows the payouts in `txid` to `replacement`, which pays them instead now
+    pub fn replace(&self, txid: Txid, replacement: Txid) {
+        for watched in self.watched.lock().unwrap().iter_mut() {
+            if watched.txid == txid {
+                watched.txid = replacement;
+            }
+        }
+    }
+
+    /// The signature receivers check a callback's `body` against
+    fn sign(&self, body: &str) -> String {
+        let mut engine = HmacEngine::<sha256::Hash>::new(self.secret.as_bytes());
+        engine.input(body.as_bytes());
+        format!("sha256={}", Hmac::<sha256::Hash>::from_engine(engine))
+    }
+
+    /// Posts `event` to `watched`'s callback url
+    fn post(&self, watched: &Watched, event: Event) -> Result<(), String> {
+        let callback = Callback {
+            event,
+            txid: watched.txid,
+            address: watched.payout.address.to_string(),
+            amount: watched.payout.amount.to_sat(),
+            sent_at: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        let body = serde_json::to_string(&callback).expect("callbacks serialize");
+
+        self.agent
+            .post(&watched.url)
+            .set("Content-Type", "application/json")
+            .set("X-Faucet-Signature", &self.sign(&body))
+            .send_string(&body)
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Whether we're done with `watched`, either because we called back or gave up
+    fn check<B: ChainBackend>(&self, data: &AppState<B>, watched: &mut Watched) -> bool {
+        let event = match data.backend.transaction_status(&watched.txid) {
+            Ok(TxState::Confirmed {
+                block_hash,
+                height,
+                confirmations,
+            }) if confirmations >= self.confirmations => Event::Confirmed {
+                block_hash,
+                height,
+                confirmations,
+            },
+            Ok(TxState::Replaced { by }) => Event::Replaced { replaced_by: by },
+            // our wallet may broadcast evicted transactions again, so they're still pending
+            Ok(_) => return watched.since.elapsed() > GIVE_UP_AFTER,
+            Err(e) => {
+                warn!(
+                    "couldn't check payout {} for its callback: {e}",
+                    watched.txid
+                );
+                return watched.since.elapsed() > GIVE_UP_AFTER;
+            }
+        };
+
+        watched.attempts += 1;
+        match self.post(watched, event) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!(
+                    "couldn't call back {} about payout {}: {e}",
+                    watched.url, watched.txid
+                );
+                watched.attempts >= MAX_ATTEMPTS
+            }
+        }
+    }
+}
+
+/// Checks `url` is something we may post a callback to, an http or https url of a public host
+pub async fn check_url(url: String) -> Result<String, Error> {
+    if url.len() > MAX_URL_LENGTH {
+        return Err(Error::InvalidRequest("the callback url is too long".into()));
+    }
+    let parsed = Url::parse(&url)
+        .map_err(|e| Error::InvalidRequest(format!("invalid callback url: {e}")))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(Error::InvalidRequest(
+            "the callback url must be http or https".into(),
+        ));
+    }
+    let (Some(host), Some(port)) = (parsed.host_str(), parsed.port_or_known_default()) else {
+        return Err(Error::InvalidRequest("the callback url has no host".into()));
+    };
+
+    let netloc = format!("{host}:{port}");
+    web::block(move || resolve_public(&netloc))
+        .await
+        .map_err(|_| Error::JsonRpcNotWorking)?
+        .map_err(|e| Error::InvalidRequest(format!("we can't call back {host}: {e}")))?;
+
+    Ok(url)
+}
+
+/// Looks up `netloc`, a `host:port`, refusing hosts with addresses that aren't public
+fn resolve_public(netloc: &str) -> io::Result<Vec<SocketAddr>> {
+    let addresses = netloc.to_socket_addrs()?.collect::<Vec<_>>();
+    if addresses.iter().any(|address| !is_public(address.ip())) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "it isn't a public host",
+        ));
+    }
+
+    Ok(addresses)
+}
+
+/// Whether `ip` is out on the internet, rather than on our machine or our network
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            // 100.64.0.0/10 is for carrier-grade NATs
+            let shared = a == 100 && (b & 0xc0) == 64;
+
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || shared)
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let first = ip.segments()[0];
+            // fc00::/7 are the unique local addresses, IPv6's private ones, and fe80::/10 the
+            // link-local ones
+            let unique_local = (first & 0xfe00) == 0xfc00;
+            let link_local = (first & 0xffc0) == 0xfe80;
+
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || unique_local
+                || link_local)
+        }
+    }
+}
+
+/// Periodically calls back about the payouts that confirmed or got replaced
+pub fn spawn_tracker<B: ChainBackend>(data: web::Data<AppState<B>>) {
+    actix::spawn(async move {
+        loop {
+            actix::clock::sleep(CHECK_INTERVAL).await;
+
+            let Some(webhooks) = &data.webhooks else {
+                return;
+            };
+
+            // we don't hold the lock while talking to the backend and the receivers, so
+            // payouts made meanwhile can still be watched
+            let mut watched = std::mem::take(&mut *webhooks.watched.lock().unwrap());
+            watched.retain_mut(|watched| !webhooks.check(&data, watched));
+            webhooks.watched.lock().unwrap().extend(watched);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn refused(url: &str) -> bool {
+        matches!(
+            check_url(url.to_string()).await,
+            Err(Error::InvalidRequest(_))
+        )
+    }
+
+    #[actix_web::test]
+    async fn takes_public_hosts() {
+        let url = "https://1.1.1.1:8443/callback?wallet=ci";
+        assert_eq!(check_url(url.to_string()).await.unwrap(), url);
+        assert!(check_url("http://[2606:4700:4700::1111]/".into())
+            .await
+            .is_ok());
+    }
+
+    #[actix_web::test]
+    async fn refuses_our_own_network() {
+        for url in [
+            "http://127.0.0.1:38332/",
+            "http://localhost:8080/",
+            "http://10.0.0.1/",
+            "http://172.16.5.4/",
+            "http://192.168.1.1/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://100.64.0.1/",
+            "http://0.0.0.0/",
+            "http://[::1]/",
+            "http://[::]/",
+            "http://[fe80::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:127.0.0.1]/",
+        ] {
+            assert!(refused(url).await, "{url} should be refused");
+        }
+    }
+
+    #[actix_web::test]
+    async fn refuses_other_urls() {
+        assert!(refused("ftp://1.1.1.1/").await);
+        assert!(refused("not a url").await);
+        assert!(refused("unix:/run/faucet.sock").await);
+        assert!(refused(&format!("https://1.1.1.1/{}", "a".repeat(MAX_URL_LENGTH))).await);
+    }
+
+    #[test]
+    fn resolves_public_hosts_only() {
+        assert_eq!(
+            resolve_public("1.1.1.1:443").unwrap(),
+            vec!["1.1.1.1:443".parse().unwrap()]
+        );
+        assert!(resolve_public("127.0.0.1:443").is_err());
+        assert!(resolve_public("localhost:443").is_err());
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231's second test case
+        let webhooks = Webhooks::new("Jefe".into(), 1);
+        assert_eq!(
+            webhooks.sign("what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn signatures_depend_on_the_secret_and_body() {
+        let webhooks = Webhooks::new("secret".into(), 1);
+        let other = Webhooks::new("other secret".into(), 1);
+
+        assert_eq!(webhooks.sign("{}"), webhooks.sign("{}"));
+        assert_ne!(webhooks.sign("{}"), webhooks.sign("{ }"));
+        assert_ne!(webhooks.sign("{}"), other.sign("{}"));
+    }
+}
@@ -0,0 +1,287 @@
+//SPDX-License-Identifier: MIT
+
+//! Just enough of a blocking websocket client (RFC 6455) to talk to Nostr relays: we connect,
+//! send text messages and read text messages back. `wss://` urls go through rustls, trusting
+//! the usual web roots.
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result;
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcoin::base64::engine::general_purpose::STANDARD;
+use bitcoin::base64::Engine;
+use bitcoin::hashes::sha1;
+use bitcoin::hashes::Hash;
+use rustls::ClientConnection;
+use rustls::OwnedTrustAnchor;
+use rustls::RootCertStore;
+use rustls::ServerName;
+use rustls::StreamOwned;
+
+/// How long we wait on the relay before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest message we accept, relays shouldn't need more for what we ask
+const MAX_MESSAGE: usize = 1 << 20;
+
+/// What the server appends to our key before hashing it, to show it speaks websocket
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+pub struct WebSocket {
+    stream: BufReader<Stream>,
+}
+
+fn tls_config() -> Arc<rustls::ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+
+    Arc::new(
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    )
+}
+
+impl WebSocket {
+    /// Connects to `url`, which must be a `ws://` or `wss://` url
+    pub fn connect(url: &str) -> Result<Self> {
+        let (tls, rest) = if let Some(rest) = url.strip_prefix("wss://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("ws://") {
+            (false, rest)
+        } else {
+            return Err(Error::new(ErrorKind::InvalidInput, "not a websocket url"));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => rest.split_at(slash),
+            None => (rest, "/"),
+        };
+        let host = match authority.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => host,
+            _ => authority,
+        };
+        let address = match authority.rsplit_once(':') {
+            Some((_, port)) if port.parse::<u16>().is_ok() => authority.to_string(),
+            _ if tls => format!("{authority}:443"),
+            _ => format!("{authority}:80"),
+        };
+
+        let tcp = TcpStream::connect(address)?;
+        tcp.set_read_timeout(Some(TIMEOUT))?;
+        tcp.set_write_timeout(Some(TIMEOUT))?;
+
+        let stream = if tls {
+            let name =
+                ServerName::try_from(host).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            let connection = ClientConnection::new(tls_config(), name).map_err(Error::other)?;
+            Stream::Tls(Box::new(StreamOwned::new(connection, tcp)))
+        } else {
+            Stream::Plain(tcp)
+        };
+
+        let mut socket = Self {
+            stream: BufReader::new(stream),
+        };
+        socket.handshake(authority, path)?;
+
+        Ok(socket)
+    }
+
+    fn handshake(&mut self, authority: &str, path: &str) -> Result<()> {
+        let key = STANDARD.encode(bitcoin::secp256k1::rand::random::<[u8; 16]>());
+        let request = format!(
+            "GET {path} HTTP/1.1\r\nHost: {authority}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+        );
+        self.stream.get_mut().write_all(request.as_bytes())?;
+
+        let mut status = String::new();
+        self.stream.read_line(&mut status)?;
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("relay answered {}", status.trim()),
+            ));
+        }
+
+        let expected = STANDARD.encode(sha1::Hash::hash(format!("{key}{ACCEPT_GUID}").as_bytes()));
+        let mut accepted = false;
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line)? == 0 {
+                return Err(ErrorKind::UnexpectedEof.into());
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+
+            if let Some((name, value)) = line.split_once(':') {
+                if name.eq_ignore_ascii_case("sec-websocket-accept") && value.trim() == expected {
+                    accepted = true;
+                }
+            }
+        }
+
+        if !accepted {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "relay didn't accept our websocket key",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<()> {
+        let mut frame = vec![0x80 | opcode];
+
+        // clients must mask what they send
+        let mask_bit = 0x80;
+        match payload.len() {
+            len @ 0..=125 => frame.push(mask_bit | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(mask_bit | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(mask_bit | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+
+        let mask = bitcoin::secp256k1::rand::random::<[u8; 4]>();
+        frame.extend_from_slice(&mask);
+        frame.extend(
+            payload
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| byte ^ mask[i % 4]),
+        );
+
+        let stream = self.stream.get_mut();
+        stream.write_all(&frame)?;
+        stream.flush()
+    }
+
+    /// Sends `text` as a single message
+    pub fn send(&mut self, text: &str) -> Result<()> {
+        self.write_frame(OP_TEXT, text.as_bytes())
+    }
+
+    /// Reads the next text message, answering pings on the way
+    pub fn receive(&mut self) -> Result<String> {
+        let mut message = Vec::new();
+
+        loop {
+            let mut header = [0; 2];
+            self.stream.read_exact(&mut header)?;
+            let fin = header[0] & 0x80 != 0;
+            let opcode = header[0] & 0x0f;
+            let masked = header[1] & 0x80 != 0;
+
+            let len = match header[1] & 0x7f {
+                126 => {
+                    let mut len = [0; 2];
+                    self.stream.read_exact(&mut len)?;
+                    u16::from_be_bytes(len) as usize
+                }
+                127 => {
+                    let mut len = [0; 8];
+                    self.stream.read_exact(&mut len)?;
+                    u64::from_be_bytes(len) as usize
+                }
+                len => len as usize,
+            };
+            if message.len() + len > MAX_MESSAGE {
+                return Err(Error::new(ErrorKind::InvalidData, "message too large"));
+            }
+
+            let mask = if masked {
+                let mut mask = [0; 4];
+                self.stream.read_exact(&mut mask)?;
+                Some(mask)
+            } else {
+                None
+            };
+
+            let mut payload = vec![0; len];
+            self.stream.read_exact(&mut payload)?;
+            if let Some(mask) = mask {
+                payload
+                    .iter_mut()
+                    .enumerate()
+                    .for_each(|(i, byte)| *byte ^= mask[i % 4]);
+            }
+
+            match opcode {
+                OP_TEXT | OP_CONTINUATION => {
+                    message.extend_from_slice(&payload);
+                    if fin {
+                        return String::from_utf8(message)
+                            .map_err(|e| Error::new(ErrorKind::InvalidData, e));
+                    }
+                }
+                OP_PING => self.write_frame(OP_PONG, &payload)?,
+                OP_CLOSE => return Err(ErrorKind::ConnectionAborted.into()),
+                // pongs and binary messages, which relays don't send
+                _ => {}
+            }
+        }
+    }
+
+    /// Tells the relay we're leaving
+    pub fn close(mut self) {
+        let _ = self.write_frame(OP_CLOSE, &[]);
+    }
+}
@@ -0,0 +1,341 @@
+//SPDX-License-Identifier: MIT
+
+//! Our WebSocket API, at /ws, for frontends and bots that want to follow their payouts instead
+//! of polling for them. (Not to be confused with [crate::websocket], our client for Nostr
+//! relays.)
+//!
+//! Clients send the same json /send/ takes, with an optional `id` of their choosing, and we
+//! push what happens to that payout as it happens: `queued` or `hold_invoice` if it has to wait,
+//! then `broadcast`, and finally `confirmed` or `replaced`. Every update carries the request's
+//! `id`, so a client may have many payouts going over the same socket. Requests that don't go
+//! through get a `failed` update, with the error /send/ would answer with.
+
+use std::time::Duration;
+use std::time::Instant;
+
+use actix::Actor;
+use actix::ActorContext;
+use actix::Addr;
+use actix::AsyncContext;
+use actix::Handler;
+use actix::Message;
+use actix::StreamHandler;
+use actix_web::web;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use actix_web_actors::ws;
+use bitcoin::BlockHash;
+use bitcoin::Txid;
+use bitcoincore_rpc::jsonrpc::serde_json;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::api::blocking;
+use crate::api::pay_request;
+use crate::api::AppState;
+use crate::api::Error;
+use crate::api::SendMoney;
+use crate::backend::ChainBackend;
+use crate::backend::TxState;
+#[cfg(feature = "lightning")]
+use crate::hold::HoldStatus;
+use crate::queue::QueueStatus;
+use crate::ratelimit;
+use crate::response::ErrorDetail;
+use crate::response::SendResponse;
+
+/// How often we ping clients
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long a client may go without answering our pings before we hang up
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// How often we check on a payout we're following
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// What clients send us
+#[derive(Deserialize)]
+struct Request {
+    /// Whatever the client wants to tell this payout's updates apart with
+    id: Option<String>,
+    #[serde(flatten)]
+    payout: SendMoney,
+}
+
+/// What happened to a payout
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Update {
+    /// Waiting in the payout queue
+    Queued {
+        request_id: String,
+    },
+    /// Waiting for the client to pay this hold invoice
+    #[cfg(feature = "lightning")]
+    HoldInvoice {
+        invoice: String,
+    },
+    Broadcast {
+        txid: Txid,
+    },
+    Confirmed {
+        txid: Txid,
+        block_hash: BlockHash,
+        height: u32,
+        confirmations: u32,
+    },
+    /// Something spending the same coins took its place
+    Replaced {
+        txid: Txid,
+        replaced_by: Option<Txid>,
+    },
+    Failed {
+        error: ErrorDetail,
+    },
+}
+
+impl Update {
+    /// A queued or hold-invoice gated payout we couldn't make, for `reason`
+    fn payout_failed(reason: String) -> Self {
+        Update::Failed {
+            error: ErrorDetail {
+                code: "payout_failed",
+                message: reason,
+            },
+        }
+    }
+}
+
+impl From<Error> for Update {
+    fn from(error: Error) -> Self {
+        Update::Failed {
+            error: ErrorDetail {
+                code: error.code(),
+                message: error.message(),
+            },
+        }
+    }
+}
+
+/// An update about the payout the client called `id`, for the socket to send
+#[derive(Debug, Serialize, Message)]
+#[rtype(result = "()")]
+struct Push {
+    id: Option<String>,
+    #[serde(flatten)]
+    update: Update,
+}
+
+/// A client's connection
+pub struct PayoutSocket<B: ChainBackend> {
+    /// The request that opened the socket, telling who the client is
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    /// When the client last showed it's still there
+    heartbeat: Instant,
+}
+
+impl<B: ChainBackend> Actor for PayoutSocket<B> {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |socket, ctx| {
+            if socket.heartbeat.elapsed() > CLIENT_TIMEOUT {
+                ctx.stop();
+                return;
+            }
+            ctx.ping(b"");
+        });
+    }
+}
+
+impl<B: ChainBackend> Handler<Push> for PayoutSocket<B> {
+    type Result = ();
+
+    fn handle(&mut self, push: Push, ctx: &mut Self::Context) {
+        ctx.text(serde_json::to_string(&push).expect("updates serialize"));
+    }
+}
+
+impl<B: ChainBackend> StreamHandler<Result<ws::Message, ws::ProtocolError>> for PayoutSocket<B> {
+    fn handle(&mut self, message: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let message = match message {
+            Ok(message) => message,
+            Err(_) => {
+                ctx.stop();
+                return;
+            }
+        };
+        self.heartbeat = Instant::now();
+
+        match message {
+            ws::Message::Ping(bytes) => ctx.pong(&bytes),
+            ws::Message::Text(text) => {
+                let request = match serde_json::from_str::<Request>(&text) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        let push = Push {
+                            id: None,
+                            update: Error::InvalidRequest(e.to_string()).into(),
+                        };
+                        ctx.text(serde_json::to_string(&push).expect("updates serialize"));
+                        return;
+                    }
+                };
+
+                // each request counts like a /send/ request would
+                if let Err(e) = ratelimit::take_request(&self.req, &self.data) {
+                    let push = Push {
+                        id: request.id,
+                        update: e.into(),
+                    };
+                    ctx.text(serde_json::to_string(&push).expect("updates serialize"));
+                    return;
+                }
+
+                actix::spawn(follow(
+                    self.req.clone(),
+                    request,
+                    self.data.clone(),
+                    ctx.address(),
+                ));
+            }
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Pays `request` and tells `socket` how it goes, until it confirms, gets replaced or fails,
+/// or the client goes away
+async fn follow<B: ChainBackend>(
+    req: HttpRequest,
+    request: Request,
+    data: web::Data<AppState<B>>,
+    socket: Addr<PayoutSocket<B>>,
+) {
+    let Request { id, payout } = request;
+    let push = |update: Update| {
+        socket.do_send(Push {
+            id: id.clone(),
+            update,
+        })
+    };
+
+    let txid = match pay_request(req, payout, data.clone()).await {
+        Ok(SendResponse::Sent { txid, .. }) => txid,
+        Ok(SendResponse::Queued { request_id }) => {
+            push(Update::Queued {
+                request_id: request_id.clone(),
+            });
+            match wait_for_queue(&data, &request_id, &socket).await {
+                Some(Ok(txid)) => txid,
+                Some(Err(reason)) => return push(Update::payout_failed(reason)),
+                None => return,
+            }
+        }
+        #[cfg(feature = "lightning")]
+        Ok(SendResponse::HoldInvoice {
+            invoice,
+            payment_hash,
+            ..
+        }) => {
+            push(Update::HoldInvoice { invoice });
+            match wait_for_hold_invoice(&data, &payment_hash, &socket).await {
+                Some(Ok(txid)) => txid,
+                Some(Err(reason)) => return push(Update::payout_failed(reason)),
+                None => return,
+            }
+        }
+        Err(e) => return push(e.into()),
+    };
+    push(Update::Broadcast { txid });
+
+    while socket.connected() {
+        actix::clock::sleep(POLL_INTERVAL).await;
+
+        match blocking(&data, move |data| data.backend.transaction_status(&txid)).await {
+            Ok(TxState::Confirmed {
+                block_hash,
+                height,
+                confirmations,
+            }) => {
+                return push(Update::Confirmed {
+                    txid,
+                    block_hash,
+                    height,
+                    confirmations,
+                })
+            }
+            Ok(TxState::Replaced { by }) => {
+                return push(Update::Replaced {
+                    txid,
+                    replaced_by: by,
+                })
+            }
+            Ok(_) => {}
+            Err(e) => warn!("couldn't check payout {txid} for a websocket client: {e}"),
+        }
+    }
+}
+
+/// Waits for the queued request `id` to be paid, returning its txid or why it failed. Returns
+/// nothing if the client went away, or we forgot about the request
+async fn wait_for_queue<B: ChainBackend>(
+    data: &AppState<B>,
+    id: &str,
+    socket: &Addr<PayoutSocket<B>>,
+) -> Option<Result<Txid, String>> {
+    let queue = data.payout_queue.as_ref()?;
+    while socket.connected() {
+        actix::clock::sleep(POLL_INTERVAL).await;
+
+        match queue.status(id)? {
+            QueueStatus::Queued { .. } => {}
+            QueueStatus::Paid { txid, .. } => return Some(Ok(txid)),
+            QueueStatus::Failed(reason) => return Some(Err(reason)),
+        }
+    }
+
+    None
+}
+
+/// Waits for the payout gated by the hold invoice paying to `payment_hash` to be made, like
+/// [wait_for_queue]
+#[cfg(feature = "lightning")]
+async fn wait_for_hold_invoice<B: ChainBackend>(
+    data: &AppState<B>,
+    payment_hash: &bitcoin::hashes::sha256::Hash,
+    socket: &Addr<PayoutSocket<B>>,
+) -> Option<Result<Txid, String>> {
+    let payouts = data.hold_payouts.as_ref()?;
+    while socket.connected() {
+        actix::clock::sleep(POLL_INTERVAL).await;
+
+        match payouts.status(payment_hash)? {
+            HoldStatus::WaitingForPayment => {}
+            HoldStatus::Paid(txid) => return Some(Ok(txid)),
+            HoldStatus::Failed(reason) => return Some(Err(reason)),
+        }
+    }
+
+    None
+}
+
+/// Upgrades the request to a websocket, for the client to send payout requests over
+pub async fn connect<B: ChainBackend>(
+    req: HttpRequest,
+    stream: web::Payload,
+    data: web::Data<AppState<B>>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let socket = PayoutSocket {
+        req: req.clone(),
+        data,
+        heartbeat: Instant::now(),
+    };
+    ws::start(socket, &req, stream)
+}
@@ -0,0 +1,98 @@
+//SPDX-License-Identifier: MIT
+
+//! Listens to bitcoind's zmq notifications and feeds them to our [PayoutTracker], this way we
+//! learn about confirmations as soon as they happen, without polling the RPC.
+//!
+//! bitcoind must be started with `-zmqpubrawblock` and, optionally, `-zmqpubhashtx`.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use bitcoin::consensus::deserialize;
+use bitcoin::hashes::Hash;
+use bitcoin::Block;
+use bitcoin::Txid;
+use tracing::warn;
+use zeromq::Socket;
+use zeromq::SocketRecv;
+use zeromq::SubSocket;
+
+use crate::tracker::PayoutTracker;
+
+/// How long we wait before reconnecting if our subscription fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Subscribes to `topics` on `endpoint` and handles messages until the connection fails
+async fn subscribe(
+    endpoint: &str,
+    topics: &[&str],
+    tracker: &PayoutTracker,
+) -> zeromq::ZmqResult<()> {
+    let mut socket = SubSocket::new();
+    socket.connect(endpoint).await?;
+
+    for topic in topics {
+        socket.subscribe(topic).await?;
+    }
+
+    loop {
+        let message = socket.recv().await?;
+        let (Some(topic), Some(body)) = (message.get(0), message.get(1)) else {
+            continue;
+        };
+
+        match topic.as_ref() {
+            b"rawblock" => match deserialize::<Block>(body) {
+                Ok(block) => tracker.block_connected(&block),
+                Err(e) => warn!("zmq sent us an invalid block: {e}"),
+            },
+            b"hashtx" => {
+                // zmq sends hashes in the same byte order we use for displaying them
+                let Ok(mut hash) = <[u8; 32]>::try_from(body.as_ref()) else {
+                    continue;
+                };
+                hash.reverse();
+                tracker.transaction_seen(&Txid::from_byte_array(hash));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Spawns a task that listens for new blocks (and transactions, if `hashtx_endpoint` is given)
+/// forever, reconnecting if needed.
+pub fn spawn_listener(
+    rawblock_endpoint: String,
+    hashtx_endpoint: Option<String>,
+    tracker: Arc<PayoutTracker>,
+) {
+    // bitcoind may publish both topics in the same endpoint
+    let same_endpoint = hashtx_endpoint.as_ref() == Some(&rawblock_endpoint);
+
+    let block_tracker = tracker.clone();
+    actix::spawn(async move {
+        let topics: &[&str] = if same_endpoint {
+            &["rawblock", "hashtx"]
+        } else {
+            &["rawblock"]
+        };
+
+        loop {
+            if let Err(e) = subscribe(&rawblock_endpoint, topics, &block_tracker).await {
+                warn!("zmq subscription to {rawblock_endpoint} failed: {e}");
+            }
+            actix::clock::sleep(RECONNECT_DELAY).await;
+        }
+    });
+
+    if let (Some(endpoint), false) = (hashtx_endpoint, same_endpoint) {
+        actix::spawn(async move {
+            loop {
+                if let Err(e) = subscribe(&endpoint, &["hashtx"], &tracker).await {
+                    warn!("zmq subscription to {endpoint} failed: {e}");
+                }
+                actix::clock::sleep(RECONNECT_DELAY).await;
+            }
+        });
+    }
+}